@@ -0,0 +1,52 @@
+// Runs the maliciously secure scheme end to end over a loopback TCP
+// connection: a server thread, and clients that register, transact
+// concurrently and settle through `net::Connection`.
+
+use std::net::TcpListener;
+use std::thread;
+use checkout::lib_mal::{Client, Malicious, Server};
+use checkout::net::{self, Connection};
+use checkout::scheme::{DEFAULT_ACCOUNT, DEFAULT_MERCHANT};
+use checkout::SchemeClient;
+
+const N_USERS: u32 = 5;
+const N_TXS: u32 = 100;
+
+fn main() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || net::serve::<Malicious>(listener, Server::new()));
+
+    let mut conn = Connection::<Malicious>::connect(addr).unwrap();
+
+    let mut clients = Vec::new();
+    for i in 0..N_USERS {
+        let client = <Client as SchemeClient>::new(rand::random());
+        let uid = conn.register(&client).unwrap();
+        assert_eq!(uid, i);
+        clients.push(client);
+    }
+    for (uid, client) in clients.iter_mut().enumerate() {
+        conn.update_state(client, uid as u32).unwrap();
+    }
+
+    // Every shopper transacts at the same time, from their own connection
+    let shoppers: Vec<_> = clients.into_iter().enumerate().map(|(uid, mut client)| {
+        thread::spawn(move || {
+            let mut conn = Connection::<Malicious>::connect(addr).unwrap();
+            for _ in 0..N_TXS / N_USERS {
+                conn.transact(&mut client, uid as u32, DEFAULT_MERCHANT, &[10]).unwrap();
+            }
+            client
+        })
+    }).collect();
+    let mut clients: Vec<Client> = shoppers.into_iter().map(|t| t.join().unwrap()).collect();
+
+    // Every user settles from their own connection
+    for (uid, client) in clients.iter_mut().enumerate() {
+        let mut conn = Connection::<Malicious>::connect(addr).unwrap();
+        conn.settle(client, uid as u32, DEFAULT_ACCOUNT).unwrap();
+    }
+
+    println!("{} transactions between {} users settled over {}", N_TXS, N_USERS, addr);
+}
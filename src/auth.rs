@@ -0,0 +1,86 @@
+// Users sign the requests that only they may make with a key they register
+// along with their barcode, so no one else can make them in the user's name.
+// Starting a transaction moves points out of the shopper's balance, so the
+// shopper signs the commitment that opens it. Acknowledging receipts deletes
+// them from the server, so the user signs how far they have processed.
+//
+// Signed messages start with a domain naming the request and include the
+// server's ID, so a signature is only good for one kind of request on one
+// server.
+
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use rand::rngs::OsRng;
+use crate::error::{CheckoutError, Result};
+use crate::scheme::{Com, MerchantId, ServerId};
+use crate::secret::Secret;
+
+// A user's verifying key, registered with the server
+pub type AuthKey = [u8; 32];
+
+const TX_HELLO_DOMAIN: &[u8] = b"checkout tx hello";
+const ACK_DOMAIN: &[u8] = b"checkout receipts ack";
+
+// Output: a new signing key, and the verifying key to register
+pub fn keygen() -> (Secret<[u8; 32]>, AuthKey) {
+    let sk = SigningKey::generate(&mut OsRng);
+    (Secret::new(sk.to_bytes()), sk.verifying_key().to_bytes())
+}
+
+// Output: the verifying key for a signing key made by `keygen`
+pub fn public_key(sk: &Secret<[u8; 32]>) -> AuthKey {
+    SigningKey::from_bytes(sk.expose()).verifying_key().to_bytes()
+}
+
+// Rejects a key that is not a valid verifying key when it is registered,
+// rather than when the user's first request is checked
+pub fn check_key(pk: &AuthKey) -> Result<()> {
+    VerifyingKey::from_bytes(pk).map(|_| ()).map_err(|_| CheckoutError::InvalidPoint)
+}
+
+fn sign(sk: &Secret<[u8; 32]>, msg: &[u8]) -> Signature {
+    SigningKey::from_bytes(sk.expose()).sign(msg)
+}
+
+fn verify(pk: &AuthKey, msg: &[u8], sigma: &Signature) -> Result<()> {
+    let vk = VerifyingKey::from_bytes(pk).map_err(|_| CheckoutError::InvalidPoint)?;
+    vk.verify(msg, sigma).map_err(|_| CheckoutError::Unauthorized)
+}
+
+fn tx_hello_message(server: &ServerId, (com, uid_s, merchant): (&Com, u32, MerchantId)) -> Vec<u8> {
+    let mut msg = TX_HELLO_DOMAIN.to_vec();
+    msg.extend_from_slice(server);
+    msg.extend_from_slice(com);
+    msg.extend_from_slice(&uid_s.to_le_bytes());
+    msg.extend_from_slice(&merchant.to_le_bytes());
+    msg
+}
+
+// Input: the shopper's signing key, the server, and the commitment that
+// opens the transaction, the shopper's user ID and the merchant
+pub fn sign_tx_hello(sk: &Secret<[u8; 32]>, server: &ServerId, hello: (&Com, u32, MerchantId)) -> Signature {
+    sign(sk, &tx_hello_message(server, hello))
+}
+
+// Output: an error unless the shopper signed the start of the transaction
+pub fn verify_tx_hello(pk: &AuthKey, server: &ServerId, hello: (&Com, u32, MerchantId), sigma: &Signature) -> Result<()> {
+    verify(pk, &tx_hello_message(server, hello), sigma)
+}
+
+fn ack_message(server: &ServerId, (uid, upto): (u32, u64)) -> Vec<u8> {
+    let mut msg = ACK_DOMAIN.to_vec();
+    msg.extend_from_slice(server);
+    msg.extend_from_slice(&uid.to_le_bytes());
+    msg.extend_from_slice(&upto.to_le_bytes());
+    msg
+}
+
+// Input: the user's signing key, the server, and the user's ID and the
+// sequence number of the last receipt they have processed
+pub fn sign_ack(sk: &Secret<[u8; 32]>, server: &ServerId, ack: (u32, u64)) -> Signature {
+    sign(sk, &ack_message(server, ack))
+}
+
+// Output: an error unless the user acknowledged their receipts up to `upto`
+pub fn verify_ack(pk: &AuthKey, server: &ServerId, ack: (u32, u64), sigma: &Signature) -> Result<()> {
+    verify(pk, &ack_message(server, ack), sigma)
+}
@@ -0,0 +1,160 @@
+// Hosts a CheckOut server over TCP.
+//
+// Usage: checkout-server [mal|sh|swap] [address] [data-dir]
+// Defaults to the maliciously secure scheme on 127.0.0.1:7878. With a data
+// directory the server's state is saved there and reloaded on restart;
+// without one it is kept in memory only. The most points a transaction may
+// move can be set with the CHECKOUT_MAX_POINTS environment variable, and the
+// number of recent barcodes a shopper is not handed again with
+// CHECKOUT_EXCLUDE_RECENT. Transactions not finished within CHECKOUT_TX_TTL
+// seconds are dropped. CHECKOUT_MERCHANTS lists the merchants that issue
+// points through the server, separated by commas; their IDs are their
+// positions in the list, starting from 0. CHECKOUT_CATEGORIES lists the
+// categories of points, such as base and bonus points, the same way; every
+// transaction moves an amount of each. Points expire after
+// CHECKOUT_POINTS_LIFETIME periods of CHECKOUT_PERIOD_DAYS days each (30 by
+// default), and never if no lifetime is set.
+
+use std::env;
+use std::io;
+use std::net::TcpListener;
+use std::path::Path;
+use std::process;
+use std::time::Duration;
+use checkout::{lib_mal, lib_sh, lib_sh_swap_only, net};
+use checkout::coin::SelectionPolicy;
+use checkout::expiry::{Expiry, DEFAULT_PERIOD_LENGTH};
+use checkout::scheme::{DEFAULT_CATEGORY_NAME, DEFAULT_MAX_POINTS, DEFAULT_MERCHANT_NAME};
+use checkout::session::DEFAULT_TX_TTL;
+
+const DEFAULT_ADDR: &str = "127.0.0.1:7878";
+const DAY: u64 = 24 * 60 * 60;
+const USAGE: &str = "usage: checkout-server [mal|sh|swap] [address] [data-dir]";
+
+// Opens the server's state in `dir`, or starts with an empty in-memory server
+fn load<T>(dir: Option<&Path>, open: fn(&Path) -> io::Result<T>, new: fn() -> T) -> T {
+    match dir {
+        Some(dir) => open(dir).unwrap_or_else(|e| {
+            eprintln!("could not load state from {}: {}", dir.display(), e);
+            process::exit(1);
+        }),
+        None => new(),
+    }
+}
+
+// Output: the whole number of `unit` set in environment variable `name`, or
+// `default` if it is not set
+fn env_number(name: &str, unit: &str, default: u32) -> u32 {
+    match env::var(name) {
+        Ok(n) => n.parse().unwrap_or_else(|_| {
+            eprintln!("{} must be a whole number of {}", name, unit);
+            process::exit(2);
+        }),
+        Err(_) => default,
+    }
+}
+
+// Output: the limit set in CHECKOUT_MAX_POINTS, or the default
+fn max_points() -> u32 {
+    env_number("CHECKOUT_MAX_POINTS", "points", DEFAULT_MAX_POINTS)
+}
+
+// Output: the default selection policy, excluding as many recent barcodes as
+// set in CHECKOUT_EXCLUDE_RECENT
+fn selection_policy() -> SelectionPolicy {
+    let recent = env_number("CHECKOUT_EXCLUDE_RECENT", "transactions", 0);
+    SelectionPolicy { recent, ..SelectionPolicy::default() }
+}
+
+// Output: the names listed in the environment variable `var`, separated by
+// commas, or just `default` if it is not set
+fn names(var: &str, kind: &str, default: &str) -> Vec<String> {
+    match env::var(var) {
+        Ok(names) => {
+            let names: Vec<String> = names.split(',').map(|name| name.trim().to_string()).collect();
+            if names.iter().any(String::is_empty) {
+                eprintln!("{} must be a comma-separated list of {} names", var, kind);
+                process::exit(2);
+            }
+            names
+        }
+        Err(_) => vec![default.to_string()],
+    }
+}
+
+// Output: the time limit set in CHECKOUT_TX_TTL, or the default
+fn tx_ttl() -> Duration {
+    Duration::from_secs(env_number("CHECKOUT_TX_TTL", "seconds", DEFAULT_TX_TTL.as_secs() as u32) as u64)
+}
+
+// Output: the point lifetime set in CHECKOUT_POINTS_LIFETIME, counted in
+// periods of CHECKOUT_PERIOD_DAYS
+fn expiry() -> Expiry {
+    let days = env_number("CHECKOUT_PERIOD_DAYS", "days", (DEFAULT_PERIOD_LENGTH.as_secs() / DAY) as u32);
+    let lifetime = env::var("CHECKOUT_POINTS_LIFETIME").ok()
+        .map(|_| env_number("CHECKOUT_POINTS_LIFETIME", "periods", 0));
+    if days == 0 || lifetime == Some(0) {
+        eprintln!("CHECKOUT_PERIOD_DAYS and CHECKOUT_POINTS_LIFETIME must be at least 1");
+        process::exit(2);
+    }
+    Expiry { period_length: Duration::from_secs(days as u64 * DAY), lifetime }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let scheme = args.first().map(String::as_str).unwrap_or("mal");
+    let addr = args.get(1).map(String::as_str).unwrap_or(DEFAULT_ADDR);
+    let dir = args.get(2).map(Path::new);
+    if !matches!(scheme, "mal" | "sh" | "swap") {
+        eprintln!("{}", USAGE);
+        process::exit(2);
+    }
+    let max_points = max_points();
+    let policy = selection_policy();
+    let ttl = tx_ttl();
+    let merchants = names("CHECKOUT_MERCHANTS", "merchant", DEFAULT_MERCHANT_NAME);
+    let categories = names("CHECKOUT_CATEGORIES", "category", DEFAULT_CATEGORY_NAME);
+    let expiry = expiry();
+
+    let listener = match TcpListener::bind(addr) {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("could not listen on {}: {}", addr, e);
+            process::exit(1);
+        }
+    };
+    println!("Serving the {} scheme on {}", scheme, addr);
+
+    let res = match scheme {
+        "mal" => {
+            let mut server = load(dir, lib_mal::Server::open, lib_mal::Server::new);
+            server.set_max_points(max_points);
+            server.set_selection_policy(policy);
+            server.set_tx_ttl(ttl);
+            server.set_merchants(merchants);
+            server.set_categories(categories);
+            server.set_expiry(expiry);
+            net::serve::<lib_mal::Malicious>(listener, server)
+        }
+        "sh" => {
+            let mut server = load(dir, lib_sh::Server::open, lib_sh::Server::new);
+            server.set_max_points(max_points);
+            server.set_selection_policy(policy);
+            server.set_tx_ttl(ttl);
+            server.set_merchants(merchants);
+            server.set_categories(categories);
+            server.set_expiry(expiry);
+            net::serve::<lib_sh::SemiHonest>(listener, server)
+        }
+        _ => {
+            let mut server = load(dir, lib_sh_swap_only::Server::open, lib_sh_swap_only::Server::new);
+            server.set_selection_policy(policy);
+            server.set_tx_ttl(ttl);
+            net::serve::<lib_sh_swap_only::SwapOnly>(listener, server)
+        }
+    };
+    if let Err(e) = res {
+        eprintln!("server stopped: {}", e);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,352 @@
+// The joint coin flip that picks whose barcode a shopper uses. The client
+// commits to its share, the server replies with 32 random bytes, and the
+// client opens its commitment. Neither party can steer the result: the
+// client's share is fixed before it sees the server's, and the server's share
+// alone makes the result uniform.
+//
+// The flip picks among the users that the server's selection policy allows.
+// The policy is published with the server's state, and both parties work out
+// the allowed users from it, so the client can check that the server kept to
+// it. The only input the client cannot check itself is the list of barcodes
+// the shopper was recently handed, which comes with the server's share; the
+// client makes sure that list is no longer than the policy allows.
+//
+// The client's share is either
+//      - an index, which is added to an index drawn from the server's share,
+//        modulo the number of allowed users (the flip described in the paper), or
+//      - 32 random bytes, which are XORed with the server's share, so the
+//        result has full entropy even if one share is only partly random.
+// The number of allowed users depends on the recent barcodes, which the client
+// only learns from the server's share, so an index share is committed to as 32
+// random bytes and drawn from them once that number is known. Either way,
+// bytes are mapped to an index with rejection sampling, so every allowed user
+// is picked with exactly the same probability, whatever the other share is.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use rand::Rng;
+use serde_derive::{Serialize, Deserialize};
+use sha2::{Digest, Sha256};
+use zeroize::Zeroize;
+use crate::error::{CheckoutError, Result};
+use crate::scheme::Com;
+
+// Which kind of share a client uses
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CoinFlip {
+    #[default]
+    Index,
+    Bytes,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, Zeroize)]
+pub enum ClientShare {
+    Index([u8; 32]),    // The bytes the index is drawn from
+    Bytes([u8; 32]),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServerShare {
+    pub bytes: [u8; 32],
+    // Barcode owners the shopper was handed in its last transactions, newest
+    // last, which the policy excludes
+    pub recent: Vec<u32>,
+}
+
+// Which users the coin flip may pick. If a policy would leave no user at all,
+// as on a server with a single user, any user may be picked.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SelectionPolicy {
+    // Never hand shoppers their own barcode
+    pub exclude_self: bool,
+    // Never hand shoppers a barcode they were handed in their last `recent`
+    // transactions
+    pub recent: u32,
+    // Users whose barcodes are never handed out, such as closed accounts
+    pub inactive: Vec<u32>,
+}
+
+impl Default for SelectionPolicy {
+    fn default() -> Self {
+        SelectionPolicy { exclude_self: true, recent: 0, inactive: Vec::new() }
+    }
+}
+
+impl SelectionPolicy {
+    // A policy that lets the coin flip pick any user
+    pub fn any() -> Self {
+        SelectionPolicy { exclude_self: false, recent: 0, inactive: Vec::new() }
+    }
+
+    // Input: the shopper, the barcode owners the server says it recently
+    // handed them, and the number of users
+    // Output: the users that may not be picked, sorted, or an error if the
+    // recent list is longer than this policy allows
+    fn excluded(&self, uid_s: u32, recent: &[u32], num_users: u32) -> Result<Vec<u32>> {
+        if recent.len() > self.recent as usize {
+            return Err(CheckoutError::PolicyViolation);
+        }
+
+        let mut excluded: Vec<u32> = self.inactive.iter().chain(recent).copied()
+            .filter(|&uid| uid < num_users)
+            .collect();
+        if self.exclude_self {
+            excluded.push(uid_s);
+        }
+        excluded.sort_unstable();
+        excluded.dedup();
+
+        if excluded.len() as u32 >= num_users {
+            excluded.clear();
+        }
+        Ok(excluded)
+    }
+}
+
+impl ClientShare {
+    // Output: a fresh share of the given kind
+    pub fn random(mode: CoinFlip) -> Self {
+        match mode {
+            CoinFlip::Index => ClientShare::Index(rand::thread_rng().gen()),
+            CoinFlip::Bytes => ClientShare::Bytes(rand::thread_rng().gen()),
+        }
+    }
+
+    // Output: the commitment to this share under the opening `r`. The kind of
+    // share is committed to, so one cannot be opened as the other.
+    pub fn commit(&self, r: &[u8; 32]) -> Com {
+        let mut hasher = Sha256::new();
+        match self {
+            ClientShare::Index(c) => {
+                hasher.update([0u8]);
+                hasher.update(c);
+            }
+            ClientShare::Bytes(c) => {
+                hasher.update([1u8]);
+                hasher.update(c);
+            }
+        }
+        hasher.update(r);
+        hasher.finalize().into()
+    }
+}
+
+// The barcode owners each shopper was recently handed, kept by the server to
+// apply the `recent` part of its policy. It lives in memory only: after a
+// restart, shoppers start with no recent barcodes. Its lock is never held
+// while another lock is taken.
+#[derive(Default)]
+pub struct RecentBarcodes {
+    by_shopper: Mutex<HashMap<u32, VecDeque<u32>>>,
+}
+
+impl RecentBarcodes {
+    // Output: the last `n` barcode owners handed to shopper `uid_s`
+    pub fn get(&self, uid_s: u32, n: u32) -> Vec<u32> {
+        match self.by_shopper.lock().unwrap().get(&uid_s) {
+            Some(recent) => recent.iter().skip(recent.len().saturating_sub(n as usize)).copied().collect(),
+            None => Vec::new(),
+        }
+    }
+
+    // Remembers that shopper `uid_s` was handed the barcode of `uid_b`,
+    // keeping the last `n`
+    pub fn record(&self, uid_s: u32, uid_b: u32, n: u32) {
+        if n == 0 {
+            return;
+        }
+        let mut by_shopper = self.by_shopper.lock().unwrap();
+        let recent = by_shopper.entry(uid_s).or_default();
+        recent.push_back(uid_b);
+        while recent.len() > n as usize {
+            recent.pop_front();
+        }
+    }
+}
+
+// Input: the barcode owners the shopper was recently handed
+pub fn server_share(recent: Vec<u32>) -> ServerShare {
+    ServerShare { bytes: rand::thread_rng().gen(), recent }
+}
+
+// Maps 32 random bytes to a uniform index in [0, n). The bytes are expanded
+// into 64-bit candidates, and a candidate is only used if it falls below the
+// largest multiple of n, so that no index is more likely than another.
+fn uniform_index(seed: &[u8; 32], n: u32) -> u32 {
+    let candidates = (0u32..).map(|counter| {
+        let mut hasher = Sha256::new();
+        hasher.update(b"checkout coin flip");
+        hasher.update(seed);
+        hasher.update(counter.to_le_bytes());
+        let digest: [u8; 32] = hasher.finalize().into();
+        u64::from_le_bytes(digest[..8].try_into().unwrap())
+    });
+    first_below_limit(candidates, n)
+}
+
+// Output: the first candidate below the largest multiple of n, modulo n
+fn first_below_limit(candidates: impl IntoIterator<Item = u64>, n: u32) -> u32 {
+    let n = n as u64;
+    let limit = u64::MAX - u64::MAX % n;
+    for v in candidates {
+        if v < limit {
+            return (v % n) as u32;
+        }
+    }
+    unreachable!("every candidate was rejected")
+}
+
+// Input: both shares, the shopper, the number of registered users and the
+// server's selection policy
+// Output: the user ID whose barcode is used
+pub fn joint_index(i_c: &ClientShare, i_s: &ServerShare, uid_s: u32, num_users: u32,
+                   policy: &SelectionPolicy) -> Result<u32> {
+    if num_users == 0 {
+        return Err(CheckoutError::NoUsers);
+    }
+
+    // Pick the k-th allowed user, then step over the excluded ones
+    let excluded = policy.excluded(uid_s, &i_s.recent, num_users)?;
+    let allowed = num_users - excluded.len() as u32;
+    let k = match i_c {
+        ClientShare::Index(c) => {
+            let n = allowed as u64;
+            let k_c = uniform_index(c, allowed) as u64;
+            let k_s = uniform_index(&i_s.bytes, allowed) as u64;
+            ((k_c + k_s) % n) as u32
+        }
+        ClientShare::Bytes(c) => {
+            let mut seed = [0u8; 32];
+            for (b, (x, y)) in seed.iter_mut().zip(c.iter().zip(&i_s.bytes)) {
+                *b = x ^ y;
+            }
+            uniform_index(&seed, allowed)
+        }
+    };
+
+    let mut uid = k;
+    for e in excluded {
+        if e <= uid {
+            uid += 1;
+        } else {
+            break;
+        }
+    }
+    Ok(uid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    const N_USERS: u32 = 7;
+    const N_FLIPS: u32 = 70_000;
+    const SHOPPER: u32 = 3;
+
+    // Chi-squared values that a uniform distribution exceeds with probability
+    // 0.0001, for 6 and 5 degrees of freedom (7 and 6 allowed users)
+    const CHI_SQUARED_LIMIT_7: f64 = 27.86;
+    const CHI_SQUARED_LIMIT_6: f64 = 25.74;
+
+    // Input: the kind of client share, and the recent barcodes to send
+    // Output: both shares of a flip, drawn from `rng`
+    fn shares(rng: &mut StdRng, mode: CoinFlip, recent: Vec<u32>) -> (ClientShare, ServerShare) {
+        let i_c = match mode {
+            CoinFlip::Index => ClientShare::Index(rng.gen()),
+            CoinFlip::Bytes => ClientShare::Bytes(rng.gen()),
+        };
+        (i_c, ServerShare { bytes: rng.gen(), recent })
+    }
+
+    // Flips many coins with shares from a fixed seed, for a number of users
+    // that is not a power of two, and compares the picks with a uniform
+    // distribution over the allowed users
+    // Input: the kind of client share, the policy, and the bytes a server
+    // sends every time, if it does not draw them at random
+    fn check_uniform(mode: CoinFlip, policy: &SelectionPolicy, server_bytes: Option<[u8; 32]>) {
+        let mut rng = StdRng::seed_from_u64(17);
+        let mut counts = vec![0u32; N_USERS as usize];
+        for _ in 0..N_FLIPS {
+            let (i_c, mut i_s) = shares(&mut rng, mode, Vec::new());
+            i_s.bytes = server_bytes.unwrap_or(i_s.bytes);
+            counts[joint_index(&i_c, &i_s, SHOPPER, N_USERS, policy).unwrap() as usize] += 1;
+        }
+        assert!(!policy.exclude_self || counts[SHOPPER as usize] == 0);
+
+        let allowed = if policy.exclude_self { N_USERS - 1 } else { N_USERS };
+        let limit = if policy.exclude_self { CHI_SQUARED_LIMIT_6 } else { CHI_SQUARED_LIMIT_7 };
+        let expected = N_FLIPS as f64 / allowed as f64;
+        let chi_squared: f64 = counts.iter().enumerate()
+            .filter(|&(uid, _)| !policy.exclude_self || uid as u32 != SHOPPER)
+            .map(|(_, &c)| (c as f64 - expected).powi(2) / expected)
+            .sum();
+        assert!(chi_squared < limit, "{:?} coin flip is not uniform: {:?}, chi-squared {:.2}", mode, counts, chi_squared);
+    }
+
+    #[test]
+    fn index_flip_is_uniform() {
+        check_uniform(CoinFlip::Index, &SelectionPolicy::any(), None);
+        check_uniform(CoinFlip::Index, &SelectionPolicy::default(), None);
+    }
+
+    #[test]
+    fn bytes_flip_is_uniform() {
+        check_uniform(CoinFlip::Bytes, &SelectionPolicy::any(), None);
+        check_uniform(CoinFlip::Bytes, &SelectionPolicy::default(), None);
+    }
+
+    // A server that sends the same share every time cannot make one allowed
+    // user more likely than another, even when fewer users are allowed than
+    // are registered
+    #[test]
+    fn flip_is_uniform_for_a_fixed_server_share() {
+        for mode in [CoinFlip::Index, CoinFlip::Bytes] {
+            for bytes in [[0; 32], [0xff; 32]] {
+                check_uniform(mode, &SelectionPolicy::any(), Some(bytes));
+                check_uniform(mode, &SelectionPolicy::default(), Some(bytes));
+            }
+        }
+    }
+
+    #[test]
+    fn recent_barcodes_are_not_handed_out() {
+        let policy = SelectionPolicy { recent: 2, ..SelectionPolicy::default() };
+        let mut rng = StdRng::seed_from_u64(17);
+        let mut picks = Vec::new();
+        for mode in [CoinFlip::Index, CoinFlip::Bytes] {
+            for _ in 0..1000 {
+                let recent = picks[picks.len().saturating_sub(2)..].to_vec();
+                let (i_c, i_s) = shares(&mut rng, mode, recent.clone());
+                let uid = joint_index(&i_c, &i_s, SHOPPER, N_USERS, &policy).unwrap();
+                assert!(uid < N_USERS && uid != SHOPPER && !recent.contains(&uid));
+                picks.push(uid);
+            }
+        }
+
+        // A server may not send more recent barcodes than its policy allows
+        let (i_c, i_s) = shares(&mut rng, CoinFlip::Index, vec![0, 1, 2]);
+        assert_eq!(joint_index(&i_c, &i_s, SHOPPER, N_USERS, &policy), Err(CheckoutError::PolicyViolation));
+    }
+
+    #[test]
+    fn uniform_index_is_in_range() {
+        let mut rng = StdRng::seed_from_u64(17);
+        for n in [1, 2, 3, 7, 1000, (1 << 31) + 1, u32::MAX] {
+            for _ in 0..100 {
+                assert!(uniform_index(&rng.gen(), n) < n);
+            }
+        }
+    }
+
+    #[test]
+    fn candidates_above_limit_are_rejected() {
+        // 2^64 - 1 is 1 mod 7, so the largest multiple of 7 below it is
+        // 2^64 - 2, and that candidate and the one after it are rejected
+        assert_eq!(first_below_limit([u64::MAX - 1, u64::MAX, 9], 7), 2);
+        assert_eq!(first_below_limit([u64::MAX - 2], 7), 6);
+        // 2^64 - 1 is a multiple of 3, and is the only candidate rejected
+        assert_eq!(first_below_limit([u64::MAX, u64::MAX - 1], 3), 2);
+    }
+}
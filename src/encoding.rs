@@ -0,0 +1,144 @@
+// Serde helpers for message fields that carry group elements, scalars and
+// Merkle proofs as raw bytes. Use with `#[serde(with = "...")]`.
+//
+// Serialization writes the bytes unchanged; deserialization rejects anything
+// that is not the canonical encoding of a value, so a malformed message fails
+// at decode time rather than partway through a protocol step. Fields typed as
+// `RistrettoPoint` or `Scalar` don't need these: curve25519-dalek's own serde
+// impls already enforce canonical encodings.
+
+use curve25519_dalek::ristretto::CompressedRistretto;
+use curve25519_dalek::scalar::Scalar;
+use serde::de::Error;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+fn check_point<E: Error>(bytes: [u8; 32]) -> Result<[u8; 32], E> {
+    match CompressedRistretto(bytes).decompress() {
+        Some(_) => Ok(bytes),
+        None => Err(E::custom("non-canonical point encoding")),
+    }
+}
+
+fn check_scalar<E: Error>(bytes: [u8; 32]) -> Result<[u8; 32], E> {
+    match Scalar::from_canonical_bytes(bytes) {
+        Some(_) => Ok(bytes),
+        None => Err(E::custom("non-canonical scalar encoding")),
+    }
+}
+
+// A compressed Ristretto point
+pub mod point {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(bytes: &[u8; 32], s: S) -> Result<S::Ok, S::Error> {
+        bytes.serialize(s)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<[u8; 32], D::Error> {
+        check_point(<[u8; 32]>::deserialize(d)?)
+    }
+}
+
+// A pair of compressed Ristretto points, e.g. an ElGamal ciphertext
+pub mod point_pair {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(pair: &([u8; 32], [u8; 32]), s: S) -> Result<S::Ok, S::Error> {
+        pair.serialize(s)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<([u8; 32], [u8; 32]), D::Error> {
+        let (p0, p1) = <([u8; 32], [u8; 32])>::deserialize(d)?;
+        Ok((check_point(p0)?, check_point(p1)?))
+    }
+}
+
+// A scalar in canonical (fully reduced) form
+pub mod scalar {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(bytes: &[u8; 32], s: S) -> Result<S::Ok, S::Error> {
+        bytes.serialize(s)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<[u8; 32], D::Error> {
+        check_scalar(<[u8; 32]>::deserialize(d)?)
+    }
+}
+
+// A Merkle inclusion proof, as the concatenation of its sibling hashes
+pub mod merkle_proof {
+    use super::*;
+    use rs_merkle::{algorithms, MerkleProof};
+
+    pub fn serialize<S: Serializer>(pi: &MerkleProof<algorithms::Sha256>, s: S) -> Result<S::Ok, S::Error> {
+        pi.to_bytes().serialize(s)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<MerkleProof<algorithms::Sha256>, D::Error> {
+        let bytes = Vec::<u8>::deserialize(d)?;
+        MerkleProof::from_bytes(&bytes).map_err(D::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use curve25519_dalek::constants::RISTRETTO_BASEPOINT_COMPRESSED;
+    use rs_merkle::{algorithms, Hasher, MerkleProof, MerkleTree};
+    use serde_derive::{Deserialize, Serialize};
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Fields {
+        #[serde(with = "point")]
+        p: [u8; 32],
+        #[serde(with = "point_pair")]
+        ct: ([u8; 32], [u8; 32]),
+        #[serde(with = "scalar")]
+        s: [u8; 32],
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct Proof {
+        #[serde(with = "merkle_proof")]
+        pi: MerkleProof<algorithms::Sha256>,
+    }
+
+    fn valid() -> Fields {
+        let g = RISTRETTO_BASEPOINT_COMPRESSED.to_bytes();
+        Fields { p: g, ct: (g, [0; 32]), s: Scalar::from(7u64).to_bytes() }
+    }
+
+    fn decode(fields: &Fields) -> bincode::Result<Fields> {
+        bincode::deserialize(&bincode::serialize(fields).unwrap())
+    }
+
+    #[test]
+    fn canonical_values_round_trip() {
+        assert_eq!(decode(&valid()).unwrap(), valid());
+    }
+
+    #[test]
+    fn non_canonical_values_are_rejected() {
+        // Not a point, and not a reduced scalar
+        let bad = [0xff; 32];
+        assert!(decode(&Fields { p: bad, ..valid() }).is_err());
+        assert!(decode(&Fields { ct: (valid().p, bad), ..valid() }).is_err());
+        assert!(decode(&Fields { s: bad, ..valid() }).is_err());
+    }
+
+    #[test]
+    fn merkle_proof_round_trip() {
+        let leaves: Vec<[u8; 32]> = (0u8..5).map(|i| algorithms::Sha256::hash(&[i])).collect();
+        let tree = MerkleTree::<algorithms::Sha256>::from_leaves(&leaves);
+        let pi = tree.proof(&[3]);
+
+        let bytes = bincode::serialize(&Proof { pi }).unwrap();
+        let decoded: Proof = bincode::deserialize(&bytes).unwrap();
+        assert!(decoded.pi.verify(tree.root().unwrap(), &[3], &[leaves[3]], leaves.len()));
+
+        // A proof must be made of whole hashes
+        let bytes = bincode::serialize(&vec![0u8; 33]).unwrap();
+        assert!(bincode::deserialize::<Proof>(&bytes).is_err());
+    }
+}
@@ -0,0 +1,110 @@
+use std::fmt;
+use serde_derive::{Serialize, Deserialize};
+
+// Everything that can go wrong while processing a protocol message. Each step
+// that handles data from the other party returns one of these instead of
+// panicking, so a malformed or malicious message cannot crash the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CheckoutError {
+    // The opened commitment does not hash to the transaction ID
+    BadCommitment,
+    // No transaction in progress with this ID (or it is at a different step)
+    UnknownTx,
+    // A zero knowledge or Merkle inclusion proof failed to verify
+    InvalidProof,
+    // A server signature failed to verify
+    InvalidSignature,
+    // Bytes that should encode a group element or scalar do not
+    InvalidPoint,
+    // A receipt ciphertext could not be made or decrypted, or is malformed
+    InvalidCiphertext,
+    // A receipt that has already been processed was delivered again
+    ReplayedReceipt,
+    // A balance could not be recovered because it is outside the supported range
+    BalanceOutOfRange,
+    // No user registered with this ID
+    UnknownUser,
+    // The server has no registered users yet
+    NoUsers,
+    // A message could not be decoded
+    MalformedMessage,
+    // A message was encoded with a protocol version this side does not support
+    UnsupportedVersion,
+    // A message was meant for a different CheckOut scheme
+    WrongScheme,
+    // A message arrived that is not valid at this point in the protocol
+    UnexpectedMessage,
+    // The connection to the other party failed
+    Network,
+    // A change to the server's state could not be written to disk
+    Storage,
+    // A wallet could not be decrypted: the passphrase is wrong or the file
+    // was modified
+    WrongPassphrase,
+    // A transaction's amount is negative or above the server's maximum
+    PointsOutOfRange,
+    // The server's share of the barcode coin flip does not follow its
+    // selection policy
+    PolicyViolation,
+    // The transaction took longer than the server allows, and was dropped
+    TxExpired,
+    // The server has as many transactions in progress as it allows
+    TooManyTxs,
+    // The transaction ID was already used for another transaction
+    DuplicateTx,
+    // The balance changed or was settled while it was being settled, or a
+    // settlement receipt is for a different user, account or epoch
+    StaleSettlement,
+    // The server has no merchant with this ID
+    UnknownMerchant,
+    // A settle or redeem request includes a receipt whose points have expired
+    PointsExpired,
+    // The server has no point category with this ID
+    UnknownCategory,
+    // A transaction does not have one amount for each of the server's
+    // categories
+    WrongCategories,
+    // A request made in a user's name is not signed with the key they
+    // registered
+    Unauthorized,
+}
+
+pub type Result<T> = std::result::Result<T, CheckoutError>;
+
+impl fmt::Display for CheckoutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let msg = match self {
+            CheckoutError::BadCommitment => "opened commitment does not match transaction ID",
+            CheckoutError::UnknownTx => "unknown transaction ID",
+            CheckoutError::InvalidProof => "proof failed to verify",
+            CheckoutError::InvalidSignature => "signature failed to verify",
+            CheckoutError::InvalidPoint => "invalid point or scalar encoding",
+            CheckoutError::InvalidCiphertext => "invalid ciphertext",
+            CheckoutError::ReplayedReceipt => "receipt has already been processed",
+            CheckoutError::BalanceOutOfRange => "balance out of range",
+            CheckoutError::UnknownUser => "unknown user ID",
+            CheckoutError::NoUsers => "no registered users",
+            CheckoutError::MalformedMessage => "malformed message",
+            CheckoutError::UnsupportedVersion => "unsupported protocol version",
+            CheckoutError::WrongScheme => "message is for a different scheme",
+            CheckoutError::UnexpectedMessage => "unexpected message",
+            CheckoutError::Network => "network error",
+            CheckoutError::Storage => "storage error",
+            CheckoutError::WrongPassphrase => "wrong passphrase or corrupted wallet",
+            CheckoutError::PointsOutOfRange => "points out of range",
+            CheckoutError::PolicyViolation => "barcode selection does not follow the server's policy",
+            CheckoutError::TxExpired => "transaction expired",
+            CheckoutError::TooManyTxs => "too many transactions in progress",
+            CheckoutError::DuplicateTx => "transaction ID has already been used",
+            CheckoutError::StaleSettlement => "settlement does not match the current balance",
+            CheckoutError::UnknownMerchant => "unknown merchant ID",
+            CheckoutError::PointsExpired => "points have expired",
+            CheckoutError::UnknownCategory => "unknown point category",
+            CheckoutError::WrongCategories => "transaction does not have one amount per category",
+            CheckoutError::Unauthorized => "request is not signed by the user it is made for",
+        };
+        f.write_str(msg)
+    }
+}
+
+impl std::error::Error for CheckoutError {}
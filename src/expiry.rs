@@ -0,0 +1,56 @@
+// Points expire. Time is divided into periods of a fixed length, counted from
+// the Unix epoch, and a server keeps each balance as one part per period: a
+// transaction changes the part for the period it is made in, and a redemption
+// the parts of the periods its points were earned in.
+// Once a period is `lifetime` periods old, its parts expire. Settle and redeem
+// proofs only cover the parts that have not expired, and the server drops the
+// expired ones (`retire_expired`). An expired part is dropped whole, without
+// being opened, so the server learns nothing about it.
+//
+// A malicious-scheme receipt is signed along with its period, and clients drop
+// the receipts of expired periods when they settle or redeem.
+//
+// Periods are unrelated to the epochs of `settlement`, which count the times
+// a balance has been settled.
+
+use std::time::Duration;
+use crate::session;
+
+pub type Period = u32;
+
+// Length of a period, unless the server is configured otherwise
+pub const DEFAULT_PERIOD_LENGTH: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Expiry {
+    // Periods are counted with this length, so it must not change once
+    // points have been issued
+    pub period_length: Duration,
+    // Number of periods that points count for, including the one they were
+    // earned in, or None if points never expire. Points earned in period `p`
+    // expire when period `p + lifetime` starts, so they last between
+    // `lifetime - 1` and `lifetime` periods.
+    pub lifetime: Option<u32>,
+}
+
+impl Default for Expiry {
+    fn default() -> Self {
+        Expiry { period_length: DEFAULT_PERIOD_LENGTH, lifetime: None }
+    }
+}
+
+impl Expiry {
+    // Output: the period it is now, by the wall clock, since periods outlast
+    // the server process
+    pub fn current(&self) -> Period {
+        (session::now() / self.period_length.as_secs().max(1)) as Period
+    }
+
+    // Output: the oldest period whose points have not expired
+    pub fn oldest(&self) -> Period {
+        match self.lifetime {
+            Some(lifetime) => (self.current() + 1).saturating_sub(lifetime),
+            None => 0,
+        }
+    }
+}
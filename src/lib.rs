@@ -0,0 +1,29 @@
+//! CheckOut: user-controlled anonymization for customer loyalty programs.
+//!
+//! Each module implements one of the three CheckOut schemes from the paper.
+//! Every scheme exposes a `Server` (run by the store) and a `Client` (run on
+//! the shopper's phone) which exchange messages to register users, swap
+//! barcodes during a transaction and, where applicable, settle balances.
+
+pub mod error;
+pub mod encoding;
+pub mod scheme;
+pub mod coin;
+pub mod session;
+pub mod settlement;
+pub mod expiry;
+pub mod store;
+pub mod lib_mal;
+pub mod lib_sh;
+pub mod lib_sh_swap_only;
+pub mod net;
+pub mod wire;
+pub mod transcript;
+pub mod range;
+pub mod wallet;
+pub mod secret;
+pub mod auth;
+
+pub use rs_merkle;
+pub use error::CheckoutError;
+pub use scheme::{LoyaltyScheme, SchemeServer, SchemeClient, SecurityLevel};
@@ -3,8 +3,8 @@ use rand::rngs;
 use curve25519_dalek::constants;
 use curve25519_dalek::ristretto::{RistrettoPoint, RistrettoBasepointTable, CompressedRistretto};
 use curve25519_dalek::scalar::Scalar;
+use curve25519_dalek::traits::{IsIdentity, VartimeMultiscalarMul};
 use sha2::{Sha256, Sha512, Digest};
-use curve25519_dalek::digest::Update;
 use ed25519_dalek::{Signer, Verifier, Signature, SigningKey, VerifyingKey};
 use aes_gcm::{
     aead::{Aead, AeadCore, KeyInit},
@@ -12,38 +12,51 @@ use aes_gcm::{
 };
 use generic_array::typenum::U12;
 use generic_array;
+use lazy_static::lazy_static;
+use serde_derive::{Serialize, Deserialize};
+use crate::error::{CheckoutError, Result};
+use crate::range::{self, RangeProof};
+use crate::expiry::Period;
+use crate::scheme::{Category, MerchantId};
+use crate::transcript::Transcript;
 
-pub(crate) const G: &RistrettoBasepointTable = &constants::RISTRETTO_BASEPOINT_TABLE;
+pub const G: &RistrettoBasepointTable = &constants::RISTRETTO_BASEPOINT_TABLE;
 
 type Point = RistrettoPoint;
 type Ciphertext = (Point, Point);
 
-pub(crate) fn h_point() -> Point {
-    RistrettoPoint::hash_from_bytes::<Sha512>("base h".as_bytes())
+// Hashing to the curve is slow, so the fixed bases are computed once
+lazy_static! {
+    static ref H_POINT: Point = RistrettoPoint::hash_from_bytes::<Sha512>("base h".as_bytes());
+    static ref U_POINT: Point = RistrettoPoint::hash_from_bytes::<Sha512>("base u".as_bytes());
+}
+
+pub fn h_point() -> Point {
+    *H_POINT
 }
 
 fn u_point() -> Point {
-    RistrettoPoint::hash_from_bytes::<Sha512>("base u".as_bytes())
+    *U_POINT
 }
 
-pub(crate) fn pzip(p: Point) -> [u8; 32] {
+pub fn pzip(p: Point) -> [u8; 32] {
     p.compress().to_bytes()
 }
 
-pub(crate) fn puzip(p: [u8; 32]) -> Point {
-    CompressedRistretto::from_slice(&p).decompress().unwrap()
+pub fn puzip(p: [u8; 32]) -> Result<Point> {
+    CompressedRistretto::from_slice(&p).decompress().ok_or(CheckoutError::InvalidPoint)
 }
 
-pub(crate) fn elgamal_keygen() -> (Scalar, Point) {
+pub fn elgamal_keygen() -> (Scalar, Point) {
     let x: Scalar = Scalar::random(&mut OsRng);
     let h: Point = &x * G;
     (x, h)
 }
 
-pub(crate) fn elgamal_enc(pk: Point, m: Point) -> Ciphertext {
+pub fn elgamal_enc(pk: Point, m: Point) -> Ciphertext {
     let r = Scalar::random(&mut OsRng);
     let c1 = &r*G;
-    let c2 = &r*pk + m;
+    let c2 = r*pk + m;
 
     (c1, c2)
 }
@@ -52,23 +65,25 @@ pub(crate) fn elgamal_enc(pk: Point, m: Point) -> Ciphertext {
 //      sk: a compressed Scalar
 //      ct:  a compressed (Point, Point) ciphertext
 // Returns the decrypted chosen mask
-pub(crate) fn elgamal_dec(sk: Scalar, ct: Ciphertext) -> Point {
+pub fn elgamal_dec(sk: Scalar, ct: Ciphertext) -> Point {
     ct.1 + (Scalar::zero() - sk) * ct.0
 }
 
-pub(crate) fn encrypt(pk: Point, x: i32, m: [u8; 32], base: [u8; 32]) -> (Ciphertext, Vec<u8>, Nonce<U12>) {
+// Encrypts a receipt's mask, base and amounts (one per category) to the
+// barcode owner
+pub fn encrypt(pk: Point, xs: &[i32], m: [u8; 32], base: [u8; 32]) -> Result<(Ciphertext, Vec<u8>, Nonce<U12>)> {
     // Choose random point p to encrypt with ElGamal. H(p) is the symmetric key
     // (we model H as a random oracle)
     let p = Point::random(&mut OsRng);
     let ct = elgamal_enc(pk, p);
 
-    // Convert x to bytes and concatenate with the bytes of m.
-    let x_bytes: [u8; 4] = x.to_be_bytes();
-
-    let mut pt: [u8; 32+4+32] = [0; 32+4+32];
-    pt[0..32].copy_from_slice(&m);
-    pt[32..36].copy_from_slice(&x_bytes);
-    pt[36..].copy_from_slice(&base);
+    // Concatenate m, the base and the bytes of each x
+    let mut pt = Vec::with_capacity(32 + 32 + 4*xs.len());
+    pt.extend_from_slice(&m);
+    pt.extend_from_slice(&base);
+    for x in xs {
+        pt.extend_from_slice(&x.to_be_bytes());
+    }
 
     let mut hasher = Sha256::new();
     Digest::update(&mut hasher, pzip(p));
@@ -76,17 +91,14 @@ pub(crate) fn encrypt(pk: Point, x: i32, m: [u8; 32], base: [u8; 32]) -> (Cipher
 
     let cipher = Aes256Gcm::new(&k);
     let nonce = Aes256Gcm::generate_nonce(&mut rngs::OsRng);
-    let sym_ct = cipher.encrypt(&nonce, pt.as_ref());
+    let sym_ct = cipher.encrypt(&nonce, pt.as_ref())
+        .map_err(|_| CheckoutError::InvalidCiphertext)?;
 
-    let sym_ct = match sym_ct {
-        Ok(ct) => ct,
-        Err(_) => panic!("Symmetric encryption failed")
-    };
-
-    (ct, sym_ct, nonce)
+    Ok((ct, sym_ct, nonce))
 }
 
-pub(crate) fn decrypt(sk: Scalar, ct: (Ciphertext, Vec<u8>), nonce: Nonce<U12>) -> ([u8; 32], i32, [u8; 32]) {
+// Output: the mask, the amounts and the base of a receipt made by `encrypt`
+pub fn decrypt(sk: Scalar, ct: (Ciphertext, Vec<u8>), nonce: Nonce<U12>) -> Result<([u8; 32], Vec<i32>, [u8; 32])> {
     let p = elgamal_dec(sk, ct.0);
 
     let mut hasher = Sha256::new();
@@ -94,34 +106,52 @@ pub(crate) fn decrypt(sk: Scalar, ct: (Ciphertext, Vec<u8>), nonce: Nonce<U12>)
     let k = hasher.finalize();
 
     let cipher = Aes256Gcm::new(&k);
-    let pt = cipher.decrypt(&nonce, ct.1.as_ref()).ok();
+    let binding = cipher.decrypt(&nonce, ct.1.as_ref())
+        .map_err(|_| CheckoutError::InvalidCiphertext)?;
+    if binding.len() <= 32+32 || (binding.len() - 32 - 32) % 4 != 0 {
+        return Err(CheckoutError::InvalidCiphertext);
+    }
 
-    let binding = pt.unwrap();
-    let out = binding.split_at(32);
-    let m_tmp = out.0;
-    let (x_tmp, base_tmp) = out.1.split_at(4);
-    let m: [u8; 32] = m_tmp.try_into().unwrap();
-    let x = i32::from_be_bytes(x_tmp.try_into().unwrap());
-    let base: [u8; 32] = base_tmp.try_into().unwrap();
+    let (m_tmp, out) = binding.split_at(32);
+    let (base_tmp, xs_tmp) = out.split_at(32);
+    let m: [u8; 32] = m_tmp.try_into().map_err(|_| CheckoutError::InvalidCiphertext)?;
+    let base: [u8; 32] = base_tmp.try_into().map_err(|_| CheckoutError::InvalidCiphertext)?;
+    let xs = xs_tmp.chunks(4)
+        .map(|x| x.try_into().map(i32::from_be_bytes).map_err(|_| CheckoutError::InvalidCiphertext))
+        .collect::<Result<Vec<i32>>>()?;
 
-    (m, x, base)
+    Ok((m, xs, base))
 }
 
-pub(crate) fn int_to_scalar(m: i32) -> Scalar {
-    let m_pos: u32 = m.abs() as u32;
-    let m_scalar = if m == m_pos as i32 { Scalar::from(m_pos) }
-                   else { Scalar::zero() - Scalar::from(m_pos) };
-    m_scalar
+// Output: the generator g that a receipt with this base uses for `category`.
+// Category 0 hashes the base alone, as receipts did before there were
+// categories; the others hash the category in too, so no two categories'
+// generators have a known relation.
+pub fn category_point(base: &[u8; 32], category: Category) -> Point {
+    if category == 0 {
+        return Point::hash_from_bytes::<Sha512>(base);
+    }
+    let mut bytes = [0u8; 36];
+    bytes[..32].copy_from_slice(base);
+    bytes[32..].copy_from_slice(&category.to_le_bytes());
+    Point::hash_from_bytes::<Sha512>(&bytes)
 }
 
-#[derive(Clone)]
-pub(crate) struct TxAndProof {
+pub fn int_to_scalar(m: i32) -> Scalar {
+    let m_pos: u32 = m.unsigned_abs();
+    if m >= 0 { Scalar::from(m_pos) }
+    else { Scalar::zero() - Scalar::from(m_pos) }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct TxAndProof {
     pub r2: Point,     // Second element of a receipt: h^m
     pub r3: Point,     // Third element of a receipt: g^mx
     v: Point,      // Auxilliary variables for nonlinear proof
     e: Point,
     vx: Point,
     ex: Point,
+    x_com: Point,  // Commitment to the amount: G^x u^s
 
     r2_t: Point,
     r3_t: Point,
@@ -129,27 +159,62 @@ pub(crate) struct TxAndProof {
     e_t: Point,
     vx_t: Point,
     ex_t: Point,
+    x_com_t: Point,
+    exx_t: Point,
 
     m_z: Scalar,
     a_z: Scalar,
     y_z: Scalar,
-    t_z: Scalar
+    t_z: Scalar,
+    x_z: Scalar,
+    s_z: Scalar,
+    d_z: Scalar,
+
+    range: RangeProof  // The committed amount is between 0 and the server's maximum
+}
+
+// Binds the base g, the statement (h^m, g^mx, the auxilliary points and the
+// amount commitment) and the prover's commitments to the transcript.
+// Output: the challenge
+fn zk_tx_challenge(transcript: &mut Transcript, g: Point, statement: [Point; 7], commitments: [Point; 8]) -> Scalar {
+    let statement_labels: [&'static [u8]; 7] = [b"r2", b"r3", b"v", b"e", b"vx", b"ex", b"x_com"];
+    let commitment_labels: [&'static [u8]; 8] = [b"r2_t", b"r3_t", b"v_t", b"e_t", b"vx_t", b"ex_t", b"x_com_t", b"exx_t"];
+
+    transcript.domain_sep(b"mal tx proof");
+    transcript.append_point(b"g", &pzip(g));
+    for (label, p) in statement_labels.into_iter().zip(statement) {
+        transcript.append_point(label, &pzip(p));
+    }
+    for (label, p) in commitment_labels.into_iter().zip(commitments) {
+        transcript.append_point(label, &pzip(p));
+    }
+    transcript.challenge_scalar(b"c")
 }
 
-pub(crate) fn zk_tx_prove(masked_m: Point, masked_x: Point, g: Point, m: Scalar, x: Scalar) -> TxAndProof {
+// Input: h^m, g^mx, the base g, the mask m, the amount x and the server's
+// maximum amount
+// Output: the proof, or an error if x is above the maximum
+pub fn zk_tx_prove(transcript: &mut Transcript, masked_m: Point, masked_x: Point, g: Point, m: Scalar, x: u32, max: u32) -> Result<TxAndProof> {
     let r2 = masked_m;
     let r3 = masked_x;
-    let a = m*x;
+    let s = Scalar::random(&mut OsRng);
+    let x_scalar = Scalar::from(x);
+    let a = m*x_scalar;
     let u = u_point();
     
     // Setup temporary variables for nonlinear proof.
     // Need to share: v, e, vx, ex
     let y = Scalar::random(&mut OsRng);
     let t = Scalar::random(&mut OsRng);
-    let v  = &y*g;
-    let e  = &y*u + &m*g;
-    let vx = &t*g;
-    let ex = &t*u + &a*g;
+    let v  = y*g;
+    let e  = y*u + m*g;
+    let vx = t*g;
+    let ex = t*u + a*g;
+
+    // Commit to x, for the range proof. ex = x*e + d*u shows that the x in
+    // g^mx is the committed one.
+    let x_com = &x_scalar*G + s*u;
+    let d = t - x_scalar*y;
 
     // Commitment
     let m_t = Scalar::random(&mut OsRng);
@@ -157,74 +222,174 @@ pub(crate) fn zk_tx_prove(masked_m: Point, masked_x: Point, g: Point, m: Scalar,
     let y_t = Scalar::random(&mut OsRng);
     let t_t = Scalar::random(&mut OsRng);
 
-    let r2_t = &m_t*h_point();
-    let r3_t = &a_t*g;
-    let v_t = &y_t*g;
-    let e_t = &y_t*u + &m_t*g;
-    let vx_t = &t_t*g;
-    let ex_t = &t_t*u + &a_t*g;
+    let r2_t = m_t*h_point();
+    let r3_t = a_t*g;
+    let v_t = y_t*g;
+    let e_t = y_t*u + m_t*g;
+    let vx_t = t_t*g;
+    let ex_t = t_t*u + a_t*g;
+
+    let x_t = Scalar::random(&mut OsRng);
+    let s_t = Scalar::random(&mut OsRng);
+    let d_t = Scalar::random(&mut OsRng);
+    let x_com_t = &x_t*G + s_t*u;
+    let exx_t = x_t*e + d_t*u;
 
     // Challenge
-    let mut hasher = Sha512::default();
-    for elt in [r2, r3, v, e, vx, ex, r2_t, r3_t, v_t, e_t, vx_t, ex_t].iter() {
-        let elt_bytes = pzip(*elt);
-        Update::update(&mut hasher, &elt_bytes);
-    }
-    
-    let c = Scalar::from_hash(hasher);
+    let c = zk_tx_challenge(transcript, g, [r2, r3, v, e, vx, ex, x_com],
+                            [r2_t, r3_t, v_t, e_t, vx_t, ex_t, x_com_t, exx_t]);
 
     // Response
     let m_z = m_t + m*c;
     let a_z = a_t + a*c;
     let y_z = y_t + y*c;
     let t_z = t_t + t*c;
-
-    TxAndProof {
-        r2: r2,
-        r3: r3,
-        v: v,
-        e: e,
-        vx: vx,
-        ex: ex,
+    let x_z = x_t + x_scalar*c;
+    let s_z = s_t + s*c;
+    let d_z = d_t + d*c;
+
+    let range = range::prove(transcript, x, s, constants::RISTRETTO_BASEPOINT_POINT, u, max)?;
+
+    Ok(TxAndProof {
+        r2,
+        r3,
+        v,
+        e,
+        vx,
+        ex,
+        x_com,
     
-        r2_t: r2_t,
-        r3_t: r3_t,
-        v_t: v_t,
-        e_t: e_t,
-        vx_t: vx_t,
-        ex_t: ex_t,
+        r2_t,
+        r3_t,
+        v_t,
+        e_t,
+        vx_t,
+        ex_t,
+        x_com_t,
+        exx_t,
     
-        m_z: m_z,
-        a_z: a_z,
-        y_z: y_z,
-        t_z: t_z
-    }
+        m_z,
+        a_z,
+        y_z,
+        t_z,
+        x_z,
+        s_z,
+        d_z,
+
+        range
+    })
 }
 
-pub(crate) fn zk_tx_verify(pi: &TxAndProof, g: Point) -> bool {
+// Recomputes a transaction proof's challenge from its transcript
+fn zk_tx_verify_challenge(transcript: &mut Transcript, pi: &TxAndProof, g: Point) -> Scalar {
+    zk_tx_challenge(transcript, g, [pi.r2, pi.r3, pi.v, pi.e, pi.vx, pi.ex, pi.x_com],
+                    [pi.r2_t, pi.r3_t, pi.v_t, pi.e_t, pi.vx_t, pi.ex_t, pi.x_com_t, pi.exx_t])
+}
+
+// Checks a transaction proof's equations against challenge c
+fn zk_tx_check(pi: &TxAndProof, g: Point, c: Scalar) -> Result<()> {
     let u = u_point();
 
+    let check1 = pi.m_z * h_point() == pi.r2_t + c * pi.r2;
+    let check2 = pi.a_z * g == pi.r3_t + c * pi.r3;
+    let check3 = pi.y_z * g == pi.v_t + c * pi.v;
+    let check4 = pi.y_z * u + pi.m_z * g == pi.e_t + c * pi.e;
+    let check5 = pi.t_z * g == pi.vx_t + c * pi.vx;
+    let check6 = pi.t_z * u + pi.a_z * g == pi.ex_t + c * pi.ex;
+    let check7 = &pi.x_z * G + pi.s_z * u == pi.x_com_t + c * pi.x_com;
+    let check8 = pi.x_z * pi.e + pi.d_z * u == pi.exx_t + c * pi.ex;
+
+    if check1 && check2 && check3 && check4 && check5 && check6 && check7 && check8 { Ok(()) }
+    else { Err(CheckoutError::InvalidProof) }
+}
+
+// Input: the proof, the base g and the server's maximum amount
+pub fn zk_tx_verify(transcript: &mut Transcript, pi: &TxAndProof, g: Point, max: u32) -> Result<()> {
     // Recompute c
-    let mut hasher = Sha512::default();
-    for elt in [pi.r2, pi.r3, pi.v, pi.e, pi.vx, pi.ex,
-                pi.r2_t, pi.r3_t, pi.v_t, pi.e_t, pi.vx_t, pi.ex_t].iter() {
-        let elt_bytes = pzip(*elt);
-        Update::update(&mut hasher, &elt_bytes);
-    }
-    let c = Scalar::from_hash(hasher);
+    let c = zk_tx_verify_challenge(transcript, pi, g);
+    zk_tx_check(pi, g, c)?;
+
+    range::verify(transcript, pi.x_com, constants::RISTRETTO_BASEPOINT_POINT, u_point(), max, &pi.range)
+}
+
+// Checks many transaction proofs at once. Every equation of every proof,
+// including the range proofs, is moved to one side, weighted by a fresh
+// random scalar, and the sum computed with a single multiscalar
+// multiplication. The sum is the identity if all the proofs are valid, and
+// otherwise only with negligible probability.
+// Input: for each proof, its transcript, the proof and the base g; the
+// server's maximum amount
+// Output: for each proof, in order, whether it is valid. If the batch fails,
+// each proof is checked on its own to find the offending ones.
+pub fn zk_tx_verify_batch(batch: Vec<(Transcript, &TxAndProof, Point)>, max: u32) -> Vec<Result<()>> {
+    let b = constants::RISTRETTO_BASEPOINT_POINT;
+    let u = u_point();
 
-    let check1 = &pi.m_z * h_point() == pi.r2_t + &c * pi.r2;
-    let check2 = &pi.a_z * g == pi.r3_t + &c * pi.r3;
-    let check3 = &pi.y_z * g == pi.v_t + &c * pi.v;
-    let check4 = &pi.y_z * u + &pi.m_z * g == pi.e_t + &c * pi.e;
-    let check5 = &pi.t_z * g == pi.vx_t + &c * pi.vx;
-    let check6 = &pi.t_z * u + &pi.a_z * g == pi.ex_t + &c * pi.ex;
+    // Recompute each proof's challenge, and the terms of its range proof
+    let prepared: Vec<_> = batch.into_iter()
+        .map(|(mut transcript, pi, g)| {
+            let c = zk_tx_verify_challenge(&mut transcript, pi, g);
+            let range_terms = range::verification_terms(&mut transcript, pi.x_com, b, u, max, &pi.range);
+            (pi, g, c, range_terms)
+        })
+        .collect();
+
+    // h, u and G are shared by every proof, so their scalars are accumulated.
+    // Proofs with a malformed range proof are left out: they fail anyway.
+    let mut h_scalar = Scalar::zero();
+    let mut u_scalar = Scalar::zero();
+    let mut b_scalar = Scalar::zero();
+    let mut scalars = Vec::<Scalar>::new();
+    let mut points = Vec::<Point>::new();
+
+    for (pi, g, c, range_terms) in &prepared {
+        let (range_scalars, range_points) = match range_terms {
+            Ok(terms) => terms,
+            Err(_) => continue,
+        };
+        let w: [Scalar; 8] = [(); 8].map(|_| Scalar::random(&mut OsRng));
+
+        h_scalar += w[0] * pi.m_z;
+        u_scalar += w[3] * pi.y_z + w[5] * pi.t_z + w[6] * pi.s_z + w[7] * pi.d_z;
+        b_scalar += w[6] * pi.x_z;
+        scalars.push(w[1] * pi.a_z + w[2] * pi.y_z + w[3] * pi.m_z + w[4] * pi.t_z + w[5] * pi.a_z);
+        points.push(*g);
+        scalars.push(w[7] * pi.x_z);
+        points.push(pi.e);
+
+        let rhs = [(pi.r2_t, pi.r2), (pi.r3_t, pi.r3), (pi.v_t, pi.v), (pi.e_t, pi.e),
+                   (pi.vx_t, pi.vx), (pi.ex_t, pi.ex), (pi.x_com_t, pi.x_com), (pi.exx_t, pi.ex)];
+        for (wi, (p_t, p)) in w.iter().zip(rhs) {
+            scalars.push(-wi);
+            points.push(p_t);
+            scalars.push(-(wi * c));
+            points.push(p);
+        }
 
-    check1 && check2 && check3 && check4 && check5 && check6
+        scalars.extend_from_slice(range_scalars);
+        points.extend_from_slice(range_points);
+    }
+    scalars.push(h_scalar);
+    points.push(h_point());
+    scalars.push(u_scalar);
+    points.push(u);
+    scalars.push(b_scalar);
+    points.push(b);
+
+    if Point::vartime_multiscalar_mul(&scalars, &points).is_identity() {
+        return prepared.into_iter().map(|(_, _, _, range_terms)| range_terms.map(|_| ())).collect();
+    }
+
+    prepared.into_iter().map(|(pi, g, c, range_terms)| {
+        let (range_scalars, range_points) = range_terms?;
+        zk_tx_check(pi, g, c)?;
+        if Point::vartime_multiscalar_mul(&range_scalars, &range_points).is_identity() { Ok(()) }
+        else { Err(CheckoutError::InvalidProof) }
+    }).collect()
 }
 
-#[derive(Clone)]
-pub(crate) struct SettleProof {
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SettleProof {
     vs: Vec::<Point>,
     es: Vec::<Point>,
     vxs: Vec::<Point>,
@@ -237,23 +402,65 @@ pub(crate) struct SettleProof {
     e_ts: Vec::<Point>,
     vx_ts: Vec::<Point>,
     ex_ts: Vec::<Point>,
+    exx_ts: Vec::<Point>,   // Commitments for ex = x*e + d*u, which binds x to a = x*m
 
     m_zs: Vec::<Scalar>,
     x_zs: Vec::<Scalar>,
     a_zs: Vec::<Scalar>,
     y_zs: Vec::<Scalar>,
-    t_zs: Vec::<Scalar>
+    t_zs: Vec::<Scalar>,
+    d_zs: Vec::<Scalar>
+}
+
+// Binds the revealed balance, the server's balance, every transaction's h^m,
+// base and auxilliary points, and the prover's commitments to the transcript.
+// The caller has checked that all the lists have the same length.
+// Output: the challenge
+fn zk_settle_challenge(transcript: &mut Transcript, b1: Point, b2: Point, b_ms: &[Point], gs: &[Point],
+                       pi: &SettleProof) -> Scalar {
+    transcript.domain_sep(b"mal settle proof");
+    transcript.append_point(b"b1", &pzip(b1));
+    transcript.append_point(b"b2", &pzip(b2));
+    transcript.append_u64(b"n", b_ms.len() as u64);
+    for i in 0..b_ms.len() {
+        transcript.append_point(b"b_m", &pzip(b_ms[i]));
+        transcript.append_point(b"g", &pzip(gs[i]));
+        transcript.append_point(b"v", &pzip(pi.vs[i]));
+        transcript.append_point(b"e", &pzip(pi.es[i]));
+        transcript.append_point(b"vx", &pzip(pi.vxs[i]));
+        transcript.append_point(b"ex", &pzip(pi.exs[i]));
+    }
+
+    transcript.append_point(b"b1_t", &pzip(pi.b1_t));
+    transcript.append_point(b"b2_t", &pzip(pi.b2_t));
+    for i in 0..b_ms.len() {
+        transcript.append_point(b"b_mt", &pzip(pi.b_mts[i]));
+        transcript.append_point(b"v_t", &pzip(pi.v_ts[i]));
+        transcript.append_point(b"e_t", &pzip(pi.e_ts[i]));
+        transcript.append_point(b"vx_t", &pzip(pi.vx_ts[i]));
+        transcript.append_point(b"ex_t", &pzip(pi.ex_ts[i]));
+        transcript.append_point(b"exx_t", &pzip(pi.exx_ts[i]));
+    }
+    transcript.challenge_scalar(b"c")
 }
 
 // Input: a public balance x, the server's balance bal, and lists of values (h^m, x, m)
 // for each transaction touching this balance.
 // Output: four auxilliary variables for each transaction, and the commitment/response
 // components of the corresponding ZK proof.
-pub(crate) fn zk_settle_prove(x: i32, bal: Point, b_ms: &Vec::<Point>, gs: &Vec::<Point>,
-                              xs: &Vec::<Scalar>, ms: &Vec::<Scalar>) -> SettleProof {
+pub fn zk_settle_prove(transcript: &mut Transcript, x: i32, bal: Point, b_ms: &[Point], gs: &[Point],
+                       xs: &[Scalar], ms: &[Scalar]) -> SettleProof {
+    settle_prove(transcript, (&int_to_scalar(x) * G, None), bal, b_ms, gs, xs, ms).0
+}
+
+// The settle proof, for a balance that is either revealed (b1 = G^x) or
+// committed to (b1 = G^x u^s).
+// Input: b1 and its blinding s, if it has one, then as for `zk_settle_prove`
+// Output: the proof, and the response for s (zero if there is none)
+fn settle_prove(transcript: &mut Transcript, (b1, s): (Point, Option<Scalar>), bal: Point, b_ms: &[Point], gs: &[Point],
+                xs: &[Scalar], ms: &[Scalar]) -> (SettleProof, Scalar) {
     // Decompress
     let n = xs.len();                        // Number of transactions
-    let b1 = &int_to_scalar(x) * G;          // Algebraic balance representation
     let b2 = bal;                            // Masked balance
     let h = h_point();
     let u = u_point();
@@ -274,12 +481,14 @@ pub(crate) fn zk_settle_prove(x: i32, bal: Point, b_ms: &Vec::<Point>, gs: &Vec:
     let mut a_ts = Vec::<Scalar>::with_capacity(n);
     let mut y_ts = Vec::<Scalar>::with_capacity(n);
     let mut t_ts = Vec::<Scalar>::with_capacity(n);
+    let mut d_ts = Vec::<Scalar>::with_capacity(n);
 
     let mut b_mts = Vec::<Point>::with_capacity(n);
     let mut v_ts = Vec::<Point>::with_capacity(n);
     let mut e_ts = Vec::<Point>::with_capacity(n);
     let mut vx_ts = Vec::<Point>::with_capacity(n);
     let mut ex_ts = Vec::<Point>::with_capacity(n);
+    let mut exx_ts = Vec::<Point>::with_capacity(n);
     for i in 0..n {
         aas.push(xs[i]*ms[i]);
         let y = Scalar::random(&mut OsRng);
@@ -287,8 +496,8 @@ pub(crate) fn zk_settle_prove(x: i32, bal: Point, b_ms: &Vec::<Point>, gs: &Vec:
         ts.push(xs[i]*ys[i]);
 
         let g = gs[i];
-        let v = &y * g;
-        let e = &y * u + &ms[i] * g;
+        let v = y * g;
+        let e = y * u + ms[i] * g;
         vs.push(v);
         es.push(e);
         vxs.push(v*xs[i]);
@@ -299,157 +508,442 @@ pub(crate) fn zk_settle_prove(x: i32, bal: Point, b_ms: &Vec::<Point>, gs: &Vec:
         a_ts.push(Scalar::random(&mut OsRng));
         y_ts.push(Scalar::random(&mut OsRng));
         t_ts.push(Scalar::random(&mut OsRng));
-
-        b_mts.push(&m_ts[i]*h);
-        v_ts.push(&y_ts[i]*g);
-        e_ts.push(&y_ts[i]*u + (&m_ts[i]*g));
-        vx_ts.push(&t_ts[i]*g);
-        ex_ts.push(&t_ts[i]*u + (&a_ts[i]*g));
+        d_ts.push(Scalar::random(&mut OsRng));
+
+        b_mts.push(m_ts[i]*h);
+        v_ts.push(y_ts[i]*g);
+        e_ts.push(y_ts[i]*u + (m_ts[i]*g));
+        vx_ts.push(t_ts[i]*g);
+        ex_ts.push(t_ts[i]*u + (a_ts[i]*g));
+        exx_ts.push(x_ts[i]*e + d_ts[i]*u);
     }
 
     let mut b2_t = G * &Scalar::zero();
     let mut xt_sum = Scalar::zero();
     for i in 0..n {
-        xt_sum = xt_sum + x_ts[i];
-        b2_t = b2_t + gs[i] * &a_ts[i];
+        xt_sum += x_ts[i];
+        b2_t += gs[i] * a_ts[i];
     }
 
-    let b1_t = &xt_sum * G;
+    let s_t = if s.is_some() { Scalar::random(&mut OsRng) } else { Scalar::zero() };
+    let b1_t = &xt_sum * G + s_t * u;
+
+    let mut pi = SettleProof {
+        vs,
+        es,
+        vxs,
+        exs,
+
+        b1_t,
+        b2_t,
+        b_mts,
+        v_ts,
+        e_ts,
+        vx_ts,
+        ex_ts,
+        exx_ts,
+
+        m_zs: Vec::<Scalar>::with_capacity(n),
+        x_zs: Vec::<Scalar>::with_capacity(n),
+        a_zs: Vec::<Scalar>::with_capacity(n),
+        y_zs: Vec::<Scalar>::with_capacity(n),
+        t_zs: Vec::<Scalar>::with_capacity(n),
+        d_zs: Vec::<Scalar>::with_capacity(n)
+    };
 
     // Challenge
-    let mut hasher = Sha512::default();
-
-    Update::update(&mut hasher, &pzip(b1));
-    Update::update(&mut hasher, &pzip(b2));
-    for i in 0..n {
-        for elt in [b_ms[i], v_ts[i], e_ts[i], vx_ts[i], ex_ts[i]].iter() {
-            let elt_bytes: [u8; 32] = pzip(*elt);
-            Update::update(&mut hasher, &elt_bytes);
-        }
-    }
-    
-    let c = Scalar::from_hash(hasher);
+    let c = zk_settle_challenge(transcript, b1, b2, b_ms, gs, &pi);
 
     // Response
-    let mut m_zs = Vec::<Scalar>::with_capacity(n);
-    let mut x_zs = Vec::<Scalar>::with_capacity(n);
-    let mut a_zs = Vec::<Scalar>::with_capacity(n);
-    let mut y_zs = Vec::<Scalar>::with_capacity(n);
-    let mut t_zs = Vec::<Scalar>::with_capacity(n);
     for i in 0..n {
-        m_zs.push(m_ts[i] + ms[i]*c);
-        x_zs.push(x_ts[i] + xs[i]*c);
-        a_zs.push(a_ts[i] + aas[i]*c);
-        y_zs.push(y_ts[i] + ys[i]*c);
-        t_zs.push(t_ts[i] + ts[i]*c);
+        pi.m_zs.push(m_ts[i] + ms[i]*c);
+        pi.x_zs.push(x_ts[i] + xs[i]*c);
+        pi.a_zs.push(a_ts[i] + aas[i]*c);
+        pi.y_zs.push(y_ts[i] + ys[i]*c);
+        pi.t_zs.push(t_ts[i] + ts[i]*c);
+        // ex = x*e exactly, since t = x*y, so d is zero
+        pi.d_zs.push(d_ts[i]);
     }
+    let s_z = s_t + s.unwrap_or_else(Scalar::zero)*c;
 
-    SettleProof {
-        vs: vs,
-        es: es,
-        vxs: vxs,
-        exs: exs,
-        
-        b1_t: b1_t,
-        b2_t: b2_t,
-        b_mts: b_mts,
-        v_ts: v_ts,
-        e_ts: e_ts,
-        vx_ts: vx_ts,
-        ex_ts: ex_ts,
-
-        m_zs: m_zs,
-        x_zs: x_zs,
-        a_zs: a_zs,
-        y_zs: y_zs,
-        t_zs: t_zs
-    }
+    (pi, s_z)
 }
 
-pub(crate) fn zk_settle_verify(x: i32, bal: Point, b_ms: Vec<Point>, gs: Vec<Point>, pi: SettleProof) -> bool {
+// A settle proof for a balance the prover does not have: an honest proof for
+// `xs`, with the first x response moved so that the responses add up to `claim`
+// Input: b1 committing to `claim` and its blinding, then as for `settle_prove`
+// Output: as for `settle_prove`
+#[cfg(test)]
+pub(crate) fn forge_settle_proof(transcript: &mut Transcript, (b1, s, claim): (Point, Option<Scalar>, i32), bal: Point,
+                                 (b_ms, gs, xs, ms): (&[Point], &[Point], &[Scalar], &[Scalar])) -> (SettleProof, Scalar) {
+    let mut challenge_transcript = transcript.clone();
+    let (mut pi, s_z) = settle_prove(transcript, (b1, s), bal, b_ms, gs, xs, ms);
+    let c = zk_settle_challenge(&mut challenge_transcript, b1, bal, b_ms, gs, &pi);
+    let x_sum: Scalar = xs.iter().sum();
+    pi.x_zs[0] += (int_to_scalar(claim) - x_sum) * c;
+    (pi, s_z)
+}
+
+pub fn zk_settle_verify(transcript: &mut Transcript, x: i32, bal: Point, b_ms: Vec<Point>, gs: Vec<Point>, pi: SettleProof) -> Result<()> {
+    let (scalars, points) = settle_verification_terms(transcript, (&int_to_scalar(x)*G, Scalar::zero()), bal, &b_ms, &gs, &pi)?;
+
+    if Point::vartime_multiscalar_mul(&scalars, &points).is_identity() { Ok(()) }
+    else { Err(CheckoutError::InvalidProof) }
+}
+
+// Every equation of a settle proof, weighted by a fresh random scalar, as
+// terms of a multiscalar multiplication that comes to the identity if the
+// proof is valid.
+// Input: b1 and the response for its blinding (see `settle_prove`), then as
+// for `zk_settle_verify`
+// Output: the scalars and points of the terms
+fn settle_verification_terms(transcript: &mut Transcript, (b1, s_z): (Point, Scalar), bal: Point, b_ms: &[Point],
+                             gs: &[Point], pi: &SettleProof) -> Result<(Vec<Scalar>, Vec<Point>)> {
     let n = b_ms.len();
-    let b1 = &int_to_scalar(x)*G;
+
+    // Every per-transaction list in the proof must cover the same transactions
+    let lens = [gs.len(), pi.vs.len(), pi.es.len(), pi.vxs.len(), pi.exs.len(),
+                pi.b_mts.len(), pi.v_ts.len(), pi.e_ts.len(), pi.vx_ts.len(), pi.ex_ts.len(),
+                pi.exx_ts.len(), pi.m_zs.len(), pi.x_zs.len(), pi.a_zs.len(), pi.y_zs.len(), pi.t_zs.len(),
+                pi.d_zs.len()];
+    if lens.iter().any(|&len| len != n) {
+        return Err(CheckoutError::InvalidProof);
+    }
     let b2 = bal;
-    let u = u_point();
 
     // Recompute c
-    let mut hasher = Sha512::default();
+    let c = zk_settle_challenge(transcript, b1, b2, b_ms, gs, pi);
+
+    // Every equation is moved to one side, weighted by a fresh random scalar,
+    // and the sum computed with a single multiscalar multiplication. The sum is
+    // the identity if every equation holds, and otherwise only with negligible
+    // probability. The bases G, h and u appear in many equations, so their
+    // scalars are accumulated.
+    let w_b1 = Scalar::random(&mut OsRng);
+    let w_b2 = Scalar::random(&mut OsRng);
+    let mut xz_sum = Scalar::zero();
+    let mut h_scalar = Scalar::zero();
+    let mut u_scalar = w_b1 * s_z;
+    let mut scalars = Vec::<Scalar>::with_capacity(14 * n + 7);
+    let mut points = Vec::<Point>::with_capacity(14 * n + 7);
 
-    Update::update(&mut hasher, &pzip(b1));
-    Update::update(&mut hasher, &pzip(b2));
     for i in 0..n {
-        for elt in [b_ms[i], pi.v_ts[i], pi.e_ts[i], pi.vx_ts[i], pi.ex_ts[i]].iter() {
-            let elt_bytes: [u8; 32] = pzip(*elt);
-            Update::update(&mut hasher, &elt_bytes);
+        let m_z = pi.m_zs[i];
+        let a_z = pi.a_zs[i];
+        let y_z = pi.y_zs[i];
+        let t_z = pi.t_zs[i];
+        let w: [Scalar; 6] = [(); 6].map(|_| Scalar::random(&mut OsRng));
+
+        xz_sum += pi.x_zs[i];
+        h_scalar += w[0] * m_z;
+        u_scalar += w[2] * y_z + w[4] * t_z + w[5] * pi.d_zs[i];
+
+        // b2 sums a_z over every g, and the last four equations are in g
+        scalars.push(w_b2 * a_z + w[1] * y_z + w[2] * m_z + w[3] * t_z + w[4] * a_z);
+        points.push(gs[i]);
+
+        let rhs = [(pi.b_mts[i], b_ms[i]), (pi.v_ts[i], pi.vs[i]), (pi.e_ts[i], pi.es[i]),
+                   (pi.vx_ts[i], pi.vxs[i]), (pi.ex_ts[i], pi.exs[i])];
+        for (wi, (p_t, p)) in w.iter().zip(rhs) {
+            scalars.push(-wi);
+            points.push(p_t);
+            scalars.push(-(wi * c));
+            points.push(p);
         }
-    }
-    
-    let c = Scalar::from_hash(hasher);
 
-    let mut xz_sum = Scalar::zero();
-    let mut b2_left = &Scalar::zero() * G;
-    for i in 0..n {
-        xz_sum = xz_sum + pi.x_zs[i];
-        b2_left = b2_left + gs[i] * &pi.a_zs[i];
+        // ex = x*e + d*u ties the x summed into b1 to the a summed into b2:
+        // with e = y*u + m*g and ex = t*u + a*g, it leaves a = x*m
+        scalars.push(w[5] * pi.x_zs[i]);
+        points.push(pi.es[i]);
+        scalars.push(-w[5]);
+        points.push(pi.exx_ts[i]);
+        scalars.push(-(w[5] * c));
+        points.push(pi.exs[i]);
     }
 
-    let check_b1 = &xz_sum * G == pi.b1_t + (&c * b1);
-    let check_b2 = b2_left == pi.b2_t + (&c * b2);
+    scalars.push(w_b1 * xz_sum);
+    points.push(constants::RISTRETTO_BASEPOINT_POINT);
+    scalars.push(-w_b1);
+    points.push(pi.b1_t);
+    scalars.push(-(w_b1 * c));
+    points.push(b1);
+    scalars.push(-w_b2);
+    points.push(pi.b2_t);
+    scalars.push(-(w_b2 * c));
+    points.push(b2);
+    scalars.push(h_scalar);
+    points.push(h_point());
+    scalars.push(u_scalar);
+    points.push(u_point());
+
+    Ok((scalars, points))
+}
 
-    let mut result = check_b1 && check_b2;
-    for i in 0..n {
-        let b_m = b_ms[i];
-        let g = gs[i];
-        let v = pi.vs[i];
-        let e = pi.es[i];
-        let vx = pi.vxs[i];
-        let ex = pi.exs[i];
+// Proof that a balance covers an amount being redeemed, without revealing the
+// balance: a settle proof for a commitment to the balance, and a range proof
+// that what is left after redeeming is between 0 and `MAX_REMAINDER`.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RedeemProof {
+    b1: Point,  // Commitment to the balance: G^x u^s
+    s_z: Scalar,
+    settle: SettleProof,
+    range: RangeProof
+}
 
-        let b_mt = pi.b_mts[i];
-        let v_t = pi.v_ts[i];
-        let e_t = pi.e_ts[i];
-        let vx_t = pi.vx_ts[i];
-        let ex_t = pi.ex_ts[i];
+// Most points that may be left in a balance after redeeming
+pub const MAX_REMAINDER: u32 = i32::MAX as u32;
 
-        let m_z = pi.m_zs[i];
-        let a_z = pi.a_zs[i];
-        let y_z = pi.y_zs[i];
-        let t_z = pi.t_zs[i];
+// Input: the amount to redeem and the balance x, then as for `zk_settle_prove`
+// Output: the proof, or an error if the balance is less than the amount
+pub fn zk_redeem_prove(transcript: &mut Transcript, (points, x): (u32, i32), bal: Point, b_ms: &[Point], gs: &[Point],
+                       xs: &[Scalar], ms: &[Scalar]) -> Result<RedeemProof> {
+    let rest: u32 = (x as i64 - points as i64).try_into().map_err(|_| CheckoutError::PointsOutOfRange)?;
+    let s = Scalar::random(&mut OsRng);
+    let u = u_point();
+    let b1 = &int_to_scalar(x)*G + s*u;
 
-        let check1 = &m_z*h_point() == b_mt + (&c*b_m);
-        let check2 = &y_z*g == v_t + (&c*v);
-        let check3 = &y_z*u + &m_z*g == e_t + (&c*e);
-        let check4 = &t_z*g == vx_t + (&c*vx);
-        let check5 = &t_z*u + &a_z*g == ex_t + (&c*ex);
+    transcript.domain_sep(b"mal redeem proof");
+    transcript.append_u64(b"points", points as u64);
+    let (settle, s_z) = settle_prove(transcript, (b1, Some(s)), bal, b_ms, gs, xs, ms);
 
-        result = result && check1 && check2 && check3 && check4 && check5;
-        if !result {
-            break
-        }
-    }
-    result
+    // b1 G^-points = G^rest u^s commits to what is left
+    let range = range::prove(transcript, rest, s, constants::RISTRETTO_BASEPOINT_POINT, u, MAX_REMAINDER)?;
+
+    Ok(RedeemProof { b1, s_z, settle, range })
 }
 
-pub(crate) fn signature_keygen() -> (SigningKey, VerifyingKey) {
+// Input: the amount redeemed, then as for `zk_settle_verify`
+pub fn zk_redeem_verify(transcript: &mut Transcript, points: u32, bal: Point, b_ms: Vec<Point>, gs: Vec<Point>,
+                        pi: RedeemProof) -> Result<()> {
+    let b = constants::RISTRETTO_BASEPOINT_POINT;
+
+    transcript.domain_sep(b"mal redeem proof");
+    transcript.append_u64(b"points", points as u64);
+    let (mut scalars, mut terms) = settle_verification_terms(transcript, (pi.b1, pi.s_z), bal, &b_ms, &gs, &pi.settle)?;
+
+    let rest = pi.b1 - Scalar::from(points) * b;
+    let (range_scalars, range_points) = range::verification_terms(transcript, rest, b, u_point(), MAX_REMAINDER, &pi.range)?;
+    scalars.extend(range_scalars);
+    terms.extend(range_points);
+
+    if Point::vartime_multiscalar_mul(&scalars, &terms).is_identity() { Ok(()) }
+    else { Err(CheckoutError::InvalidProof) }
+}
+
+pub fn signature_keygen() -> (SigningKey, VerifyingKey) {
     let sk = SigningKey::generate(&mut rngs::OsRng);
     let vk = sk.verifying_key();
     (sk, vk)
 }
 
-pub(crate) fn sign(sk: &SigningKey, p: &Point, r: [u8; 32]) -> Signature {
+// Receipt signatures are on (merchant, period, h^m, base), so a receipt earned
+// with one merchant cannot be settled with another, nor counted in another
+// period (see `expiry`)
+fn signed_message((merchant, period): (MerchantId, Period), p: &Point, r: &[u8; 32]) -> [u8; 72] {
+    let mut m: [u8; 72] = [0; 72];
+    m[..4].copy_from_slice(&merchant.to_le_bytes());
+    m[4..8].copy_from_slice(&period.to_le_bytes());
+    m[8..40].copy_from_slice(&pzip(*p));
+    m[40..].copy_from_slice(r);
+    m
+}
+
+pub fn sign(sk: &SigningKey, scope: (MerchantId, Period), p: &Point, r: [u8; 32]) -> Signature {
+    (*sk).sign(&signed_message(scope, p, &r))
+}
+
+pub fn verify(vk: VerifyingKey, scope: (MerchantId, Period), p: &Point, r: &[u8; 32], s: Signature) -> Result<()> {
+    vk.verify(&signed_message(scope, p, r), &s).map_err(|_| CheckoutError::InvalidSignature)
+}
+
+// Checks many signatures made with the same key for the same merchant at once
+// Input: for each signature, the period, and the signed h^m and base
+pub fn verify_batch(vk: VerifyingKey, merchant: MerchantId, periods: &[Period], ps: &[Point], rs: &[[u8; 32]], ss: &[Signature])
+                    -> Result<()> {
+    if ps.len() != rs.len() || ps.len() != ss.len() || ps.len() != periods.len() {
+        return Err(CheckoutError::InvalidSignature);
+    }
 
-    let mut to_sign: [u8; 64] = [0; 64];
-    to_sign[..32].copy_from_slice(&pzip(*p));
-    to_sign[32..].copy_from_slice(&r);
+    let to_verify: Vec<[u8; 72]> = periods.iter().zip(ps).zip(rs)
+        .map(|((period, p), r)| signed_message((merchant, *period), p, r))
+        .collect();
+    let messages: Vec<&[u8]> = to_verify.iter().map(|m| &m[..]).collect();
+    let vks = vec![vk; ss.len()];
 
-    (*sk).sign(&to_sign)
+    ed25519_dalek::verify_batch(&messages, ss, &vks).map_err(|_| CheckoutError::InvalidSignature)
 }
 
-pub(crate) fn verify(vk: VerifyingKey, p: &Point, r: &[u8; 32], s: Signature) -> bool {
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX: u32 = 1000;
+    const SERVER: [u8; 32] = [1; 32];
+    const TX_ID: [u8; 32] = [2; 32];
+
+    // Input: the amount g^mx is made with, and the amount the proof claims
+    // Output: the base g and the proof, or the prover's error
+    fn prove(amount: i32, x: u32, max: u32) -> Result<(Point, TxAndProof)> {
+        let g = &Scalar::random(&mut OsRng) * G;
+        let m = Scalar::random(&mut OsRng);
+        let gmx = g * (m * int_to_scalar(amount));
+        let pi = zk_tx_prove(&mut Transcript::for_tx(&SERVER, &TX_ID, 0), m * h_point(), gmx, g, m, x, max)?;
+        Ok((g, pi))
+    }
+
+    fn verify((g, pi): &(Point, TxAndProof)) -> Result<()> {
+        zk_tx_verify(&mut Transcript::for_tx(&SERVER, &TX_ID, 0), pi, *g, MAX)
+    }
+
+    #[test]
+    fn round_trip() {
+        for x in [0, 1, MAX] {
+            assert_eq!(verify(&prove(x as i32, x, MAX).unwrap()), Ok(()));
+        }
+    }
 
-    let mut to_verify: [u8; 64] = [0; 64];
-    to_verify[..32].copy_from_slice(&pzip(*p));
-    to_verify[32..].copy_from_slice(r);
+    #[test]
+    fn amount_above_max() {
+        assert_eq!(prove(MAX as i32 + 1, MAX + 1, MAX).err(), Some(CheckoutError::PointsOutOfRange));
+        assert_eq!(verify(&prove(MAX as i32 + 1, MAX + 1, 1023).unwrap()), Err(CheckoutError::InvalidProof));
+    }
 
-    vk.verify(&to_verify, &s).is_ok()
-}
\ No newline at end of file
+    #[test]
+    fn negative_amount() {
+        assert_eq!(prove(-5, -5i32 as u32, MAX).err(), Some(CheckoutError::PointsOutOfRange));
+        // The committed amount must be the one in g^mx
+        assert_eq!(verify(&prove(-5, 5, MAX).unwrap()), Err(CheckoutError::InvalidProof));
+    }
+
+    #[test]
+    fn tampered_commitment() {
+        let (g, pi) = prove(5, 5, MAX).unwrap();
+        let (_, other) = prove(5, 5, MAX).unwrap();
+
+        let mut tampered = pi.clone();
+        tampered.range = other.range.clone();
+        assert_eq!(verify(&(g, tampered)), Err(CheckoutError::InvalidProof));
+
+        let mut tampered = pi.clone();
+        tampered.x_com = other.x_com;
+        assert_eq!(verify(&(g, tampered)), Err(CheckoutError::InvalidProof));
+    }
+
+    #[test]
+    fn bound_to_server_and_tx() {
+        let (g, pi) = prove(5, 5, MAX).unwrap();
+        let others = [Transcript::for_tx(&[3; 32], &TX_ID, 0), Transcript::for_tx(&SERVER, &[3; 32], 0),
+                      Transcript::for_tx(&SERVER, &TX_ID, 1)];
+        for mut transcript in others {
+            assert_eq!(zk_tx_verify(&mut transcript, &pi, g, MAX), Err(CheckoutError::InvalidProof));
+        }
+    }
+
+    #[test]
+    fn batch_with_one_bad_proof() {
+        let proofs = [prove(5, 5, MAX).unwrap(), prove(-5, 5, MAX).unwrap(), prove(MAX as i32, MAX, MAX).unwrap()];
+        let batch = proofs.iter().map(|(g, pi)| (Transcript::for_tx(&SERVER, &TX_ID, 0), pi, *g)).collect();
+        assert_eq!(zk_tx_verify_batch(batch, MAX), vec![Ok(()), Err(CheckoutError::InvalidProof), Ok(())]);
+
+
+        // Checked one at a time, the proofs give the same results
+        let results: Vec<Result<()>> = proofs.iter().map(verify).collect();
+        assert_eq!(results, vec![Ok(()), Err(CheckoutError::InvalidProof), Ok(())]);
+    }
+
+    // One receipt for `x` points, and the server's balance for it
+    // Output: the balance, then (h^m, g, x, m) for the receipt
+    fn receipt(x: i32) -> (Point, [Point; 2], [Scalar; 2]) {
+        let g = &Scalar::random(&mut OsRng) * G;
+        let m = Scalar::random(&mut OsRng);
+        let x = int_to_scalar(x);
+        (g * (x * m), [m * h_point(), g], [x, m])
+    }
+
+    fn settle_transcript() -> Transcript {
+        Transcript::for_settle(&SERVER, 0, (0, 0), 0)
+    }
+
+    #[test]
+    fn settle_round_trip() {
+        let (bal, [b_m, g], [x, m]) = receipt(10);
+        let pi = zk_settle_prove(&mut settle_transcript(), 10, bal, &[b_m], &[g], &[x], &[m]);
+        assert_eq!(zk_settle_verify(&mut settle_transcript(), 10, bal, vec![b_m], vec![g], pi.clone()), Ok(()));
+        assert_eq!(zk_settle_verify(&mut settle_transcript(), 11, bal, vec![b_m], vec![g], pi),
+                   Err(CheckoutError::InvalidProof));
+    }
+
+    #[test]
+    fn settle_forged_total() {
+        let (bal, [b_m, g], [x, m]) = receipt(10);
+        let claim = 1_000_000;
+        let (pi, _) = forge_settle_proof(&mut settle_transcript(), (&int_to_scalar(claim) * G, None, claim), bal,
+                                         (&[b_m], &[g], &[x], &[m]));
+        assert_eq!(zk_settle_verify(&mut settle_transcript(), claim, bal, vec![b_m], vec![g], pi),
+                   Err(CheckoutError::InvalidProof));
+    }
+
+    #[test]
+    fn redeem_round_trip() {
+        let (bal, [b_m, g], [x, m]) = receipt(10);
+        let pi = zk_redeem_prove(&mut settle_transcript(), (10, 10), bal, &[b_m], &[g], &[x], &[m]).unwrap();
+        assert_eq!(zk_redeem_verify(&mut settle_transcript(), 10, bal, vec![b_m], vec![g], pi.clone()), Ok(()));
+        assert_eq!(zk_redeem_verify(&mut settle_transcript(), 9, bal, vec![b_m], vec![g], pi),
+                   Err(CheckoutError::InvalidProof));
+        assert_eq!(zk_redeem_prove(&mut settle_transcript(), (11, 10), bal, &[b_m], &[g], &[x], &[m]).err(),
+                   Some(CheckoutError::PointsOutOfRange));
+    }
+
+    // A proof built by hand, as `zk_redeem_prove` would for a balance of
+    // `claim`, but from a receipt for only 10 points
+    #[test]
+    fn redeem_forged_balance() {
+        let (bal, [b_m, g], [x, m]) = receipt(10);
+        let (points, claim) = (500, 500);
+        let s = Scalar::random(&mut OsRng);
+        let u = u_point();
+        let b1 = &int_to_scalar(claim) * G + s * u;
+
+        let mut transcript = settle_transcript();
+        transcript.domain_sep(b"mal redeem proof");
+        transcript.append_u64(b"points", points as u64);
+        let (settle, s_z) = forge_settle_proof(&mut transcript, (b1, Some(s), claim), bal, (&[b_m], &[g], &[x], &[m]));
+        let range = range::prove(&mut transcript, claim as u32 - points, s, constants::RISTRETTO_BASEPOINT_POINT, u,
+                                 MAX_REMAINDER).unwrap();
+
+        let pi = RedeemProof { b1, s_z, settle, range };
+        assert_eq!(zk_redeem_verify(&mut settle_transcript(), points, bal, vec![b_m], vec![g], pi),
+                   Err(CheckoutError::InvalidProof));
+    }
+
+    #[test]
+    fn signature_is_scoped() {
+        let (sk, vk) = signature_keygen();
+        let hm = &Scalar::random(&mut OsRng) * G;
+        let base = [3; 32];
+        let sigma = sign(&sk, (1, 7), &hm, base);
+
+        assert_eq!(super::verify(vk, (1, 7), &hm, &base, sigma), Ok(()));
+        for scope in [(2, 7), (1, 8)] {
+            assert_eq!(super::verify(vk, scope, &hm, &base, sigma), Err(CheckoutError::InvalidSignature));
+        }
+
+        // One receipt from another merchant spoils the batch
+        let other = sign(&sk, (2, 7), &hm, base);
+        assert_eq!(verify_batch(vk, 1, &[7, 7], &[hm, hm], &[base, base], &[sigma, sigma]), Ok(()));
+        assert_eq!(verify_batch(vk, 1, &[7, 7], &[hm, hm], &[base, base], &[sigma, other]),
+                   Err(CheckoutError::InvalidSignature));
+        assert_eq!(verify_batch(vk, 2, &[7], &[hm], &[base], &[sigma]), Err(CheckoutError::InvalidSignature));
+    }
+
+    #[test]
+    fn receipt_carries_every_category() {
+        let (sk, pk) = elgamal_keygen();
+        let (m, base) = ([4; 32], [5; 32]);
+        let (ct, sym_ct, nonce) = encrypt(pk, &[10, -3, 0], m, base).unwrap();
+        assert_eq!(decrypt(sk, (ct, sym_ct), nonce), Ok((m, vec![10, -3, 0], base)));
+
+        let gs: Vec<Point> = (0..3).map(|category| category_point(&base, category)).collect();
+        assert!(gs[0] != gs[1] && gs[1] != gs[2] && gs[0] != gs[2]);
+    }
+}
@@ -1,52 +1,191 @@
-mod crypto;
-pub(crate) use crypto::{pzip, puzip, TxAndProof, h_point, SettleProof};
+pub mod crypto;
+pub use crypto::{pzip, puzip, TxAndProof, h_point, SettleProof, RedeemProof};
 use rs_merkle::{MerkleTree, algorithms, Hasher, MerkleProof};
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::io;
+use std::path::Path;
+use std::sync::{Mutex, RwLock};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
 use std::vec::Vec;
-use serde_derive::Serialize;
+use serde_derive::{Serialize, Deserialize};
 use rand::Rng;
-use sha2::{Sha256, Digest};
 use ed25519_dalek::{Signature, SigningKey, VerifyingKey};
 use curve25519_dalek::ristretto::RistrettoPoint;
 use curve25519_dalek::scalar::Scalar;
 use aes_gcm::{Nonce};
 use generic_array::typenum::U12;
-use sha2::Sha512;
-
-pub(crate) type Com = [u8; 32];
-pub(crate) type Point = RistrettoPoint;
-pub(crate) type CPoint = [u8; 32];
-pub(crate) type Ciphertext = ((Point, Point), Vec<u8>, Nonce<U12>);
-pub(crate) type Receipt = (Ciphertext, TxAndProof);
+use zeroize::{Zeroize, ZeroizeOnDrop};
+use crate::auth::{self, AuthKey};
+use crate::coin::{self, ClientShare, CoinFlip, RecentBarcodes, SelectionPolicy, ServerShare};
+use crate::encoding;
+use crate::error::{CheckoutError, Result};
+use crate::expiry::{Expiry, Period};
+use crate::secret::Secret;
+use crate::session::{self, Sessions};
+use crate::settlement::{self, RedeemedPart, Redemption, Settlement, SettleStatus};
+use crate::store::Store;
+use crate::scheme::{LoyaltyScheme, SchemeServer, SchemeClient, SecurityLevel, Root, ServerId, MerchantId, Category, Account,
+                    MAX_RECEIPT_PAGE, DEFAULT_MAX_POINTS, DEFAULT_MERCHANT_NAME, DEFAULT_CATEGORY_NAME};
+use crate::transcript::Transcript;
+
+pub type Com = [u8; 32];
+pub type Point = RistrettoPoint;
+pub type CPoint = [u8; 32];
+pub type Ciphertext = ((Point, Point), Vec<u8>, Nonce<U12>);
+// A receipt ciphertext and the transaction's proofs, one for each category
+pub type Receipt = (Ciphertext, Vec<TxAndProof>);
+// A receipt, the merchant and period it was earned in, and the server's
+// signature on (merchant, period, h^m, base)
+pub type SignedReceipt = (Receipt, MerchantId, Period, Signature);
+// Barcode owner's user ID, barcode, public key, receipt base and period, and
+// Merkle inclusion proof
+pub type BarcodeData = (u32, u64, Point, ([u8; 32], Period), MerkleProof<algorithms::Sha256>);
+// Revealed balance, every h^m, base and period, the signatures on them, and
+// the settle proof
+pub type SettleData = (i32, Vec<Point>, Vec<[u8; 32]>, Vec<Period>, Vec<Signature>, SettleProof);
+// The period and amount of each part of a redemption, every h^m, base and
+// period in those periods, the signatures on them, and a redeem proof for
+// each part
+pub type RedeemData = (Vec<(Period, u32)>, Vec<Point>, Vec<[u8; 32]>, Vec<Period>, Vec<Signature>, Vec<RedeemProof>);
 
 //////////////////////////////////////////////////////////////////
 // Server code
 //////////////////////////////////////////////////////////////////
 
-pub(crate) struct Server {
-    num_users: u32,
+// Every method takes `&self`, so one server can process many transactions at
+// once. Locks are always taken in the order users, merkle_tree, receipts, and
+// tmp is never held while taking another lock.
+pub struct Server {
     sk: SigningKey,
     vk: VerifyingKey,
-    users: HashMap<u32, UserRecord>,
-    receipts: HashMap<u32, Vec<(Receipt, [u8; 32])>>,
-    merkle_tree: MerkleTree<algorithms::Sha256>,
-    tmp: HashMap<Com, ServerTxTmp>,
-    default_bal: CPoint
+    users: RwLock<HashMap<u32, UserRecord>>,
+    receipts: Mutex<HashMap<u32, Mailbox>>,
+    merkle_tree: RwLock<MerkleTree<algorithms::Sha256>>,
+    tmp: Sessions<ServerTxTmp, Signature>,
+    max_points: u32,   // Most points a single transaction may move
+    policy: SelectionPolicy, // Which users the coin flip may pick
+    recent: RecentBarcodes,
+    merchants: Vec<String>, // Merchant names, indexed by merchant ID
+    categories: Vec<String>, // Category names, indexed by category ID
+    expiry: Expiry,
+    retired: AtomicU32, // Oldest unexpired period when expired points were last dropped
+    store: Option<Store<Event>>
+}
+
+// A receipt waiting for delivery, the base string to sign it with, and the
+// merchant and period it was earned in
+type PendingReceipt = (Receipt, [u8; 32], MerchantId, Period);
+
+// Shopper's and barcode owner's user IDs, the merchant, and the base and
+// period of a transaction
+type TxParties = (u32, u32, MerchantId, ([u8; 32], Period));
+
+// A user's undelivered receipts, numbered in the order they were queued.
+// Receipts stay here until the user acknowledges them, so a fetch whose reply
+// is lost can simply be repeated.
+#[derive(Default, Serialize, Deserialize)]
+struct Mailbox {
+    last_seq: u64, // Sequence number of the most recently queued receipt
+    rcts: VecDeque<(u64, PendingReceipt)>,
 }
 
+impl Mailbox {
+    fn push(&mut self, rct: PendingReceipt) {
+        self.last_seq += 1;
+        self.rcts.push_back((self.last_seq, rct));
+    }
+
+    // Output: up to `limit` receipts queued after sequence number `after`, and
+    // whether more follow them
+    fn page(&self, after: u64, limit: usize) -> (Vec<(u64, PendingReceipt)>, bool) {
+        let start = self.rcts.partition_point(|(seq, _)| *seq <= after);
+        let page: Vec<_> = self.rcts.range(start..).take(limit).cloned().collect();
+        (page, start + limit < self.rcts.len())
+    }
+
+    // Removes every receipt up to and including sequence number `upto`
+    fn ack(&mut self, upto: u64) {
+        while self.rcts.front().is_some_and(|(seq, _)| *seq <= upto) {
+            self.rcts.pop_front();
+        }
+    }
+}
+
+// Changes to the server's durable state, in the order they are logged
+#[derive(Serialize, Deserialize)]
+enum Event {
+    Register { barcode: u64, pk_enc: CPoint, pk_auth: AuthKey },
+    // Both users' new balances with the merchant in the period, one for each
+    // category, and the receipt queued for the barcode owner
+    Tx { uid_s: u32, uid_b: u32, merchant: MerchantId, period: Period, bal_s: Vec<CPoint>, bal_b: Vec<CPoint>, rct: Box<PendingReceipt> },
+    // The user acknowledged their receipts up to sequence number `upto`
+    ReceiptsAcked { uid: u32, upto: u64 },
+    // The user's balance in an account was settled and reset to zero
+    Settled { settlement: Settlement },
+    // Points were redeemed from the user's balance in an account, leaving
+    // `balances` in the periods of the redemption's parts
+    Redeemed { balances: Vec<(Period, CPoint)>, redemption: Redemption },
+    // Every balance's parts for periods before `oldest` expired and were dropped
+    Retired { oldest: Period },
+}
+
+// Signing key, users and pending receipts. The Merkle tree is rebuilt from
+// the users when the snapshot is loaded.
+type Snapshot = (Secret<[u8; 32]>, HashMap<u32, UserRecord>, HashMap<u32, Mailbox>);
+
+#[derive(Clone)]
 struct ServerTxTmp {
     uid_s: u32, // Shopper's user ID
-    i_s: Option<u32>, // Server's chosen index for card-swapping phase
+    merchant: MerchantId, // Merchant the shopper is buying from
+    i_s: Option<ServerShare>, // Server's share of the coin flip that picks the barcode
     uid_b: Option<u32>, // Barcode owner's user ID
-    base: Option<[u8;32]>
+    base: Option<[u8;32]>,
+    period: Option<Period> // Period the transaction is recorded in, fixed when the base is chosen
 }
 
 // The server's record of a user in the system
-#[derive(Debug, Serialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 struct UserRecord {
     barcode: u64,
-    balance: CPoint,
-    pk_enc: CPoint
+    pk_enc: CPoint,
+    pk_auth: AuthKey, // Checks the requests only the user may make (see `auth`)
+    ledgers: HashMap<Account, Ledger> // Only for accounts the user has used
+}
+
+// A user's balance in one account, in one part for each period it was
+// changed in (see `expiry`). A missing part is the identity, an empty sum of
+// receipts.
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+struct Ledger {
+    balances: BTreeMap<Period, CPoint>,
+    epoch: u64, // Number of times the balance has been settled
+    last_settlement: Option<Settlement>,
+    last_redemption: Option<Redemption>
+}
+
+impl UserRecord {
+    // Output: the user's ledger in an account, which is empty if they have
+    // not used it
+    fn ledger(&self, account: Account) -> Ledger {
+        self.ledgers.get(&account).cloned().unwrap_or_default()
+    }
+
+    fn ledger_mut(&mut self, account: Account) -> &mut Ledger {
+        self.ledgers.entry(account).or_default()
+    }
+}
+
+impl Ledger {
+    // Output: the part of the balance for a period
+    fn part(&self, period: Period) -> Result<Point> {
+        puzip(self.balances.get(&period).copied().unwrap_or_default())
+    }
+
+    // Output: the balance made of the parts that have not expired
+    fn balance(&self, oldest: Period) -> Result<Point> {
+        self.balances.range(oldest..).map(|(_, bal)| puzip(*bal)).sum()
+    }
 }
 
 // User data stored in the server's Merkle tree
@@ -63,179 +202,696 @@ impl TreeEntry {
     }
 }
 
+// Applies a logged change to the server's state, both when it first happens
+// and when the log is replayed after a restart.
+// Output: None if the change refers to a user that doesn't exist
+fn apply(users: &mut HashMap<u32, UserRecord>, receipts: &mut HashMap<u32, Mailbox>, event: Event) -> Option<()> {
+    match event {
+        Event::Register { barcode, pk_enc, pk_auth } => {
+            let uid = users.len() as u32;
+            users.insert(uid, UserRecord { barcode, pk_enc, pk_auth, ledgers: HashMap::new() });
+            receipts.insert(uid, Mailbox::default());
+        }
+        Event::Tx { uid_s, uid_b, merchant, period, bal_s, bal_b, rct } => {
+            for (category, (bal_s, bal_b)) in bal_s.into_iter().zip(bal_b).enumerate() {
+                let account = (merchant, category as Category);
+                users.get_mut(&uid_s)?.ledger_mut(account).balances.insert(period, bal_s);
+                users.get_mut(&uid_b)?.ledger_mut(account).balances.insert(period, bal_b);
+            }
+            receipts.get_mut(&uid_b)?.push(*rct);
+        }
+        Event::ReceiptsAcked { uid, upto } => {
+            receipts.get_mut(&uid)?.ack(upto);
+        }
+        Event::Settled { settlement } => {
+            let ledger = users.get_mut(&settlement.uid)?.ledger_mut(settlement.account());
+            ledger.balances.clear();
+            ledger.epoch = settlement.epoch + 1;
+            ledger.last_settlement = Some(settlement);
+        }
+        Event::Redeemed { balances, redemption } => {
+            let ledger = users.get_mut(&redemption.uid)?.ledger_mut(redemption.account());
+            ledger.balances.extend(balances);
+            ledger.last_redemption = Some(redemption);
+        }
+        Event::Retired { oldest } => {
+            for ledger in users.values_mut().flat_map(|user| user.ledgers.values_mut()) {
+                ledger.balances.retain(|period, _| *period >= oldest);
+            }
+        }
+    }
+    Some(())
+}
+
+impl Default for Server {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Server {
-    pub(crate) fn new() -> Self {
+    pub fn new() -> Self {
         let keys = crypto::signature_keygen();
         Server {
-            num_users: 0,
             sk: keys.0,
             vk: keys.1,
-            users: HashMap::new(),
-            receipts: HashMap::new(),
-            merkle_tree: MerkleTree::<algorithms::Sha256>::new(),
-            tmp: HashMap::new(),
-            default_bal: pzip(crypto::G*&crypto::int_to_scalar(0))
+            users: RwLock::new(HashMap::new()),
+            receipts: Mutex::new(HashMap::new()),
+            merkle_tree: RwLock::new(MerkleTree::<algorithms::Sha256>::new()),
+            tmp: Sessions::default(),
+            max_points: DEFAULT_MAX_POINTS,
+            policy: SelectionPolicy::default(),
+            recent: RecentBarcodes::default(),
+            merchants: vec![DEFAULT_MERCHANT_NAME.to_string()],
+            categories: vec![DEFAULT_CATEGORY_NAME.to_string()],
+            expiry: Expiry::default(),
+            retired: AtomicU32::new(0),
+            store: None
+        }
+    }
+
+    // A server whose state is kept in `dir` and survives restarts. Opening a
+    // directory that holds no state starts a new server with a new signing key.
+    pub fn open(dir: &Path) -> io::Result<Self> {
+        let (store, snapshot, events) = Store::<Event>::open::<Snapshot>(dir)?;
+        let is_new = snapshot.is_none();
+        let (sk, mut users, mut receipts) = match snapshot {
+            Some((sk, users, receipts)) => (SigningKey::from_bytes(sk.expose()), users, receipts),
+            None => (crypto::signature_keygen().0, HashMap::new(), HashMap::new()),
+        };
+
+        // Redo every change logged since the snapshot
+        for event in events {
+            apply(&mut users, &mut receipts, event)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "log refers to an unknown user"))?;
+        }
+
+        let mut merkle_tree = MerkleTree::<algorithms::Sha256>::new();
+        for uid in 0..users.len() as u32 {
+            let user = &users[&uid];
+            let leaf = TreeEntry { uid, barcode: user.barcode, pk_enc: user.pk_enc };
+            merkle_tree.insert(algorithms::Sha256::hash(leaf.to_bytes().as_slice()));
+        }
+        merkle_tree.commit();
+
+        let server = Server {
+            vk: sk.verifying_key(),
+            sk,
+            users: RwLock::new(users),
+            receipts: Mutex::new(receipts),
+            merkle_tree: RwLock::new(merkle_tree),
+            tmp: Sessions::default(),
+            max_points: DEFAULT_MAX_POINTS,
+            policy: SelectionPolicy::default(),
+            recent: RecentBarcodes::default(),
+            merchants: vec![DEFAULT_MERCHANT_NAME.to_string()],
+            categories: vec![DEFAULT_CATEGORY_NAME.to_string()],
+            expiry: Expiry::default(),
+            retired: AtomicU32::new(0),
+            store: Some(store)
+        };
+
+        // Save the new signing key before it signs anything
+        if is_new {
+            server.checkpoint().map_err(|_| io::Error::other("could not write snapshot"))?;
+        }
+        Ok(server)
+    }
+
+    // Writes a snapshot of the server's state and clears its log. This also
+    // happens automatically every `store::CHECKPOINT_INTERVAL` changes.
+    pub fn checkpoint(&self) -> Result<()> {
+        let users = self.users.read().unwrap();
+        let receipts = self.receipts.lock().unwrap();
+        self.checkpoint_locked(&users, &receipts)
+    }
+
+    fn checkpoint_locked(&self, users: &HashMap<u32, UserRecord>, receipts: &HashMap<u32, Mailbox>) -> Result<()> {
+        match &self.store {
+            Some(store) => store.checkpoint(&(Secret::new(self.sk.to_bytes()), users, receipts)).map_err(|_| CheckoutError::Storage),
+            None => Ok(())
+        }
+    }
+
+    // Logs a change before it is applied.
+    // Output: whether a checkpoint is due
+    fn log(&self, event: &Event) -> Result<bool> {
+        match &self.store {
+            Some(store) => store.append(event).map_err(|_| CheckoutError::Storage),
+            None => Ok(false)
         }
     }
 
-    pub(crate) fn register_user(&mut self, barcode: u64, pk_enc: CPoint) {
-        let user_rec = UserRecord {
-            barcode: barcode,
-            balance: self.default_bal.clone(),
-            pk_enc: pk_enc};
+    // Output: the new user's ID
+    pub fn register_user(&self, barcode: u64, pk_enc: CPoint, pk_auth: AuthKey) -> Result<u32> {
+        // Reject keys that are not valid points now, rather than when they are
+        // handed out to a shopper during a transaction
+        puzip(pk_enc)?;
+        auth::check_key(&pk_auth)?;
+
+        let mut users = self.users.write().unwrap();
+        let mut merkle_tree = self.merkle_tree.write().unwrap();
+        let mut receipts = self.receipts.lock().unwrap();
+        let uid = users.len() as u32;
+
         let leaf = TreeEntry {
-            uid: self.num_users,
-            barcode: barcode,
-            pk_enc: pk_enc
+            uid,
+            barcode,
+            pk_enc
         };
 
         // Add user to list and to merkle tree, and make a place to put receipts in transit
-        self.users.insert(
-            self.num_users,
-            user_rec
-        );
-        self.merkle_tree.insert(algorithms::Sha256::hash(leaf.to_bytes().as_slice()));
-        self.merkle_tree.commit();
+        let event = Event::Register { barcode, pk_enc, pk_auth };
+        let checkpoint_due = self.log(&event)?;
+        apply(&mut users, &mut receipts, event);
+        merkle_tree.insert(algorithms::Sha256::hash(leaf.to_bytes().as_slice()));
+        merkle_tree.commit();
+
+        if checkpoint_due {
+            // A failed checkpoint loses nothing, since the log is kept
+            let _ = self.checkpoint_locked(&users, &receipts);
+        }
 
-        self.receipts.insert(
-            self.num_users,
-            Vec::new()
-        );
+        Ok(uid)
+    }
+
+    // The server is identified by its signature verification key
+    pub fn server_id(&self) -> ServerId {
+        self.vk.to_bytes()
+    }
+
+    // Sets the most points a single transaction may move. Clients learn it
+    // with the rest of the server's state, and prove that every transaction
+    // is within it.
+    pub fn set_max_points(&mut self, max: u32) {
+        self.max_points = max;
+    }
 
-        self.num_users += 1;
+    // Sets which users the barcode coin flip may pick. Clients learn the
+    // policy along with the Merkle root.
+    pub fn set_selection_policy(&mut self, policy: SelectionPolicy) {
+        self.policy = policy;
     }
 
-    pub(crate) fn share_state(&self) -> (u32, <algorithms::Sha256 as rs_merkle::Hasher>::Hash) {
-        let root = self.merkle_tree.root().unwrap();
-        return (self.num_users, root);
+    // Sets the merchants that issue points through this server. A merchant's
+    // ID is its position in `names`, and balances are kept by ID, so a server
+    // that is set up again must keep its merchants in the same order and only
+    // add new ones at the end.
+    pub fn set_merchants(&mut self, names: Vec<String>) {
+        self.merchants = names;
+    }
+
+    // Output: the merchants' names, indexed by merchant ID
+    pub fn merchants(&self) -> &[String] {
+        &self.merchants
+    }
+
+    fn check_merchant(&self, merchant: MerchantId) -> Result<()> {
+        if merchant as usize >= self.merchants.len() {
+            return Err(CheckoutError::UnknownMerchant);
+        }
+        Ok(())
+    }
+
+    // Sets the categories of points the server keeps. Every transaction moves
+    // an amount of each, in this order. Like merchants, categories are kept
+    // by ID, so new ones may only be added at the end.
+    pub fn set_categories(&mut self, names: Vec<String>) {
+        self.categories = names;
+    }
+
+    // Output: the categories' names, indexed by category ID
+    pub fn categories(&self) -> &[String] {
+        &self.categories
+    }
+
+    fn check_account(&self, (merchant, category): Account) -> Result<()> {
+        self.check_merchant(merchant)?;
+        if category as usize >= self.categories.len() {
+            return Err(CheckoutError::UnknownCategory);
+        }
+        Ok(())
+    }
+
+    // Checks that a transaction has a proof for each category, and that the
+    // proofs are all about the same h^m, so every category's amount is masked
+    // with the same m and the receipt's one signature covers them all
+    fn check_categories(&self, txs: &[TxAndProof]) -> Result<()> {
+        if txs.is_empty() || txs.len() != self.categories.len() {
+            return Err(CheckoutError::WrongCategories);
+        }
+        if txs.iter().any(|tx| tx.r2 != txs[0].r2) {
+            return Err(CheckoutError::InvalidProof);
+        }
+        Ok(())
+    }
+
+    // Sets how long points count for (see `expiry`)
+    pub fn set_expiry(&mut self, expiry: Expiry) {
+        self.expiry = expiry;
+    }
+
+    // Drops the parts of every balance whose points have expired. Nothing is
+    // done if no period has expired since the last time.
+    // Output: how many parts were dropped
+    pub fn retire_expired(&self) -> Result<usize> {
+        let oldest = self.expiry.oldest();
+        if oldest <= self.retired.load(Ordering::Relaxed) {
+            return Ok(0);
+        }
+
+        let mut users = self.users.write().unwrap();
+        let mut receipts = self.receipts.lock().unwrap();
+        let expired = users.values()
+            .flat_map(|user| user.ledgers.values())
+            .map(|ledger| ledger.balances.range(..oldest).count())
+            .sum();
+        if expired > 0 {
+            let event = Event::Retired { oldest };
+            let checkpoint_due = self.log(&event)?;
+            apply(&mut users, &mut receipts, event);
+            if checkpoint_due {
+                // A failed checkpoint loses nothing, since the log is kept
+                let _ = self.checkpoint_locked(&users, &receipts);
+            }
+        }
+        self.retired.store(oldest, Ordering::Relaxed);
+        Ok(expired)
+    }
+
+    // Sets how long a transaction may take before the server drops it
+    pub fn set_tx_ttl(&mut self, ttl: Duration) {
+        self.tmp.set_ttl(ttl);
+    }
+
+    // Sets how many transactions may be in progress at once
+    pub fn set_max_open_txs(&mut self, max: usize) {
+        self.tmp.set_max_open(max);
+    }
+
+    // Sets how long the server remembers the transactions it has carried
+    // out, and so refuses their IDs and answers their replays
+    pub fn set_replay_window(&mut self, window: Duration) {
+        self.tmp.set_replay_window(window);
+    }
+
+    // Drops a transaction that the client has given up on
+    pub fn abort_tx(&self, tx_id: Com) {
+        self.tmp.abort(&tx_id);
+    }
+
+    // Drops every transaction that has taken too long.
+    // Output: how many were dropped
+    pub fn sweep_txs(&self) -> usize {
+        self.tmp.sweep()
+    }
+
+    pub fn share_state(&self) -> Result<(u32, <algorithms::Sha256 as rs_merkle::Hasher>::Hash, ServerId, u32, SelectionPolicy)> {
+        let users = self.users.read().unwrap();
+        let root = self.merkle_tree.read().unwrap().root().ok_or(CheckoutError::NoUsers)?;
+        Ok((users.len() as u32, root, self.server_id(), self.max_points, self.policy.clone()))
     }
 
     // Step 1 of a transaction request
     
-    // Input: shopper user ID, commitment to a chosed random ID
-    // Output: a server-chosen random ID
-    pub(crate) fn process_tx_hello_response(&mut self, com: Com, uid_s: u32) -> u32 {
-        let i_s = rand::thread_rng().gen_range(0..self.num_users);
+    // Input: shopper user ID, commitment to the client's share of the coin
+    // flip, the merchant the shopper is buying from, and the shopper's
+    // signature on them
+    // Output: the server's share of the coin flip
+    pub fn process_tx_hello_response(&self, com: Com, uid_s: u32, merchant: MerchantId, sigma: &Signature) -> Result<ServerShare> {
+        let pk_auth = self.users.read().unwrap().get(&uid_s).ok_or(CheckoutError::UnknownUser)?.pk_auth;
+        auth::verify_tx_hello(&pk_auth, &self.server_id(), (&com, uid_s, merchant), sigma)?;
+        self.check_merchant(merchant)?;
+
+        let i_s = coin::server_share(self.recent.get(uid_s, self.policy.recent));
         let tmp = ServerTxTmp {
-            uid_s: uid_s,
-            i_s: Some(i_s),
+            uid_s,
+            merchant,
+            i_s: Some(i_s.clone()),
             uid_b: None,
-            base: None
+            base: None,
+            period: None
         };
 
         // Store in-progress TX info server side
-        self.tmp.insert(
-            com,
-            tmp
-        );
+        self.tmp.open(com, tmp)?;
         
-        i_s
+        Ok(i_s)
     }
 
     // Step 2 of a transaction request
 
-    // Input: shopper UID, opened commitment contents: client-chosen random ID and mask
-    // Output: barcode owner's UID, barcode, and public key, and merkle inclusion proof
-    pub(crate) fn process_tx_barcode_gen(&mut self, i_c: u32, r: [u8; 32], tx_id: Com) -> (u32, u64, Point, [u8; 32], MerkleProof<algorithms::Sha256>) {
-        let tmp: &mut ServerTxTmp = self.tmp.get_mut(&tx_id).unwrap();
+    // Input: opened commitment contents: the client's share and mask
+    // Output: barcode owner's UID, barcode, and public key, the base and period
+    // of the receipt, and merkle inclusion proof
+    pub fn process_tx_barcode_gen(&self, i_c: ClientShare, r: [u8; 32], tx_id: Com) -> Result<BarcodeData> {
+        let tmp: ServerTxTmp = self.tmp.get(&tx_id)?;
 
         // Recompute commitment and check that it matches.
-        let mut hasher = Sha256::new();
-        hasher.update(i_c.to_le_bytes());
-        hasher.update(r);
-        let com_test: Com = hasher.finalize().into();
-
-        assert!(com_test == tx_id, "Invalid commit");
-
-        let uid_b = (i_c + tmp.i_s.unwrap()) % self.num_users;
+        if i_c.commit(&r) != tx_id {
+            return Err(CheckoutError::BadCommitment);
+        }
 
-        tmp.uid_b = Some(uid_b);
+        let i_s = tmp.i_s.ok_or(CheckoutError::UnknownTx)?;
 
-        let user_b: &UserRecord = &self.users.get(&uid_b).unwrap();
-        let barcode = user_b.barcode;
-        let pk_b = user_b.pk_enc;
+        let (uid_b, barcode, pk_b, pi) = {
+            let users = self.users.read().unwrap();
+            let uid_b = coin::joint_index(&i_c, &i_s, tmp.uid_s, users.len() as u32, &self.policy)?;
 
-        let pi: MerkleProof<algorithms::Sha256> = self.merkle_tree.proof(&[uid_b.try_into().unwrap()]);
+            let user_b: &UserRecord = users.get(&uid_b).ok_or(CheckoutError::UnknownUser)?;
+            let pi: MerkleProof<algorithms::Sha256> = self.merkle_tree.read().unwrap().proof(&[uid_b.try_into().unwrap()]);
+            (uid_b, user_b.barcode, puzip(user_b.pk_enc)?, pi)
+        };
+        self.recent.record(tmp.uid_s, uid_b, self.policy.recent);
 
         // Select random base for the client to use
         let base = rand::thread_rng().gen::<[u8; 32]>();
-        tmp.base = Some(base);
+        let period = self.expiry.current();
+
+        self.tmp.update(&tx_id, |tmp| {
+            tmp.uid_b = Some(uid_b);
+            tmp.base = Some(base);
+            tmp.period = Some(period);
+        })?;
 
-        (uid_b, barcode, puzip(pk_b), base, pi)
+        Ok((uid_b, barcode, pk_b, (base, period), pi))
     }
 
     // Step 3 of a transaction request
 
-    // Input: tx_id, encrypted m, masked m (h^m) masked points (g^mx), and ZK correctness proof
-    // Output: a signature on h^m
-    pub(crate) fn process_tx(&mut self, ct: Ciphertext, tx: TxAndProof, tx_id: Com) -> Signature {
+    // Input: tx_id, encrypted m and amounts, and for each category, masked m
+    // (h^m), masked points (g^mx) and ZK correctness proof
+    // Output: a signature on the merchant, period and h^m
+    pub fn process_tx(&self, ct: Ciphertext, txs: Vec<TxAndProof>, tx_id: Com) -> Result<Signature> {
+        // Sending a transaction that was already carried out again returns
+        // the same signature, but it is not applied twice
+        let hash = session::submission_hash(&(&ct, &txs));
+        if let Some(sigma) = self.tmp.replayed(&tx_id, &hash)? {
+            return Ok(sigma);
+        }
 
-        let tmp: &ServerTxTmp = self.tmp.get(&tx_id).unwrap();
-        let uid_s = tmp.uid_s;
-        let uid_b = tmp.uid_b.unwrap();
-        let base = tmp.base.unwrap();
-        let g = Point::hash_from_bytes::<Sha512>(&base);
+        let parties = self.tx_parties(&tx_id)?;
+        self.check_categories(&txs)?;
 
-        assert!(crypto::zk_tx_verify(&tx, g), "Transaction proof failed");
-        
-        let hm = tx.r2.clone();
-        let gmx = tx.r3.clone(); // base^mx
-        
-        // Update both users' balances
-        let bal_s = puzip(self.users[&uid_s].balance);
-        self.users.get_mut(&uid_s).unwrap().balance = pzip(bal_s + gmx);
+        // Each category has its own generator, derived from the same base
+        for (category, tx) in txs.iter().enumerate() {
+            let category = category as Category;
+            let g = crypto::category_point(&parties.3.0, category);
+            crypto::zk_tx_verify(&mut Transcript::for_tx(&self.server_id(), &tx_id, category), tx, g, self.max_points)?;
+        }
 
-        let bal_b = puzip(self.users[&uid_b].balance);
-        self.users.get_mut(&uid_b).unwrap().balance = pzip(bal_b + gmx * &crypto::int_to_scalar(-1));
-        
-        // Store the receipt to send to the barcode owner
-        let rct  = (ct, tx);
-        let rcts = self.receipts.get_mut(&uid_b).unwrap();
-        rcts.push((rct, base));
+        self.record_tx(ct, txs, parties, tx_id, hash)
+    }
 
-        // Sign and return (h^m, r)
-        crypto::sign(&self.sk, &hm, base)
+    // Step 3 for many transactions at once. The proofs are checked together
+    // (see `crypto::zk_tx_verify_batch`), which is much cheaper than checking
+    // them one at a time; the transactions are then applied in order.
+    // Output: for each transaction, its signature or why it was rejected
+    pub fn process_tx_batch(&self, txs: Vec<(Ciphertext, Vec<TxAndProof>, Com)>) -> Vec<Result<Signature>> {
+        let server_id = self.server_id();
+        let hashes: Vec<[u8; 32]> = txs.iter()
+            .map(|(ct, tx, _)| session::submission_hash(&(ct, tx)))
+            .collect();
+        // Transactions sent again after being carried out are answered
+        // without being checked or applied again
+        let replies: Vec<Option<Result<Signature>>> = txs.iter().zip(&hashes)
+            .map(|((_, _, tx_id), hash)| self.tmp.replayed(tx_id, hash).transpose())
+            .collect();
+        let parties: Vec<Result<TxParties>> = txs.iter().zip(&replies)
+            .map(|((_, tx, tx_id), reply)| match reply {
+                Some(_) => Err(CheckoutError::DuplicateTx),
+                None => self.check_categories(tx).and_then(|_| self.tx_parties(tx_id)),
+            })
+            .collect();
+
+        // Only transactions the server knows about are put in the batch, with
+        // one proof for each category
+        let batch = txs.iter().zip(&parties)
+            .filter_map(|((_, tx, tx_id), p)| p.as_ref().ok().map(|(_, _, _, (base, _))| (tx, tx_id, base)))
+            .flat_map(|(tx, tx_id, base)| tx.iter().enumerate().map(move |(category, pi)| {
+                let category = category as Category;
+                (Transcript::for_tx(&server_id, tx_id, category), pi, crypto::category_point(base, category))
+            }))
+            .collect();
+        let mut verified = crypto::zk_tx_verify_batch(batch, self.max_points).into_iter();
+
+        txs.into_iter().zip(parties).zip(replies.into_iter().zip(hashes)).map(|(((ct, tx, tx_id), p), (reply, hash))| {
+            if let Some(reply) = reply {
+                return reply;
+            }
+            let parties = p?;
+            // Every category's result is taken before any is checked, so the
+            // next transaction starts at its own
+            let checks: Vec<Result<()>> = verified.by_ref().take(tx.len()).collect();
+            checks.into_iter().collect::<Result<Vec<()>>>()?;
+            self.record_tx(ct, tx, parties, tx_id, hash)
+        }).collect()
+    }
+
+    // Output: the shopper, barcode owner, merchant, and base and period of a
+    // transaction that has reached step 3
+    fn tx_parties(&self, tx_id: &Com) -> Result<TxParties> {
+        let tmp: ServerTxTmp = self.tmp.get(tx_id)?;
+        let uid_b = tmp.uid_b.ok_or(CheckoutError::UnknownTx)?;
+        let base = tmp.base.ok_or(CheckoutError::UnknownTx)?;
+        let period = tmp.period.ok_or(CheckoutError::UnknownTx)?;
+        Ok((tmp.uid_s, uid_b, tmp.merchant, (base, period)))
+    }
+
+    // Applies a transaction whose proof has been verified
+    // Input: the transaction, its parties and base (see `tx_parties`), its ID
+    // and the hash of the submission
+    fn record_tx(&self, ct: Ciphertext, txs: Vec<TxAndProof>, parties: TxParties, tx_id: Com, hash: [u8; 32]) -> Result<Signature> {
+        let (uid_s, uid_b, merchant, (base, period)) = parties;
+        let hm = txs[0].r2; // The same in every category (see `check_categories`)
+        let sigma = crypto::sign(&self.sk, (merchant, period), &hm, base);
+
+        {
+            // Both balances and the receipt queue are updated under the same
+            // locks, so concurrent transactions touching the same users see
+            // each other's updates
+            let mut users = self.users.write().unwrap();
+            let mut receipts = self.receipts.lock().unwrap();
+
+            // Checking the ID is still open under the same locks makes sure
+            // that a transaction submitted twice at once is only applied once
+            self.tmp.get(&tx_id)?;
+
+            // Compute both users' new balances in every category before
+            // touching any, so a failure leaves them all unchanged
+            let user_s = users.get(&uid_s).ok_or(CheckoutError::UnknownUser)?;
+            let user_b = users.get(&uid_b).ok_or(CheckoutError::UnknownUser)?;
+            if !receipts.contains_key(&uid_b) {
+                return Err(CheckoutError::UnknownUser);
+            }
+
+            let mut bals_s = Vec::with_capacity(txs.len());
+            let mut bals_b = Vec::with_capacity(txs.len());
+            for (category, tx) in txs.iter().enumerate() {
+                let account = (merchant, category as Category);
+                let gmx = tx.r3; // g^mx, for the category's generator g
+                let bal_s = user_s.ledger(account).part(period)? + gmx;
+                let bal_b = if uid_b == uid_s { bal_s } else { user_b.ledger(account).part(period)? };
+                let bal_b = bal_b + gmx * crypto::int_to_scalar(-1);
+                bals_s.push(pzip(bal_s));
+                bals_b.push(pzip(bal_b));
+            }
+
+            // Update both users' balances, and store the receipt to send to the
+            // barcode owner
+            let rct  = (ct, txs);
+            let event = Event::Tx {
+                uid_s, uid_b, merchant, period, bal_s: bals_s, bal_b: bals_b, rct: Box::new((rct, base, merchant, period))
+            };
+            let checkpoint_due = self.log(&event)?;
+            apply(&mut users, &mut receipts, event);
+
+            // The ID is only spent once the transaction is logged, so if
+            // logging fails it stays open and can be submitted again. One sent
+            // again later gets the same signature.
+            self.tmp.spend(&tx_id, hash, sigma);
+
+            if checkpoint_due {
+                // A failed checkpoint loses nothing, since the log is kept
+                let _ = self.checkpoint_locked(&users, &receipts);
+            }
+        }
+
+        // Return the signature on (merchant, period, h^m, r)
+        Ok(sigma)
     }
 
     // Receipt distribution
-    pub(crate) fn send_receipts(&mut self, uid: u32) -> Vec<(Receipt, Signature)> {
+    // Input: user ID, sequence number of the last receipt already fetched (0
+    // for the oldest), maximum number of receipts to return
+    // Output: the receipts, the sequence number of the last one, and whether
+    // more are queued after it
+    pub fn send_receipts(&self, uid: u32, after: u64, limit: u32) -> Result<(Vec<SignedReceipt>, u64, bool)> {
         let mut out = Vec::new();
 
-        let rcts = self.receipts.get_mut(&uid).unwrap();
+        // Receipts are only removed once acknowledged, so nothing is logged here
+        let limit = limit.clamp(1, MAX_RECEIPT_PAGE) as usize;
+        let (pending, more) = self.receipts.lock().unwrap()
+            .get(&uid).ok_or(CheckoutError::UnknownUser)?
+            .page(after, limit);
+        let last = pending.last().map_or(after, |(seq, _)| *seq);
 
-        // Unpack h^m and base, and sign (h^m, base)
-        for (rct, base) in &*rcts {
-            let hm = rct.1.r2;
-            let sigma = crypto::sign(&self.sk, &hm, *base);
+        // Unpack h^m and base, and sign (merchant, period, h^m, base)
+        for (_, (rct, base, merchant, period)) in pending {
+            let hm = rct.1[0].r2;
+            let sigma = crypto::sign(&self.sk, (merchant, period), &hm, base);
 
-            out.push((rct.clone(), sigma));
+            out.push((rct, merchant, period, sigma));
         }
 
-        rcts.remove(uid.try_into().unwrap());
-        out
+        Ok((out, last, more))
     }
 
-    // Accept or reject a client's request to settle
-    pub(crate) fn settle_balance(&self, uid: u32, x: i32, hms: Vec<Point>, rs: Vec<[u8; 32]>, sigmas: Vec<Signature>, pi: SettleProof) -> bool {
-        let server_bal = crypto::puzip(self.users[&uid].balance);
+    // Removes a user's receipts up to and including sequence number `upto`,
+    // once they have processed them
+    // Input: user ID, sequence number, and the user's signature on both
+    pub fn ack_receipts(&self, uid: u32, upto: u64, sigma: &Signature) -> Result<()> {
+        let pk_auth = self.users.read().unwrap().get(&uid).ok_or(CheckoutError::UnknownUser)?.pk_auth;
+        auth::verify_ack(&pk_auth, &self.server_id(), (uid, upto), sigma)?;
+
+        let mut receipts = self.receipts.lock().unwrap();
+        let mailbox = receipts.get_mut(&uid).ok_or(CheckoutError::UnknownUser)?;
+
+        // Acknowledging the same receipts twice changes nothing
+        if mailbox.rcts.front().is_some_and(|(seq, _)| *seq <= upto) {
+            // Not checkpointing here even if one is due, since that needs the
+            // users lock; the next registration or transaction will do it
+            self.log(&Event::ReceiptsAcked { uid, upto })?;
+            mailbox.ack(upto);
+        }
+        Ok(())
+    }
 
-        let mut gs = Vec::<Point>::with_capacity(sigmas.len());
+    // Output: the user's current epoch in an account, the oldest period whose
+    // points have not expired, and their last settlement and redemption in
+    // the account, which start a settlement or redemption (see `settlement`)
+    pub fn settle_balance_hello(&self, uid: u32, account: Account) -> Result<SettleStatus> {
+        self.check_account(account)?;
+        let users = self.users.read().unwrap();
+        let ledger = users.get(&uid).ok_or(CheckoutError::UnknownUser)?.ledger(account);
+        Ok(SettleStatus {
+            merchant: account.0, category: account.1, epoch: ledger.epoch, oldest: self.expiry.oldest(),
+            last: ledger.last_settlement, last_redemption: ledger.last_redemption
+        })
+    }
 
-        // Issue is with verifying signatures
-        for i in 0..sigmas.len() {
-            let hm = &hms[i];
-            let r = &rs[i];
-            let s = sigmas[i];
+    // Accept or reject a client's request to settle their balance in an
+    // account. An accepted request resets the balance to zero and starts
+    // their next epoch in the account.
+    // Output: the signed settlement receipt
+    pub fn settle_balance(&self, uid: u32, account: Account, req: SettleData) -> Result<Settlement> {
+        let (x, hms, rs, periods, sigmas, pi) = req;
+        let (ledger, oldest) = self.check_receipts(uid, account, (&hms, &rs, &periods), &sigmas)?;
+        let (server_bal, epoch) = (ledger.balance(oldest)?, ledger.epoch);
+        let gs: Vec<Point> = rs.iter().map(|r| crypto::category_point(r, account.1)).collect();
+
+        crypto::zk_settle_verify(&mut Transcript::for_settle(&self.server_id(), uid, account, epoch), x, server_bal, hms, gs, pi)?;
+
+        // Expired parts of the balance are not settled, and are dropped along
+        // with the rest
+        let settlement = Settlement::sign(&self.sk, (uid, account), epoch, x, sigmas.len() as u32);
+        self.record_settle((uid, account), (epoch, oldest, server_bal), Event::Settled { settlement })?;
+        Ok(settlement)
+    }
 
-            if !crypto::verify(self.vk, hm, r, s) {
-                return false;
-            }
+    // Redeems part of a user's balance in an account, without the rest being
+    // revealed. The amount is split over the periods it is taken from (see
+    // `settlement`).
+    // Input: user ID, the account, the period and amount of each part, every
+    // h^m, base and period the client holds a receipt for in those periods,
+    // the signatures on them, and a redeem proof for each part
+    // Output: the signed redemption receipt
+    pub fn redeem(&self, uid: u32, account: Account, req: RedeemData) -> Result<Redemption> {
+        let (parts, hms, rs, periods, sigmas, pis) = req;
+        let (ledger, oldest) = self.check_receipts(uid, account, (&hms, &rs, &periods), &sigmas)?;
+        settlement::check_parts(&parts, oldest, crypto::MAX_REMAINDER)?;
+        if pis.len() != parts.len() || periods.iter().any(|period| !parts.iter().any(|(p, _)| p == period)) {
+            return Err(CheckoutError::InvalidProof);
+        }
+        let (server_bal, epoch) = (ledger.balance(oldest)?, ledger.epoch);
+
+        // Each part is proven against its period's share of the balance, made
+        // of the receipts from that period
+        let mut transcript = Transcript::for_settle(&self.server_id(), uid, account, epoch);
+        for (&(period, points), pi) in parts.iter().zip(pis) {
+            let in_period: Vec<usize> = (0..periods.len()).filter(|i| periods[*i] == period).collect();
+            let hms = in_period.iter().map(|i| hms[*i]).collect();
+            let gs = in_period.iter().map(|i| crypto::category_point(&rs[*i], account.1)).collect();
+            transcript.append_u64(b"period", period as u64);
+            crypto::zk_redeem_verify(&mut transcript, points, ledger.part(period)?, hms, gs, pi)?;
+        }
 
-            let g = Point::hash_from_bytes::<Sha512>(r);
-            gs.push(g);
+        // Each part is recorded like a receipt for -points in its period, with
+        // mask 1 and a base of its own, so the balance stays a sum of receipts
+        let nonce = rand::thread_rng().gen::<[u8; 32]>();
+        let mut redemption = Redemption { uid, merchant: account.0, category: account.1, epoch, parts: Vec::new(), nonce };
+        let mut balances = Vec::with_capacity(parts.len());
+        for (period, points) in parts {
+            let base = Redemption::base_for((uid, account), (epoch, period), points, &nonce);
+            let sigma = crypto::sign(&self.sk, (account.0, period), &h_point(), base);
+            let g = crypto::category_point(&base, account.1);
+            balances.push((period, pzip(ledger.part(period)? - g * Scalar::from(points))));
+            redemption.parts.push(RedeemedPart { period, points, sigma });
+        }
+
+        let event = Event::Redeemed { balances, redemption: redemption.clone() };
+        self.record_settle((uid, account), (epoch, oldest, server_bal), event)?;
+        Ok(redemption)
+    }
+
+    // Checks the signatures on the receipts a settle or redeem request is
+    // made from, which must all have been earned with the account's merchant
+    // in periods that have not expired.
+    // Input: the user and account, every h^m, base and period, and the
+    // signatures on them
+    // Output: the user's ledger in the account, which the request's proof is
+    // checked against, and the oldest period that has not expired
+    fn check_receipts(&self, uid: u32, account: Account, (hms, rs, periods): (&[Point], &[[u8; 32]], &[Period]),
+                      sigmas: &[Signature]) -> Result<(Ledger, Period)> {
+        self.check_account(account)?;
+        let ledger = self.users.read().unwrap().get(&uid).ok_or(CheckoutError::UnknownUser)?.ledger(account);
+        let oldest = self.expiry.oldest();
+
+        if hms.len() != sigmas.len() || rs.len() != sigmas.len() || periods.len() != sigmas.len() {
+            return Err(CheckoutError::InvalidProof);
+        }
+        if periods.iter().any(|period| *period < oldest) {
+            return Err(CheckoutError::PointsExpired);
+        }
+        crypto::verify_batch(self.vk, account.0, periods, hms, rs, sigmas)?;
+
+        Ok((ledger, oldest))
+    }
+
+    // Applies a settlement or redemption whose proof has been verified
+    // Input: the user and account, the epoch, oldest unexpired period and
+    // balance the proof was checked against, and the change
+    fn record_settle(&self, (uid, account): (u32, Account), (epoch, oldest, bal): (u64, Period, Point), event: Event)
+                     -> Result<()> {
+        let mut users = self.users.write().unwrap();
+        let mut receipts = self.receipts.lock().unwrap();
+
+        // A transaction, another settlement or a period ending may have
+        // changed the balance while the proof was checked, in which case it no
+        // longer applies
+        let ledger = users.get(&uid).ok_or(CheckoutError::UnknownUser)?.ledger(account);
+        if ledger.epoch != epoch || self.expiry.oldest() != oldest || ledger.balance(oldest)? != bal {
+            return Err(CheckoutError::StaleSettlement);
         }
 
-        crypto::zk_settle_verify(x, server_bal, hms, gs, pi)
+        let checkpoint_due = self.log(&event)?;
+        apply(&mut users, &mut receipts, event);
+
+        if checkpoint_due {
+            // A failed checkpoint loses nothing, since the log is kept
+            let _ = self.checkpoint_locked(&users, &receipts);
+        }
+        Ok(())
     }
 }
 
@@ -243,240 +899,900 @@ impl Server {
 // Client code
 //////////////////////////////////////////////////////////////////
 
-// m, h^m, base string, sigma_(h^m) stored until settling time
-type ClientReceipt = (Scalar, Scalar, Point, [u8;32], Signature);
+// x, m, h^m, base string, period, sigma_(h^m) stored until settling time
+type ClientReceipt = (i32, Scalar, Point, [u8;32], Period, Signature);
 
-pub(crate) struct Client {
+// Compressed receipt ciphertext, remembered to detect a receipt delivered twice
+type SeenCiphertext = ([u8; 32], [u8;32], Vec<u8>, Nonce<U12>);
+
+#[derive(Serialize, Deserialize)]
+pub struct Client {
     pub barcode: u64,
     uid: u32,
     num_users: u32,
     merkle_root: Option<<algorithms::Sha256 as rs_merkle::Hasher>::Hash>,
+    server_id: ServerId,
+    max_points: u32,
+    coin_flip: CoinFlip,
+    policy: SelectionPolicy,
+    ledgers: HashMap<Account, ClientLedger>,
+    seen_cts: HashSet<SeenCiphertext>,
+    tmp: HashMap<Com, ClientTxTmp>,
+    sk_enc: Secret<Scalar>,
+    pk_enc: Point,
+    sk_auth: Secret<[u8; 32]> // Signs the requests only this user may make (see `auth`)
+}
+
+// The client's balance in one account, and the receipts it is made of
+#[derive(Serialize, Deserialize, Default)]
+struct ClientLedger {
     bal: i32,
     server_bal: Point,
     receipts: Vec<ClientReceipt>,
-    seen_cts: HashSet<([u8; 32], [u8;32], Vec<u8>, Nonce<U12>)>,
-    tmp: HashMap<Com, ClientTxTmp>,
-    sk_enc: Scalar,
-    pk_enc: Point
+    epoch: u64, // Number of times the balance has been settled
+}
+
+impl ClientLedger {
+    // Drops the receipts of periods before `oldest`, whose points have
+    // expired, and takes them out of the balance
+    // Input: the ledger's category, which picks each receipt's generator
+    fn expire(&mut self, category: Category, oldest: Period) {
+        for (x, m, _, base, _, _) in self.receipts.iter().filter(|rct| rct.4 < oldest) {
+            self.bal -= x;
+            self.server_bal -= crypto::category_point(base, category) * (m * crypto::int_to_scalar(*x));
+        }
+        self.receipts.retain(|rct| rct.4 >= oldest);
+    }
 }
 
+#[derive(Serialize, Deserialize, Zeroize, ZeroizeOnDrop)]
 struct ClientTxTmp {
-    i_c: Option<u32>,
+    started: u64, // When the transaction started (see `session::now`)
+    #[zeroize(skip)]
+    merchant: MerchantId,
+    i_c: Option<ClientShare>,
     r: Option<[u8; 32]>,
     uid_b: Option<u32>,
     m: Option<Scalar>,
     hm: Option<Point>,
-    x: Option<Scalar>,
-    base: Option<[u8; 32]>
+    xs: Option<Vec<i32>>,
+    base: Option<[u8; 32]>,
+    period: Option<Period>
 }
 
 impl Client {
-    pub(crate) fn new(barcode: u64) -> Self {
+    pub fn new(barcode: u64) -> Self {
         let keys = crypto::elgamal_keygen();
+        let (sk_auth, _) = auth::keygen();
         Client {
-            barcode: barcode,
+            barcode,
             uid: 1,
             num_users: 1,
             merkle_root: None,
-            bal: 0,
-            server_bal: crypto::G*&crypto::int_to_scalar(0),
-            receipts: Vec::new(),
+            server_id: [0u8; 32],
+            max_points: DEFAULT_MAX_POINTS,
+            coin_flip: CoinFlip::default(),
+            policy: SelectionPolicy::default(),
+            ledgers: HashMap::new(),
             seen_cts: HashSet::new(),
             tmp: HashMap::new(),
-            sk_enc: keys.0,
-            pk_enc: keys.1
+            sk_enc: Secret::new(keys.0),
+            pk_enc: keys.1,
+            sk_auth
         }
     }
 
-    pub(crate) fn register_with_server(&self) -> (u64, [u8; 32]) {
-        (self.barcode, crypto::pzip(self.pk_enc))
+    pub fn register_with_server(&self) -> (u64, [u8; 32], AuthKey) {
+        (self.barcode, crypto::pzip(self.pk_enc), auth::public_key(&self.sk_auth))
     }
 
-    pub(crate) fn update_state(&mut self, uid: u32, num_users: u32, merkle_root: <algorithms::Sha256 as Hasher>::Hash) {
+    pub fn update_state(&mut self, uid: u32, num_users: u32, merkle_root: <algorithms::Sha256 as Hasher>::Hash, server_id: ServerId, max_points: u32, policy: SelectionPolicy) {
         self.uid = uid;
         self.num_users = num_users;
         self.merkle_root = Some(merkle_root);
+        self.server_id = server_id;
+        self.max_points = max_points;
+        self.policy = policy;
+    }
+
+    // Sets the kind of share this client uses in the coin flip that picks
+    // the barcode (see `coin`)
+    pub fn set_coin_flip(&mut self, mode: CoinFlip) {
+        self.coin_flip = mode;
     }
 
     // Step 1 of a transaction request
 
-    // Input: N/A
-    // Output: commitment to a randomly chosen user ID
-    pub(crate) fn process_tx_hello(&mut self) -> Com {
-        // Commit to a random index and send it to the server
-        let i_c = rand::thread_rng().gen_range(0..self.num_users);
+    // Output: the client's balance in an account
+    pub fn balance(&self, account: Account) -> i32 {
+        self.ledgers.get(&account).map_or(0, |ledger| ledger.bal)
+    }
+
+    // Output: the client's epoch in an account
+    pub fn epoch(&self, account: Account) -> u64 {
+        self.ledgers.get(&account).map_or(0, |ledger| ledger.epoch)
+    }
+
+    fn ledger_mut(&mut self, account: Account) -> &mut ClientLedger {
+        self.ledgers.entry(account).or_default()
+    }
+
+    // Input: the merchant the shopper is buying from
+    // Output: commitment to the client's share of the coin flip, and the
+    // shopper's signature on it
+    pub fn process_tx_hello(&mut self, merchant: MerchantId) -> (Com, Signature) {
+        // Commit to a random share and send it to the server
+        let i_c = ClientShare::random(self.coin_flip);
         let r = rand::thread_rng().gen::<[u8; 32]>();
-        let mut hasher = Sha256::new();
-        hasher.update(i_c.to_le_bytes());
-        hasher.update(r);
-        let com: Com = hasher.finalize().into();
+        let com = i_c.commit(&r);
 
         let tx_id = com;
         self.tmp.insert(
             tx_id,
             ClientTxTmp {
+                started: session::now(),
+                merchant,
                 i_c: Some(i_c),
                 r: Some(r),
                 uid_b: None,
                 m: None,
                 hm: None,
-                x: None,
-                base: None
+                xs: None,
+                base: None,
+                period: None
             }
         );
 
-        com
+        let sigma = auth::sign_tx_hello(&self.sk_auth, &self.server_id, (&com, self.uid, merchant));
+        (com, sigma)
     }
 
     // Step 2 of a transaction request
 
-    // Input: server's randomly chosen barcode UID
-    // Output: opened commitment to client-chosed barcode UID
-    pub(crate) fn process_tx_compute_id(&mut self, i_s: u32, tx_id: Com) -> (u32, [u8; 32]) {
-        let tmp: &mut ClientTxTmp = self.tmp.get_mut(&tx_id).unwrap();
+    // Input: the server's share of the coin flip
+    // Output: opened commitment to the client's share
+    pub fn process_tx_compute_id(&mut self, i_s: ServerShare, tx_id: Com) -> Result<(ClientShare, [u8; 32])> {
+        let tmp: &mut ClientTxTmp = self.tmp.get_mut(&tx_id).ok_or(CheckoutError::UnknownTx)?;
+        let i_c = tmp.i_c.ok_or(CheckoutError::UnknownTx)?;
+        let r = tmp.r.ok_or(CheckoutError::UnknownTx)?;
 
-        let i = (tmp.i_c.unwrap() + i_s) % self.num_users;
+        let i = coin::joint_index(&i_c, &i_s, self.uid, self.num_users, &self.policy)?;
         tmp.uid_b = Some(i);
 
-        (tmp.i_c.unwrap(), tmp.r.unwrap())
+        Ok((i_c, r))
     }
 
-    pub(crate) fn verify_merkle_proof(&mut self, barcode: u64, pi: &MerkleProof<algorithms::Sha256>, pkb: Point, tx_id: Com) -> bool {
-        let tmp: &ClientTxTmp = self.tmp.get(&tx_id).unwrap();
+    pub fn verify_merkle_proof(&mut self, barcode: u64, pi: &MerkleProof<algorithms::Sha256>, pkb: Point, tx_id: Com) -> Result<()> {
+        let tmp: &ClientTxTmp = self.tmp.get(&tx_id).ok_or(CheckoutError::UnknownTx)?;
+        let uid_b = tmp.uid_b.ok_or(CheckoutError::UnknownTx)?;
+        let merkle_root = self.merkle_root.ok_or(CheckoutError::InvalidProof)?;
 
         let leaf = TreeEntry {
-            uid: tmp.uid_b.unwrap(),
-            barcode: barcode,
+            uid: uid_b,
+            barcode,
             pk_enc: pzip(pkb)
         };
         let tree_contents = algorithms::Sha256::hash(leaf.to_bytes().as_slice());
 
-        let test = pi.verify(self.merkle_root.unwrap(), &[tmp.uid_b.unwrap().try_into().unwrap()], &[tree_contents], self.num_users.try_into().unwrap());
-
-        assert!(test);
-
-        test
+        if pi.verify(merkle_root, &[uid_b as usize], &[tree_contents], self.num_users as usize) { Ok(()) }
+        else { Err(CheckoutError::InvalidProof) }
     }
 
     // Step 3 of a transaction request
-    pub(crate) fn process_tx(&mut self, pi: &MerkleProof<algorithms::Sha256>, barcode: u64, points: i32, pkb: Point, base: [u8; 32], tx_id: Com) -> (Ciphertext, TxAndProof) {
-        self.bal += points;
 
+    // Input: the amount of each category, in the server's order
+    // Output: encrypted m and amounts, and for each category, (h^m, g^mx) and
+    // the proof that it is well-formed
+    pub fn process_tx(&mut self, pi: &MerkleProof<algorithms::Sha256>, barcode: u64, points: &[i32], pkb: Point, (base, period): ([u8; 32], Period), tx_id: Com) -> Result<(Ciphertext, Vec<TxAndProof>)> {
         // Verify Merkle proof that the agreed upon index is in the tree
-        self.verify_merkle_proof(barcode, pi, pkb, tx_id);
+        self.verify_merkle_proof(barcode, pi, pkb, tx_id)?;
 
-        // Choose a random mask to encrypt
+        if points.is_empty() {
+            return Err(CheckoutError::WrongCategories);
+        }
+        // The server rejects any amount it has not been shown to be in range
+        let x_ints = points.iter()
+            .map(|x| u32::try_from(*x).ok().filter(|x| *x <= self.max_points).ok_or(CheckoutError::PointsOutOfRange))
+            .collect::<Result<Vec<u32>>>()?;
+
+        // Choose a random mask to encrypt, shared by every category
         let m_bits = rand::thread_rng().gen::<[u8; 32]>();
-        let m_ct = crypto::encrypt(pkb, points, m_bits, base);
+        let m_ct = crypto::encrypt(pkb, points, m_bits, base)?;
 
-        // Convert mask to scalar and compute h^m and g^mx
+        // Convert mask to scalar and compute h^m, and g^mx for each category
         let m = Scalar::from_bytes_mod_order(m_bits);
-        let x = crypto::int_to_scalar(points);
-        let hm = h_point()*&m;
-        let g = Point::hash_from_bytes::<Sha512>(&base);
-        let gmx = g * &(m * x);
-
-        self.server_bal += gmx;
-
-        // Prove that (h^m, g^mx) is well-formed
-        let pi = crypto::zk_tx_prove(hm, gmx, g, m, x);
+        let hm = h_point()*m;
+
+        let mut proofs = Vec::with_capacity(points.len());
+        for (category, (points, x_int)) in points.iter().zip(x_ints).enumerate() {
+            let category = category as Category;
+            let x = crypto::int_to_scalar(*points);
+            let g = crypto::category_point(&base, category);
+            let gmx = g * (m * x);
+
+            // Prove that (h^m, g^mx) is well-formed
+            let mut transcript = Transcript::for_tx(&self.server_id, &tx_id, category);
+            proofs.push(crypto::zk_tx_prove(&mut transcript, hm, gmx, g, m, x_int, self.max_points)?);
+        }
 
-        // Store m, h^m to associate with the signature from the server
-        let tmp: &mut ClientTxTmp = self.tmp.get_mut(&tx_id).unwrap();
+        // Store m, h^m to associate with the signature from the server. The
+        // balances are only updated once the server has accepted the
+        // transaction.
+        let tmp: &mut ClientTxTmp = self.tmp.get_mut(&tx_id).ok_or(CheckoutError::UnknownTx)?;
         tmp.m = Some(m);
         tmp.hm = Some(hm);
-        tmp.x = Some(x);
+        tmp.xs = Some(points.to_vec());
         tmp.base = Some(base);
+        tmp.period = Some(period);
 
-        (m_ct, pi)
+        Ok((m_ct, proofs))
     }
 
-    pub(crate) fn process_tx_coda(&mut self, sigma: Signature, tx_id: Com) {
-        let tmp: &ClientTxTmp = self.tmp.get(&tx_id).unwrap();
-        let m = tmp.m.unwrap();
-        let hm = tmp.hm.unwrap();
-        let x = tmp.x.unwrap();
-        let base = tmp.base.unwrap();
+    pub fn process_tx_coda(&mut self, sigma: Signature, tx_id: Com) -> Result<()> {
+        let tmp: &ClientTxTmp = self.tmp.get(&tx_id).ok_or(CheckoutError::UnknownTx)?;
+        let m = tmp.m.ok_or(CheckoutError::UnknownTx)?;
+        let hm = tmp.hm.ok_or(CheckoutError::UnknownTx)?;
+        let xs = tmp.xs.clone().ok_or(CheckoutError::UnknownTx)?;
+        let base = tmp.base.ok_or(CheckoutError::UnknownTx)?;
+        let period = tmp.period.ok_or(CheckoutError::UnknownTx)?;
+        let merchant = tmp.merchant;
+
+        // A category the transaction moved no points in needs no receipt
+        for (category, x) in xs.into_iter().enumerate().filter(|(_, x)| *x != 0) {
+            let category = category as Category;
+            let ledger = self.ledger_mut((merchant, category));
+            ledger.bal += x;
+            ledger.server_bal += crypto::category_point(&base, category) * (m * crypto::int_to_scalar(x));
+            ledger.receipts.push((x, m, hm, base, period, sigma));
+        }
 
-        self.receipts.push((x, m, hm, base, sigma));
+        self.forget_tx(tx_id);
+        Ok(())
+    }
 
+    // Wipes what the client kept about a transaction. Called when the
+    // transaction finishes; apps call it for a transaction they give up on.
+    pub fn forget_tx(&mut self, tx_id: Com) {
+        if let Some(tmp) = self.tmp.get_mut(&tx_id) {
+            tmp.zeroize();
+        }
         self.tmp.remove(&tx_id);
     }
 
-    // Receipt = (Ciphertext, TxAndProof)
+    // Forgets every transaction that started longer than `ttl` ago, since the
+    // server has dropped it by now.
+    // Output: how many were forgotten
+    pub fn expire_txs(&mut self, ttl: Duration) -> usize {
+        let expired: Vec<Com> = self.tmp.iter()
+            .filter(|(_, tmp)| session::expired(tmp.started, ttl))
+            .map(|(tx_id, _)| *tx_id)
+            .collect();
+        for tx_id in &expired {
+            self.forget_tx(*tx_id);
+        }
+        expired.len()
+    }
+
+    // Receipt = (Ciphertext, Vec<TxAndProof>)
     // Ciphertext = ((Point, Point), Vec<u8>, Nonce<U12>)
-    pub(crate) fn process_receipts(&mut self, rcts: Vec<(Receipt, Signature)>) {
+    // Each receipt is counted in the balances with the merchant it was earned
+    // with, one for each category, in the period it was earned in.
+    // Stops at the first invalid receipt; every receipt before it has been applied.
+    pub fn process_receipts(&mut self, rcts: Vec<SignedReceipt>) -> Result<()> {
         for rct in rcts {
             let ct = rct.0.0;
-            let tx_and_proof = rct.0.1;
-            let hm = tx_and_proof.r2;
-            let gmx = tx_and_proof.r3;
+            let txs = rct.0.1;
 
             let pk_ct = ct.0;
             let sym_ct = ct.1;
             let nonce = ct.2;
 
-            let (m_bits, x, base) = crypto::decrypt(self.sk_enc, (pk_ct, sym_ct.clone()), nonce);
-            let m = Scalar::from_bytes_mod_order(m_bits);
-
-            if self.seen_cts.contains(&(pzip(pk_ct.0), pzip(pk_ct.1), sym_ct.clone(), nonce)) {
-                panic!("Invalid m");
+            // Receipts are delivered again if an acknowledgment is lost, so one
+            // already counted is skipped rather than counted twice
+            let seen = (pzip(pk_ct.0), pzip(pk_ct.1), sym_ct.clone(), nonce);
+            if self.seen_cts.contains(&seen) {
+                continue;
             }
-            self.seen_cts.insert((pzip(pk_ct.0), pzip(pk_ct.1), sym_ct.clone(), nonce));
 
-            let g = Point::hash_from_bytes::<Sha512>(&base);
-            let x_scalar = crypto::int_to_scalar(x);
+            let (m_bits, xs, base) = crypto::decrypt(*self.sk_enc.expose(), (pk_ct, sym_ct), nonce)?;
+            let m = Scalar::from_bytes_mod_order(m_bits);
+            let hm = crypto::h_point() * m;
+            if xs.len() != txs.len() {
+                return Err(CheckoutError::InvalidProof);
+            }
 
-            // No need to compute the entire ZK proof.
-            assert!(crypto::h_point() * &m == hm);
-            assert!(g * &(m * x_scalar) == gmx);
+            // No need to compute the entire ZK proof. Every category is
+            // checked before any is counted.
+            for (category, (x, tx)) in xs.iter().zip(&txs).enumerate() {
+                let g = crypto::category_point(&base, category as Category);
+                if tx.r2 != hm || g * (m * crypto::int_to_scalar(*x)) != tx.r3 {
+                    return Err(CheckoutError::InvalidProof);
+                }
+            }
 
-            self.bal -= x;
-            self.server_bal = self.server_bal + (gmx * crypto::int_to_scalar(-1));
-            self.receipts.push((x_scalar*crypto::int_to_scalar(-1), m, hm, base, rct.1));
+            self.seen_cts.insert(seen);
+            for (category, (x, tx)) in xs.into_iter().zip(txs).enumerate().filter(|(_, (x, _))| *x != 0) {
+                let ledger = self.ledger_mut((rct.1, category as Category));
+                ledger.bal -= x;
+                ledger.server_bal += tx.r3 * crypto::int_to_scalar(-1);
+                ledger.receipts.push((-x, m, hm, base, rct.2, rct.3));
+            }
         }
+        Ok(())
     }
 
-    /* The client settles by providing:
+    // Output: the user's signature acknowledging their receipts up to and
+    // including sequence number `upto`, which lets the server delete them
+    pub fn ack_receipts(&self, upto: u64) -> Signature {
+        auth::sign_ack(&self.sk_auth, &self.server_id, (self.uid, upto))
+    }
+
+    /* The client settles its balance in an account by providing:
        - their balance (x)
        - a list of all masked m values, h^(m_i)
        - a list of all signatures on the above values
        - a proof that the information is related correctly
 
-       The client then can reset their state.
+       The client then resets their state with the server's settlement
+       receipt (see `finish_settlement`).
     */
-    pub(crate) fn settle_balance(&self) -> (i32, Vec<Point>, Vec<[u8; 32]>, Vec<Signature>, SettleProof) {
+    pub fn settle_balance(&mut self, status: SettleStatus) -> Result<SettleData> {
+        self.catch_up(&status)?;
 
-        let x = self.bal;
-        let server_bal = self.server_bal;
-        let rcts = &self.receipts;
+        let account = status.account();
+        let ledger = self.ledger_mut(account);
+        let x = ledger.bal;
+        let server_bal = ledger.server_bal;
+        let epoch = ledger.epoch;
+        let rcts = &ledger.receipts;
 
         let mut xs = Vec::new();
         let mut ms = Vec::new();
         let mut hms = Vec::new();
         let mut bases = Vec::new();
+        let mut periods = Vec::new();
         let mut gs = Vec::new();
         let mut signatures = Vec::new();
 
         for rct in rcts {
-            let x = rct.0;
+            let x = crypto::int_to_scalar(rct.0);
             let m = rct.1;
             let hm = rct.2;
             let base = rct.3;
-            let sigma = rct.4;
+            let period = rct.4;
+            let sigma = rct.5;
 
             xs.push(x);
             ms.push(m);
             hms.push(hm);
             bases.push(base);
+            periods.push(period);
             signatures.push(sigma);
 
-            let g = Point::hash_from_bytes::<Sha512>(&base);
+            let g = crypto::category_point(&base, account.1);
             gs.push(g);
         }
 
-        let pi = crypto::zk_settle_prove(x, server_bal, &hms, &gs, &xs, &ms);
+        let mut transcript = Transcript::for_settle(&self.server_id, self.uid, account, epoch);
+        let pi = crypto::zk_settle_prove(&mut transcript, x, server_bal, &hms, &gs, &xs, &ms);
+
+        Ok((x, hms, bases, periods, signatures, pi))
+    }
+
+    // Resets the balance in the settlement's account once the server has
+    // settled it, and starts the next epoch in the account. Receipts the
+    // client got after it asked to settle are not covered by the settlement,
+    // and are kept for the next one.
+    pub fn finish_settlement(&mut self, settlement: Settlement) -> Result<()> {
+        let (server_id, uid, category) = (self.server_id, self.uid, settlement.category);
+        let ledger = self.ledger_mut(settlement.account());
+        settlement.verify(&server_id, (uid, settlement.account()), ledger.epoch)?;
+        let n = settlement.receipts as usize;
+        if n > ledger.receipts.len() {
+            return Err(CheckoutError::StaleSettlement);
+        }
+
+        let settled: Point = ledger.receipts[..n].iter()
+            .map(|(x, m, _, base, _, _)| crypto::category_point(base, category) * (m * crypto::int_to_scalar(*x)))
+            .sum();
+        ledger.bal -= settlement.points;
+        ledger.server_bal -= settled;
+        ledger.receipts.drain(..n);
+        ledger.epoch += 1;
+        Ok(())
+    }
+
+    // Redeems part of the balance without revealing the rest. The client
+    // proves what it would to settle, except that the balance stays hidden
+    // behind a commitment, and that what is left is not negative.
+    // Input: the settlement status from the server, which names the account,
+    // and the amount to redeem
+    // Output: the request, whose points are taken from the oldest periods
+    // first. For each period they are taken from, the client proves that the
+    // receipts of that period cover them (see `settlement`).
+    pub fn redeem(&mut self, status: SettleStatus, points: u32) -> Result<RedeemData> {
+        self.catch_up(&status)?;
+        let (server_id, uid, account) = (self.server_id, self.uid, status.account());
+        let ledger = self.ledger_mut(account);
+        if points == 0 || points as i64 > ledger.bal as i64 {
+            return Err(CheckoutError::PointsOutOfRange);
+        }
+
+        let mut shares = BTreeMap::<Period, i32>::new();
+        for rct in &ledger.receipts {
+            *shares.entry(rct.4).or_default() += rct.0;
+        }
+        let parts = settlement::split(&shares.into_iter().collect::<Vec<_>>(), points)?;
+
+        // Only the receipts of those periods are sent
+        let rcts: Vec<&ClientReceipt> = ledger.receipts.iter()
+            .filter(|rct| parts.iter().any(|(period, _)| *period == rct.4))
+            .collect();
+        let mut transcript = Transcript::for_settle(&server_id, uid, account, ledger.epoch);
+        let mut pis = Vec::with_capacity(parts.len());
+        for &(period, points) in &parts {
+            let in_period: Vec<&ClientReceipt> = rcts.iter().copied().filter(|rct| rct.4 == period).collect();
+            let xs: Vec<Scalar> = in_period.iter().map(|rct| crypto::int_to_scalar(rct.0)).collect();
+            let ms: Vec<Scalar> = in_period.iter().map(|rct| rct.1).collect();
+            let hms: Vec<Point> = in_period.iter().map(|rct| rct.2).collect();
+            let gs: Vec<Point> = in_period.iter().map(|rct| crypto::category_point(&rct.3, account.1)).collect();
+            let x: i32 = in_period.iter().map(|rct| rct.0).sum();
+            let bal: Point = gs.iter().zip(&ms).zip(&xs).map(|((g, m), x)| g * (m * x)).sum();
+
+            transcript.append_u64(b"period", period as u64);
+            pis.push(crypto::zk_redeem_prove(&mut transcript, (points, x), bal, &hms, &gs, &xs, &ms)?);
+        }
+
+        let hms = rcts.iter().map(|rct| rct.2).collect();
+        let bases = rcts.iter().map(|rct| rct.3).collect();
+        let periods = rcts.iter().map(|rct| rct.4).collect();
+        let sigmas = rcts.iter().map(|rct| rct.5).collect();
+        Ok((parts, hms, bases, periods, sigmas, pis))
+    }
+
+    // Takes the redeemed points out of the balance. Each part of the
+    // redemption is kept with the receipts, as one for -points with mask 1 in
+    // its period, and is settled or expires with them.
+    pub fn finish_redemption(&mut self, redemption: Redemption) -> Result<()> {
+        let vk = VerifyingKey::from_bytes(&self.server_id).map_err(|_| CheckoutError::InvalidPoint)?;
+        let bases: Vec<[u8; 32]> = redemption.parts.iter().map(|part| redemption.base(part)).collect();
+        for (part, base) in redemption.parts.iter().zip(&bases) {
+            crypto::verify(vk, (redemption.merchant, part.period), &h_point(), base, part.sigma)?;
+        }
+        let uid = self.uid;
+        let ledger = self.ledger_mut(redemption.account());
+        if redemption.uid != uid || redemption.epoch != ledger.epoch {
+            return Err(CheckoutError::StaleSettlement);
+        }
+        if ledger.receipts.iter().any(|rct| bases.contains(&rct.3)) {
+            return Err(CheckoutError::ReplayedReceipt);
+        }
+
+        for (part, base) in redemption.parts.iter().zip(bases) {
+            let x = -(part.points as i32);
+            let g = crypto::category_point(&base, redemption.category);
+            ledger.bal += x;
+            ledger.server_bal += g * crypto::int_to_scalar(x);
+            ledger.receipts.push((x, Scalar::one(), h_point(), base, part.period, part.sigma));
+        }
+        Ok(())
+    }
+
+    // Applies first any settlement or redemption whose receipt never arrived,
+    // then drops the receipts that have expired.
+    // A redemption is always older than a settlement in the same epoch.
+    // Output: an error if the client is still not in the server's epoch in
+    // the account
+    fn catch_up(&mut self, status: &SettleStatus) -> Result<()> {
+        let account = status.account();
+        let ledger = self.ledger_mut(account);
+        if let Some(redemption) = &status.last_redemption {
+            let bases: Vec<[u8; 32]> = redemption.parts.iter().map(|part| redemption.base(part)).collect();
+            if redemption.account() == account && redemption.epoch == ledger.epoch
+                && !ledger.receipts.iter().any(|rct| bases.contains(&rct.3)) {
+                self.finish_redemption(redemption.clone())?;
+            }
+        }
+        if let Some(last) = status.last {
+            if last.account() == account && last.epoch == self.epoch(account) {
+                self.finish_settlement(last)?;
+            }
+        }
+        if status.epoch != self.epoch(account) {
+            return Err(CheckoutError::StaleSettlement);
+        }
+        // Only after the missed receipts: a settlement counts the receipts
+        // it covered, expired or not
+        self.ledger_mut(account).expire(account.1, status.oldest);
+        Ok(())
+    }
+}
+
+//////////////////////////////////////////////////////////////////
+// LoyaltyScheme implementation
+//////////////////////////////////////////////////////////////////
+
+// Malicious security: balances are masked points, and every step is proven.
+pub struct Malicious;
+
+#[derive(Serialize, Deserialize)]
+pub struct BarcodeGen {
+    pub uid_b: u32,
+    pub barcode: u64,
+    pub pk_b: Point,
+    pub base: [u8; 32],
+    pub period: Period,
+    #[serde(with = "encoding::merkle_proof")]
+    pub pi: MerkleProof<algorithms::Sha256>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TxSubmit {
+    pub ct: Ciphertext,
+    pub tx: Vec<TxAndProof>, // One for each category
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SettleRequest {
+    pub x: i32,
+    pub hms: Vec<Point>,
+    pub rs: Vec<[u8; 32]>,
+    pub periods: Vec<Period>,
+    pub sigmas: Vec<Signature>,
+    pub pi: SettleProof,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RedeemRequest {
+    pub parts: Vec<(Period, u32)>, // Oldest first
+    pub hms: Vec<Point>,
+    pub rs: Vec<[u8; 32]>,
+    pub periods: Vec<Period>,
+    pub sigmas: Vec<Signature>,
+    pub pis: Vec<RedeemProof>, // One for each part
+}
+
+impl LoyaltyScheme for Malicious {
+    const LEVEL: SecurityLevel = SecurityLevel::Malicious;
+
+    type Server = Server;
+    type Client = Client;
+
+    type Registration = (u64, CPoint, AuthKey);
+    type BarcodeGen = BarcodeGen;
+    type TxSubmit = TxSubmit;
+    type TxSignature = Signature;
+    type Receipts = Vec<SignedReceipt>;
+    type SettleHello = SettleStatus;
+    type SettleRequest = SettleRequest;
+    type Settlement = Settlement;
+    type RedeemRequest = RedeemRequest;
+    type Redemption = Redemption;
+}
+
+impl SchemeServer for Server {
+    type Scheme = Malicious;
+
+    fn register_user(&self, reg: (u64, CPoint, AuthKey)) -> Result<u32> {
+        Server::register_user(self, reg.0, reg.1, reg.2)
+    }
+
+    fn share_state(&self) -> Result<(u32, Root, ServerId, u32, SelectionPolicy)> {
+        Server::share_state(self)
+    }
+
+    fn process_tx_hello_response(&self, com: Com, uid_s: u32, merchant: MerchantId, sigma: &Signature) -> Result<ServerShare> {
+        Server::process_tx_hello_response(self, com, uid_s, merchant, sigma)
+    }
+
+    fn process_tx_barcode_gen(&self, i_c: ClientShare, r: [u8; 32], tx_id: Com) -> Result<BarcodeGen> {
+        let (uid_b, barcode, pk_b, (base, period), pi) = Server::process_tx_barcode_gen(self, i_c, r, tx_id)?;
+        Ok(BarcodeGen { uid_b, barcode, pk_b, base, period, pi })
+    }
 
-        (x, hms, bases, signatures, pi)
+    fn process_tx(&self, tx: TxSubmit, tx_id: Com) -> Result<Signature> {
+        Server::process_tx(self, tx.ct, tx.tx, tx_id)
     }
-}
\ No newline at end of file
+
+    fn process_tx_batch(&self, txs: Vec<(TxSubmit, Com)>) -> Vec<Result<Signature>> {
+        Server::process_tx_batch(self, txs.into_iter().map(|(tx, tx_id)| (tx.ct, tx.tx, tx_id)).collect())
+    }
+
+    fn abort_tx(&self, tx_id: Com) {
+        Server::abort_tx(self, tx_id)
+    }
+
+    fn sweep_txs(&self) -> usize {
+        Server::sweep_txs(self)
+    }
+
+    fn retire_expired(&self) -> Result<usize> {
+        Server::retire_expired(self)
+    }
+
+    fn send_receipts(&self, uid: u32, after: u64, limit: u32) -> Result<(Vec<SignedReceipt>, u64, bool)> {
+        Server::send_receipts(self, uid, after, limit)
+    }
+
+    fn ack_receipts(&self, uid: u32, upto: u64, sigma: &Signature) -> Result<()> {
+        Server::ack_receipts(self, uid, upto, sigma)
+    }
+
+    fn settle_balance_hello(&self, uid: u32, account: Account) -> Result<SettleStatus> {
+        Server::settle_balance_hello(self, uid, account)
+    }
+
+    fn settle_balance(&self, uid: u32, account: Account, req: SettleRequest) -> Result<Settlement> {
+        Server::settle_balance(self, uid, account, (req.x, req.hms, req.rs, req.periods, req.sigmas, req.pi))
+    }
+
+    fn redeem(&self, uid: u32, account: Account, req: RedeemRequest) -> Result<Redemption> {
+        Server::redeem(self, uid, account, (req.parts, req.hms, req.rs, req.periods, req.sigmas, req.pis))
+    }
+}
+
+impl SchemeClient for Client {
+    type Scheme = Malicious;
+
+    fn new(barcode: u64) -> Self {
+        Client::new(barcode)
+    }
+
+    fn register_with_server(&self) -> (u64, CPoint, AuthKey) {
+        Client::register_with_server(self)
+    }
+
+    fn update_state(&mut self, uid: u32, num_users: u32, merkle_root: Root, server_id: ServerId, max_points: u32, policy: SelectionPolicy) {
+        Client::update_state(self, uid, num_users, merkle_root, server_id, max_points, policy)
+    }
+
+    fn set_coin_flip(&mut self, mode: CoinFlip) {
+        Client::set_coin_flip(self, mode)
+    }
+
+    fn process_tx_hello(&mut self, merchant: MerchantId) -> (Com, Signature) {
+        Client::process_tx_hello(self, merchant)
+    }
+
+    fn process_tx_compute_id(&mut self, i_s: ServerShare, tx_id: Com) -> Result<(ClientShare, [u8; 32])> {
+        Client::process_tx_compute_id(self, i_s, tx_id)
+    }
+
+    fn process_tx(&mut self, bg: &BarcodeGen, points: &[i32], tx_id: Com) -> Result<TxSubmit> {
+        let (ct, tx) = Client::process_tx(self, &bg.pi, bg.barcode, points, bg.pk_b, (bg.base, bg.period), tx_id)?;
+        Ok(TxSubmit { ct, tx })
+    }
+
+    fn process_tx_coda(&mut self, sigma: Signature, tx_id: Com) -> Result<()> {
+        Client::process_tx_coda(self, sigma, tx_id)
+    }
+
+    fn forget_tx(&mut self, tx_id: Com) {
+        Client::forget_tx(self, tx_id)
+    }
+
+    fn expire_txs(&mut self, ttl: Duration) -> usize {
+        Client::expire_txs(self, ttl)
+    }
+
+    fn process_receipts(&mut self, rcts: Vec<SignedReceipt>) -> Result<()> {
+        Client::process_receipts(self, rcts)
+    }
+
+    fn ack_receipts(&self, upto: u64) -> Signature {
+        Client::ack_receipts(self, upto)
+    }
+
+    fn settle_balance(&mut self, hello: SettleStatus) -> Result<SettleRequest> {
+        let (x, hms, rs, periods, sigmas, pi) = Client::settle_balance(self, hello)?;
+        Ok(SettleRequest { x, hms, rs, periods, sigmas, pi })
+    }
+
+    fn finish_settlement(&mut self, settlement: Settlement) -> Result<()> {
+        Client::finish_settlement(self, settlement)
+    }
+
+    fn redeem(&mut self, hello: SettleStatus, points: u32) -> Result<RedeemRequest> {
+        let (parts, hms, rs, periods, sigmas, pis) = Client::redeem(self, hello, points)?;
+        Ok(RedeemRequest { parts, hms, rs, periods, sigmas, pis })
+    }
+
+    fn finish_redemption(&mut self, redemption: Redemption) -> Result<()> {
+        Client::finish_redemption(self, redemption)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scheme::{self, DEFAULT_ACCOUNT, DEFAULT_MERCHANT};
+
+    // Registers `n` clients and tells each of them the server's state
+    fn register(server: &Server, n: u32) -> Vec<Client> {
+        let mut clients: Vec<Client> = (0..n).map(|i| Client::new(i as u64)).collect();
+        for client in &clients {
+            SchemeServer::register_user(server, client.register_with_server()).unwrap();
+        }
+        let (num_users, root, server_id, max_points, policy) = server.share_state().unwrap();
+        for (uid, client) in clients.iter_mut().enumerate() {
+            client.update_state(uid as u32, num_users, root, server_id, max_points, policy.clone());
+        }
+        clients
+    }
+
+    // Runs a transaction up to the submission.
+    // Output: the submission and the transaction's ID
+    fn start_tx(server: &Server, shopper: &mut Client, uid_s: u32, points: &[i32]) -> (TxSubmit, Com) {
+        let (com, sigma) = shopper.process_tx_hello(DEFAULT_MERCHANT);
+        let i_s = server.process_tx_hello_response(com, uid_s, DEFAULT_MERCHANT, &sigma).unwrap();
+        let (i_c, r) = shopper.process_tx_compute_id(i_s, com).unwrap();
+        let bg = SchemeServer::process_tx_barcode_gen(server, i_c, r, com).unwrap();
+        (SchemeClient::process_tx(shopper, &bg, points, com).unwrap(), com)
+    }
+
+    #[test]
+    fn failed_log_leaves_tx_open() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut server = Server::new();
+        let mut clients = register(&server, 3);
+        let (tx, com) = start_tx(&server, &mut clients[0], 0, &[10]);
+        let resent: TxSubmit = bincode::deserialize(&bincode::serialize(&tx).unwrap()).unwrap();
+
+        server.store = Some(Store::failing(dir.path()).unwrap());
+        assert_eq!(SchemeServer::process_tx(&server, tx, com).err(), Some(CheckoutError::Storage));
+        assert!(server.users.read().unwrap().values().all(|user| user.ledgers.is_empty()));
+        assert!(server.receipts.lock().unwrap().values().all(|mailbox| mailbox.rcts.is_empty()));
+
+        // Once the log works again, the same submission is carried out
+        server.store = None;
+        let sigma = SchemeServer::process_tx(&server, resent, com).unwrap();
+        clients[0].process_tx_coda(sigma, com).unwrap();
+        scheme::settle::<Malicious>(&server, &mut clients[0], 0, DEFAULT_ACCOUNT).unwrap();
+    }
+
+    #[test]
+    fn settle_more_than_earned() {
+        let server = Server::new();
+        let mut clients = register(&server, 3);
+        let (tx, com) = start_tx(&server, &mut clients[0], 0, &[10]);
+        let sigma = SchemeServer::process_tx(&server, tx, com).unwrap();
+        clients[0].process_tx_coda(sigma, com).unwrap();
+        scheme::deliver_receipts::<Malicious>(&server, &mut clients[0], 0).unwrap();
+
+        // The client's request, with its balance raised and the proof forged
+        // to add up to it
+        let claim = 1_000_000;
+        let hello = server.settle_balance_hello(0, DEFAULT_ACCOUNT).unwrap();
+        let (_, hms, rs, periods, sigmas, _) = clients[0].settle_balance(hello).unwrap();
+        let ledger = clients[0].ledger_mut(DEFAULT_ACCOUNT);
+        let xs: Vec<Scalar> = ledger.receipts.iter().map(|rct| crypto::int_to_scalar(rct.0)).collect();
+        let ms: Vec<Scalar> = ledger.receipts.iter().map(|rct| rct.1).collect();
+        let gs: Vec<Point> = rs.iter().map(|r| crypto::category_point(r, DEFAULT_ACCOUNT.1)).collect();
+        let b1 = curve25519_dalek::constants::RISTRETTO_BASEPOINT_POINT * crypto::int_to_scalar(claim);
+        let mut transcript = Transcript::for_settle(&server.server_id(), 0, DEFAULT_ACCOUNT, ledger.epoch);
+        let (pi, _) = crypto::forge_settle_proof(&mut transcript, (b1, None, claim), ledger.server_bal,
+                                                 (&hms, &gs, &xs, &ms));
+        assert_eq!(server.settle_balance(0, DEFAULT_ACCOUNT, (claim, hms, rs, periods, sigmas, pi)).err(),
+                   Some(CheckoutError::InvalidProof));
+
+        // No settlement was issued, and the 10 points are still there to settle
+        let hello = server.settle_balance_hello(0, DEFAULT_ACCOUNT).unwrap();
+        assert_eq!((hello.epoch, hello.last), (0, None));
+        let req = clients[0].settle_balance(hello).unwrap();
+        assert_eq!(server.settle_balance(0, DEFAULT_ACCOUNT, req).unwrap().points, 10);
+    }
+
+    // Output: the points a user's settled balance comes to
+    fn settled_points(server: &Server, client: &mut Client, uid: u32) -> i32 {
+        scheme::deliver_receipts::<Malicious>(server, client, uid).unwrap();
+        let hello = SchemeServer::settle_balance_hello(server, uid, DEFAULT_ACCOUNT).unwrap();
+        let req = SchemeClient::settle_balance(client, hello).unwrap();
+        SchemeServer::settle_balance(server, uid, DEFAULT_ACCOUNT, req).unwrap().points
+    }
+
+    #[test]
+    fn concurrent_transactions() {
+        let server = Server::new();
+        let mut clients = register(&server, 4);
+        std::thread::scope(|s| {
+            for (uid, client) in clients.iter_mut().enumerate() {
+                let server = &server;
+                s.spawn(move || {
+                    for _ in 0..5 {
+                        scheme::transact::<Malicious>(server, client, uid as u32, DEFAULT_MERCHANT, &[10]).unwrap();
+                    }
+                });
+            }
+        });
+
+        // Every shopper earned 50 points, and every point came from a barcode
+        // owner
+        let mut total = 0;
+        for (uid, client) in clients.iter_mut().enumerate() {
+            let points = settled_points(&server, client, uid as u32);
+            assert!(points <= 50);
+            total += points;
+        }
+        assert_eq!(total, 0);
+    }
+
+    #[test]
+    fn submission_sent_twice_at_once() {
+        let server = Server::new();
+        let mut clients = register(&server, 3);
+        let (tx, com) = start_tx(&server, &mut clients[0], 0, &[10]);
+        let resent: TxSubmit = bincode::deserialize(&bincode::serialize(&tx).unwrap()).unwrap();
+
+        let results = std::thread::scope(|s| {
+            let first = s.spawn(|| SchemeServer::process_tx(&server, tx, com));
+            let second = s.spawn(|| SchemeServer::process_tx(&server, resent, com));
+            [first.join().unwrap(), second.join().unwrap()]
+        });
+
+        // The transaction is applied once, however the two submissions met
+        let sigma = results.into_iter().find_map(|res| res.ok()).unwrap();
+        clients[0].process_tx_coda(sigma, com).unwrap();
+        assert_eq!(settled_points(&server, &mut clients[0], 0), 10);
+    }
+
+    #[test]
+    fn batch_with_one_bad_proof() {
+        let server = Server::new();
+        let mut clients = register(&server, 3);
+        let (tx_a, com_a) = start_tx(&server, &mut clients[0], 0, &[10]);
+        let (tx_b, _) = start_tx(&server, &mut clients[0], 0, &[10]);
+        let (tx_c, com_c) = start_tx(&server, &mut clients[0], 0, &[10]);
+
+        // B's proof is sent for transaction A, and A's for one the server
+        // never started
+        let batch = vec![(tx_c, com_c), (tx_b, com_a), (tx_a, [9; 32])];
+        let mut results = SchemeServer::process_tx_batch(&server, batch).into_iter();
+        let sigma = results.next().unwrap().unwrap();
+        assert_eq!(results.map(|res| res.err()).collect::<Vec<_>>(),
+                   vec![Some(CheckoutError::InvalidProof), Some(CheckoutError::UnknownTx)]);
+
+        // Only the good transaction was applied
+        clients[0].process_tx_coda(sigma, com_c).unwrap();
+        assert_eq!(settled_points(&server, &mut clients[0], 0), 10);
+    }
+
+    #[test]
+    fn forgotten_tx_cannot_be_finished() {
+        let server = Server::new();
+        let mut clients = register(&server, 3);
+        let (tx, com) = start_tx(&server, &mut clients[0], 0, &[10]);
+        let sigma = SchemeServer::process_tx(&server, tx, com).unwrap();
+
+        clients[0].forget_tx(com);
+        assert!(clients[0].tmp.is_empty());
+        assert_eq!(SchemeClient::process_tx_coda(&mut clients[0], sigma, com).err(), Some(CheckoutError::UnknownTx));
+    }
+
+    #[test]
+    fn categories_are_proven_apart() {
+        let mut server = Server::new();
+        server.set_categories(vec!["points".to_string(), "miles".to_string()]);
+        let mut clients = register(&server, 3);
+        let (tx, com) = start_tx(&server, &mut clients[0], 0, &[10, 20]);
+        let resend = |tx: &TxSubmit| -> TxSubmit { bincode::deserialize(&bincode::serialize(tx).unwrap()).unwrap() };
+
+        // Each proof only holds for its own category
+        let mut swapped = resend(&tx);
+        swapped.tx.swap(0, 1);
+        assert_eq!(SchemeServer::process_tx(&server, swapped, com).err(), Some(CheckoutError::InvalidProof));
+
+        let mut short = resend(&tx);
+        short.tx.pop();
+        assert_eq!(SchemeServer::process_tx(&server, short, com).err(), Some(CheckoutError::WrongCategories));
+
+        let sigma = SchemeServer::process_tx(&server, tx, com).unwrap();
+        clients[0].process_tx_coda(sigma, com).unwrap();
+        scheme::deliver_receipts::<Malicious>(&server, &mut clients[0], 0).unwrap();
+        for (category, points) in [(0, 10), (1, 20)] {
+            let account = (DEFAULT_MERCHANT, category);
+            let hello = SchemeServer::settle_balance_hello(&server, 0, account).unwrap();
+            let req = SchemeClient::settle_balance(&mut clients[0], hello).unwrap();
+            assert_eq!(SchemeServer::settle_balance(&server, 0, account, req).unwrap().points, points);
+        }
+    }
+}
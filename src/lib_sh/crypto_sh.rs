@@ -4,49 +4,43 @@ use curve25519_dalek::ristretto::CompressedRistretto;
 use curve25519_dalek::ristretto::RistrettoPoint;
 use curve25519_dalek::ristretto::RistrettoBasepointTable;
 use curve25519_dalek::scalar::Scalar;
-use sha2::Sha512;
-use curve25519_dalek::digest::Update;
+use curve25519_dalek::traits::{IsIdentity, VartimeMultiscalarMul};
 use lazy_static::lazy_static;
-use std::collections::HashMap;
+use serde_derive::{Serialize, Deserialize};
+use sha2::Sha512;
+use zeroize::{Zeroize, ZeroizeOnDrop};
+use crate::encoding;
+use crate::lib_sh::dlog;
+use crate::error::{CheckoutError, Result};
+use crate::range::{self, RangeProof};
+use crate::transcript::Transcript;
 
 const G: &RistrettoBasepointTable = &constants::RISTRETTO_BASEPOINT_TABLE;
 
-const MAX_POINTS: u32 = 100000;
-
-fn create_dlog_table() -> HashMap<[u8; 32], i32> {
-    let mut table = HashMap::new();
-
-    let m = (MAX_POINTS as f32).sqrt() as i32 + 1;
-    for i in 0..m {
-        let k = pzip(&int_to_scalar(i)*G);
-        table.insert(k, i);
-    }
-
-    table
-}
-
+// Second base of the commitments in redeem proofs, with no known discrete log
+// with respect to G
 lazy_static! {
-    static ref DLOG_TABLE: HashMap<[u8; 32], i32> = create_dlog_table();
+    static ref H_POINT: RistrettoPoint = RistrettoPoint::hash_from_bytes::<Sha512>("sh redeem base h".as_bytes());
 }
 
 fn pzip(p: RistrettoPoint) -> [u8; 32] {
     p.compress().to_bytes()
 }
 
-fn puzip(p: [u8; 32]) -> RistrettoPoint {
-    CompressedRistretto::from_slice(&p).decompress().unwrap()
+pub(crate) fn puzip(p: [u8; 32]) -> Result<RistrettoPoint> {
+    CompressedRistretto::from_slice(&p).decompress().ok_or(CheckoutError::InvalidPoint)
 }
 
 fn szip(s: Scalar) -> [u8; 32] {
     s.to_bytes()
 }
 
-fn suzip(s: [u8; 32]) -> Scalar {
-    Scalar::from_bytes_mod_order(s)
+fn suzip(s: [u8; 32]) -> Result<Scalar> {
+    Scalar::from_canonical_bytes(s).ok_or(CheckoutError::InvalidPoint)
 }
 
 // Returns a compressed version of a tuple (Scalar, RistrettoPoint)
-pub(crate) fn elgamal_keygen() -> ([u8; 32], [u8; 32]) {
+pub fn elgamal_keygen() -> ([u8; 32], [u8; 32]) {
     let x: Scalar = Scalar::random(&mut OsRng);
     let h: RistrettoPoint = &x * G;
     (szip(x), pzip(h))
@@ -56,12 +50,12 @@ pub(crate) fn elgamal_keygen() -> ([u8; 32], [u8; 32]) {
 //      pk: a compressed RistrettoPoint
 //      m:  a message to encrypt (number of loyalty points)
 // Returns a compressed version of a tuple (RistrettoPoint, RistrettoPoint, Scalar)
-pub(crate) fn elgamal_enc(pk: [u8; 32], m: i32) -> ([u8; 32], [u8; 32], [u8; 32]) {
-    let pk = puzip(pk);
+pub fn elgamal_enc(pk: [u8; 32], m: i32) -> Result<([u8; 32], [u8; 32], [u8; 32])> {
+    let pk = puzip(pk)?;
     let y: Scalar = Scalar::random(&mut OsRng);
 
     // We need to convert m to a scalar
-    let m_pos: u32 = m.abs() as u32;
+    let m_pos: u32 = m.unsigned_abs();
     let m_scalar = if m == m_pos as i32 { Scalar::from(m_pos) }
                    else { Scalar::zero() - Scalar::from(m_pos) };
 
@@ -70,63 +64,47 @@ pub(crate) fn elgamal_enc(pk: [u8; 32], m: i32) -> ([u8; 32], [u8; 32], [u8; 32]
 
     // y is a secret; it needs to be kept by the client to generate a proof
     // and then is discarded afterwards.
-    (pzip(c1), pzip(c2), szip(y))
-}
-
-// Use the baby-step giant-step algorithm to compute the discrete log between
-// two values (when the discrete log is small). This is only ever used to unmask a
-// number of loyalty points which, by construction of our scheme, is always
-// positive, so we limit our search space to (0, max points).
-pub(crate) fn dlog_base_g(gx: RistrettoPoint) -> i32 {
-    let m = (MAX_POINTS as f32).sqrt() as i32 + 1;
-
-    let mut res: Option<i32> = None;
-    let gm_inv = &int_to_scalar(-1*m)*G;
-    let mut gamma = gx.clone();
-    for i in 0..m {
-        let k = pzip(gamma);
-        if DLOG_TABLE.contains_key(&k) {
-            res = Some(i*m + DLOG_TABLE[&k]);
-            break;
-        }
-        gamma = gamma + gm_inv;
-    }
+    Ok((pzip(c1), pzip(c2), szip(y)))
+}
 
-    match res {
-        Some(x) => x,
-        None => panic!("Number of points out of bounds")
-    }
+// Recovers a balance x from G^x. Balances are negative when a shopper's
+// swapped barcodes earned more than their own purchases, so the search covers
+// [-bound, bound] of the installed table (see `dlog`).
+pub fn dlog_base_g(gx: RistrettoPoint) -> Result<i32> {
+    dlog::installed().dlog(gx)
 }
 
 // Takes as parameters:
 //      sk: a compressed Scala
 //      ct:  a compressed (RistrettoPoint, RistrettoPoint) ciphertext
 // Returns the decrypted number of loyalty points
-pub(crate) fn elgamal_dec(sk: [u8; 32], ct: ([u8; 32], [u8; 32])) -> i32 {
-    let sk = suzip(sk);
-    let ct0 = puzip(ct.0);
-    let ct1 = puzip(ct.1);
+pub fn elgamal_dec(sk: [u8; 32], ct: ([u8; 32], [u8; 32])) -> Result<i32> {
+    let sk = suzip(sk)?;
+    let ct0 = puzip(ct.0)?;
+    let ct1 = puzip(ct.1)?;
     let mg = ct1 + (Scalar::zero() - sk) * ct0;
 
     dlog_base_g(mg)
 }
 
-pub(crate) fn add_ciphertexts(ct0: ([u8; 32], [u8; 32]), ct1: ([u8; 32], [u8; 32])) -> ([u8; 32], [u8; 32]) {
-    let ct0 = (puzip(ct0.0), puzip(ct0.1));
-    let ct1 = (puzip(ct1.0), puzip(ct1.1));
+pub fn add_ciphertexts(ct0: ([u8; 32], [u8; 32]), ct1: ([u8; 32], [u8; 32])) -> Result<([u8; 32], [u8; 32])> {
+    let ct0 = (puzip(ct0.0)?, puzip(ct0.1)?);
+    let ct1 = (puzip(ct1.0)?, puzip(ct1.1)?);
 
-    (pzip(ct0.0 + ct1.0), pzip(ct0.1 + ct1.1))
+    Ok((pzip(ct0.0 + ct1.0), pzip(ct0.1 + ct1.1)))
 }
 
-pub(crate) struct TxCiphertextData {
+// The secrets of an encrypted amount: the randomness y and the amount m
+#[derive(Zeroize, ZeroizeOnDrop)]
+pub struct TxCiphertextData {
     ciphertext: (RistrettoPoint, RistrettoPoint),
     y: Scalar,
     m: Scalar,
     public_h: RistrettoPoint
 }
 
-#[derive(Clone)]
-pub(crate) struct CompressedTxCiphertextData {
+#[derive(Zeroize, ZeroizeOnDrop)]
+pub struct CompressedTxCiphertextData {
     ciphertext: ([u8; 32], [u8; 32]),
     y: [u8; 32],
     m: [u8; 32],
@@ -134,57 +112,88 @@ pub(crate) struct CompressedTxCiphertextData {
 }
 
 impl CompressedTxCiphertextData {
-    pub(crate) fn new(ct: ([u8; 32], [u8; 32]), y: [u8; 32], m: i32, h: [u8; 32]) -> Self {
+    pub fn new(ct: ([u8; 32], [u8; 32]), y: [u8; 32], m: i32, h: [u8; 32]) -> Self {
         CompressedTxCiphertextData {
             ciphertext: ct,
-            y: y,
+            y,
             m: szip(int_to_scalar(m)),
             public_h: h,
         }
     }
 }
 
-pub(crate) fn int_to_scalar(m: i32) -> Scalar {
-    let m_pos: u32 = m.abs() as u32;
-    let m_scalar = if m == m_pos as i32 { Scalar::from(m_pos) }
-                   else { Scalar::zero() - Scalar::from(m_pos) };
-    m_scalar
+pub fn int_to_scalar(m: i32) -> Scalar {
+    let m_pos: u32 = m.unsigned_abs();
+    if m >= 0 { Scalar::from(m_pos) }
+    else { Scalar::zero() - Scalar::from(m_pos) }
 }
 
 impl CompressedTxCiphertextData {
-    pub(crate) fn decompress(&self) -> TxCiphertextData {
-        TxCiphertextData {
-            ciphertext: (puzip(self.ciphertext.0),
-                         puzip(self.ciphertext.1)),
-            y: suzip(self.y),
-            m: suzip(self.m),
-            public_h: puzip(self.public_h),
-        }
+    pub fn decompress(&self) -> Result<TxCiphertextData> {
+        Ok(TxCiphertextData {
+            ciphertext: (puzip(self.ciphertext.0)?,
+                         puzip(self.ciphertext.1)?),
+            y: suzip(self.y)?,
+            m: suzip(self.m)?,
+            public_h: puzip(self.public_h)?,
+        })
     }
 }
 
-#[derive(Clone)]
-pub(crate) struct CompressedCtEqProof {
-    shopper_ct: ([u8; 32], [u8; 32]),
-    barcode_ct: ([u8; 32], [u8; 32]),
-    hs: [u8; 32],
-    hb: [u8; 32],
+// Proof that two ciphertexts encrypt opposite amounts. The ciphertexts and
+// public keys are not part of the proof: the verifier supplies its own copies.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct CompressedCtEqProof {
+    #[serde(with = "encoding::point")]
     cs0_t: [u8; 32],
+    #[serde(with = "encoding::point")]
     cs1_t: [u8; 32],
+    #[serde(with = "encoding::point")]
     cb0_t: [u8; 32],
+    #[serde(with = "encoding::point")]
     cb1_t: [u8; 32],
+    #[serde(with = "encoding::point")]
     i_t: [u8; 32],
+    #[serde(with = "encoding::scalar")]
     m_z: [u8; 32],
+    #[serde(with = "encoding::scalar")]
     mp_z: [u8; 32],
+    #[serde(with = "encoding::scalar")]
     ys_z: [u8; 32],
+    #[serde(with = "encoding::scalar")]
     yb_z: [u8; 32],
+    // The barcode owner's amount is between 0 and the server's maximum
+    range: RangeProof,
+}
+
+// Binds both ciphertexts, both public keys and the prover's commitments to
+// the transcript.
+// Output: the challenge
+fn zk_ct_eq_challenge(transcript: &mut Transcript, cts: ([u8; 32], [u8; 32]), ctb: ([u8; 32], [u8; 32]),
+                      hs: [u8; 32], hb: [u8; 32], commitments: [[u8; 32]; 5]) -> Scalar {
+    let commitment_labels: [&'static [u8]; 5] = [b"cs0_t", b"cs1_t", b"cb0_t", b"cb1_t", b"i_t"];
+
+    transcript.domain_sep(b"sh ciphertext equality proof");
+    transcript.append_point(b"cs0", &cts.0);
+    transcript.append_point(b"cs1", &cts.1);
+    transcript.append_point(b"cb0", &ctb.0);
+    transcript.append_point(b"cb1", &ctb.1);
+    transcript.append_point(b"hs", &hs);
+    transcript.append_point(b"hb", &hb);
+    for (label, p) in commitment_labels.into_iter().zip(commitments) {
+        transcript.append_point(label, &p);
+    }
+    transcript.challenge_scalar(b"c")
 }
 
-pub(crate) fn zk_ct_eq_prove(shopper_tx: CompressedTxCiphertextData, barcode_tx: CompressedTxCiphertextData)
-                 -> CompressedCtEqProof {
+// Input: the shopper's and barcode owner's ciphertexts with their
+// randomness, the amount the barcode owner receives and the server's maximum
+// amount
+pub fn zk_ct_eq_prove(transcript: &mut Transcript, shopper_tx: CompressedTxCiphertextData,
+                      barcode_tx: CompressedTxCiphertextData, points: u32, max: u32) -> Result<CompressedCtEqProof> {
     
-    let shopper_tx: TxCiphertextData = shopper_tx.decompress();
-    let barcode_tx: TxCiphertextData = barcode_tx.decompress();
+    let shopper_tx: TxCiphertextData = shopper_tx.decompress()?;
+    let barcode_tx: TxCiphertextData = barcode_tx.decompress()?;
 
     let cs0 = shopper_tx.ciphertext.0;
     let cs1 = shopper_tx.ciphertext.1;
@@ -210,13 +219,8 @@ pub(crate) fn zk_ct_eq_prove(shopper_tx: CompressedTxCiphertextData, barcode_tx:
     let i_t = &m_t*G + &mp_t*G;
 
     // Challenge
-    let mut hasher = Sha512::default();
-    for elt in [cs0, cs1, cb0, cb1, cs0_t, cs1_t, cb0_t, cb1_t, i_t].iter() {
-        let elt_bytes: [u8; 32] = pzip(*elt);
-        hasher.update(&elt_bytes);
-    }
-    
-    let c = Scalar::from_hash(hasher);
+    let c = zk_ct_eq_challenge(transcript, (pzip(cs0), pzip(cs1)), (pzip(cb0), pzip(cb1)), pzip(hs), pzip(hb),
+                               [pzip(cs0_t), pzip(cs1_t), pzip(cb0_t), pzip(cb1_t), pzip(i_t)]);
 
     // Response
     let m_z = m_t + m*c;
@@ -224,9 +228,11 @@ pub(crate) fn zk_ct_eq_prove(shopper_tx: CompressedTxCiphertextData, barcode_tx:
     let ys_z = ys_t + ys*c;
     let yb_z = yb_t + yb*c;
 
-    CompressedCtEqProof {
-        shopper_ct: (pzip(cs0), pzip(cs1)),
-        barcode_ct: (pzip(cb0), pzip(cb1)),
+    // The second half of the barcode owner's ciphertext, G^m hb^yb, is a
+    // commitment to their amount
+    let range = range::prove(transcript, points, yb, constants::RISTRETTO_BASEPOINT_POINT, hb, max)?;
+
+    Ok(CompressedCtEqProof {
         cs0_t: pzip(cs0_t),
         cs1_t: pzip(cs1_t),
         cb0_t: pzip(cb0_t),
@@ -236,64 +242,172 @@ pub(crate) fn zk_ct_eq_prove(shopper_tx: CompressedTxCiphertextData, barcode_tx:
         mp_z: szip(mp_z),
         ys_z: szip(ys_z),
         yb_z: szip(yb_z),
-        hs: pzip(hs),
-        hb: pzip(hb),
+        range,
+    })
+}
+
+// A ciphertext equality proof together with the statement it is checked
+// against, decompressed, and its recomputed challenge
+struct CtEqInstance {
+    cs0: RistrettoPoint,
+    cs1: RistrettoPoint,
+    cb0: RistrettoPoint,
+    cb1: RistrettoPoint,
+    hs: RistrettoPoint,
+    hb: RistrettoPoint,
+    cs0_t: RistrettoPoint,
+    cs1_t: RistrettoPoint,
+    cb0_t: RistrettoPoint,
+    cb1_t: RistrettoPoint,
+    i_t: RistrettoPoint,
+    m_z: Scalar,
+    mp_z: Scalar,
+    ys_z: Scalar,
+    yb_z: Scalar,
+    c: Scalar,
+    range_terms: (Vec<Scalar>, Vec<RistrettoPoint>),
+}
+
+impl CtEqInstance {
+    fn new(transcript: &mut Transcript, cts: ([u8; 32], [u8; 32]), ctb: ([u8; 32], [u8; 32]),
+           hs: [u8; 32], hb: [u8; 32], pi: CompressedCtEqProof, max: u32) -> Result<Self> {
+        // Recompute c
+        let c = zk_ct_eq_challenge(transcript, cts, ctb, hs, hb, [pi.cs0_t, pi.cs1_t, pi.cb0_t, pi.cb1_t, pi.i_t]);
+
+        let cb1 = puzip(ctb.1)?;
+        let hb = puzip(hb)?;
+        let range_terms = range::verification_terms(transcript, cb1, constants::RISTRETTO_BASEPOINT_POINT,
+                                                    hb, max, &pi.range)?;
+
+        Ok(CtEqInstance {
+            cs0: puzip(cts.0)?,
+            cs1: puzip(cts.1)?,
+            cb0: puzip(ctb.0)?,
+            cb1,
+            hs: puzip(hs)?,
+            hb,
+            cs0_t: puzip(pi.cs0_t)?,
+            cs1_t: puzip(pi.cs1_t)?,
+            cb0_t: puzip(pi.cb0_t)?,
+            cb1_t: puzip(pi.cb1_t)?,
+            i_t: puzip(pi.i_t)?,
+            m_z: suzip(pi.m_z)?,
+            mp_z: suzip(pi.mp_z)?,
+            ys_z: suzip(pi.ys_z)?,
+            yb_z: suzip(pi.yb_z)?,
+            c,
+            range_terms,
+        })
+    }
+
+    fn check(&self) -> Result<()> {
+        let c = self.c;
+        let check1 = G * &self.ys_z == self.cs0_t + self.cs0 * c;
+        let check2 = G * &self.mp_z + self.hs * self.ys_z == self.cs1_t + self.cs1 * c;
+        let check3 = G * &self.yb_z == self.cb0_t + self.cb0 * c;
+        let check4 = G * &self.m_z + self.hb * self.yb_z == self.cb1_t + self.cb1 * c;
+        let check5 = G * &self.m_z + G * &self.mp_z == self.i_t;
+        let check_range = RistrettoPoint::vartime_multiscalar_mul(&self.range_terms.0, &self.range_terms.1).is_identity();
+
+        if check1 && check2 && check3 && check4 && check5 && check_range { Ok(()) }
+        else { Err(CheckoutError::InvalidProof) }
     }
 }
 
-pub(crate) fn zk_ct_eq_verify(pi: CompressedCtEqProof) -> bool {
-    // Recompute c
-    let mut hasher = Sha512::default();
-    for elt in [pi.shopper_ct.0, pi.shopper_ct.1, pi.barcode_ct.0, pi.barcode_ct.1,
-                pi.cs0_t, pi.cs1_t, pi.cb0_t, pi.cb1_t, pi.i_t].iter() {
-        hasher.update(&elt);
+// Input: the shopper's and barcode owner's ciphertexts and public keys, as
+// known to the verifier, the proof, and the server's maximum amount
+pub fn zk_ct_eq_verify(transcript: &mut Transcript, cts: ([u8; 32], [u8; 32]), ctb: ([u8; 32], [u8; 32]),
+                       hs: [u8; 32], hb: [u8; 32], pi: CompressedCtEqProof, max: u32) -> Result<()> {
+    CtEqInstance::new(transcript, cts, ctb, hs, hb, pi, max)?.check()
+}
+
+// One entry of a batch: the arguments of `zk_ct_eq_verify`
+pub type CtEqBatchEntry = (Transcript, ([u8; 32], [u8; 32]), ([u8; 32], [u8; 32]), [u8; 32], [u8; 32], CompressedCtEqProof);
+
+// Checks many ciphertext equality proofs at once, by summing every equation,
+// range proofs included, each weighted by a fresh random scalar, in one
+// multiscalar multiplication.
+// Output: for each proof, in order, whether it is valid. If the batch fails,
+// each proof is checked on its own to find the offending ones.
+pub fn zk_ct_eq_verify_batch(batch: Vec<CtEqBatchEntry>, max: u32) -> Vec<Result<()>> {
+    let instances: Vec<Result<CtEqInstance>> = batch.into_iter()
+        .map(|(mut transcript, cts, ctb, hs, hb, pi)| CtEqInstance::new(&mut transcript, cts, ctb, hs, hb, pi, max))
+        .collect();
+
+    // The generator is shared by every proof, so its scalar is accumulated.
+    // Proofs with points that do not decompress are left out: they fail anyway.
+    let mut g_scalar = Scalar::zero();
+    let mut scalars = Vec::<Scalar>::with_capacity(11 * instances.len() + 1);
+    let mut points = Vec::<RistrettoPoint>::with_capacity(11 * instances.len() + 1);
+
+    for inst in instances.iter().flatten() {
+        let w: [Scalar; 5] = [(); 5].map(|_| Scalar::random(&mut OsRng));
+
+        g_scalar += w[0] * inst.ys_z + w[1] * inst.mp_z + w[2] * inst.yb_z + w[3] * inst.m_z
+                  + w[4] * (inst.m_z + inst.mp_z);
+        scalars.push(w[1] * inst.ys_z);
+        points.push(inst.hs);
+        scalars.push(w[3] * inst.yb_z);
+        points.push(inst.hb);
+
+        let rhs = [(inst.cs0_t, inst.cs0), (inst.cs1_t, inst.cs1), (inst.cb0_t, inst.cb0), (inst.cb1_t, inst.cb1)];
+        for (wi, (p_t, p)) in w.iter().zip(rhs) {
+            scalars.push(-wi);
+            points.push(p_t);
+            scalars.push(-(wi * inst.c));
+            points.push(p);
+        }
+        scalars.push(-w[4]);
+        points.push(inst.i_t);
+
+        scalars.extend_from_slice(&inst.range_terms.0);
+        points.extend_from_slice(&inst.range_terms.1);
     }
-    let c = Scalar::from_hash(hasher);
-
-    let cs0 = puzip(pi.shopper_ct.0);
-    let cs1 = puzip(pi.shopper_ct.1);
-    let cb0 = puzip(pi.barcode_ct.0);
-    let cb1 = puzip(pi.barcode_ct.1);
-    let cs0_t = puzip(pi.cs0_t);
-    let cs1_t = puzip(pi.cs1_t);
-    let cb0_t = puzip(pi.cb0_t);
-    let cb1_t = puzip(pi.cb1_t);
-    let i_t = puzip(pi.i_t);
-    let m_z = suzip(pi.m_z);
-    let mp_z = suzip(pi.mp_z);
-    let ys_z = suzip(pi.ys_z);
-    let yb_z = suzip(pi.yb_z);
-    let hs = puzip(pi.hs);
-    let hb = puzip(pi.hb);
-
-    let check1 = G * &ys_z == cs0_t + cs0 * &c;
-    let check2 = G * &mp_z + hs*&ys_z == cs1_t + cs1 * &c;
-    let check3 = G * &yb_z == cb0_t + cb0 * &c;
-    let check4 = G * &m_z + hb * &yb_z == cb1_t + cb1 * &c;
-    let check5 = G * &m_z + G * &mp_z == i_t;
-
-    check1 && check2 && check3 && check4 && check5
-}
-
-#[derive(Clone)]
-pub(crate) struct CompressedCtDecProof {
-    ct: ([u8; 32], [u8; 32]),
-    pt: [u8; 32],
-    h: [u8; 32],
+    scalars.push(g_scalar);
+    points.push(constants::RISTRETTO_BASEPOINT_POINT);
+
+    if RistrettoPoint::vartime_multiscalar_mul(&scalars, &points).is_identity() {
+        return instances.into_iter().map(|inst| inst.map(|_| ())).collect();
+    }
+
+    instances.into_iter().map(|inst| inst?.check()).collect()
+}
+
+// Proof that a ciphertext decrypts to a given amount. The ciphertext, amount
+// and public key are supplied by the verifier.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct CompressedCtDecProof {
+    #[serde(with = "encoding::point")]
     v_t: [u8; 32],
+    #[serde(with = "encoding::point")]
     w_t: [u8; 32],
+    #[serde(with = "encoding::scalar")]
     x_z: [u8; 32],
 }
 
-pub(crate) fn zk_ct_dec_prove(ct: ([u8; 32], [u8; 32]), pt: i32, x: [u8; 32], h: [u8; 32]) -> CompressedCtDecProof {
-    let c0 = puzip(ct.0);
-    let c1 = puzip(ct.1);
+// Binds the ciphertext, the amount, the public key and the prover's
+// commitments to the transcript.
+// Output: the challenge
+fn zk_ct_dec_challenge(transcript: &mut Transcript, ct: ([u8; 32], [u8; 32]), pt: Scalar, h: [u8; 32],
+                       v_t: [u8; 32], w_t: [u8; 32]) -> Scalar {
+    transcript.domain_sep(b"sh decryption proof");
+    transcript.append_point(b"c0", &ct.0);
+    transcript.append_point(b"c1", &ct.1);
+    transcript.append_scalar(b"pt", &pt);
+    transcript.append_point(b"h", &h);
+    transcript.append_point(b"v_t", &v_t);
+    transcript.append_point(b"w_t", &w_t);
+    transcript.challenge_scalar(b"c")
+}
+
+pub fn zk_ct_dec_prove(transcript: &mut Transcript, ct: ([u8; 32], [u8; 32]), pt: i32, x: [u8; 32], h: [u8; 32])
+                       -> Result<CompressedCtDecProof> {
+    let c0 = puzip(ct.0)?;
     let pt = int_to_scalar(pt);
-    let x = suzip(x);
+    let x = suzip(x)?;
 
     // Generate Chaum-Pedersen proof
     let u = c0;
-    let v = puzip(h);
 
     // Commitment
     let x_t = Scalar::random(&mut OsRng);
@@ -302,42 +416,33 @@ pub(crate) fn zk_ct_dec_prove(ct: ([u8; 32], [u8; 32]), pt: i32, x: [u8; 32], h:
     let w_t = u * x_t;
 
     // Challenge
-    let mut hasher = Sha512::default();
-    for elt in [c0, c1, v_t, w_t].iter() {
-        let elt_bytes: [u8; 32] = pzip(*elt);
-        hasher.update(&elt_bytes);
-    }
-    
-    let c = Scalar::from_hash(hasher);
+    let c = zk_ct_dec_challenge(transcript, ct, pt, h, pzip(v_t), pzip(w_t));
 
     // Response
     let x_z = x_t + x * c;
 
-    CompressedCtDecProof {
-        ct: (pzip(c0), pzip(c1)),
-        pt: szip(pt),
-        h: pzip(v),
+    Ok(CompressedCtDecProof {
         v_t: pzip(v_t),
         w_t: pzip(w_t),
         x_z: szip(x_z),
-    }
+    })
 }
 
-pub(crate) fn zk_ct_dec_verify(pi: CompressedCtDecProof) -> bool {
+// Input: the ciphertext, the amount it is claimed to decrypt to and the
+// public key, as known to the verifier, and the proof
+pub fn zk_ct_dec_verify(transcript: &mut Transcript, ct: ([u8; 32], [u8; 32]), pt: i32, h: [u8; 32],
+                        pi: CompressedCtDecProof) -> Result<()> {
+    let pt = int_to_scalar(pt);
+
     // Recompute c
-    let mut hasher = Sha512::default();
-    for elt in [pi.ct.0, pi.ct.1, pi.v_t, pi.w_t].iter() {
-        hasher.update(&elt);
-    }
-    let c = Scalar::from_hash(hasher);
+    let c = zk_ct_dec_challenge(transcript, ct, pt, h, pi.v_t, pi.w_t);
 
-    let c0 = puzip(pi.ct.0);
-    let c1 = puzip(pi.ct.1);
-    let pt = suzip(pi.pt);
-    let h = puzip(pi.h);
-    let v_t = puzip(pi.v_t);
-    let w_t = puzip(pi.w_t);
-    let x_z = suzip(pi.x_z);
+    let c0 = puzip(ct.0)?;
+    let c1 = puzip(ct.1)?;
+    let h = puzip(h)?;
+    let v_t = puzip(pi.v_t)?;
+    let w_t = puzip(pi.w_t)?;
+    let x_z = suzip(pi.x_z)?;
 
     let v = h;
     let w = c1 + G * &(Scalar::zero() - pt);
@@ -345,5 +450,212 @@ pub(crate) fn zk_ct_dec_verify(pi: CompressedCtDecProof) -> bool {
     let check1 = G * &x_z == v_t + v * c;
     let check2 = c0 * x_z == w_t + w * c;
 
-    check1 && check2
-}
\ No newline at end of file
+    if check1 && check2 { Ok(()) }
+    else { Err(CheckoutError::InvalidProof) }
+}
+// Most points that may be left in a balance after redeeming
+pub const MAX_REMAINDER: u32 = i32::MAX as u32;
+
+// Output: the encrypted balance once `points` are redeemed from it
+pub fn subtract_points(ct: ([u8; 32], [u8; 32]), points: u32) -> Result<([u8; 32], [u8; 32])> {
+    let c1 = puzip(ct.1)? - &Scalar::from(points) * G;
+    Ok((ct.0, pzip(c1)))
+}
+
+// Proof that an encrypted balance covers an amount being redeemed, without
+// revealing the balance. With (c0, c1') the balance once the amount is taken
+// out, the prover knows sk and rest with h = G^sk and c1' = G^rest c0^sk, and
+// commits to rest in d = G^rest H^s, with a range proof that rest is between 0
+// and `MAX_REMAINDER`.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct CompressedRedeemProof {
+    #[serde(with = "encoding::point")]
+    d: [u8; 32],
+    #[serde(with = "encoding::point")]
+    h_t: [u8; 32],
+    #[serde(with = "encoding::point")]
+    c1_t: [u8; 32],
+    #[serde(with = "encoding::point")]
+    d_t: [u8; 32],
+    #[serde(with = "encoding::scalar")]
+    sk_z: [u8; 32],
+    #[serde(with = "encoding::scalar")]
+    rest_z: [u8; 32],
+    #[serde(with = "encoding::scalar")]
+    s_z: [u8; 32],
+    range: RangeProof,
+}
+
+// Binds the balance, the amount, the public key, the commitment to what is
+// left and the prover's commitments to the transcript.
+// Output: the challenge
+fn zk_redeem_challenge(transcript: &mut Transcript, ct: ([u8; 32], [u8; 32]), points: u32, h: [u8; 32],
+                       d: [u8; 32], commitments: [[u8; 32]; 3]) -> Scalar {
+    let commitment_labels: [&'static [u8]; 3] = [b"h_t", b"c1_t", b"d_t"];
+
+    transcript.domain_sep(b"sh redeem proof");
+    transcript.append_point(b"c0", &ct.0);
+    transcript.append_point(b"c1", &ct.1);
+    transcript.append_u64(b"points", points as u64);
+    transcript.append_point(b"h", &h);
+    transcript.append_point(b"d", &d);
+    for (label, p) in commitment_labels.into_iter().zip(commitments) {
+        transcript.append_point(label, &p);
+    }
+    transcript.challenge_scalar(b"c")
+}
+
+// Input: the encrypted balance, the amount to redeem, the balance itself and
+// the key pair it is encrypted under
+// Output: the proof, or an error if the balance is less than the amount
+pub fn zk_redeem_prove(transcript: &mut Transcript, ct: ([u8; 32], [u8; 32]), points: u32, bal: i32,
+                       sk: [u8; 32], h: [u8; 32]) -> Result<CompressedRedeemProof> {
+    let rest: u32 = (bal as i64 - points as i64).try_into().map_err(|_| CheckoutError::PointsOutOfRange)?;
+    let c0 = puzip(ct.0)?;
+    let sk = suzip(sk)?;
+    let rest_scalar = Scalar::from(rest);
+    let s = Scalar::random(&mut OsRng);
+    let d = &rest_scalar*G + s * *H_POINT;
+
+    // Commitment
+    let sk_t = Scalar::random(&mut OsRng);
+    let rest_t = Scalar::random(&mut OsRng);
+    let s_t = Scalar::random(&mut OsRng);
+
+    let h_t = &sk_t*G;
+    let c1_t = &rest_t*G + sk_t*c0;
+    let d_t = &rest_t*G + s_t * *H_POINT;
+
+    // Challenge
+    let c = zk_redeem_challenge(transcript, ct, points, h, pzip(d), [pzip(h_t), pzip(c1_t), pzip(d_t)]);
+
+    // Response
+    let sk_z = sk_t + sk*c;
+    let rest_z = rest_t + rest_scalar*c;
+    let s_z = s_t + s*c;
+
+    let range = range::prove(transcript, rest, s, constants::RISTRETTO_BASEPOINT_POINT, *H_POINT, MAX_REMAINDER)?;
+
+    Ok(CompressedRedeemProof {
+        d: pzip(d),
+        h_t: pzip(h_t),
+        c1_t: pzip(c1_t),
+        d_t: pzip(d_t),
+        sk_z: szip(sk_z),
+        rest_z: szip(rest_z),
+        s_z: szip(s_z),
+        range,
+    })
+}
+
+// Input: the encrypted balance and public key, as known to the verifier, the
+// amount redeemed and the proof
+pub fn zk_redeem_verify(transcript: &mut Transcript, ct: ([u8; 32], [u8; 32]), points: u32, h: [u8; 32],
+                        pi: CompressedRedeemProof) -> Result<()> {
+    // Recompute c
+    let c = zk_redeem_challenge(transcript, ct, points, h, pi.d, [pi.h_t, pi.c1_t, pi.d_t]);
+
+    let c0 = puzip(ct.0)?;
+    let c1 = puzip(subtract_points(ct, points)?.1)?;
+    let h = puzip(h)?;
+    let d = puzip(pi.d)?;
+    let h_t = puzip(pi.h_t)?;
+    let c1_t = puzip(pi.c1_t)?;
+    let d_t = puzip(pi.d_t)?;
+    let sk_z = suzip(pi.sk_z)?;
+    let rest_z = suzip(pi.rest_z)?;
+    let s_z = suzip(pi.s_z)?;
+
+    let check1 = G * &sk_z == h_t + h * c;
+    let check2 = G * &rest_z + c0 * sk_z == c1_t + c1 * c;
+    let check3 = G * &rest_z + *H_POINT * s_z == d_t + d * c;
+    let check_range = range::verify(transcript, d, constants::RISTRETTO_BASEPOINT_POINT, *H_POINT,
+                                    MAX_REMAINDER, &pi.range).is_ok();
+
+    if check1 && check2 && check3 && check_range { Ok(()) }
+    else { Err(CheckoutError::InvalidProof) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX: u32 = 1000;
+    const SERVER: [u8; 32] = [1; 32];
+    const TX_ID: [u8; 32] = [2; 32];
+
+    fn transcript() -> Transcript {
+        Transcript::for_tx(&SERVER, &TX_ID, 0)
+    }
+
+    // Input: the amounts encrypted for the shopper and the barcode owner, and
+    // the amount the proof claims
+    // Output: the transaction as the server sees it, or the prover's error
+    fn prove(shopper: i32, owner: i32, x: u32, max: u32) -> Result<CtEqBatchEntry> {
+        let ((_, hs), (_, hb)) = (elgamal_keygen(), elgamal_keygen());
+        let cts = elgamal_enc(hs, shopper)?;
+        let ctb = elgamal_enc(hb, owner)?;
+        let pi = zk_ct_eq_prove(&mut transcript(), CompressedTxCiphertextData::new((cts.0, cts.1), cts.2, shopper, hs),
+                                CompressedTxCiphertextData::new((ctb.0, ctb.1), ctb.2, owner, hb), x, max)?;
+        Ok((transcript(), (cts.0, cts.1), (ctb.0, ctb.1), hs, hb, pi))
+    }
+
+    fn verify((mut transcript, cts, ctb, hs, hb, pi): CtEqBatchEntry) -> Result<()> {
+        zk_ct_eq_verify(&mut transcript, cts, ctb, hs, hb, pi, MAX)
+    }
+
+    #[test]
+    fn round_trip() {
+        for x in [0, 1, MAX] {
+            assert_eq!(verify(prove(-(x as i32), x as i32, x, MAX).unwrap()), Ok(()));
+        }
+    }
+
+    #[test]
+    fn amount_above_max() {
+        let x = MAX + 1;
+        assert_eq!(prove(-(x as i32), x as i32, x, MAX).err(), Some(CheckoutError::PointsOutOfRange));
+        assert_eq!(verify(prove(-(x as i32), x as i32, x, 1023).unwrap()), Err(CheckoutError::InvalidProof));
+    }
+
+    #[test]
+    fn negative_amount() {
+        assert_eq!(prove(5, -5, -5i32 as u32, MAX).err(), Some(CheckoutError::PointsOutOfRange));
+        // The barcode owner's ciphertext must hold the amount in range
+        assert_eq!(verify(prove(5, -5, 5, MAX).unwrap()), Err(CheckoutError::InvalidProof));
+    }
+
+    #[test]
+    fn tampered_commitment() {
+        let (transcript, cts, ctb, hs, hb, pi) = prove(-5, 5, 5, MAX).unwrap();
+        let other = prove(-5, 5, 5, MAX).unwrap();
+
+        let mut tampered = pi.clone();
+        tampered.range = other.5.range.clone();
+        assert_eq!(verify((transcript.clone(), cts, ctb, hs, hb, tampered)), Err(CheckoutError::InvalidProof));
+
+        // The barcode owner's ciphertext is the commitment the range proof is for
+        assert_eq!(verify((transcript, cts, (ctb.0, other.2.1), hs, hb, pi)), Err(CheckoutError::InvalidProof));
+    }
+
+    #[test]
+    fn bound_to_server_and_tx() {
+        let (_, cts, ctb, hs, hb, pi) = prove(-5, 5, 5, MAX).unwrap();
+        let others = [Transcript::for_tx(&[3; 32], &TX_ID, 0), Transcript::for_tx(&SERVER, &[3; 32], 0),
+                      Transcript::for_tx(&SERVER, &TX_ID, 1)];
+        for transcript in others {
+            assert_eq!(verify((transcript, cts, ctb, hs, hb, pi.clone())), Err(CheckoutError::InvalidProof));
+        }
+    }
+
+    #[test]
+    fn batch_with_one_bad_proof() {
+        let batch = vec![prove(-5, 5, 5, MAX).unwrap(), prove(5, -5, 5, MAX).unwrap(),
+                         prove(-(MAX as i32), MAX as i32, MAX, MAX).unwrap()];
+        assert_eq!(zk_ct_eq_verify_batch(batch.clone(), MAX), vec![Ok(()), Err(CheckoutError::InvalidProof), Ok(())]);
+
+        // Checked one at a time, the proofs give the same results
+        let results: Vec<Result<()>> = batch.into_iter().map(verify).collect();
+        assert_eq!(results, vec![Ok(()), Err(CheckoutError::InvalidProof), Ok(())]);
+    }
+}
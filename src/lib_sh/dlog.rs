@@ -0,0 +1,266 @@
+// Recovering small discrete logs, to decrypt ElGamal-encrypted balances.
+//
+// A balance x is found from G^x with the baby-step giant-step algorithm. The
+// table holds the baby steps j*G for j < size, and each giant step moves
+// size*G closer to zero until it lands on a table entry. Positive and
+// negative balances are searched side by side, so balances near zero are
+// found first. A bigger table means fewer giant steps; since building one
+// takes a while, it can be saved to disk and loaded later.
+//
+// Entries are keyed by the first 8 bytes of the compressed point. Every hit
+// is checked against the point itself, so a truncated key (or a damaged
+// table file) can make a search fail but never return a wrong balance.
+
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use curve25519_dalek::constants;
+use curve25519_dalek::ristretto::{CompressedRistretto, RistrettoPoint};
+use curve25519_dalek::scalar::Scalar;
+use curve25519_dalek::traits::Identity;
+use lazy_static::lazy_static;
+use serde_derive::{Serialize, Deserialize};
+use crate::error::{CheckoutError, Result};
+
+// Largest |balance| recovered by the table that is built when none has been
+// installed
+pub const DEFAULT_BOUND: u32 = 10_000_000;
+
+// Version of the table file format
+const TABLE_VERSION: u16 = 1;
+const MAGIC: [u8; 4] = *b"CKDL";
+
+// Points compressed at once while building a table
+const BUILD_BATCH: usize = 4096;
+
+pub struct DlogTable {
+    bound: u32,                   // Largest |x| that is searched for
+    size: u32,                    // Number of baby steps
+    steps: HashMap<u64, u32>      // Key of j*G -> j
+}
+
+// A table file: the header, then the key of j*G for every j in order
+#[derive(Serialize, Deserialize)]
+struct TableFile {
+    magic: [u8; 4],
+    version: u16,
+    bound: u32,
+    size: u32,
+    keys: Vec<u64>,
+}
+
+fn key(p: &CompressedRistretto) -> u64 {
+    u64::from_le_bytes(p.as_bytes()[..8].try_into().unwrap())
+}
+
+fn int_to_point(x: i64) -> RistrettoPoint {
+    let s = Scalar::from(x.unsigned_abs());
+    let s = if x < 0 { -s } else { s };
+    &s * &constants::RISTRETTO_BASEPOINT_TABLE
+}
+
+impl DlogTable {
+    // A table that recovers balances in [-bound, bound], with `size` baby
+    // steps. The bound is capped at the largest i32.
+    pub fn new(bound: u32, size: u32) -> Self {
+        let bound = bound.min(i32::MAX as u32);
+        let size = size.clamp(1, bound.max(1));
+
+        // Doubling and compressing many points at once shares one field
+        // inversion between them, so the table is built from multiples of G/2.
+        // The batch cannot hold the identity, so 0*G goes in on its own.
+        let half = &Scalar::from(2u8).invert() * &constants::RISTRETTO_BASEPOINT_TABLE;
+        let mut p = half;
+        let mut keys = Vec::<u64>::with_capacity(size as usize);
+        keys.push(key(&RistrettoPoint::identity().compress()));
+        while keys.len() < size as usize {
+            let n = BUILD_BATCH.min(size as usize - keys.len());
+            let chunk: Vec<RistrettoPoint> = (0..n).map(|_| {
+                let q = p;
+                p += half;
+                q
+            }).collect();
+            keys.extend(RistrettoPoint::double_and_compress_batch(&chunk).iter().map(key));
+        }
+
+        DlogTable::from_keys(bound, size, keys)
+    }
+
+    // A table for balances in [-bound, bound], with the number of baby steps
+    // that makes building it and searching it take about as long
+    pub fn with_bound(bound: u32) -> Self {
+        DlogTable::new(bound, (bound as f64).sqrt() as u32 + 1)
+    }
+
+    fn from_keys(bound: u32, size: u32, keys: Vec<u64>) -> Self {
+        let mut steps = HashMap::with_capacity(keys.len());
+        for (j, k) in keys.into_iter().enumerate() {
+            // On the rare clash of two keys, the smaller step is kept
+            steps.entry(k).or_insert(j as u32);
+        }
+        DlogTable { bound, size, steps }
+    }
+
+    // Largest |x| this table recovers
+    pub fn bound(&self) -> u32 {
+        self.bound
+    }
+
+    // Output: x, or an error if |x| is above the table's bound
+    pub fn dlog(&self, gx: RistrettoPoint) -> Result<i32> {
+        let giant = &Scalar::from(self.size) * &constants::RISTRETTO_BASEPOINT_TABLE;
+        let mut pos = gx;
+        let mut neg = -gx;
+
+        for i in 0..=(self.bound / self.size) {
+            for (gamma, sign) in [(pos, 1i64), (neg, -1i64)] {
+                let j = match self.steps.get(&key(&gamma.compress())) {
+                    Some(j) => *j,
+                    None => continue,
+                };
+                let x = sign * (i as i64 * self.size as i64 + j as i64);
+                if x.unsigned_abs() <= self.bound as u64 && int_to_point(x) == gx {
+                    return Ok(x as i32);
+                }
+            }
+            pos -= giant;
+            neg -= giant;
+        }
+
+        Err(CheckoutError::BalanceOutOfRange)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut keys = vec![0u64; self.size as usize];
+        for (k, j) in &self.steps {
+            keys[*j as usize] = *k;
+        }
+        let file = TableFile { magic: MAGIC, version: TABLE_VERSION, bound: self.bound, size: self.size, keys };
+        bincode::serialize(&file).unwrap()
+    }
+
+    // Loads a table written by `to_bytes`. Its last entry is checked, so a
+    // file that is truncated or was built differently is rejected.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let file: TableFile = bincode::deserialize(bytes).map_err(|_| CheckoutError::MalformedMessage)?;
+        if file.magic != MAGIC {
+            return Err(CheckoutError::MalformedMessage);
+        }
+        if file.version != TABLE_VERSION {
+            return Err(CheckoutError::UnsupportedVersion);
+        }
+        if file.size == 0 || file.keys.len() != file.size as usize || file.bound > i32::MAX as u32 {
+            return Err(CheckoutError::MalformedMessage);
+        }
+        let last = file.size as i64 - 1;
+        if file.keys[last as usize] != key(&int_to_point(last).compress()) {
+            return Err(CheckoutError::MalformedMessage);
+        }
+
+        Ok(DlogTable::from_keys(file.bound, file.size, file.keys))
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        std::fs::write(path, self.to_bytes())
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self> {
+        DlogTable::from_bytes(&std::fs::read(path)?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+lazy_static! {
+    static ref INSTALLED: RwLock<Option<Arc<DlogTable>>> = RwLock::new(None);
+}
+
+// Makes every later decryption in this process use `table`. Apps that expect
+// balances beyond `DEFAULT_BOUND` install a bigger table at startup.
+pub fn install(table: DlogTable) {
+    *INSTALLED.write().unwrap() = Some(Arc::new(table));
+}
+
+// Output: the installed table, building the default one on first use
+pub fn installed() -> Arc<DlogTable> {
+    if let Some(table) = INSTALLED.read().unwrap().as_ref() {
+        return table.clone();
+    }
+    INSTALLED.write().unwrap()
+        .get_or_insert_with(|| Arc::new(DlogTable::with_bound(DEFAULT_BOUND)))
+        .clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BOUND: u32 = 1000;
+
+    fn gx(x: i64) -> RistrettoPoint {
+        int_to_point(x)
+    }
+
+    #[test]
+    fn positive_and_negative_balances() {
+        // A size that does not divide the bound leaves a short last giant step
+        for table in [DlogTable::with_bound(BOUND), DlogTable::new(BOUND, 7), DlogTable::new(BOUND, 1)] {
+            for x in [0, 1, -1, 6, 7, -7, 500, -999, BOUND as i32, -(BOUND as i32)] {
+                assert_eq!(table.dlog(gx(x as i64)), Ok(x));
+            }
+            for x in [BOUND as i64 + 1, -(BOUND as i64) - 1, 1 << 40] {
+                assert_eq!(table.dlog(gx(x)), Err(CheckoutError::BalanceOutOfRange));
+            }
+        }
+    }
+
+    #[test]
+    fn bound_is_capped() {
+        assert_eq!(DlogTable::new(u32::MAX, 1 << 10).bound(), i32::MAX as u32);
+    }
+
+    #[test]
+    fn saved_table_loads() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("table");
+        DlogTable::new(BOUND, 30).save(&path).unwrap();
+
+        let table = DlogTable::load(&path).unwrap();
+        assert_eq!(table.bound(), BOUND);
+        assert_eq!(table.dlog(gx(-777)), Ok(-777));
+    }
+
+    #[test]
+    fn damaged_table_file_is_refused() {
+        let table = DlogTable::new(BOUND, 30);
+        let bytes = table.to_bytes();
+        let file = || -> TableFile { bincode::deserialize(&bytes).unwrap() };
+        let encode = |file: TableFile| bincode::serialize(&file).unwrap();
+
+        assert_eq!(DlogTable::from_bytes(&bytes[..bytes.len() - 1]).err(), Some(CheckoutError::MalformedMessage));
+        assert_eq!(DlogTable::from_bytes(&encode(TableFile { magic: *b"XXXX", ..file() })).err(),
+                   Some(CheckoutError::MalformedMessage));
+        assert_eq!(DlogTable::from_bytes(&encode(TableFile { version: TABLE_VERSION + 1, ..file() })).err(),
+                   Some(CheckoutError::UnsupportedVersion));
+        assert_eq!(DlogTable::from_bytes(&encode(TableFile { size: 31, ..file() })).err(),
+                   Some(CheckoutError::MalformedMessage));
+
+        // A table built for another base point fails the check on its last entry
+        let mut keys = file().keys;
+        keys.swap(28, 29);
+        assert_eq!(DlogTable::from_bytes(&encode(TableFile { keys, ..file() })).err(),
+                   Some(CheckoutError::MalformedMessage));
+    }
+
+    #[test]
+    fn damaged_entry_never_gives_a_wrong_balance() {
+        // Step 3 is recorded where step 4 should be, so a search that lands on
+        // it must not take it
+        let mut keys: Vec<u64> = (0..30).map(|j| key(&gx(j).compress())).collect();
+        keys[4] = keys[3];
+        keys[3] = 0;
+        let table = DlogTable::from_keys(BOUND, 30, keys);
+        assert_eq!(table.dlog(gx(3)), Err(CheckoutError::BalanceOutOfRange));
+        assert_eq!(table.dlog(gx(5)), Ok(5));
+    }
+}
@@ -1,33 +1,137 @@
-pub(crate) mod crypto_sh;
+pub mod crypto_sh;
+pub mod dlog;
 use rs_merkle::{MerkleTree, algorithms, Hasher, MerkleProof};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::path::Path;
+use std::sync::RwLock;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
 use std::vec::Vec;
-use serde_derive::Serialize;
-use rand::Rng;
-use sha2::{Sha256, Digest};
-
-pub(crate) type Com = [u8; 32];
-pub(crate) type Ciphertext = ([u8; 32], [u8; 32]);
-pub(crate) type Key = [u8; 32];
-
-pub(crate) struct Server {
-    num_users: u32, 
-    pub users: HashMap<u32, UserRecord>,
-    merkle_tree: MerkleTree<algorithms::Sha256>,
-    tmp: HashMap<Com, ServerTxTmp>,
+use serde_derive::{Serialize, Deserialize};
+use rand::{rngs, Rng};
+use ed25519_dalek::{Signature, SigningKey};
+use zeroize::{Zeroize, ZeroizeOnDrop};
+use crate::auth::{self, AuthKey};
+use crate::coin::{self, ClientShare, CoinFlip, RecentBarcodes, SelectionPolicy, ServerShare};
+use crate::encoding;
+use crate::error::{CheckoutError, Result};
+use crate::expiry::{Expiry, Period};
+use crate::secret::Secret;
+use crate::session::{self, Sessions};
+use crate::settlement::{self, Redemption, Settlement, SettleStatus};
+use crate::store::Store;
+use crate::scheme::{LoyaltyScheme, SchemeServer, SchemeClient, SecurityLevel, Root, ServerId, MerchantId, Category, Account,
+                    DEFAULT_MAX_POINTS, DEFAULT_MERCHANT_NAME, DEFAULT_CATEGORY_NAME};
+use crate::transcript::Transcript;
+
+pub type Com = [u8; 32];
+pub type Ciphertext = ([u8; 32], [u8; 32]);
+pub type Key = [u8; 32];
+// Shopper's and barcode owner's user IDs, and the merchant of a transaction
+type TxParties = (u32, u32, MerchantId);
+// For each category of a transaction, the amount encrypted for the shopper
+// and for the barcode owner, and the proof that they are the same
+pub type TxCiphertexts = Vec<(Ciphertext, Ciphertext, crypto_sh::CompressedCtEqProof)>;
+
+// The period and amount of each part of a redemption, and a redeem proof for
+// each part
+pub type RedeemData = (Vec<(Period, u32)>, Vec<crypto_sh::CompressedRedeemProof>);
+
+// Each unexpired period and the balance's share in it, oldest first
+pub type BalanceParts = Vec<(Period, Ciphertext)>;
+
+// Every method takes `&self`, so one server can process many transactions at
+// once. Locks are always taken in the order users, then merkle_tree, and tmp
+// is never held while taking another lock.
+pub struct Server {
+    sk: SigningKey, // Signs settlement receipts
+    id: ServerId, // The verification key for `sk`
+    pub(crate) users: RwLock<HashMap<u32, UserRecord>>,
+    merkle_tree: RwLock<MerkleTree<algorithms::Sha256>>,
+    tmp: Sessions<ServerTxTmp, ()>,
+    max_points: u32, // Most points a single transaction may move
+    policy: SelectionPolicy, // Which users the coin flip may pick
+    recent: RecentBarcodes,
+    merchants: Vec<String>, // Merchant names, indexed by merchant ID
+    categories: Vec<String>, // Category names, indexed by category ID
+    expiry: Expiry,
+    retired: AtomicU32, // Oldest unexpired period when expired points were last dropped
+    store: Option<Store<Event>>,
+}
+
+// Changes to the server's durable state, in the order they are logged
+#[derive(Serialize, Deserialize)]
+enum Event {
+    Register { barcode: u64, pk_enc: Key, pk_auth: AuthKey },
+    // Both users' new balances with the merchant for the period, one for each
+    // category
+    Tx { uid_s: u32, uid_b: u32, merchant: MerchantId, period: Period, bal_s: Vec<Ciphertext>, bal_b: Vec<Ciphertext> },
+    // The user's balance in an account was settled and replaced with a fresh
+    // encryption of zero for the period
+    Settled { period: Period, balance: Ciphertext, settlement: Settlement },
+    // Points were redeemed from the user's balance in an account, leaving
+    // `balances` in the periods of the redemption's parts
+    Redeemed { balances: Vec<(Period, Ciphertext)>, redemption: Redemption },
+    // Every part of a balance from before `oldest` expired
+    Retired { oldest: Period },
 }
 
+// Signing key and users. The Merkle tree is rebuilt from the users when the
+// snapshot is loaded.
+type Snapshot = (Secret<[u8; 32]>, HashMap<u32, UserRecord>);
+
+#[derive(Clone)]
 struct ServerTxTmp {
-    i_s: Option<u32>, // Server's chosen index for card-swapping phase
+    uid_s: u32, // Shopper's user ID
+    merchant: MerchantId, // Merchant the shopper is buying from
+    i_s: Option<ServerShare>, // Server's share of the coin flip that picks the barcode
     uid_b: Option<u32> // Barcode owner's user ID
 }
 
 // The server's record of a user in the system
-#[derive(Debug, Serialize, Clone)]
-pub struct UserRecord {
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub(crate) struct UserRecord {
     barcode: u64,
-    pub balance: Ciphertext,
-    pk_enc: Key
+    pk_enc: Key,
+    pk_auth: AuthKey, // Checks the requests only the user may make (see `auth`)
+    ledgers: HashMap<Account, Ledger> // Only for accounts the user has used
+}
+
+// A user's balance in one account, kept as one part for each period it
+// changed in (see `expiry`). A missing part is (1, 1), the encryption of zero
+// with no randomness.
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct Ledger {
+    pub(crate) balances: BTreeMap<Period, Ciphertext>,
+    epoch: u64, // Number of times the balance has been settled
+    last_settlement: Option<Settlement>,
+    last_redemption: Option<Redemption>
+}
+
+impl UserRecord {
+    // Output: the user's ledger in an account, which is empty if they have
+    // not used it
+    pub fn ledger(&self, account: Account) -> Ledger {
+        self.ledgers.get(&account).cloned().unwrap_or_default()
+    }
+
+    pub(crate) fn ledger_mut(&mut self, account: Account) -> &mut Ledger {
+        self.ledgers.entry(account).or_default()
+    }
+}
+
+impl Ledger {
+    // Output: the part of the balance for a period
+    pub fn part(&self, period: Period) -> Ciphertext {
+        self.balances.get(&period).copied().unwrap_or_default()
+    }
+
+    // Output: the encryption of the balance made of the parts that have not
+    // expired
+    pub fn balance(&self, oldest: Period) -> Result<Ciphertext> {
+        self.balances.range(oldest..).try_fold(Ciphertext::default(), |sum, (_, bal)| crypto_sh::add_ciphertexts(sum, *bal))
+    }
 }
 
 // User data stored in the server's Merkle tree
@@ -44,246 +148,1219 @@ impl TreeEntry {
     }
 }
 
+// Applies a logged change to the server's state, both when it first happens
+// and when the log is replayed after a restart.
+// Output: None if the change refers to a user that doesn't exist
+fn apply(users: &mut HashMap<u32, UserRecord>, event: Event) -> Option<()> {
+    match event {
+        Event::Register { barcode, pk_enc, pk_auth } => {
+            let uid = users.len() as u32;
+            users.insert(uid, UserRecord { barcode, pk_enc, pk_auth, ledgers: HashMap::new() });
+        }
+        Event::Tx { uid_s, uid_b, merchant, period, bal_s, bal_b } => {
+            for (category, (bal_s, bal_b)) in bal_s.into_iter().zip(bal_b).enumerate() {
+                let account = (merchant, category as Category);
+                users.get_mut(&uid_s)?.ledger_mut(account).balances.insert(period, bal_s);
+                users.get_mut(&uid_b)?.ledger_mut(account).balances.insert(period, bal_b);
+            }
+        }
+        Event::Settled { period, balance, settlement } => {
+            let ledger = users.get_mut(&settlement.uid)?.ledger_mut(settlement.account());
+            ledger.balances = BTreeMap::from([(period, balance)]);
+            ledger.epoch = settlement.epoch + 1;
+            ledger.last_settlement = Some(settlement);
+        }
+        Event::Redeemed { balances, redemption } => {
+            let ledger = users.get_mut(&redemption.uid)?.ledger_mut(redemption.account());
+            ledger.balances.extend(balances);
+            ledger.last_redemption = Some(redemption);
+        }
+        Event::Retired { oldest } => {
+            for ledger in users.values_mut().flat_map(|user| user.ledgers.values_mut()) {
+                ledger.balances.retain(|period, _| *period >= oldest);
+            }
+        }
+    }
+    Some(())
+}
+
+impl Default for Server {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Server {
-    pub(crate) fn new() -> Self {
+    pub fn new() -> Self {
+        let sk = SigningKey::generate(&mut rngs::OsRng);
         Server {
-            num_users: 0,
-            users: HashMap::new(),
-            merkle_tree: MerkleTree::<algorithms::Sha256>::new(),
-            tmp: HashMap::new()
+            id: sk.verifying_key().to_bytes(),
+            sk,
+            users: RwLock::new(HashMap::new()),
+            merkle_tree: RwLock::new(MerkleTree::<algorithms::Sha256>::new()),
+            tmp: Sessions::default(),
+            max_points: DEFAULT_MAX_POINTS,
+            policy: SelectionPolicy::default(),
+            recent: RecentBarcodes::default(),
+            merchants: vec![DEFAULT_MERCHANT_NAME.to_string()],
+            categories: vec![DEFAULT_CATEGORY_NAME.to_string()],
+            expiry: Expiry::default(),
+            retired: AtomicU32::new(0),
+            store: None
         }
     }
 
-    pub(crate) fn register_user(&mut self, barcode: u64, pk_enc: Key) {
-        let ct = &crypto_sh::elgamal_enc(pk_enc, 0);
-        let init_balance = (ct.0, ct.1);
+    // A server whose state is kept in `dir` and survives restarts. Opening a
+    // directory that holds no state starts a new server with a new signing key.
+    pub fn open(dir: &Path) -> io::Result<Self> {
+        let (store, snapshot, events) = Store::<Event>::open::<Snapshot>(dir)?;
+        let is_new = snapshot.is_none();
+        let (sk, mut users) = match snapshot {
+            Some((sk, users)) => (SigningKey::from_bytes(sk.expose()), users),
+            None => (SigningKey::generate(&mut rngs::OsRng), HashMap::new()),
+        };
+
+        // Redo every change logged since the snapshot
+        for event in events {
+            apply(&mut users, event)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "log refers to an unknown user"))?;
+        }
+
+        let mut merkle_tree = MerkleTree::<algorithms::Sha256>::new();
+        for uid in 0..users.len() as u32 {
+            let user = &users[&uid];
+            let leaf = TreeEntry { uid, barcode: user.barcode, pk_enc: user.pk_enc };
+            merkle_tree.insert(algorithms::Sha256::hash(leaf.to_bytes().as_slice()));
+        }
+        merkle_tree.commit();
+
+        let server = Server {
+            id: sk.verifying_key().to_bytes(),
+            sk,
+            users: RwLock::new(users),
+            merkle_tree: RwLock::new(merkle_tree),
+            tmp: Sessions::default(),
+            max_points: DEFAULT_MAX_POINTS,
+            policy: SelectionPolicy::default(),
+            recent: RecentBarcodes::default(),
+            merchants: vec![DEFAULT_MERCHANT_NAME.to_string()],
+            categories: vec![DEFAULT_CATEGORY_NAME.to_string()],
+            expiry: Expiry::default(),
+            retired: AtomicU32::new(0),
+            store: Some(store)
+        };
+
+        // Save the new signing key before any proof is made for its server ID
+        if is_new {
+            server.checkpoint().map_err(|_| io::Error::other("could not write snapshot"))?;
+        }
+        Ok(server)
+    }
+
+    // Writes a snapshot of the server's state and clears its log. This also
+    // happens automatically every `store::CHECKPOINT_INTERVAL` changes.
+    pub fn checkpoint(&self) -> Result<()> {
+        self.checkpoint_locked(&self.users.read().unwrap())
+    }
+
+    fn checkpoint_locked(&self, users: &HashMap<u32, UserRecord>) -> Result<()> {
+        match &self.store {
+            Some(store) => store.checkpoint(&(Secret::new(self.sk.to_bytes()), users)).map_err(|_| CheckoutError::Storage),
+            None => Ok(())
+        }
+    }
+
+    // Logs a change before it is applied.
+    // Output: whether a checkpoint is due
+    fn log(&self, event: &Event) -> Result<bool> {
+        match &self.store {
+            Some(store) => store.append(event).map_err(|_| CheckoutError::Storage),
+            None => Ok(false)
+        }
+    }
+
+    // Output: the new user's ID
+    pub fn register_user(&self, barcode: u64, pk_enc: Key, pk_auth: AuthKey) -> Result<u32> {
+        // Reject keys that are not valid points now, rather than when they are
+        // handed out to a shopper during a transaction
+        crypto_sh::puzip(pk_enc)?;
+        auth::check_key(&pk_auth)?;
+
+        let mut users = self.users.write().unwrap();
+        let mut merkle_tree = self.merkle_tree.write().unwrap();
+        let uid = users.len() as u32;
 
-        let user_rec = UserRecord {
-            barcode: barcode,
-            balance: init_balance,
-            pk_enc: pk_enc};
         let leaf = TreeEntry {
-            uid: self.num_users,
-            barcode: barcode,
-            pk_enc: pk_enc
+            uid,
+            barcode,
+            pk_enc
         };
 
         // Add user to list and to merkle tree
-        self.users.insert(
-            self.num_users,
-            user_rec
-        );
-        self.merkle_tree.insert(algorithms::Sha256::hash(leaf.to_bytes().as_slice()));
-        self.merkle_tree.commit();
+        let event = Event::Register { barcode, pk_enc, pk_auth };
+        let checkpoint_due = self.log(&event)?;
+        apply(&mut users, event);
+        merkle_tree.insert(algorithms::Sha256::hash(leaf.to_bytes().as_slice()));
+        merkle_tree.commit();
+
+        if checkpoint_due {
+            // A failed checkpoint loses nothing, since the log is kept
+            let _ = self.checkpoint_locked(&users);
+        }
 
-        self.num_users += 1;
+        Ok(uid)
     }
 
-    pub(crate) fn share_state(&self) -> (u32, <algorithms::Sha256 as rs_merkle::Hasher>::Hash) {
-        let root = self.merkle_tree.root().unwrap();
-        return (self.num_users, root);
+    // Sets the most points a single transaction may move. Clients learn it
+    // with the rest of the server's state, and prove that every transaction
+    // is within it.
+    pub fn set_max_points(&mut self, max: u32) {
+        self.max_points = max;
+    }
+
+    // Sets which users the barcode coin flip may pick. Clients learn the
+    // policy along with the Merkle root.
+    pub fn set_selection_policy(&mut self, policy: SelectionPolicy) {
+        self.policy = policy;
+    }
+
+    // Sets the merchants that issue points through this server. A merchant's
+    // ID is its position in `names`, and balances are kept by ID, so a server
+    // that is set up again must keep its merchants in the same order and only
+    // add new ones at the end.
+    pub fn set_merchants(&mut self, names: Vec<String>) {
+        self.merchants = names;
+    }
+
+    // Output: the merchants' names, indexed by merchant ID
+    pub fn merchants(&self) -> &[String] {
+        &self.merchants
+    }
+
+    fn check_merchant(&self, merchant: MerchantId) -> Result<()> {
+        if merchant as usize >= self.merchants.len() {
+            return Err(CheckoutError::UnknownMerchant);
+        }
+        Ok(())
+    }
+
+    // Sets the categories of points the server keeps. Every transaction moves
+    // an amount of each, in this order. Like merchants, categories are kept
+    // by ID, so new ones may only be added at the end.
+    pub fn set_categories(&mut self, names: Vec<String>) {
+        self.categories = names;
+    }
+
+    // Output: the categories' names, indexed by category ID
+    pub fn categories(&self) -> &[String] {
+        &self.categories
+    }
+
+    fn check_account(&self, (merchant, category): Account) -> Result<()> {
+        self.check_merchant(merchant)?;
+        if category as usize >= self.categories.len() {
+            return Err(CheckoutError::UnknownCategory);
+        }
+        Ok(())
+    }
+
+    // Checks that a transaction has ciphertexts for each category
+    fn check_categories(&self, txs: &TxCiphertexts) -> Result<()> {
+        if txs.is_empty() || txs.len() != self.categories.len() {
+            return Err(CheckoutError::WrongCategories);
+        }
+        Ok(())
+    }
+
+    // Sets how long points count for (see `expiry`)
+    pub fn set_expiry(&mut self, expiry: Expiry) {
+        self.expiry = expiry;
+    }
+
+    // Replaces a user's balance in an account with an encryption of `points`
+    // in the current period, so that the benchmark can settle the same balance
+    // again and again. The change is not logged. The benchmark is a binary of
+    // its own, so this is public, but it is not part of the library's API.
+    #[doc(hidden)]
+    pub fn bench_set_balance(&mut self, uid: u32, account: Account, points: i32) -> Result<()> {
+        let period = self.expiry.current();
+        let user = self.users.get_mut().unwrap().get_mut(&uid).ok_or(CheckoutError::UnknownUser)?;
+        let ct = crypto_sh::elgamal_enc(user.pk_enc, points)?;
+        user.ledger_mut(account).balances = BTreeMap::from([(period, (ct.0, ct.1))]);
+        Ok(())
+    }
+
+    // Drops the parts of every balance whose points have expired. Nothing is
+    // done if no period has expired since the last time.
+    // Output: how many parts were dropped
+    pub fn retire_expired(&self) -> Result<usize> {
+        let oldest = self.expiry.oldest();
+        if oldest <= self.retired.load(Ordering::Relaxed) {
+            return Ok(0);
+        }
+
+        let mut users = self.users.write().unwrap();
+        let expired = users.values()
+            .flat_map(|user| user.ledgers.values())
+            .map(|ledger| ledger.balances.range(..oldest).count())
+            .sum();
+        if expired > 0 {
+            let event = Event::Retired { oldest };
+            let checkpoint_due = self.log(&event)?;
+            apply(&mut users, event);
+            if checkpoint_due {
+                // A failed checkpoint loses nothing, since the log is kept
+                let _ = self.checkpoint_locked(&users);
+            }
+        }
+        self.retired.store(oldest, Ordering::Relaxed);
+        Ok(expired)
+    }
+
+    // Sets how long a transaction may take before the server drops it
+    pub fn set_tx_ttl(&mut self, ttl: Duration) {
+        self.tmp.set_ttl(ttl);
+    }
+
+    // Sets how many transactions may be in progress at once
+    pub fn set_max_open_txs(&mut self, max: usize) {
+        self.tmp.set_max_open(max);
+    }
+
+    // Sets how long the server remembers the transactions it has carried
+    // out, and so refuses their IDs and answers their replays
+    pub fn set_replay_window(&mut self, window: Duration) {
+        self.tmp.set_replay_window(window);
+    }
+
+    // Drops a transaction that the client has given up on
+    pub fn abort_tx(&self, tx_id: Com) {
+        self.tmp.abort(&tx_id);
+    }
+
+    // Drops every transaction that has taken too long.
+    // Output: how many were dropped
+    pub fn sweep_txs(&self) -> usize {
+        self.tmp.sweep()
+    }
+
+    pub fn share_state(&self) -> Result<(u32, <algorithms::Sha256 as rs_merkle::Hasher>::Hash, ServerId, u32, SelectionPolicy)> {
+        let users = self.users.read().unwrap();
+        let root = self.merkle_tree.read().unwrap().root().ok_or(CheckoutError::NoUsers)?;
+        Ok((users.len() as u32, root, self.id, self.max_points, self.policy.clone()))
     }
 
     // Step 1 of a transaction request
     
-    // Input: shopper user ID, commitment to a chosed random ID
-    // Output: a server-chosen random ID
-    pub(crate) fn process_tx_hello_response(&mut self, com: Com) -> u32 {
-        let i_s = rand::thread_rng().gen_range(0..self.num_users);
+    // Input: shopper user ID, commitment to the client's share of the coin
+    // flip, the merchant the shopper is buying from, and the shopper's
+    // signature on them
+    // Output: the server's share of the coin flip
+    pub fn process_tx_hello_response(&self, com: Com, uid_s: u32, merchant: MerchantId, sigma: &Signature) -> Result<ServerShare> {
+        let pk_auth = self.users.read().unwrap().get(&uid_s).ok_or(CheckoutError::UnknownUser)?.pk_auth;
+        auth::verify_tx_hello(&pk_auth, &self.id, (&com, uid_s, merchant), sigma)?;
+        self.check_merchant(merchant)?;
+
+        let i_s = coin::server_share(self.recent.get(uid_s, self.policy.recent));
         let tmp = ServerTxTmp {
-            i_s: Some(i_s),
+            uid_s,
+            merchant,
+            i_s: Some(i_s.clone()),
             uid_b: None
         };
 
         // Store in-progress TX info server side
-        self.tmp.insert(
-            com,
-            tmp
-        );
+        self.tmp.open(com, tmp)?;
         
-        i_s
+        Ok(i_s)
     }
 
     // Step 2 of a transaction request
 
-    // Input: shopper UID, opened commitment contents: client-chosen random ID and mask
+    // Input: opened commitment contents: the client's share and mask
     // Output: barcode owner's UID, barcode, and public key, and merkle inclusion proof
-    pub(crate) fn process_tx_barcode_gen(&mut self, i_c: u32, r: [u8; 32], tx_id: Com) -> (u32, u64, Key, MerkleProof<algorithms::Sha256>) {
-        let tmp: &mut ServerTxTmp = self.tmp.get_mut(&tx_id).unwrap();
+    pub fn process_tx_barcode_gen(&self, i_c: ClientShare, r: [u8; 32], tx_id: Com) -> Result<(u32, u64, Key, MerkleProof<algorithms::Sha256>)> {
+        let tmp: ServerTxTmp = self.tmp.get(&tx_id)?;
 
         // Recompute commitment and check that it matches.
-        let mut hasher = Sha256::new();
-        hasher.update(i_c.to_le_bytes());
-        hasher.update(r);
-        let com_test: [u8; 32] = hasher.finalize().into();
-
-        assert!(com_test == tx_id, "Invalid commit");
+        if i_c.commit(&r) != tx_id {
+            return Err(CheckoutError::BadCommitment);
+        }
 
-        let uid_b = (i_c + tmp.i_s.unwrap()) % self.num_users;
+        let i_s = tmp.i_s.ok_or(CheckoutError::UnknownTx)?;
 
-        tmp.uid_b = Some(uid_b);
+        let (uid_b, barcode, pk_b, pi) = {
+            let users = self.users.read().unwrap();
+            let uid_b = coin::joint_index(&i_c, &i_s, tmp.uid_s, users.len() as u32, &self.policy)?;
 
-        let user_b: &UserRecord = &self.users.get(&uid_b).unwrap();
-        let barcode = user_b.barcode;
-        let pk_b = user_b.pk_enc;
+            let user_b: &UserRecord = users.get(&uid_b).ok_or(CheckoutError::UnknownUser)?;
+            let pi: MerkleProof<algorithms::Sha256> = self.merkle_tree.read().unwrap().proof(&[uid_b.try_into().unwrap()]);
+            (uid_b, user_b.barcode, user_b.pk_enc, pi)
+        };
 
-        let pi: MerkleProof<algorithms::Sha256> = self.merkle_tree.proof(&[uid_b.try_into().unwrap()]);
+        self.tmp.update(&tx_id, |tmp| tmp.uid_b = Some(uid_b))?;
+        self.recent.record(tmp.uid_s, uid_b, self.policy.recent);
 
-        (uid_b, barcode, pk_b, pi)
+        Ok((uid_b, barcode, pk_b, pi))
     }
 
     // Step 3 of a transaction request
-    pub(crate) fn process_tx(&mut self, shopper: u32, cts: Ciphertext, ctb: Ciphertext, pi: crypto_sh::CompressedCtEqProof, tx_id: Com) {
-        let tmp: &mut ServerTxTmp = self.tmp.get_mut(&tx_id).unwrap();
 
-        assert!(crypto_sh::zk_ct_eq_verify(pi));
+    // Input: the shopper's and barcode owner's ciphertexts for each category,
+    // with the proof that they encrypt the same amount
+    pub fn process_tx(&self, txs: TxCiphertexts, tx_id: Com) -> Result<()> {
+        // Sending a transaction that was already carried out again succeeds,
+        // but it is not applied twice
+        let hash = session::submission_hash(&txs);
+        if self.tmp.replayed(&tx_id, &hash)?.is_some() {
+            return Ok(());
+        }
 
-        let uid_s = shopper;
-        let uid_b = tmp.uid_b.unwrap();
+        let (parties, hs, hb) = self.tx_parties(&tx_id)?;
+        self.check_categories(&txs)?;
+        for (category, (cts, ctb, pi)) in txs.iter().enumerate() {
+            let mut transcript = Transcript::for_tx(&self.id, &tx_id, category as Category);
+            crypto_sh::zk_ct_eq_verify(&mut transcript, *cts, *ctb, hs, hb, pi.clone(), self.max_points)?;
+        }
 
-        self.users.get_mut(&uid_s).unwrap().balance = crypto_sh::add_ciphertexts(
-            self.users[&uid_s].balance,
-            cts
-        );
-        self.users.get_mut(&uid_b).unwrap().balance = crypto_sh::add_ciphertexts(
-            self.users[&uid_b].balance,
-            ctb
-        );
+        self.record_tx(txs, parties, tx_id, hash)
+    }
 
-        self.tmp.remove(&tx_id);
+    // Step 3 for many transactions at once. The proofs are checked together
+    // (see `crypto_sh::zk_ct_eq_verify_batch`), and the transactions are then
+    // applied in order.
+    // Output: for each transaction, whether it was accepted
+    pub fn process_tx_batch(&self, txs: Vec<(TxCiphertexts, Com)>) -> Vec<Result<()>> {
+        let hashes: Vec<[u8; 32]> = txs.iter()
+            .map(|(tx, _)| session::submission_hash(tx))
+            .collect();
+        // Transactions sent again after being carried out are answered
+        // without being checked or applied again
+        let replies: Vec<Option<Result<()>>> = txs.iter().zip(&hashes)
+            .map(|((_, tx_id), hash)| self.tmp.replayed(tx_id, hash).transpose())
+            .collect();
+        let parties: Vec<Result<(TxParties, Key, Key)>> = txs.iter().zip(&replies)
+            .map(|((tx, tx_id), reply)| match reply {
+                Some(_) => Err(CheckoutError::DuplicateTx),
+                None => self.check_categories(tx).and_then(|_| self.tx_parties(tx_id)),
+            })
+            .collect();
+
+        // Only transactions the server knows about are put in the batch, with
+        // one proof for each category
+        let mut applied = Vec::with_capacity(txs.len());
+        let mut batch = Vec::with_capacity(txs.len());
+        for (((tx, tx_id), p), hash) in txs.into_iter().zip(parties).zip(hashes) {
+            match p {
+                Ok((parties, hs, hb)) => {
+                    for (category, (cts, ctb, pi)) in tx.iter().enumerate() {
+                        batch.push((Transcript::for_tx(&self.id, &tx_id, category as Category), *cts, *ctb, hs, hb, pi.clone()));
+                    }
+                    applied.push(Ok((tx, parties, tx_id, hash)));
+                }
+                Err(e) => applied.push(Err(e)),
+            }
+        }
+        let mut verified = crypto_sh::zk_ct_eq_verify_batch(batch, self.max_points).into_iter();
+
+        applied.into_iter().zip(replies).map(|(tx, reply)| {
+            if let Some(reply) = reply {
+                return reply;
+            }
+            let (tx, parties, tx_id, hash) = tx?;
+            // Every category's result is taken before any is checked, so the
+            // next transaction starts at its own
+            let checks: Vec<Result<()>> = verified.by_ref().take(tx.len()).collect();
+            checks.into_iter().collect::<Result<Vec<()>>>()?;
+            self.record_tx(tx, parties, tx_id, hash)
+        }).collect()
+    }
+
+    // Output: the shopper, barcode owner and merchant of a transaction that
+    // has reached step 3, and the public keys the server has on record for
+    // the two users
+    fn tx_parties(&self, tx_id: &Com) -> Result<(TxParties, Key, Key)> {
+        let tmp: ServerTxTmp = self.tmp.get(tx_id)?;
+        let uid_s = tmp.uid_s;
+        let uid_b = tmp.uid_b.ok_or(CheckoutError::UnknownTx)?;
+
+        let users = self.users.read().unwrap();
+        Ok(((uid_s, uid_b, tmp.merchant),
+            users.get(&uid_s).ok_or(CheckoutError::UnknownUser)?.pk_enc,
+            users.get(&uid_b).ok_or(CheckoutError::UnknownUser)?.pk_enc))
+    }
+
+    // Applies a transaction whose proof has been verified
+    fn record_tx(&self, txs: TxCiphertexts, parties: TxParties, tx_id: Com, hash: [u8; 32]) -> Result<()> {
+        let (uid_s, uid_b, merchant) = parties;
+
+        // Both balances are updated under one lock, so concurrent transactions
+        // touching the same users see each other's updates
+        let mut users = self.users.write().unwrap();
+        let period = self.expiry.current();
+
+        // Checking the ID is still open under the same lock makes sure that a
+        // transaction submitted twice at once is only applied once
+        self.tmp.get(&tx_id)?;
+
+        // Compute both users' new balances in every category before touching
+        // any, so a failure leaves them all unchanged
+        let user_s = users.get(&uid_s).ok_or(CheckoutError::UnknownUser)?;
+        let user_b = users.get(&uid_b).ok_or(CheckoutError::UnknownUser)?;
+        let mut bals_s = Vec::with_capacity(txs.len());
+        let mut bals_b = Vec::with_capacity(txs.len());
+        for (category, (cts, ctb, _)) in txs.iter().enumerate() {
+            let account = (merchant, category as Category);
+            let bal_s = crypto_sh::add_ciphertexts(user_s.ledger(account).part(period), *cts)?;
+            let bal_b = if uid_b == uid_s { bal_s } else { user_b.ledger(account).part(period) };
+            bals_s.push(bal_s);
+            bals_b.push(crypto_sh::add_ciphertexts(bal_b, *ctb)?);
+        }
+
+        let event = Event::Tx { uid_s, uid_b, merchant, period, bal_s: bals_s, bal_b: bals_b };
+        let checkpoint_due = self.log(&event)?;
+        apply(&mut users, event);
+
+        // The ID is only spent once the transaction is logged, so if logging
+        // fails it stays open and can be submitted again
+        self.tmp.spend(&tx_id, hash, ());
+
+        if checkpoint_due {
+            // A failed checkpoint loses nothing, since the log is kept
+            let _ = self.checkpoint_locked(&users);
+        }
+
+        Ok(())
+    }
+
+    // Output: the user's encrypted unexpired balance in an account, its
+    // share in each unexpired period, oldest first, the user's current epoch
+    // in the account, and their last settlement and redemption in it (see
+    // `settlement`)
+    pub fn settle_balance_hello(&self, uid: u32, account: Account) -> Result<(Ciphertext, BalanceParts, SettleStatus)> {
+        self.check_account(account)?;
+        let oldest = self.expiry.oldest();
+        let users = self.users.read().unwrap();
+        let ledger = users.get(&uid).ok_or(CheckoutError::UnknownUser)?.ledger(account);
+        let parts = ledger.balances.range(oldest..).map(|(period, part)| (*period, *part)).collect();
+        let status = SettleStatus {
+            merchant: account.0, category: account.1, epoch: ledger.epoch, oldest, last: ledger.last_settlement,
+            last_redemption: ledger.last_redemption.clone()
+        };
+        Ok((ledger.balance(oldest)?, parts, status))
+    }
+
+    // Input: user ID, the account, the balance they revealed, and a proof
+    // that their encrypted balance decrypts to it
+    // Output: the signed settlement receipt. The balance, expired parts
+    // included, is replaced with a fresh encryption of zero, and the user's
+    // next epoch in the account starts.
+    pub fn settle_balance_finalize(&self, uid: u32, account: Account, x: i32, pi: crypto_sh::CompressedCtDecProof) -> Result<Settlement> {
+        let (balance, pk_enc, epoch, oldest) = self.settle_state(uid, account)?;
+        crypto_sh::zk_ct_dec_verify(&mut Transcript::for_settle(&self.id, uid, account, epoch), balance, x, pk_enc, pi)?;
+
+        let zero = crypto_sh::elgamal_enc(pk_enc, 0)?;
+        let settlement = Settlement::sign(&self.sk, (uid, account), epoch, x, 0);
+        let event = Event::Settled { period: self.expiry.current(), balance: (zero.0, zero.1), settlement };
+        self.record_settle((uid, account), (epoch, oldest, balance), event)?;
+        Ok(settlement)
     }
 
-    pub(crate) fn settle_balance_hello(&self, uid: u32) -> Ciphertext {
-        self.users.get(&uid).unwrap().balance
+    // Input: user ID, the account, the period and amount of each part of the
+    // redemption (see `settlement`), and for each a proof that the user's
+    // share of the balance in that period covers it
+    // Output: the signed redemption receipt. Each part is taken out of the
+    // share of its period; the epoch does not change.
+    pub fn redeem(&self, uid: u32, account: Account, (parts, pis): RedeemData) -> Result<Redemption> {
+        let (balance, pk_enc, epoch, oldest) = self.settle_state(uid, account)?;
+        settlement::check_parts(&parts, oldest, crypto_sh::MAX_REMAINDER)?;
+        if pis.len() != parts.len() {
+            return Err(CheckoutError::InvalidProof);
+        }
+        let ledger = self.users.read().unwrap().get(&uid).ok_or(CheckoutError::UnknownUser)?.ledger(account);
+
+        let mut transcript = Transcript::for_settle(&self.id, uid, account, epoch);
+        let mut balances = Vec::with_capacity(parts.len());
+        for (&(period, points), pi) in parts.iter().zip(pis) {
+            transcript.append_u64(b"period", period as u64);
+            crypto_sh::zk_redeem_verify(&mut transcript, ledger.part(period), points, pk_enc, pi)?;
+            balances.push((period, crypto_sh::subtract_points(ledger.part(period), points)?));
+        }
+
+        let nonce = rand::thread_rng().gen::<[u8; 32]>();
+        let redemption = Redemption::sign(&self.sk, (uid, account), epoch, &parts, nonce);
+        let event = Event::Redeemed { balances, redemption: redemption.clone() };
+        self.record_settle((uid, account), (epoch, oldest, balance), event)?;
+        Ok(redemption)
     }
 
-    pub(crate) fn settle_balance_finalize(&self, pi: crypto_sh::CompressedCtDecProof) -> bool {
-        crypto_sh::zk_ct_dec_verify(pi)
+    // Output: the user's unexpired balance in an account, their public key,
+    // their epoch in the account, and the oldest unexpired period, which
+    // settle and redeem proofs are checked against
+    fn settle_state(&self, uid: u32, account: Account) -> Result<(Ciphertext, Key, u64, Period)> {
+        self.check_account(account)?;
+        let oldest = self.expiry.oldest();
+        let users = self.users.read().unwrap();
+        let user = users.get(&uid).ok_or(CheckoutError::UnknownUser)?;
+        let ledger = user.ledger(account);
+        Ok((ledger.balance(oldest)?, user.pk_enc, ledger.epoch, oldest))
+    }
+
+    // Logs and applies a settlement or redemption, unless a transaction,
+    // another settlement or a new period has changed the balance since its
+    // proof was checked, in which case the proof no longer applies.
+    // Input: user ID and account, and the epoch, oldest unexpired period and
+    // balance the proof was checked against
+    fn record_settle(&self, (uid, account): (u32, Account), (epoch, oldest, balance): (u64, Period, Ciphertext), event: Event) -> Result<()> {
+        let mut users = self.users.write().unwrap();
+        let ledger = users.get(&uid).ok_or(CheckoutError::UnknownUser)?.ledger(account);
+        if ledger.epoch != epoch || self.expiry.oldest() != oldest || ledger.balance(oldest)? != balance {
+            return Err(CheckoutError::StaleSettlement);
+        }
+
+        let checkpoint_due = self.log(&event)?;
+        apply(&mut users, event);
+
+        if checkpoint_due {
+            // A failed checkpoint loses nothing, since the log is kept
+            let _ = self.checkpoint_locked(&users);
+        }
+        Ok(())
     }
 }
 
-pub(crate) struct Client {
+#[derive(Serialize, Deserialize)]
+pub struct Client {
     barcode: u64,
+    uid: u32,
     num_users: u32,
     merkle_root: Option<<algorithms::Sha256 as rs_merkle::Hasher>::Hash>,
+    server_id: ServerId,
+    max_points: u32,
+    coin_flip: CoinFlip,
+    policy: SelectionPolicy,
+    // Number of times the balance in each account has been settled, for
+    // accounts that have been settled
+    epochs: HashMap<Account, u64>,
     tmp: HashMap<Com, ClientTxTmp>,
-    sk_enc: Key,
-    pub pk_enc: Key
+    sk_enc: Secret<Key>,
+    pub pk_enc: Key,
+    sk_auth: Secret<[u8; 32]> // Signs the requests only this user may make (see `auth`)
 }
 
+#[derive(Serialize, Deserialize, Zeroize, ZeroizeOnDrop)]
 struct ClientTxTmp {
-    i_c: Option<u32>,
+    started: u64, // When the transaction started (see `session::now`)
+    i_c: Option<ClientShare>,
     r: Option<[u8; 32]>,
     uid_b: Option<u32>,
 
 }
 
 impl Client {
-    pub(crate) fn new(barcode: u64) -> Self {
+    pub fn new(barcode: u64) -> Self {
         let keys = crypto_sh::elgamal_keygen();
+        let (sk_auth, _) = auth::keygen();
         Client {
-            barcode: barcode,
+            barcode,
+            uid: 0,
             num_users: 1,
             merkle_root: None,
+            server_id: [0u8; 32],
+            max_points: DEFAULT_MAX_POINTS,
+            coin_flip: CoinFlip::default(),
+            policy: SelectionPolicy::default(),
+            epochs: HashMap::new(),
             tmp: HashMap::new(),
-            sk_enc: keys.0,
-            pk_enc: keys.1
+            sk_enc: Secret::new(keys.0),
+            pk_enc: keys.1,
+            sk_auth
         }
     }
 
-    pub(crate) fn register_with_server(&self) -> (u64, Key) {
-        (self.barcode, self.pk_enc)
+    pub fn register_with_server(&self) -> (u64, Key, AuthKey) {
+        (self.barcode, self.pk_enc, auth::public_key(&self.sk_auth))
     }
 
-    pub(crate) fn update_state(&mut self, num_users: u32, merkle_root: <algorithms::Sha256 as Hasher>::Hash) {
+    pub fn update_state(&mut self, uid: u32, num_users: u32, merkle_root: <algorithms::Sha256 as Hasher>::Hash, server_id: ServerId, max_points: u32, policy: SelectionPolicy) {
+        self.uid = uid;
         self.num_users = num_users;
         self.merkle_root = Some(merkle_root);
+        self.server_id = server_id;
+        self.max_points = max_points;
+        self.policy = policy;
+    }
+
+    // Sets the kind of share this client uses in the coin flip that picks
+    // the barcode (see `coin`)
+    pub fn set_coin_flip(&mut self, mode: CoinFlip) {
+        self.coin_flip = mode;
     }
 
     // Step 1 of a transaction request
 
-    // Input: N/A
-    // Output: commitment to a randomly chosen user ID
-    pub(crate) fn process_tx_hello(&mut self) -> Com {
-        // Commit to a random index and send it to the server
-        let i_c = rand::thread_rng().gen_range(0..self.num_users);
+    // Input: the merchant the shopper is buying from
+    // Output: commitment to the client's share of the coin flip, and the
+    // shopper's signature on it
+    pub fn process_tx_hello(&mut self, merchant: MerchantId) -> (Com, Signature) {
+        // Commit to a random share and send it to the server
+        let i_c = ClientShare::random(self.coin_flip);
         let r = rand::thread_rng().gen::<[u8; 32]>();
-        let mut hasher = Sha256::new();
-        hasher.update(i_c.to_le_bytes());
-        hasher.update(r);
-        let com: [u8; 32] = hasher.finalize().into();
+        let com = i_c.commit(&r);
 
         let tx_id = com;
         self.tmp.insert(
             tx_id,
             ClientTxTmp {
+                started: session::now(),
                 i_c: Some(i_c),
                 r: Some(r),
                 uid_b: None,
             }
         );
 
-        com
+        let sigma = auth::sign_tx_hello(&self.sk_auth, &self.server_id, (&com, self.uid, merchant));
+        (com, sigma)
     }
 
     // Step 2 of a transaction request
 
-    // Input: server's randomly chosen barcode UID
-    // Output: opened commitment to client-chosed barcode UID
-    pub(crate) fn process_tx_compute_id(&mut self, i_s: u32, tx_id: Com) -> (u32, [u8; 32]) {
-        let tmp: &mut ClientTxTmp = self.tmp.get_mut(&tx_id).unwrap();
+    // Input: the server's share of the coin flip
+    // Output: opened commitment to the client's share
+    pub fn process_tx_compute_id(&mut self, i_s: ServerShare, tx_id: Com) -> Result<(ClientShare, [u8; 32])> {
+        let tmp: &mut ClientTxTmp = self.tmp.get_mut(&tx_id).ok_or(CheckoutError::UnknownTx)?;
+        let i_c = tmp.i_c.ok_or(CheckoutError::UnknownTx)?;
+        let r = tmp.r.ok_or(CheckoutError::UnknownTx)?;
 
-        let i = (tmp.i_c.unwrap() + i_s) % self.num_users;
+        let i = coin::joint_index(&i_c, &i_s, self.uid, self.num_users, &self.policy)?;
         tmp.uid_b = Some(i);
 
-        (tmp.i_c.unwrap(), tmp.r.unwrap())
+        Ok((i_c, r))
     }
 
-    pub(crate) fn verify_merkle_proof(&mut self, barcode: u64, pi: &MerkleProof<algorithms::Sha256>, pkb: Key, tx_id: Com) -> bool {
-        let tmp: &ClientTxTmp = self.tmp.get(&tx_id).unwrap();
+    pub fn verify_merkle_proof(&mut self, barcode: u64, pi: &MerkleProof<algorithms::Sha256>, pkb: Key, tx_id: Com) -> Result<()> {
+        let tmp: &ClientTxTmp = self.tmp.get(&tx_id).ok_or(CheckoutError::UnknownTx)?;
+        let uid_b = tmp.uid_b.ok_or(CheckoutError::UnknownTx)?;
+        let merkle_root = self.merkle_root.ok_or(CheckoutError::InvalidProof)?;
 
         let leaf = TreeEntry {
-            uid: tmp.uid_b.unwrap(),
-            barcode: barcode,
+            uid: uid_b,
+            barcode,
             pk_enc: pkb
         };
         let tree_contents = algorithms::Sha256::hash(leaf.to_bytes().as_slice());
 
-        let test = pi.verify(self.merkle_root.unwrap(), &[tmp.uid_b.unwrap().try_into().unwrap()], &[tree_contents], self.num_users.try_into().unwrap());
-
-        assert!(test);
-
-        test
+        if pi.verify(merkle_root, &[uid_b as usize], &[tree_contents], self.num_users as usize) { Ok(()) }
+        else { Err(CheckoutError::InvalidProof) }
     }
 
     // Step 3 of a transaction request
-    pub(crate) fn process_tx(&mut self, pi: &MerkleProof<algorithms::Sha256>, barcode: u64, points: i32, pkb: Key, tx_id: Com) -> (Ciphertext, Ciphertext, crypto_sh::CompressedCtEqProof) {
+
+    // Input: the amount of each category, in the server's order
+    pub fn process_tx(&mut self, pi: &MerkleProof<algorithms::Sha256>, barcode: u64, points: &[i32], pkb: Key, tx_id: Com) -> Result<TxCiphertexts> {
         // Verify Merkle proof that the agreed upon index is in the tree
-        self.verify_merkle_proof(barcode, pi, pkb, tx_id);
+        self.verify_merkle_proof(barcode, pi, pkb, tx_id)?;
 
-        // Encrypt the number of points under both public keys
-        let cts = crypto_sh::elgamal_enc(self.pk_enc, -1*points);
-        let cts_data = crypto_sh::CompressedTxCiphertextData::new(
-            (cts.0, cts.1), cts.2, -1*points, self.pk_enc
-        );
+        if points.is_empty() {
+            return Err(CheckoutError::WrongCategories);
+        }
+        // The server rejects any amount it has not been shown to be in range
+        let xs = points.iter()
+            .map(|x| u32::try_from(*x).ok().filter(|x| *x <= self.max_points).ok_or(CheckoutError::PointsOutOfRange))
+            .collect::<Result<Vec<u32>>>()?;
 
-        let ctb = crypto_sh::elgamal_enc(pkb, points);
-        let ctb_data = crypto_sh::CompressedTxCiphertextData::new(
-            (ctb.0, ctb.1), ctb.2, points, pkb
-        );
+        let mut txs = Vec::with_capacity(points.len());
+        for (category, (points, x)) in points.iter().zip(xs).enumerate() {
+            // Encrypt the number of points under both public keys
+            let cts = crypto_sh::elgamal_enc(self.pk_enc, -points)?;
+            let cts_data = crypto_sh::CompressedTxCiphertextData::new(
+                (cts.0, cts.1), cts.2, -points, self.pk_enc
+            );
+
+            let ctb = crypto_sh::elgamal_enc(pkb, *points)?;
+            let ctb_data = crypto_sh::CompressedTxCiphertextData::new(
+                (ctb.0, ctb.1), ctb.2, *points, pkb
+            );
+
+            // Generate a zero knowledge proof that these encrypt the same value
+            let mut transcript = Transcript::for_tx(&self.server_id, &tx_id, category as Category);
+            let pi = crypto_sh::zk_ct_eq_prove(&mut transcript, cts_data, ctb_data, x, self.max_points)?;
+            txs.push(((cts.0, cts.1), (ctb.0, ctb.1), pi));
+        }
 
-        // Generate a zero knowledge proof that these encrypt the same value
-        let pi = crypto_sh::zk_ct_eq_prove(cts_data, ctb_data);
+        self.forget_tx(tx_id);
 
+        Ok(txs)
+    }
+
+    // Wipes what the client kept about a transaction. Called when the
+    // transaction finishes; apps call it for a transaction they give up on.
+    pub fn forget_tx(&mut self, tx_id: Com) {
+        if let Some(tmp) = self.tmp.get_mut(&tx_id) {
+            tmp.zeroize();
+        }
         self.tmp.remove(&tx_id);
+    }
+
+    // Forgets every transaction that started longer than `ttl` ago, since the
+    // server has dropped it by now.
+    // Output: how many were forgotten
+    pub fn expire_txs(&mut self, ttl: Duration) -> usize {
+        let expired: Vec<Com> = self.tmp.iter()
+            .filter(|(_, tmp)| session::expired(tmp.started, ttl))
+            .map(|(tx_id, _)| *tx_id)
+            .collect();
+        for tx_id in &expired {
+            self.forget_tx(*tx_id);
+        }
+        expired.len()
+    }
+
+    // Input: the encrypted balance and settlement status from the server
+    // Output: the balance and a proof that it is what the ciphertext holds
+    pub fn settle_balance(&mut self, ct: Ciphertext, status: SettleStatus) -> Result<(i32, crypto_sh::CompressedCtDecProof)> {
+        let epoch = self.catch_up(&status)?;
+
+        let plaintext = crypto_sh::elgamal_dec(*self.sk_enc.expose(), ct)?;
+        let mut transcript = Transcript::for_settle(&self.server_id, self.uid, status.account(), epoch);
+        let pi = crypto_sh::zk_ct_dec_prove(&mut transcript, ct, plaintext, *self.sk_enc.expose(), self.pk_enc)?;
+
+        Ok((plaintext, pi))
+    }
+
+    // Starts the next epoch in the account once the server has settled the
+    // balance. The client keeps no balance of its own in this scheme, so
+    // there is nothing else to reset.
+    pub fn finish_settlement(&mut self, settlement: Settlement) -> Result<()> {
+        let epoch = self.epochs.entry(settlement.account()).or_default();
+        settlement.verify(&self.server_id, (self.uid, settlement.account()), *epoch)?;
+        *epoch += 1;
+        Ok(())
+    }
 
-        ((cts.0, cts.1), (ctb.0, ctb.1), pi)
+    // Output: the number of times the balance in an account has been settled
+    pub fn epoch(&self, account: Account) -> u64 {
+        self.epochs.get(&account).copied().unwrap_or(0)
     }
 
-    pub(crate) fn settle_balance(&self, ct: Ciphertext) -> (i32, crypto_sh::CompressedCtDecProof) {
-        let plaintext = crypto_sh::elgamal_dec(self.sk_enc, ct);
-        let pi = crypto_sh::zk_ct_dec_prove(ct, plaintext, self.sk_enc, self.pk_enc);
+    // Input: the encrypted share of the balance in each unexpired period,
+    // oldest first, the settlement status from the server, and the amount to
+    // redeem
+    // Output: the period and amount of each part of the redemption, taken
+    // from the oldest periods first (see `settlement`), and for each a proof
+    // that the period's share covers it
+    pub fn redeem(&mut self, shares: &[(Period, Ciphertext)], status: SettleStatus, points: u32) -> Result<RedeemData> {
+        let epoch = self.catch_up(&status)?;
+        let sk = *self.sk_enc.expose();
+        let bals = shares.iter().map(|(period, ct)| Ok((*period, crypto_sh::elgamal_dec(sk, *ct)?)))
+            .collect::<Result<Vec<_>>>()?;
+        let parts = settlement::split(&bals, points)?;
+
+        let mut transcript = Transcript::for_settle(&self.server_id, self.uid, status.account(), epoch);
+        let mut pis = Vec::with_capacity(parts.len());
+        for &(period, points) in &parts {
+            let i = shares.iter().position(|(p, _)| *p == period).ok_or(CheckoutError::PointsOutOfRange)?;
+            transcript.append_u64(b"period", period as u64);
+            pis.push(crypto_sh::zk_redeem_prove(&mut transcript, shares[i].1, points, bals[i].1, sk, self.pk_enc)?);
+        }
+        Ok((parts, pis))
+    }
+
+    // Checks the server's receipt for a redemption. The balance the server
+    // keeps is already reduced, and there is none to reduce here.
+    pub fn finish_redemption(&mut self, redemption: Redemption) -> Result<()> {
+        redemption.verify(&self.server_id, (self.uid, redemption.account()), self.epoch(redemption.account()))
+    }
+
+    // Applies first a settlement whose receipt never arrived.
+    // Output: the client's epoch in the account, or an error if it is still
+    // not the server's
+    fn catch_up(&mut self, status: &SettleStatus) -> Result<u64> {
+        let account = status.account();
+        if let Some(last) = status.last {
+            if last.account() == account && last.epoch == self.epoch(account) {
+                self.finish_settlement(last)?;
+            }
+        }
+        let epoch = self.epoch(account);
+        if status.epoch != epoch {
+            return Err(CheckoutError::StaleSettlement);
+        }
+        Ok(epoch)
+    }
+}
+
+//////////////////////////////////////////////////////////////////
+// LoyaltyScheme implementation
+//////////////////////////////////////////////////////////////////
+
+// Semihonest security: balances are ElGamal ciphertexts under each user's key.
+pub struct SemiHonest;
+
+#[derive(Serialize, Deserialize)]
+pub struct BarcodeGen {
+    pub uid_b: u32,
+    pub barcode: u64,
+    #[serde(with = "encoding::point")]
+    pub pk_b: Key,
+    #[serde(with = "encoding::merkle_proof")]
+    pub pi: MerkleProof<algorithms::Sha256>,
+}
+
+// One category of a transaction
+#[derive(Serialize, Deserialize)]
+pub struct CategoryTx {
+    #[serde(with = "encoding::point_pair")]
+    pub cts: Ciphertext,
+    #[serde(with = "encoding::point_pair")]
+    pub ctb: Ciphertext,
+    pub pi: crypto_sh::CompressedCtEqProof,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TxSubmit {
+    pub categories: Vec<CategoryTx>,
+}
+
+impl TxSubmit {
+    fn into_ciphertexts(self) -> TxCiphertexts {
+        self.categories.into_iter().map(|tx| (tx.cts, tx.ctb, tx.pi)).collect()
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SettleHello {
+    #[serde(with = "encoding::point_pair")]
+    pub balance: Ciphertext,
+    // The balance's share in each unexpired period, oldest first
+    pub parts: Vec<BalancePart>,
+    pub status: SettleStatus,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct BalancePart {
+    pub period: Period,
+    #[serde(with = "encoding::point_pair")]
+    pub balance: Ciphertext,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SettleRequest {
+    pub x: i32,
+    pub pi: crypto_sh::CompressedCtDecProof,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RedeemRequest {
+    pub parts: Vec<(Period, u32)>,
+    pub pis: Vec<crypto_sh::CompressedRedeemProof>,
+}
+
+impl LoyaltyScheme for SemiHonest {
+    const LEVEL: SecurityLevel = SecurityLevel::SemiHonest;
+
+    type Server = Server;
+    type Client = Client;
+
+    type Registration = (u64, Key, AuthKey);
+    type BarcodeGen = BarcodeGen;
+    type TxSubmit = TxSubmit;
+    type TxSignature = ();
+    type Receipts = ();
+    type SettleHello = SettleHello;
+    type SettleRequest = SettleRequest;
+    type Settlement = Settlement;
+    type RedeemRequest = RedeemRequest;
+    type Redemption = Redemption;
+}
+
+impl SchemeServer for Server {
+    type Scheme = SemiHonest;
+
+    fn register_user(&self, reg: (u64, Key, AuthKey)) -> Result<u32> {
+        Server::register_user(self, reg.0, reg.1, reg.2)
+    }
+
+    fn share_state(&self) -> Result<(u32, Root, ServerId, u32, SelectionPolicy)> {
+        Server::share_state(self)
+    }
+
+    fn process_tx_hello_response(&self, com: Com, uid_s: u32, merchant: MerchantId, sigma: &Signature) -> Result<ServerShare> {
+        Server::process_tx_hello_response(self, com, uid_s, merchant, sigma)
+    }
+
+    fn process_tx_barcode_gen(&self, i_c: ClientShare, r: [u8; 32], tx_id: Com) -> Result<BarcodeGen> {
+        let (uid_b, barcode, pk_b, pi) = Server::process_tx_barcode_gen(self, i_c, r, tx_id)?;
+        Ok(BarcodeGen { uid_b, barcode, pk_b, pi })
+    }
+
+    fn process_tx(&self, tx: TxSubmit, tx_id: Com) -> Result<()> {
+        Server::process_tx(self, tx.into_ciphertexts(), tx_id)
+    }
+
+    fn process_tx_batch(&self, txs: Vec<(TxSubmit, Com)>) -> Vec<Result<()>> {
+        Server::process_tx_batch(self, txs.into_iter().map(|(tx, tx_id)| (tx.into_ciphertexts(), tx_id)).collect())
+    }
+
+    fn abort_tx(&self, tx_id: Com) {
+        Server::abort_tx(self, tx_id)
+    }
+
+    fn sweep_txs(&self) -> usize {
+        Server::sweep_txs(self)
+    }
+
+    fn retire_expired(&self) -> Result<usize> {
+        Server::retire_expired(self)
+    }
+
+    fn send_receipts(&self, _uid: u32, after: u64, _limit: u32) -> Result<((), u64, bool)> {
+        Ok(((), after, false))
+    }
+
+    fn ack_receipts(&self, _uid: u32, _upto: u64, _sigma: &Signature) -> Result<()> {
+        Ok(())
+    }
 
-        (plaintext, pi)
+    fn settle_balance_hello(&self, uid: u32, account: Account) -> Result<SettleHello> {
+        let (balance, parts, status) = Server::settle_balance_hello(self, uid, account)?;
+        let parts = parts.into_iter().map(|(period, balance)| BalancePart { period, balance }).collect();
+        Ok(SettleHello { balance, parts, status })
     }
-}
\ No newline at end of file
+
+    fn settle_balance(&self, uid: u32, account: Account, req: SettleRequest) -> Result<Settlement> {
+        Server::settle_balance_finalize(self, uid, account, req.x, req.pi)
+    }
+
+    fn redeem(&self, uid: u32, account: Account, req: RedeemRequest) -> Result<Redemption> {
+        Server::redeem(self, uid, account, (req.parts, req.pis))
+    }
+}
+
+impl SchemeClient for Client {
+    type Scheme = SemiHonest;
+
+    fn new(barcode: u64) -> Self {
+        Client::new(barcode)
+    }
+
+    fn register_with_server(&self) -> (u64, Key, AuthKey) {
+        Client::register_with_server(self)
+    }
+
+    fn update_state(&mut self, uid: u32, num_users: u32, merkle_root: Root, server_id: ServerId, max_points: u32, policy: SelectionPolicy) {
+        Client::update_state(self, uid, num_users, merkle_root, server_id, max_points, policy)
+    }
+
+    fn set_coin_flip(&mut self, mode: CoinFlip) {
+        Client::set_coin_flip(self, mode)
+    }
+
+    fn process_tx_hello(&mut self, merchant: MerchantId) -> (Com, Signature) {
+        Client::process_tx_hello(self, merchant)
+    }
+
+    fn process_tx_compute_id(&mut self, i_s: ServerShare, tx_id: Com) -> Result<(ClientShare, [u8; 32])> {
+        Client::process_tx_compute_id(self, i_s, tx_id)
+    }
+
+    fn process_tx(&mut self, bg: &BarcodeGen, points: &[i32], tx_id: Com) -> Result<TxSubmit> {
+        let txs = Client::process_tx(self, &bg.pi, bg.barcode, points, bg.pk_b, tx_id)?;
+        Ok(TxSubmit { categories: txs.into_iter().map(|(cts, ctb, pi)| CategoryTx { cts, ctb, pi }).collect() })
+    }
+
+    fn process_tx_coda(&mut self, _sigma: (), _tx_id: Com) -> Result<()> {
+        Ok(())
+    }
+
+    fn forget_tx(&mut self, tx_id: Com) {
+        Client::forget_tx(self, tx_id)
+    }
+
+    fn expire_txs(&mut self, ttl: Duration) -> usize {
+        Client::expire_txs(self, ttl)
+    }
+
+    fn process_receipts(&mut self, _rcts: ()) -> Result<()> {
+        Ok(())
+    }
+
+    fn ack_receipts(&self, upto: u64) -> Signature {
+        auth::sign_ack(&self.sk_auth, &self.server_id, (self.uid, upto))
+    }
+
+    fn settle_balance(&mut self, hello: SettleHello) -> Result<SettleRequest> {
+        let (x, pi) = Client::settle_balance(self, hello.balance, hello.status)?;
+        Ok(SettleRequest { x, pi })
+    }
+
+    fn finish_settlement(&mut self, settlement: Settlement) -> Result<()> {
+        Client::finish_settlement(self, settlement)
+    }
+
+    fn redeem(&mut self, hello: SettleHello, points: u32) -> Result<RedeemRequest> {
+        let shares: Vec<(Period, Ciphertext)> = hello.parts.iter().map(|part| (part.period, part.balance)).collect();
+        let (parts, pis) = Client::redeem(self, &shares, hello.status, points)?;
+        Ok(RedeemRequest { parts, pis })
+    }
+
+    fn finish_redemption(&mut self, redemption: Redemption) -> Result<()> {
+        Client::finish_redemption(self, redemption)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scheme::{self, DEFAULT_ACCOUNT, DEFAULT_MERCHANT};
+
+    // Registers `n` clients and tells each of them the server's state
+    fn register(server: &Server, n: u32) -> Vec<Client> {
+        let mut clients: Vec<Client> = (0..n).map(|i| Client::new(i as u64)).collect();
+        for client in &clients {
+            SchemeServer::register_user(server, client.register_with_server()).unwrap();
+        }
+        let (num_users, root, server_id, max_points, policy) = server.share_state().unwrap();
+        for (uid, client) in clients.iter_mut().enumerate() {
+            client.update_state(uid as u32, num_users, root, server_id, max_points, policy.clone());
+        }
+        clients
+    }
+
+    // Runs a transaction up to the submission.
+    // Output: the submission and the transaction's ID
+    fn start_tx(server: &Server, shopper: &mut Client, uid_s: u32, points: &[i32]) -> (TxSubmit, Com) {
+        let (com, sigma) = shopper.process_tx_hello(DEFAULT_MERCHANT);
+        let i_s = server.process_tx_hello_response(com, uid_s, DEFAULT_MERCHANT, &sigma).unwrap();
+        let (i_c, r) = shopper.process_tx_compute_id(i_s, com).unwrap();
+        let bg = SchemeServer::process_tx_barcode_gen(server, i_c, r, com).unwrap();
+        (SchemeClient::process_tx(shopper, &bg, points, com).unwrap(), com)
+    }
+
+    // Output: the points a user's settled balance comes to
+    fn settled_points(server: &Server, client: &mut Client, uid: u32) -> i32 {
+        let hello = SchemeServer::settle_balance_hello(server, uid, DEFAULT_ACCOUNT).unwrap();
+        let req = SchemeClient::settle_balance(client, hello).unwrap();
+        SchemeServer::settle_balance(server, uid, DEFAULT_ACCOUNT, req).unwrap().points
+    }
+
+    #[test]
+    fn failed_log_leaves_tx_open() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut server = Server::new();
+        let mut clients = register(&server, 3);
+
+        let (tx, com) = start_tx(&server, &mut clients[0], 0, &[10]);
+        let resent: TxSubmit = bincode::deserialize(&bincode::serialize(&tx).unwrap()).unwrap();
+
+        server.store = Some(Store::failing(dir.path()).unwrap());
+        assert_eq!(SchemeServer::process_tx(&server, tx, com).err(), Some(CheckoutError::Storage));
+        assert!(server.users.read().unwrap().values().all(|user| user.ledgers.is_empty()));
+
+        // Once the log works again, the same submission is carried out, and
+        // the points move from the shopper to the barcode owner
+        server.store = None;
+        SchemeServer::process_tx(&server, resent, com).unwrap();
+        let (ct, _, _) = server.settle_balance_hello(0, DEFAULT_ACCOUNT).unwrap();
+        assert_eq!(crypto_sh::elgamal_dec(*clients[0].sk_enc.expose(), ct).unwrap(), -10);
+    }
+
+    #[test]
+    fn invalid_key_is_not_registered() {
+        let server = Server::new();
+        let (barcode, _, pk_auth) = Client::new(0).register_with_server();
+        assert_eq!(server.register_user(barcode, [0xff; 32], pk_auth), Err(CheckoutError::InvalidPoint));
+        assert!(server.users.read().unwrap().is_empty());
+
+        // The next user to register with a valid key is the first
+        let (barcode, pk_enc, pk_auth) = Client::new(1).register_with_server();
+        assert_eq!(server.register_user(barcode, pk_enc, pk_auth), Ok(0));
+    }
+
+    #[test]
+    fn concurrent_transactions() {
+        let server = Server::new();
+        let mut clients = register(&server, 4);
+        std::thread::scope(|s| {
+            for (uid, client) in clients.iter_mut().enumerate() {
+                let server = &server;
+                s.spawn(move || {
+                    for _ in 0..5 {
+                        scheme::transact::<SemiHonest>(server, client, uid as u32, DEFAULT_MERCHANT, &[10]).unwrap();
+                    }
+                });
+            }
+        });
+
+        // Every shopper paid 50 points, and every point went to a barcode owner
+        let mut total = 0;
+        for (uid, client) in clients.iter_mut().enumerate() {
+            let points = settled_points(&server, client, uid as u32);
+            assert!(points >= -50);
+            total += points;
+        }
+        assert_eq!(total, 0);
+    }
+
+    #[test]
+    fn batch_with_one_bad_proof() {
+        let server = Server::new();
+        let mut clients = register(&server, 3);
+        let (tx_a, com_a) = start_tx(&server, &mut clients[0], 0, &[10]);
+        let (tx_b, _) = start_tx(&server, &mut clients[0], 0, &[10]);
+        let (tx_c, com_c) = start_tx(&server, &mut clients[0], 0, &[10]);
+
+        // B's proof is sent for transaction A, and A's for one the server
+        // never started
+        let batch = vec![(tx_c, com_c), (tx_b, com_a), (tx_a, [9; 32])];
+        assert_eq!(SchemeServer::process_tx_batch(&server, batch),
+                   vec![Ok(()), Err(CheckoutError::InvalidProof), Err(CheckoutError::UnknownTx)]);
+
+        // Only the good transaction was applied
+        assert_eq!(settled_points(&server, &mut clients[0], 0), -10);
+    }
+
+    #[test]
+    fn forgotten_tx_cannot_be_finished() {
+        let server = Server::new();
+        let mut clients = register(&server, 3);
+        let (com, sigma) = clients[0].process_tx_hello(DEFAULT_MERCHANT);
+        let i_s = server.process_tx_hello_response(com, 0, DEFAULT_MERCHANT, &sigma).unwrap();
+        let (i_c, r) = clients[0].process_tx_compute_id(i_s, com).unwrap();
+        let bg = SchemeServer::process_tx_barcode_gen(&server, i_c, r, com).unwrap();
+
+        clients[0].forget_tx(com);
+        assert!(clients[0].tmp.is_empty());
+        assert_eq!(SchemeClient::process_tx(&mut clients[0], &bg, &[10], com).err(), Some(CheckoutError::UnknownTx));
+
+        // A submitted transaction is forgotten without being asked
+        start_tx(&server, &mut clients[0], 0, &[10]);
+        assert!(clients[0].tmp.is_empty());
+    }
+
+    #[test]
+    fn categories_are_proven_apart() {
+        let mut server = Server::new();
+        server.set_categories(vec!["points".to_string(), "miles".to_string()]);
+        let mut clients = register(&server, 3);
+        let (tx, com) = start_tx(&server, &mut clients[0], 0, &[10, 20]);
+        let resend = |tx: &TxSubmit| -> TxSubmit { bincode::deserialize(&bincode::serialize(tx).unwrap()).unwrap() };
+
+        // Each proof only holds for its own category
+        let mut swapped = resend(&tx);
+        swapped.categories.swap(0, 1);
+        assert_eq!(SchemeServer::process_tx(&server, swapped, com).err(), Some(CheckoutError::InvalidProof));
+
+        let mut short = resend(&tx);
+        short.categories.pop();
+        assert_eq!(SchemeServer::process_tx(&server, short, com).err(), Some(CheckoutError::WrongCategories));
+
+        SchemeServer::process_tx(&server, tx, com).unwrap();
+        for (category, points) in [(0, -10), (1, -20)] {
+            let account = (DEFAULT_MERCHANT, category);
+            let hello = SchemeServer::settle_balance_hello(&server, 0, account).unwrap();
+            let req = SchemeClient::settle_balance(&mut clients[0], hello).unwrap();
+            assert_eq!(SchemeServer::settle_balance(&server, 0, account, req).unwrap().points, points);
+        }
+    }
+}
@@ -1,28 +1,61 @@
 use rs_merkle::{MerkleTree, algorithms, Hasher, MerkleProof};
 use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+use std::sync::RwLock;
+use std::time::Duration;
 use std::vec::Vec;
-use serde_derive::Serialize;
+use serde_derive::{Serialize, Deserialize};
 use rand::Rng;
-use sha2::{Sha256, Digest};
-
-pub(crate) type Com = [u8; 32];
+use ed25519_dalek::Signature;
+use crate::auth::{self, AuthKey};
+use crate::coin::{self, ClientShare, CoinFlip, RecentBarcodes, SelectionPolicy, ServerShare};
+use crate::encoding;
+use crate::error::{CheckoutError, Result};
+use crate::secret::Secret;
+use crate::session::{self, Sessions};
+use crate::store::Store;
+use crate::scheme::{LoyaltyScheme, SchemeServer, SchemeClient, SecurityLevel, Root, ServerId, MerchantId, Account};
+
+pub type Com = [u8; 32];
+
+// No proofs are made and no points are moved in this scheme, so the server
+// has no ID of its own, and shoppers sign under this one
+const SERVER_ID: ServerId = [0u8; 32];
+
+// Every method takes `&self`, so one server can process many transactions at
+// once. Locks are always taken in the order users, then merkle_tree, and tmp
+// is never held while taking another lock.
+pub struct Server {
+    pub(crate) users: RwLock<HashMap<u32, UserRecord>>,
+    merkle_tree: RwLock<MerkleTree<algorithms::Sha256>>,
+    tmp: Sessions<ServerTxTmp, ()>,
+    policy: SelectionPolicy, // Which users the coin flip may pick
+    recent: RecentBarcodes,
+    store: Option<Store<Event>>,
+}
 
-pub(crate) struct Server {
-    num_users: u32, 
-    pub users: HashMap<u32, UserRecord>,
-    merkle_tree: MerkleTree<algorithms::Sha256>,
-    tmp: HashMap<Com, ServerTxTmp>,
+// Changes to the server's durable state, in the order they are logged
+#[derive(Serialize, Deserialize)]
+enum Event {
+    Register { barcode: u64, pk_auth: AuthKey },
 }
 
+// The Merkle tree is rebuilt from the users when the snapshot is loaded
+type Snapshot = HashMap<u32, UserRecord>;
+
+#[derive(Clone)]
 struct ServerTxTmp {
-    i_s: Option<u32>, // Server's chosen index for card-swapping phase
+    uid_s: u32, // Shopper's user ID
+    i_s: Option<ServerShare>, // Server's share of the coin flip that picks the barcode
     uid_b: Option<u32> // Barcode owner's user ID
 }
 
 // The server's record of a user in the system
-#[derive(Debug, Serialize, Clone)]
-pub struct UserRecord {
-    barcode: u64
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub(crate) struct UserRecord {
+    barcode: u64,
+    pk_auth: AuthKey // Checks the requests only the user may make (see `auth`)
 }
 
 // User data stored in the server's Merkle tree
@@ -38,181 +71,494 @@ impl TreeEntry {
     }
 }
 
+// Applies a logged change to the server's state, both when it first happens
+// and when the log is replayed after a restart
+fn apply(users: &mut HashMap<u32, UserRecord>, event: Event) {
+    match event {
+        Event::Register { barcode, pk_auth } => {
+            let uid = users.len() as u32;
+            users.insert(uid, UserRecord { barcode, pk_auth });
+        }
+    }
+}
+
+impl Default for Server {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Server {
-    pub(crate) fn new() -> Self {
+    pub fn new() -> Self {
         Server {
-            num_users: 0,
-            users: HashMap::new(),
-            merkle_tree: MerkleTree::<algorithms::Sha256>::new(),
-            tmp: HashMap::new()
+            users: RwLock::new(HashMap::new()),
+            merkle_tree: RwLock::new(MerkleTree::<algorithms::Sha256>::new()),
+            tmp: Sessions::default(),
+            policy: SelectionPolicy::default(),
+            recent: RecentBarcodes::default(),
+            store: None
         }
     }
 
-    pub(crate) fn register_user(&mut self, barcode: u64) {
+    // A server whose state is kept in `dir` and survives restarts
+    pub fn open(dir: &Path) -> io::Result<Self> {
+        let (store, snapshot, events) = Store::<Event>::open::<Snapshot>(dir)?;
+        let mut users = snapshot.unwrap_or_default();
+
+        // Redo every change logged since the snapshot
+        for event in events {
+            apply(&mut users, event);
+        }
+
+        let mut merkle_tree = MerkleTree::<algorithms::Sha256>::new();
+        for uid in 0..users.len() as u32 {
+            let leaf = TreeEntry { uid, barcode: users[&uid].barcode };
+            merkle_tree.insert(algorithms::Sha256::hash(leaf.to_bytes().as_slice()));
+        }
+        merkle_tree.commit();
+
+        Ok(Server {
+            users: RwLock::new(users),
+            merkle_tree: RwLock::new(merkle_tree),
+            tmp: Sessions::default(),
+            policy: SelectionPolicy::default(),
+            recent: RecentBarcodes::default(),
+            store: Some(store)
+        })
+    }
+
+    // Writes a snapshot of the server's state and clears its log. This also
+    // happens automatically every `store::CHECKPOINT_INTERVAL` changes.
+    pub fn checkpoint(&self) -> Result<()> {
+        self.checkpoint_locked(&self.users.read().unwrap())
+    }
+
+    fn checkpoint_locked(&self, users: &HashMap<u32, UserRecord>) -> Result<()> {
+        match &self.store {
+            Some(store) => store.checkpoint(users).map_err(|_| CheckoutError::Storage),
+            None => Ok(())
+        }
+    }
+
+    // Logs a change before it is applied.
+    // Output: whether a checkpoint is due
+    fn log(&self, event: &Event) -> Result<bool> {
+        match &self.store {
+            Some(store) => store.append(event).map_err(|_| CheckoutError::Storage),
+            None => Ok(false)
+        }
+    }
+
+    // Output: the new user's ID
+    pub fn register_user(&self, barcode: u64, pk_auth: AuthKey) -> Result<u32> {
+        auth::check_key(&pk_auth)?;
+
+        let mut users = self.users.write().unwrap();
+        let mut merkle_tree = self.merkle_tree.write().unwrap();
+        let uid = users.len() as u32;
 
-        let user_rec = UserRecord {
-            barcode: barcode};
         let leaf = TreeEntry {
-            uid: self.num_users,
-            barcode: barcode
+            uid,
+            barcode
         };
 
         // Add user to list and to merkle tree
-        self.users.insert(
-            self.num_users,
-            user_rec
-        );
-        self.merkle_tree.insert(algorithms::Sha256::hash(leaf.to_bytes().as_slice()));
-        self.merkle_tree.commit();
+        let event = Event::Register { barcode, pk_auth };
+        let checkpoint_due = self.log(&event)?;
+        apply(&mut users, event);
+        merkle_tree.insert(algorithms::Sha256::hash(leaf.to_bytes().as_slice()));
+        merkle_tree.commit();
+
+        if checkpoint_due {
+            // A failed checkpoint loses nothing, since the log is kept
+            let _ = self.checkpoint_locked(&users);
+        }
+
+        Ok(uid)
+    }
 
-        self.num_users += 1;
+    // Sets which users the barcode coin flip may pick. Clients learn the
+    // policy along with the Merkle root.
+    pub fn set_selection_policy(&mut self, policy: SelectionPolicy) {
+        self.policy = policy;
     }
 
-    pub(crate) fn share_state(&self) -> (u32, <algorithms::Sha256 as rs_merkle::Hasher>::Hash) {
-        let root = self.merkle_tree.root().unwrap();
-        return (self.num_users, root);
+    // Sets how long a transaction may take before the server drops it
+    pub fn set_tx_ttl(&mut self, ttl: Duration) {
+        self.tmp.set_ttl(ttl);
+    }
+
+    // Sets how many transactions may be in progress at once
+    pub fn set_max_open_txs(&mut self, max: usize) {
+        self.tmp.set_max_open(max);
+    }
+
+    // Sets how long the server remembers the transactions it has carried
+    // out, and so refuses their IDs and answers their replays
+    pub fn set_replay_window(&mut self, window: Duration) {
+        self.tmp.set_replay_window(window);
+    }
+
+    // Drops a transaction that the client has given up on
+    pub fn abort_tx(&self, tx_id: Com) {
+        self.tmp.abort(&tx_id);
+    }
+
+    // Drops every transaction that has taken too long.
+    // Output: how many were dropped
+    pub fn sweep_txs(&self) -> usize {
+        self.tmp.sweep()
+    }
+
+    pub fn share_state(&self) -> Result<(u32, <algorithms::Sha256 as rs_merkle::Hasher>::Hash, SelectionPolicy)> {
+        let users = self.users.read().unwrap();
+        let root = self.merkle_tree.read().unwrap().root().ok_or(CheckoutError::NoUsers)?;
+        Ok((users.len() as u32, root, self.policy.clone()))
     }
 
     // Step 1 of a transaction request
     
-    // Input: shopper user ID, commitment to a chosed random ID
-    // Output: a server-chosen random ID
-    pub(crate) fn process_tx_hello_response(&mut self, com: Com) -> u32 {
-        let i_s = rand::thread_rng().gen_range(0..self.num_users);
+    // Input: shopper user ID, commitment to the client's share of the coin
+    // flip, the merchant the shopper is buying from, and the shopper's
+    // signature on them
+    // Output: the server's share of the coin flip
+    pub fn process_tx_hello_response(&self, com: Com, uid_s: u32, merchant: MerchantId, sigma: &Signature) -> Result<ServerShare> {
+        let pk_auth = self.users.read().unwrap().get(&uid_s).ok_or(CheckoutError::UnknownUser)?.pk_auth;
+        auth::verify_tx_hello(&pk_auth, &SERVER_ID, (&com, uid_s, merchant), sigma)?;
+
+        let i_s = coin::server_share(self.recent.get(uid_s, self.policy.recent));
         let tmp = ServerTxTmp {
-            i_s: Some(i_s),
+            uid_s,
+            i_s: Some(i_s.clone()),
             uid_b: None
         };
 
         // Store in-progress TX info server side
-        self.tmp.insert(
-            com,
-            tmp
-        );
+        self.tmp.open(com, tmp)?;
         
-        i_s
+        Ok(i_s)
     }
 
     // Step 2 of a transaction request
 
-    // Input: shopper UID, opened commitment contents: client-chosen random ID and mask
+    // Input: opened commitment contents: the client's share and mask
     // Output: barcode owner's UID, barcode, and public key, and merkle inclusion proof
-    pub(crate) fn process_tx_barcode_gen(&mut self, i_c: u32, r: [u8; 32], tx_id: Com) -> (u32, u64, MerkleProof<algorithms::Sha256>) {
-        let tmp: &mut ServerTxTmp = self.tmp.get_mut(&tx_id).unwrap();
+    pub fn process_tx_barcode_gen(&self, i_c: ClientShare, r: [u8; 32], tx_id: Com) -> Result<(u32, u64, MerkleProof<algorithms::Sha256>)> {
+        let tmp: ServerTxTmp = self.tmp.get(&tx_id)?;
 
         // Recompute commitment and check that it matches.
-        let mut hasher = Sha256::new();
-        hasher.update(i_c.to_le_bytes());
-        hasher.update(r);
-        let com_test: [u8; 32] = hasher.finalize().into();
-
-        assert!(com_test == tx_id, "Invalid commit");
+        if i_c.commit(&r) != tx_id {
+            return Err(CheckoutError::BadCommitment);
+        }
 
-        let uid_b = (i_c + tmp.i_s.unwrap()) % self.num_users;
+        let i_s = tmp.i_s.ok_or(CheckoutError::UnknownTx)?;
 
-        tmp.uid_b = Some(uid_b);
+        let (uid_b, barcode, pi) = {
+            let users = self.users.read().unwrap();
+            let uid_b = coin::joint_index(&i_c, &i_s, tmp.uid_s, users.len() as u32, &self.policy)?;
 
-        let user_b: &UserRecord = &self.users.get(&uid_b).unwrap();
-        let barcode = user_b.barcode;
+            let user_b: &UserRecord = users.get(&uid_b).ok_or(CheckoutError::UnknownUser)?;
+            let pi: MerkleProof<algorithms::Sha256> = self.merkle_tree.read().unwrap().proof(&[uid_b.try_into().unwrap()]);
+            (uid_b, user_b.barcode, pi)
+        };
 
-        let pi: MerkleProof<algorithms::Sha256> = self.merkle_tree.proof(&[uid_b.try_into().unwrap()]);
+        self.tmp.update(&tx_id, |tmp| tmp.uid_b = Some(uid_b))?;
+        self.recent.record(tmp.uid_s, uid_b, self.policy.recent);
 
-        (uid_b, barcode, pi)
+        Ok((uid_b, barcode, pi))
     }
 }
 
-pub(crate) struct Client {
+#[derive(Serialize, Deserialize)]
+pub struct Client {
     barcode: u64,
+    uid: u32,
     num_users: u32,
     merkle_root: Option<<algorithms::Sha256 as rs_merkle::Hasher>::Hash>,
-    tmp: HashMap<Com, ClientTxTmp>
+    policy: SelectionPolicy,
+    coin_flip: CoinFlip,
+    tmp: HashMap<Com, ClientTxTmp>,
+    sk_auth: Secret<[u8; 32]> // Signs the requests only this user may make (see `auth`)
 }
 
+#[derive(Serialize, Deserialize)]
 struct ClientTxTmp {
-    i_c: Option<u32>,
+    started: u64, // When the transaction started (see `session::now`)
+    i_c: Option<ClientShare>,
     r: Option<[u8; 32]>,
     uid_b: Option<u32>,
 
 }
 
 impl Client {
-    pub(crate) fn new(barcode: u64) -> Self {
+    pub fn new(barcode: u64) -> Self {
         Client {
-            barcode: barcode,
+            barcode,
+            uid: 0,
             num_users: 1,
             merkle_root: None,
-            tmp: HashMap::new()
+            policy: SelectionPolicy::default(),
+            coin_flip: CoinFlip::default(),
+            tmp: HashMap::new(),
+            sk_auth: auth::keygen().0
         }
     }
 
-    pub(crate) fn register_with_server(&self) -> u64 {
-        self.barcode
+    pub fn register_with_server(&self) -> (u64, AuthKey) {
+        (self.barcode, auth::public_key(&self.sk_auth))
     }
 
-    pub(crate) fn update_state(&mut self, num_users: u32, merkle_root: <algorithms::Sha256 as Hasher>::Hash) {
+    pub fn update_state(&mut self, uid: u32, num_users: u32, merkle_root: <algorithms::Sha256 as Hasher>::Hash, policy: SelectionPolicy) {
+        self.uid = uid;
         self.num_users = num_users;
         self.merkle_root = Some(merkle_root);
+        self.policy = policy;
+    }
+
+    // Sets the kind of share this client uses in the coin flip that picks
+    // the barcode (see `coin`)
+    pub fn set_coin_flip(&mut self, mode: CoinFlip) {
+        self.coin_flip = mode;
     }
 
     // Step 1 of a transaction request
 
-    // Input: N/A
-    // Output: commitment to a randomly chosen user ID
-    pub(crate) fn process_tx_hello(&mut self) -> Com {
-        // Commit to a random index and send it to the server
-        let i_c = rand::thread_rng().gen_range(0..self.num_users);
+    // Input: the merchant the shopper is buying from
+    // Output: commitment to the client's share of the coin flip, and the
+    // shopper's signature on it
+    pub fn process_tx_hello(&mut self, merchant: MerchantId) -> (Com, Signature) {
+        // Commit to a random share and send it to the server
+        let i_c = ClientShare::random(self.coin_flip);
         let r = rand::thread_rng().gen::<[u8; 32]>();
-        let mut hasher = Sha256::new();
-        hasher.update(i_c.to_le_bytes());
-        hasher.update(r);
-        let com: [u8; 32] = hasher.finalize().into();
+        let com = i_c.commit(&r);
 
         let tx_id = com;
         self.tmp.insert(
             tx_id,
             ClientTxTmp {
+                started: session::now(),
                 i_c: Some(i_c),
                 r: Some(r),
                 uid_b: None,
             }
         );
 
-        com
+        let sigma = auth::sign_tx_hello(&self.sk_auth, &SERVER_ID, (&com, self.uid, merchant));
+        (com, sigma)
     }
 
     // Step 2 of a transaction request
 
-    // Input: server's randomly chosen barcode UID
-    // Output: opened commitment to client-chosed barcode UID
-    pub(crate) fn process_tx_compute_id(&mut self, i_s: u32, tx_id: Com) -> (u32, [u8; 32]) {
-        let tmp: &mut ClientTxTmp = self.tmp.get_mut(&tx_id).unwrap();
+    // Input: the server's share of the coin flip
+    // Output: opened commitment to the client's share
+    pub fn process_tx_compute_id(&mut self, i_s: ServerShare, tx_id: Com) -> Result<(ClientShare, [u8; 32])> {
+        let tmp: &mut ClientTxTmp = self.tmp.get_mut(&tx_id).ok_or(CheckoutError::UnknownTx)?;
+        let i_c = tmp.i_c.ok_or(CheckoutError::UnknownTx)?;
+        let r = tmp.r.ok_or(CheckoutError::UnknownTx)?;
 
-        let i = (tmp.i_c.unwrap() + i_s) % self.num_users;
+        let i = coin::joint_index(&i_c, &i_s, self.uid, self.num_users, &self.policy)?;
         tmp.uid_b = Some(i);
 
-        (tmp.i_c.unwrap(), tmp.r.unwrap())
+        Ok((i_c, r))
     }
 
-    pub(crate) fn verify_merkle_proof(&mut self, barcode: u64, pi: &MerkleProof<algorithms::Sha256>, tx_id: Com) -> bool {
-        let tmp: &ClientTxTmp = self.tmp.get(&tx_id).unwrap();
+    pub fn verify_merkle_proof(&mut self, barcode: u64, pi: &MerkleProof<algorithms::Sha256>, tx_id: Com) -> Result<()> {
+        let tmp: &ClientTxTmp = self.tmp.get(&tx_id).ok_or(CheckoutError::UnknownTx)?;
+        let uid_b = tmp.uid_b.ok_or(CheckoutError::UnknownTx)?;
+        let merkle_root = self.merkle_root.ok_or(CheckoutError::InvalidProof)?;
 
         let leaf = TreeEntry {
-            uid: tmp.uid_b.unwrap(),
-            barcode: barcode
+            uid: uid_b,
+            barcode
         };
         let tree_contents = algorithms::Sha256::hash(leaf.to_bytes().as_slice());
 
-        let test = pi.verify(self.merkle_root.unwrap(), &[tmp.uid_b.unwrap().try_into().unwrap()], &[tree_contents], self.num_users.try_into().unwrap());
-
-        assert!(test);
-
-        test
+        if pi.verify(merkle_root, &[uid_b as usize], &[tree_contents], self.num_users as usize) { Ok(()) }
+        else { Err(CheckoutError::InvalidProof) }
     }
 
     // Step 3 of a transaction request
-    pub(crate) fn process_tx(&mut self, pi: &MerkleProof<algorithms::Sha256>, barcode: u64, tx_id: Com) {
+    pub fn process_tx(&mut self, pi: &MerkleProof<algorithms::Sha256>, barcode: u64, tx_id: Com) -> Result<()> {
         // Verify Merkle proof that the agreed upon index is in the tree
-        self.verify_merkle_proof(barcode, pi, tx_id);
+        self.verify_merkle_proof(barcode, pi, tx_id)
     }
 
-}
\ No newline at end of file
+    // Forgets every transaction that started longer than `ttl` ago, since the
+    // server has dropped it by now.
+    // Output: how many were forgotten
+    pub fn expire_txs(&mut self, ttl: Duration) -> usize {
+        let before = self.tmp.len();
+        self.tmp.retain(|_, tmp| !session::expired(tmp.started, ttl));
+        before - self.tmp.len()
+    }
+
+}
+
+//////////////////////////////////////////////////////////////////
+// LoyaltyScheme implementation
+//////////////////////////////////////////////////////////////////
+
+// Barcode swapping only: points are credited in the clear by the store's
+// existing system, so there are no receipts or balances to settle.
+pub struct SwapOnly;
+
+#[derive(Serialize, Deserialize)]
+pub struct BarcodeGen {
+    pub uid_b: u32,
+    pub barcode: u64,
+    #[serde(with = "encoding::merkle_proof")]
+    pub pi: MerkleProof<algorithms::Sha256>,
+}
+
+impl LoyaltyScheme for SwapOnly {
+    const LEVEL: SecurityLevel = SecurityLevel::SwapOnly;
+
+    type Server = Server;
+    type Client = Client;
+
+    type Registration = (u64, AuthKey);
+    type BarcodeGen = BarcodeGen;
+    type TxSubmit = ();
+    type TxSignature = ();
+    type Receipts = ();
+    type SettleHello = ();
+    type SettleRequest = ();
+    type Settlement = ();
+    type RedeemRequest = ();
+    type Redemption = ();
+}
+
+impl SchemeServer for Server {
+    type Scheme = SwapOnly;
+
+    fn register_user(&self, reg: (u64, AuthKey)) -> Result<u32> {
+        Server::register_user(self, reg.0, reg.1)
+    }
+
+    // No points are moved in this scheme, so the server sets no limit
+    fn share_state(&self) -> Result<(u32, Root, ServerId, u32, SelectionPolicy)> {
+        let (num_users, root, policy) = Server::share_state(self)?;
+        Ok((num_users, root, SERVER_ID, 0, policy))
+    }
+
+    fn process_tx_hello_response(&self, com: Com, uid_s: u32, merchant: MerchantId, sigma: &Signature) -> Result<ServerShare> {
+        Server::process_tx_hello_response(self, com, uid_s, merchant, sigma)
+    }
+
+    fn process_tx_barcode_gen(&self, i_c: ClientShare, r: [u8; 32], tx_id: Com) -> Result<BarcodeGen> {
+        let (uid_b, barcode, pi) = Server::process_tx_barcode_gen(self, i_c, r, tx_id)?;
+        Ok(BarcodeGen { uid_b, barcode, pi })
+    }
+
+    // Sending a transaction that was already carried out again succeeds
+    fn process_tx(&self, tx: (), tx_id: Com) -> Result<()> {
+        let hash = session::submission_hash(&tx);
+        if self.tmp.replayed(&tx_id, &hash)?.is_none() {
+            self.tmp.get(&tx_id)?;
+            self.tmp.spend(&tx_id, hash, ());
+        }
+        Ok(())
+    }
+
+    fn abort_tx(&self, tx_id: Com) {
+        Server::abort_tx(self, tx_id)
+    }
+
+    fn sweep_txs(&self) -> usize {
+        Server::sweep_txs(self)
+    }
+
+    fn retire_expired(&self) -> Result<usize> {
+        Ok(0)
+    }
+
+    fn send_receipts(&self, _uid: u32, after: u64, _limit: u32) -> Result<((), u64, bool)> {
+        Ok(((), after, false))
+    }
+
+    fn ack_receipts(&self, _uid: u32, _upto: u64, _sigma: &Signature) -> Result<()> {
+        Ok(())
+    }
+
+    fn settle_balance_hello(&self, _uid: u32, _account: Account) -> Result<()> {
+        Ok(())
+    }
+
+    fn settle_balance(&self, _uid: u32, _account: Account, _req: ()) -> Result<()> {
+        Ok(())
+    }
+
+    fn redeem(&self, _uid: u32, _account: Account, _req: ()) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl SchemeClient for Client {
+    type Scheme = SwapOnly;
+
+    fn new(barcode: u64) -> Self {
+        Client::new(barcode)
+    }
+
+    fn register_with_server(&self) -> (u64, AuthKey) {
+        Client::register_with_server(self)
+    }
+
+    fn update_state(&mut self, uid: u32, num_users: u32, merkle_root: Root, _server_id: ServerId, _max_points: u32, policy: SelectionPolicy) {
+        Client::update_state(self, uid, num_users, merkle_root, policy)
+    }
+
+    fn set_coin_flip(&mut self, mode: CoinFlip) {
+        Client::set_coin_flip(self, mode)
+    }
+
+    fn process_tx_hello(&mut self, merchant: MerchantId) -> (Com, Signature) {
+        Client::process_tx_hello(self, merchant)
+    }
+
+    fn process_tx_compute_id(&mut self, i_s: ServerShare, tx_id: Com) -> Result<(ClientShare, [u8; 32])> {
+        Client::process_tx_compute_id(self, i_s, tx_id)
+    }
+
+    fn process_tx(&mut self, bg: &BarcodeGen, _points: &[i32], tx_id: Com) -> Result<()> {
+        Client::process_tx(self, &bg.pi, bg.barcode, tx_id)
+    }
+
+    fn process_tx_coda(&mut self, _sigma: (), tx_id: Com) -> Result<()> {
+        self.tmp.remove(&tx_id).ok_or(CheckoutError::UnknownTx)?;
+        Ok(())
+    }
+
+    // Nothing kept about a transaction is secret here, so it is only dropped
+    fn forget_tx(&mut self, tx_id: Com) {
+        self.tmp.remove(&tx_id);
+    }
+
+    fn expire_txs(&mut self, ttl: Duration) -> usize {
+        Client::expire_txs(self, ttl)
+    }
+
+    fn process_receipts(&mut self, _rcts: ()) -> Result<()> {
+        Ok(())
+    }
+
+    fn ack_receipts(&self, upto: u64) -> Signature {
+        auth::sign_ack(&self.sk_auth, &SERVER_ID, (self.uid, upto))
+    }
+
+    fn settle_balance(&mut self, _hello: ()) -> Result<()> {
+        Ok(())
+    }
+
+    fn finish_settlement(&mut self, _settlement: ()) -> Result<()> {
+        Ok(())
+    }
+
+    fn redeem(&mut self, _hello: (), _points: u32) -> Result<()> {
+        Ok(())
+    }
+
+    fn finish_redemption(&mut self, _redemption: ()) -> Result<()> {
+        Ok(())
+    }
+}
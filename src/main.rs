@@ -1,11 +1,12 @@
-mod lib_mal;
-mod lib_sh;
-mod lib_sh_swap_only;
-use lib_mal::*;
+use checkout::lib_mal::*;
+use checkout::{lib_sh, lib_sh_swap_only};
 use std::vec::Vec;
 use rand::Rng;
 use std::time::{Instant, Duration};
-use rs_merkle::{algorithms, MerkleProof};
+use checkout::rs_merkle::{algorithms, MerkleProof};
+use checkout::scheme::{DEFAULT_ACCOUNT, DEFAULT_MERCHANT, MAX_RECEIPT_PAGE};
+use checkout::coin::{ClientShare, ServerShare};
+use checkout::expiry::Period;
 use ed25519_dalek::Signature;
 
 const DEBUG: bool = false;
@@ -14,13 +15,13 @@ const N_CLIENTS: usize = 500;
 fn main() {
 
     println!("Malicious security protocol");
-    println!("");
+    println!();
 
     println!("---------------------------");
     println!("--- Client Registration --- (User ID)");
     println!("---------------------------");
 
-    let mut server = Server::new();
+    let server = Server::new();
     let mut clients = Vec::<Client>::with_capacity(N_CLIENTS);
 
     for i in 0..N_CLIENTS {
@@ -38,7 +39,7 @@ fn main() {
         let barcode = client_data.0;
         let pk = client_data.1;
 
-        server.register_user(barcode, pk);
+        server.register_user(barcode, pk, client_data.2).unwrap();
         let time_server = now.elapsed();
 
         let res = format!("{: <10} {: <10} {: <10.3?} {: <10} {: <10.3?}",
@@ -56,16 +57,17 @@ fn main() {
         uid_s: u32,
         points: i32,
         com: Option<Com>,
-        i_s: Option<u32>,
-        i_c: Option<u32>,
+        sigma_hello: Option<Signature>,
+        i_s: Option<ServerShare>,
+        i_c: Option<ClientShare>,
         r: Option<[u8; 32]>,
         uid_b: Option<u32>,
         barcode: Option<u64>,
         pk_b: Option<Point>,
-        base: Option<[u8; 32]>,
+        base: Option<([u8; 32], Period)>,
         pi_merkle: Option<MerkleProof<algorithms::Sha256>>,
         m_ct: Option<Ciphertext>,
-        pi_tx: Option<TxAndProof>,
+        pi_tx: Option<Vec<TxAndProof>>,
         sigma: Option<Signature>
     }
 
@@ -79,7 +81,7 @@ fn main() {
     }
     let step = min_users;
 
-    let mut server = Server::new();
+    let server = Server::new();
     let mut clients = Vec::<Client>::with_capacity(max_users);
 
     // Initialize a system with a certain number of users,
@@ -95,24 +97,25 @@ fn main() {
             clients.push(c);
 
             let client_data = clients.last_mut().unwrap().register_with_server();
-            server.register_user(client_data.0, client_data.1);
+            server.register_user(client_data.0, client_data.1, client_data.2).unwrap();
         }
 
         // Inform every user of the new merkle root
-        let server_data = server.share_state();
-        for i in 0..n_users {
-            clients[i].update_state(i.try_into().unwrap(), server_data.0, server_data.1);
+        let server_data = server.share_state().unwrap();
+        for (i, client) in clients.iter_mut().enumerate().take(n_users) {
+            client.update_state(i.try_into().unwrap(), server_data.0, server_data.1, server_data.2, server_data.3, server_data.4.clone());
         }
 
         // Process transactions
         let mut txs = Vec::<Tx>::with_capacity(n_txs);
         for _i in 0..n_txs {
             let shopper_uid: u32 = rand::thread_rng().gen_range(0..n_users).try_into().unwrap();
-            let points_used: i32 = rand::thread_rng().gen_range(0..300).try_into().unwrap();
+            let points_used: i32 = rand::thread_rng().gen_range(0..300);
             txs.push(Tx {
                 uid_s: shopper_uid,
                 points: points_used,
                 com: None,
+                sigma_hello: None,
                 i_s: None,
                 i_c: None,
                 r: None,
@@ -131,8 +134,9 @@ fn main() {
         let now = Instant::now();
         for tx in &mut txs {
             let shopper: &mut Client = &mut clients[tx.uid_s as usize];
-            let com = shopper.process_tx_hello();
+            let (com, sigma_hello) = shopper.process_tx_hello(DEFAULT_MERCHANT);
             tx.com = Some(com);
+            tx.sigma_hello = Some(sigma_hello);
         }
         time_client += now.elapsed();
         // -----------------------------
@@ -140,7 +144,7 @@ fn main() {
         // -----------------------------
         let now = Instant::now();
         for tx in &mut txs {
-            let i_s = server.process_tx_hello_response(tx.com.unwrap(), tx.uid_s);
+            let i_s = server.process_tx_hello_response(tx.com.unwrap(), tx.uid_s, DEFAULT_MERCHANT, &tx.sigma_hello.unwrap()).unwrap();
             tx.i_s = Some(i_s);
         }
         time_server += now.elapsed();
@@ -149,10 +153,10 @@ fn main() {
         // -----------------------------
         let now = Instant::now();
         for tx in &mut txs {
-            let i_s = tx.i_s.unwrap();
+            let i_s = tx.i_s.take().unwrap();
             let com = tx.com.unwrap();
             let shopper = &mut clients[tx.uid_s as usize];
-            let i_c_r = shopper.process_tx_compute_id(i_s, com);
+            let i_c_r = shopper.process_tx_compute_id(i_s, com).unwrap();
             tx.i_c = Some(i_c_r.0);
             tx.r = Some(i_c_r.1);
         }
@@ -166,7 +170,7 @@ fn main() {
             let r = tx.r.unwrap();
             let com = tx.com.unwrap();
 
-            let out = server.process_tx_barcode_gen(i_c, r, com);
+            let out = server.process_tx_barcode_gen(i_c, r, com).unwrap();
             tx.uid_b = Some(out.0);
             tx.barcode = Some(out.1);
             tx.pk_b = Some(out.2);
@@ -188,7 +192,7 @@ fn main() {
             let com = tx.com.unwrap();
             let points = tx.points;
 
-            let out = shopper.process_tx(pi_merkle, barcode, points, pk_b, base, com);
+            let out = shopper.process_tx(pi_merkle, barcode, &[points], pk_b, base, com).unwrap();
             tx.m_ct = Some(out.0);
             tx.pi_tx = Some(out.1);
         }
@@ -202,7 +206,7 @@ fn main() {
             let pi_tx = tx.pi_tx.clone().unwrap();
             let com = tx.com.unwrap();
 
-            let sigma = server.process_tx(m_ct, pi_tx, com);
+            let sigma = server.process_tx(m_ct, pi_tx, com).unwrap();
             tx.sigma = Some(sigma);
         }
         time_server += now.elapsed();
@@ -215,7 +219,7 @@ fn main() {
             let com = tx.com.unwrap();
             let sigma = tx.sigma.unwrap();
 
-            shopper.process_tx_coda(sigma, com);
+            shopper.process_tx_coda(sigma, com).unwrap();
         }
         time_client += now.elapsed();
         // -----------------------------
@@ -227,6 +231,58 @@ fn main() {
         println!("{}", res);
     }
 
+    println!("--------------------------");
+    println!("--- Batch Verification --- (batch size)");
+    println!("--------------------------");
+    // Server time per transaction for step 3, checking each proof on its own
+    // and checking a whole batch at once.
+
+    let mut batch_sizes = vec![1, 10, 100, 500];
+    if DEBUG {
+        batch_sizes = vec![1, 5];
+    }
+
+    for batch_size in batch_sizes {
+        let server = Server::new();
+        let mut client = Client::new(1);
+
+        let client_data = client.register_with_server();
+        server.register_user(client_data.0, client_data.1, client_data.2).unwrap();
+        let server_data = server.share_state().unwrap();
+        client.update_state(0, server_data.0, server_data.1, server_data.2, server_data.3, server_data.4.clone());
+
+        // Run steps 1 and 2 for two sets of transactions
+        let mut txs = Vec::<(Ciphertext, Vec<TxAndProof>, Com)>::with_capacity(2 * batch_size);
+        for _i in 0..(2 * batch_size) {
+            let (com, sigma_hello) = client.process_tx_hello(DEFAULT_MERCHANT);
+            let i_s = server.process_tx_hello_response(com, 0, DEFAULT_MERCHANT, &sigma_hello).unwrap();
+            let (i_c, r) = client.process_tx_compute_id(i_s, com).unwrap();
+            let (_, barcode, pk_b, base, pi_merkle) = server.process_tx_barcode_gen(i_c, r, com).unwrap();
+            let points: i32 = rand::thread_rng().gen_range(0..300);
+            let (m_ct, pi_tx) = client.process_tx(&pi_merkle, barcode, &[points], pk_b, base, com).unwrap();
+            txs.push((m_ct, pi_tx, com));
+        }
+        let batch = txs.split_off(batch_size);
+
+        let now = Instant::now();
+        for (m_ct, pi_tx, com) in txs {
+            server.process_tx(m_ct, pi_tx, com).unwrap();
+        }
+        let time_single = now.elapsed();
+
+        let now = Instant::now();
+        for sigma in server.process_tx_batch(batch) {
+            sigma.unwrap();
+        }
+        let time_batch = now.elapsed();
+
+        let res = format!("{: <10} {: <10} {: <10.3?} {: <10} {: <10.3?}",
+            batch_size,
+            "Single:", time_single.div_f32(batch_size as f32),
+            "Batch:", time_batch.div_f32(batch_size as f32));
+        println!("{}", res);
+    }
+
     println!("--------------------------");
     println!("--- Receipt Processing --- (number of points)");
     println!("--------------------------");
@@ -245,43 +301,43 @@ fn main() {
     for n_points in (min_points..(max_points+1)).step_by(step.try_into().unwrap()) {
         // Only initialize one client, so every receipt will go
         // back to their account
-        let mut server = Server::new();
+        let server = Server::new();
         let mut client = Client::new(1);
 
         let client_data = client.register_with_server();
-        server.register_user(client_data.0, client_data.1);
-        let server_data = server.share_state();
-        client.update_state(0, server_data.0, server_data.1);
+        server.register_user(client_data.0, client_data.1, client_data.2).unwrap();
+        let server_data = server.share_state().unwrap();
+        client.update_state(0, server_data.0, server_data.1, server_data.2, server_data.3, server_data.4.clone());
 
         // Process n_txs transactions
         for _i in 0..n_txs {
-            let com = client.process_tx_hello();
-            let i_s = server.process_tx_hello_response(com, 0);
-            let i_c_r = client.process_tx_compute_id(i_s, com);
+            let (com, sigma_hello) = client.process_tx_hello(DEFAULT_MERCHANT);
+            let i_s = server.process_tx_hello_response(com, 0, DEFAULT_MERCHANT, &sigma_hello).unwrap();
+            let i_c_r = client.process_tx_compute_id(i_s, com).unwrap();
             let i_c = i_c_r.0;
             let r = i_c_r.1;
 
-            let out = server.process_tx_barcode_gen(i_c, r, com);
+            let out = server.process_tx_barcode_gen(i_c, r, com).unwrap();
             let barcode = out.1;
             let pk_b = out.2;
             let base = out.3;
             let pi_merkle = out.4;
 
-            let out = client.process_tx(&pi_merkle, barcode, n_points.try_into().unwrap(), pk_b, base, com);
+            let out = client.process_tx(&pi_merkle, barcode, &[n_points], pk_b, base, com).unwrap();
             let m_ct = out.0;
             let pi_tx = out.1;
 
-            let sigma = server.process_tx(m_ct, pi_tx, com);
-            client.process_tx_coda(sigma, com);
+            let sigma = server.process_tx(m_ct, pi_tx, com).unwrap();
+            client.process_tx_coda(sigma, com).unwrap();
         }
 
         // Distribute receipts
         let now = Instant::now();
-        let rcts = server.send_receipts(0);
+        let (rcts, _, _) = server.send_receipts(0, 0, MAX_RECEIPT_PAGE).unwrap();
         let time_server = now.elapsed();
 
         let now = Instant::now();
-        client.process_receipts(rcts);
+        client.process_receipts(rcts).unwrap();
         let time_client = now.elapsed();
 
         let res = format!("{: <10} {: <10} {: <10.3?} {: <10} {: <10.3?}",
@@ -308,51 +364,52 @@ fn main() {
     for n_txs in (min_txs..(max_txs+1)).step_by(step) {
         // Only initialize one client, so every receipt will go
         // back to their account
-        let mut server = Server::new();
+        let server = Server::new();
         let mut client = Client::new(1);
 
         let client_data = client.register_with_server();
-        server.register_user(client_data.0, client_data.1);
-        let server_data = server.share_state();
-        client.update_state(0, server_data.0, server_data.1);
+        server.register_user(client_data.0, client_data.1, client_data.2).unwrap();
+        let server_data = server.share_state().unwrap();
+        client.update_state(0, server_data.0, server_data.1, server_data.2, server_data.3, server_data.4.clone());
 
         // Process n_txs transactions
         for _i in 0..n_txs {
-            let com = client.process_tx_hello();
-            let i_s = server.process_tx_hello_response(com, 0);
-            let i_c_r = client.process_tx_compute_id(i_s, com);
+            let (com, sigma_hello) = client.process_tx_hello(DEFAULT_MERCHANT);
+            let i_s = server.process_tx_hello_response(com, 0, DEFAULT_MERCHANT, &sigma_hello).unwrap();
+            let i_c_r = client.process_tx_compute_id(i_s, com).unwrap();
             let i_c = i_c_r.0;
             let r = i_c_r.1;
 
-            let out = server.process_tx_barcode_gen(i_c, r, com);
+            let out = server.process_tx_barcode_gen(i_c, r, com).unwrap();
             let barcode = out.1;
             let pk_b = out.2;
             let base = out.3;
             let pi_merkle = out.4;
 
-            let n_points: i32 = rand::thread_rng().gen_range(0..300).try_into().unwrap();
-            let out = client.process_tx(&pi_merkle, barcode, n_points, pk_b, base, com);
+            let n_points: i32 = rand::thread_rng().gen_range(0..300);
+            let out = client.process_tx(&pi_merkle, barcode, &[n_points], pk_b, base, com).unwrap();
             let m_ct = out.0;
             let pi_tx = out.1;
 
-            let sigma = server.process_tx(m_ct, pi_tx, com);
-            client.process_tx_coda(sigma, com);
+            let sigma = server.process_tx(m_ct, pi_tx, com).unwrap();
+            client.process_tx_coda(sigma, com).unwrap();
         }
 
         // Distribute receipts
-        let rcts = server.send_receipts(0);
-        client.process_receipts(rcts);
+        let (rcts, _, _) = server.send_receipts(0, 0, MAX_RECEIPT_PAGE).unwrap();
+        client.process_receipts(rcts).unwrap();
 
         // Settle balances
+        let status = server.settle_balance_hello(0, DEFAULT_ACCOUNT).unwrap();
         let now = Instant::now();
-        let out = client.settle_balance();
+        let out = client.settle_balance(status).unwrap();
         let time_client = now.elapsed();
 
         let now = Instant::now();
-        let test = server.settle_balance(0, out.0, out.1, out.2, out.3, out.4);
+        let test = server.settle_balance(0, DEFAULT_ACCOUNT, out);
         let time_server = now.elapsed();
         
-        assert!(test);
+        assert!(test.is_ok());
         
         let res = format!("{: <10} {: <10} {: <10.3?} {: <10} {: <10.3?}",
             n_txs*2, // Since we only initialize one client, every tx touches their account twice
@@ -361,15 +418,15 @@ fn main() {
         println!("{}", res);
     }
 
-    println!("");
+    println!();
     println!("Semihonest protocol");
-    println!("");
+    println!();
 
     println!("---------------------------");
     println!("--- Client Registration --- (User ID)");
     println!("---------------------------");
 
-    let mut server = Server::new();
+    let server = Server::new();
     let mut clients = Vec::<Client>::with_capacity(N_CLIENTS);
 
     for i in 0..N_CLIENTS {
@@ -387,7 +444,7 @@ fn main() {
         let barcode = client_data.0;
         let pk = client_data.1;
 
-        server.register_user(barcode, pk);
+        server.register_user(barcode, pk, client_data.2).unwrap();
         let time_server = now.elapsed();
 
         let res = format!("{: <10} {: <10} {: <10.3?} {: <10} {: <10.3?}",
@@ -405,8 +462,9 @@ fn main() {
         uid_s: u32,
         points: i32,
         com: Option<Com>,
-        i_s: Option<u32>,
-        i_c: Option<u32>,
+        sigma_hello: Option<Signature>,
+        i_s: Option<ServerShare>,
+        i_c: Option<ClientShare>,
         r: Option<[u8; 32]>,
         uid_b: Option<u32>,
         barcode: Option<u64>,
@@ -427,7 +485,7 @@ fn main() {
     }
     let step = min_users;
 
-    let mut server = lib_sh::Server::new();
+    let server = lib_sh::Server::new();
     let mut clients = Vec::<lib_sh::Client>::with_capacity(max_users);
 
     // Initialize a system with a certain number of users,
@@ -443,24 +501,25 @@ fn main() {
             clients.push(c);
 
             let client_data = clients.last_mut().unwrap().register_with_server();
-            server.register_user(client_data.0, client_data.1);
+            server.register_user(client_data.0, client_data.1, client_data.2).unwrap();
         }
 
         // Inform every user of the new merkle root
-        let server_data = server.share_state();
-        for i in 0..n_users {
-            clients[i].update_state(server_data.0, server_data.1);
+        let server_data = server.share_state().unwrap();
+        for (i, client) in clients.iter_mut().enumerate().take(n_users) {
+            client.update_state(i.try_into().unwrap(), server_data.0, server_data.1, server_data.2, server_data.3, server_data.4.clone());
         }
 
         // Process transactions
         let mut txs = Vec::<TxOld>::with_capacity(n_txs);
         for _i in 0..n_txs {
             let shopper_uid: u32 = rand::thread_rng().gen_range(0..n_users).try_into().unwrap();
-            let points_used: i32 = rand::thread_rng().gen_range(0..300).try_into().unwrap();
+            let points_used: i32 = rand::thread_rng().gen_range(0..300);
             txs.push(TxOld {
                 uid_s: shopper_uid,
                 points: points_used,
                 com: None,
+                sigma_hello: None,
                 i_s: None,
                 i_c: None,
                 r: None,
@@ -478,8 +537,9 @@ fn main() {
         let now = Instant::now();
         for tx in &mut txs {
             let shopper: &mut lib_sh::Client = &mut clients[tx.uid_s as usize];
-            let com = shopper.process_tx_hello();
+            let (com, sigma_hello) = shopper.process_tx_hello(DEFAULT_MERCHANT);
             tx.com = Some(com);
+            tx.sigma_hello = Some(sigma_hello);
         }
         time_client += now.elapsed();
         // -----------------------------
@@ -487,7 +547,7 @@ fn main() {
         // -----------------------------
         let now = Instant::now();
         for tx in &mut txs {
-            let i_s = server.process_tx_hello_response(tx.com.unwrap());
+            let i_s = server.process_tx_hello_response(tx.com.unwrap(), tx.uid_s, DEFAULT_MERCHANT, &tx.sigma_hello.unwrap()).unwrap();
             tx.i_s = Some(i_s);
         }
         time_server += now.elapsed();
@@ -496,10 +556,10 @@ fn main() {
         // -----------------------------
         let now = Instant::now();
         for tx in &mut txs {
-            let i_s = tx.i_s.unwrap();
+            let i_s = tx.i_s.take().unwrap();
             let com = tx.com.unwrap();
             let shopper = &mut clients[tx.uid_s as usize];
-            let i_c_r = shopper.process_tx_compute_id(i_s, com);
+            let i_c_r = shopper.process_tx_compute_id(i_s, com).unwrap();
             tx.i_c = Some(i_c_r.0);
             tx.r = Some(i_c_r.1);
         }
@@ -513,7 +573,7 @@ fn main() {
             let r = tx.r.unwrap();
             let com = tx.com.unwrap();
 
-            let out = server.process_tx_barcode_gen(i_c, r, com);
+            let out = server.process_tx_barcode_gen(i_c, r, com).unwrap();
             tx.uid_b = Some(out.0);
             tx.barcode = Some(out.1);
             tx.pk_b = Some(out.2);
@@ -533,10 +593,11 @@ fn main() {
             let com = tx.com.unwrap();
             let points = tx.points;
 
-            let out = shopper.process_tx(pi_merkle, barcode, points, pk_b, com);
-            tx.cts = Some(out.0);
-            tx.ctb = Some(out.1);
-            tx.pi_tx = Some(out.2);
+            let out = shopper.process_tx(pi_merkle, barcode, &[points], pk_b, com).unwrap();
+            let (cts, ctb, pi_tx) = out.into_iter().next().unwrap();
+            tx.cts = Some(cts);
+            tx.ctb = Some(ctb);
+            tx.pi_tx = Some(pi_tx);
         }
         time_client += now.elapsed();
         // -----------------------------
@@ -544,12 +605,12 @@ fn main() {
         // -----------------------------
         let now = Instant::now();
         for tx in &mut txs {
-            let cts = tx.cts.clone().unwrap();
-            let ctb = tx.ctb.clone().unwrap();
+            let cts = tx.cts.unwrap();
+            let ctb = tx.ctb.unwrap();
             let pi_tx = tx.pi_tx.clone().unwrap();
             let com = tx.com.unwrap();
 
-            server.process_tx(tx.uid_s, cts, ctb, pi_tx, com);
+            server.process_tx(vec![(cts, ctb, pi_tx)], com).unwrap();
         }
         time_server += now.elapsed();
         // -----------------------------
@@ -564,13 +625,15 @@ fn main() {
     println!("------------------------");
     println!("--- Balance Settling --- (number of points)");
     println!("------------------------");
-    // Scales with number of points in balance.
+    // Scales with the absolute number of points in balance. Balances are
+    // negative when a shopper's swapped barcodes earned more than they did.
     // Process with varying numbers of points
 
     let n_settles = 20;
-    let min_points = 0;
+    let mut min_points = -2000;
     let mut max_points = 2000;
     if DEBUG {
+        min_points = -50;
         max_points = 50;
     }
     let step = 25;
@@ -579,22 +642,16 @@ fn main() {
     let mut server = lib_sh::Server::new();
     let mut client = lib_sh::Client::new(1);
     let client_data = client.register_with_server();
-    server.register_user(client_data.0, client_data.1);
-    let server_data = server.share_state();
-    client.update_state(server_data.0, server_data.1);
-    let ct = lib_sh::crypto_sh::elgamal_enc(client.pk_enc, min_points);
-    server.users.get_mut(&0).unwrap().balance = lib_sh::crypto_sh::add_ciphertexts(
-        server.users[&0u32].balance, (ct.0, ct.1)
-    );
-    let _balance = server.settle_balance_hello(0);
-    let mut proofs = Vec::<lib_sh::crypto_sh::CompressedCtDecProof>::with_capacity(n_settles);
+    server.register_user(client_data.0, client_data.1, client_data.2).unwrap();
+    let server_data = server.share_state().unwrap();
+    client.update_state(0, server_data.0, server_data.1, server_data.2, server_data.3, server_data.4.clone());
     for _i in 0..n_settles {
-        let out = client.settle_balance((ct.0, ct.1));
-        proofs.push(out.1);
-    }
-    for i in 0..n_settles {
-        let test = server.settle_balance_finalize(proofs[i].clone());
-        assert!(test);
+        // Settling resets the balance, so the points are put back every time
+        server.bench_set_balance(0, DEFAULT_ACCOUNT, min_points).unwrap();
+        let (balance, _, status) = server.settle_balance_hello(0, DEFAULT_ACCOUNT).unwrap();
+        let (x, pi) = client.settle_balance(balance, status).unwrap();
+        let settlement = server.settle_balance_finalize(0, DEFAULT_ACCOUNT, x, pi).unwrap();
+        client.finish_settlement(settlement).unwrap();
     }
 
     for n_points in (min_points..(max_points+1)).step_by(step) {
@@ -604,33 +661,30 @@ fn main() {
         let mut client = lib_sh::Client::new(1);
 
         let client_data = client.register_with_server();
-        server.register_user(client_data.0, client_data.1);
-        let server_data = server.share_state();
-        client.update_state(server_data.0, server_data.1);
-
-        // Insert the correct number of points into the client's account
-        let ct = lib_sh::crypto_sh::elgamal_enc(client.pk_enc, n_points);
-        server.users.get_mut(&0).unwrap().balance = lib_sh::crypto_sh::add_ciphertexts(
-            server.users[&0u32].balance, (ct.0, ct.1)
-        );
-        let _balance = server.settle_balance_hello(0);
+        server.register_user(client_data.0, client_data.1, client_data.2).unwrap();
+        let server_data = server.share_state().unwrap();
+        client.update_state(0, server_data.0, server_data.1, server_data.2, server_data.3, server_data.4.clone());
 
-        let mut proofs = Vec::<lib_sh::crypto_sh::CompressedCtDecProof>::with_capacity(n_settles);
-
-        // Settle balances
-        let now = Instant::now();
+        let mut time_client = Duration::ZERO;
+        let mut time_server = Duration::ZERO;
         for _i in 0..n_settles {
-            let out = client.settle_balance((ct.0, ct.1));
-            proofs.push(out.1);
-        }
-        let time_client = now.elapsed();
-
-        let now = Instant::now();
-        for i in 0..n_settles {
-            let test = server.settle_balance_finalize(proofs[i].clone());
-            assert!(test);
+            // Insert the correct number of points into the client's account.
+            // Settling resets the balance, so this is done before every settle.
+            server.bench_set_balance(0, DEFAULT_ACCOUNT, n_points).unwrap();
+            let (balance, _, status) = server.settle_balance_hello(0, DEFAULT_ACCOUNT).unwrap();
+
+            // Settle balances
+            let now = Instant::now();
+            let (x, pi) = client.settle_balance(balance, status).unwrap();
+            time_client += now.elapsed();
+
+            let now = Instant::now();
+            let test = server.settle_balance_finalize(0, DEFAULT_ACCOUNT, x, pi);
+            time_server += now.elapsed();
+
+            assert!(test.is_ok());
+            client.finish_settlement(test.unwrap()).unwrap();
         }
-        let time_server = now.elapsed();
         
         let res = format!("{: <10} {: <10} {: <10.3?} {: <10} {: <10.3?}",
             n_points,
@@ -639,15 +693,15 @@ fn main() {
         println!("{}", res);
     }
 
-    println!("");
+    println!();
     println!("Semihonest protocol (Barcode swapping only)");
-    println!("");
+    println!();
 
     println!("---------------------------");
     println!("--- Client Registration --- (User ID)");
     println!("---------------------------");
 
-    let mut server = Server::new();
+    let server = Server::new();
     let mut clients = Vec::<Client>::with_capacity(N_CLIENTS);
 
     for i in 0..N_CLIENTS {
@@ -665,7 +719,7 @@ fn main() {
         let barcode = client_data.0;
         let pk = client_data.1;
 
-        server.register_user(barcode, pk);
+        server.register_user(barcode, pk, client_data.2).unwrap();
         let time_server = now.elapsed();
 
         let res = format!("{: <10} {: <10} {: <10.3?} {: <10} {: <10.3?}",
@@ -682,8 +736,9 @@ fn main() {
     struct TxOldSwapOnly {
         uid_s: u32,
         com: Option<Com>,
-        i_s: Option<u32>,
-        i_c: Option<u32>,
+        sigma_hello: Option<Signature>,
+        i_s: Option<ServerShare>,
+        i_c: Option<ClientShare>,
         r: Option<[u8; 32]>,
         uid_b: Option<u32>,
         barcode: Option<u64>,
@@ -700,7 +755,7 @@ fn main() {
     }
     let step = min_users;
 
-    let mut server = lib_sh_swap_only::Server::new();
+    let server = lib_sh_swap_only::Server::new();
     let mut clients = Vec::<lib_sh_swap_only::Client>::with_capacity(max_users);
 
     // Initialize a system with a certain number of users,
@@ -716,13 +771,13 @@ fn main() {
             clients.push(c);
 
             let client_data = clients.last_mut().unwrap().register_with_server();
-            server.register_user(client_data);
+            server.register_user(client_data.0, client_data.1).unwrap();
         }
 
         // Inform every user of the new merkle root
-        let server_data = server.share_state();
-        for i in 0..n_users {
-            clients[i].update_state(server_data.0, server_data.1);
+        let server_data = server.share_state().unwrap();
+        for (i, client) in clients.iter_mut().enumerate().take(n_users) {
+            client.update_state(i.try_into().unwrap(), server_data.0, server_data.1, server_data.2.clone());
         }
 
         // Process transactions
@@ -732,6 +787,7 @@ fn main() {
             txs.push(TxOldSwapOnly {
                 uid_s: shopper_uid,
                 com: None,
+                sigma_hello: None,
                 i_s: None,
                 i_c: None,
                 r: None,
@@ -745,8 +801,9 @@ fn main() {
         let now = Instant::now();
         for tx in &mut txs {
             let shopper: &mut lib_sh_swap_only::Client = &mut clients[tx.uid_s as usize];
-            let com = shopper.process_tx_hello();
+            let (com, sigma_hello) = shopper.process_tx_hello(DEFAULT_MERCHANT);
             tx.com = Some(com);
+            tx.sigma_hello = Some(sigma_hello);
         }
         time_client += now.elapsed();
         // -----------------------------
@@ -754,7 +811,7 @@ fn main() {
         // -----------------------------
         let now = Instant::now();
         for tx in &mut txs {
-            let i_s = server.process_tx_hello_response(tx.com.unwrap());
+            let i_s = server.process_tx_hello_response(tx.com.unwrap(), tx.uid_s, DEFAULT_MERCHANT, &tx.sigma_hello.unwrap()).unwrap();
             tx.i_s = Some(i_s);
         }
         time_server += now.elapsed();
@@ -763,10 +820,10 @@ fn main() {
         // -----------------------------
         let now = Instant::now();
         for tx in &mut txs {
-            let i_s = tx.i_s.unwrap();
+            let i_s = tx.i_s.take().unwrap();
             let com = tx.com.unwrap();
             let shopper = &mut clients[tx.uid_s as usize];
-            let i_c_r = shopper.process_tx_compute_id(i_s, com);
+            let i_c_r = shopper.process_tx_compute_id(i_s, com).unwrap();
             tx.i_c = Some(i_c_r.0);
             tx.r = Some(i_c_r.1);
         }
@@ -780,7 +837,7 @@ fn main() {
             let r = tx.r.unwrap();
             let com = tx.com.unwrap();
 
-            let out = server.process_tx_barcode_gen(i_c, r, com);
+            let out = server.process_tx_barcode_gen(i_c, r, com).unwrap();
             tx.uid_b = Some(out.0);
             tx.barcode = Some(out.1);
             tx.pi_merkle = Some(out.2);
@@ -797,7 +854,7 @@ fn main() {
             let barcode = tx.barcode.unwrap();
             let com = tx.com.unwrap();
 
-            shopper.process_tx(pi_merkle, barcode, com);
+            shopper.process_tx(pi_merkle, barcode, com).unwrap();
         }
         time_client += now.elapsed();
         // -----------------------------
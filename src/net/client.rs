@@ -0,0 +1,161 @@
+use std::marker::PhantomData;
+use std::net::{TcpStream, ToSocketAddrs};
+use ed25519_dalek::Signature;
+use crate::error::{CheckoutError, Result};
+use crate::net::{read_frame, write_frame};
+use crate::scheme::{Account, Com, LoyaltyScheme, MerchantId, SchemeClient, MAX_RECEIPT_PAGE};
+use crate::wire::{Envelope, Message, NO_TX};
+
+// A client's connection to a CheckOut server running scheme `S`. The methods
+// mirror `scheme::transact` and `scheme::settle`, with each server call made
+// over the network.
+pub struct Connection<S: LoyaltyScheme> {
+    stream: TcpStream,
+    scheme: PhantomData<S>,
+}
+
+impl<S: LoyaltyScheme> Connection<S> {
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self> {
+        let stream = TcpStream::connect(addr).map_err(|_| CheckoutError::Network)?;
+        Ok(Connection { stream, scheme: PhantomData })
+    }
+
+    // Sends one message and waits for the server's reply
+    pub fn call(&mut self, tx_id: Com, msg: Message<S>) -> Result<Message<S>> {
+        let env = Envelope::new(tx_id, msg);
+        write_frame(&mut self.stream, &env.to_bytes()).map_err(|_| CheckoutError::Network)?;
+        let bytes = read_frame(&mut self.stream).map_err(|_| CheckoutError::Network)?;
+
+        let reply = Envelope::<S>::from_bytes(&bytes)?;
+        match reply.msg {
+            Message::Error(e) => Err(e),
+            _ if reply.tx_id != tx_id => Err(CheckoutError::UnexpectedMessage),
+            msg => Ok(msg),
+        }
+    }
+
+    // Registers `client` with the server and returns its user ID
+    pub fn register(&mut self, client: &S::Client) -> Result<u32> {
+        match self.call(NO_TX, Message::Register(client.register_with_server()))? {
+            Message::Registered { uid } => Ok(uid),
+            _ => Err(CheckoutError::UnexpectedMessage),
+        }
+    }
+
+    // Fetches the current Merkle root, which must be done after new users
+    // register and before the next transaction
+    pub fn update_state(&mut self, client: &mut S::Client, uid: u32) -> Result<()> {
+        match self.call(NO_TX, Message::GetState)? {
+            Message::State { num_users, root, server_id, max_points, policy } => {
+                client.update_state(uid, num_users, root, server_id, max_points, policy);
+                Ok(())
+            }
+            _ => Err(CheckoutError::UnexpectedMessage),
+        }
+    }
+
+    // Runs one full transaction in which the shopper earns `points` with
+    // `merchant`, one amount for each of the server's categories. If any step
+    // fails, the shopper's secrets for the transaction are wiped and the
+    // server is asked to drop it.
+    pub fn transact(&mut self, shopper: &mut S::Client, uid_s: u32, merchant: MerchantId, points: &[i32]) -> Result<()> {
+        let (com, sigma) = shopper.process_tx_hello(merchant);
+        let res = self.transact_steps(shopper, (uid_s, merchant, sigma), points, com);
+        if res.is_err() {
+            shopper.forget_tx(com);
+            // If this fails too, the server drops the transaction once it expires
+            let _ = self.abort(com);
+        }
+        res
+    }
+
+    // Tells the server to drop a transaction the client has given up on
+    pub fn abort(&mut self, tx_id: Com) -> Result<()> {
+        match self.call(tx_id, Message::TxAbort)? {
+            Message::TxAborted => Ok(()),
+            _ => Err(CheckoutError::UnexpectedMessage),
+        }
+    }
+
+    fn transact_steps(&mut self, shopper: &mut S::Client, (uid_s, merchant, sigma): (u32, MerchantId, Signature),
+                      points: &[i32], com: Com) -> Result<()> {
+        let i_s = match self.call(com, Message::TxHello { uid_s, merchant, sigma })? {
+            Message::TxHelloResponse { i_s } => i_s,
+            _ => return Err(CheckoutError::UnexpectedMessage),
+        };
+
+        let (i_c, r) = shopper.process_tx_compute_id(i_s, com)?;
+        let bg = match self.call(com, Message::TxOpen { i_c, r })? {
+            Message::BarcodeGen(bg) => bg,
+            _ => return Err(CheckoutError::UnexpectedMessage),
+        };
+
+        let tx = shopper.process_tx(&bg, points, com)?;
+        let sigma = match self.call(com, Message::TxSubmit(tx))? {
+            Message::TxSignature(sigma) => sigma,
+            _ => return Err(CheckoutError::UnexpectedMessage),
+        };
+
+        shopper.process_tx_coda(sigma, com)
+    }
+
+    // Fetches and processes all pending receipts for a user, one page at a
+    // time. Each page is acknowledged once processed, so if the connection
+    // drops, calling this again picks up where it left off.
+    pub fn fetch_receipts(&mut self, client: &mut S::Client, uid: u32) -> Result<()> {
+        let mut after = 0;
+        loop {
+            let (rcts, last, more) = match self.call(NO_TX, Message::FetchReceipts { uid, after, limit: MAX_RECEIPT_PAGE })? {
+                Message::ReceiptPage { uid: u, rcts, last, more } if u == uid => (rcts, last, more),
+                _ => return Err(CheckoutError::UnexpectedMessage),
+            };
+            client.process_receipts(rcts)?;
+
+            let sigma = client.ack_receipts(last);
+            match self.call(NO_TX, Message::AckReceipts { uid, upto: last, sigma })? {
+                Message::Acked { uid: u } if u == uid => {}
+                _ => return Err(CheckoutError::UnexpectedMessage),
+            }
+            if !more {
+                return Ok(());
+            }
+            after = last;
+        }
+    }
+
+    // Delivers pending receipts to a user and settles their balance in an
+    // account
+    pub fn settle(&mut self, client: &mut S::Client, uid: u32, account: Account) -> Result<()> {
+        self.fetch_receipts(client, uid)?;
+
+        let hello = match self.call(NO_TX, Message::SettleStart { uid, account })? {
+            Message::SettleHello { uid: u, hello } if u == uid => hello,
+            _ => return Err(CheckoutError::UnexpectedMessage),
+        };
+
+        let req = client.settle_balance(hello)?;
+        let settlement = match self.call(NO_TX, Message::SettleRequest { uid, account, req })? {
+            Message::SettleResult { uid: u, result } if u == uid => result?,
+            _ => return Err(CheckoutError::UnexpectedMessage),
+        };
+        client.finish_settlement(settlement)
+    }
+
+    // Delivers pending receipts to a user and redeems `points` of their
+    // balance in an account
+    pub fn redeem(&mut self, client: &mut S::Client, uid: u32, account: Account, points: u32) -> Result<()> {
+        self.fetch_receipts(client, uid)?;
+
+        let hello = match self.call(NO_TX, Message::SettleStart { uid, account })? {
+            Message::SettleHello { uid: u, hello } if u == uid => hello,
+            _ => return Err(CheckoutError::UnexpectedMessage),
+        };
+
+        let req = client.redeem(hello, points)?;
+        let redemption = match self.call(NO_TX, Message::RedeemRequest { uid, account, req })? {
+            Message::RedeemResult { uid: u, result } if u == uid => result?,
+            _ => return Err(CheckoutError::UnexpectedMessage),
+        };
+        client.finish_redemption(redemption)
+    }
+}
@@ -0,0 +1,71 @@
+// Running the protocol over TCP. Each frame on the connection is a 4-byte
+// big-endian length followed by one encoded `wire::Envelope`. The client
+// sends a request and waits for the server's reply before sending the next.
+
+use std::io::{self, Read, Write};
+
+pub mod client;
+pub mod server;
+
+pub use client::Connection;
+pub use server::{handle, serve};
+
+// Largest frame either side will accept. Settle requests grow with the number
+// of transactions a user has made; this leaves plenty of room for them.
+pub const MAX_FRAME_LEN: u32 = 16 * 1024 * 1024;
+
+pub fn write_frame<W: Write>(w: &mut W, bytes: &[u8]) -> io::Result<()> {
+    let len: u32 = bytes.len().try_into()
+        .ok().filter(|&len| len <= MAX_FRAME_LEN)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "frame too long"))?;
+    w.write_all(&len.to_be_bytes())?;
+    w.write_all(bytes)?;
+    w.flush()
+}
+
+pub fn read_frame<R: Read>(r: &mut R) -> io::Result<Vec<u8>> {
+    let mut len = [0u8; 4];
+    r.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len);
+    if len > MAX_FRAME_LEN {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "frame too long"));
+    }
+
+    let mut bytes = vec![0u8; len as usize];
+    r.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frame_round_trip() {
+        let mut bytes = Vec::new();
+        write_frame(&mut bytes, b"hello").unwrap();
+        write_frame(&mut bytes, b"").unwrap();
+        assert_eq!(bytes[..4], 5u32.to_be_bytes());
+
+        let mut r = bytes.as_slice();
+        assert_eq!(read_frame(&mut r).unwrap(), b"hello");
+        assert_eq!(read_frame(&mut r).unwrap(), b"");
+        assert_eq!(read_frame(&mut r).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn frame_too_long() {
+        let bytes = vec![0u8; MAX_FRAME_LEN as usize + 1];
+        assert_eq!(write_frame(&mut Vec::new(), &bytes).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+
+        // The length is refused before anything is read for it
+        let mut r: &[u8] = &(MAX_FRAME_LEN + 1).to_be_bytes();
+        assert_eq!(read_frame(&mut r).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn frame_cut_short() {
+        let mut r: &[u8] = &[0, 0, 0, 5, 1, 2];
+        assert_eq!(read_frame(&mut r).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,195 @@
+use std::io;
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use crate::error::{CheckoutError, Result};
+use crate::net::{read_frame, write_frame};
+use crate::session;
+use crate::scheme::{Com, LoyaltyScheme, SchemeServer, MAX_RECEIPT_PAGE};
+use crate::wire::{Envelope, Message, NO_TX};
+
+// Accepts connections on `listener` forever, serving each one on its own
+// thread. All connections share `server`, and another thread drops its
+// abandoned transactions and expired points every `session::SWEEP_INTERVAL`.
+pub fn serve<S: LoyaltyScheme>(listener: TcpListener, server: S::Server) -> io::Result<()>
+where S::Server: 'static {
+    serve_sweeping::<S>(listener, server, session::SWEEP_INTERVAL)
+}
+
+// `serve`, sweeping every `interval`
+fn serve_sweeping<S: LoyaltyScheme>(listener: TcpListener, server: S::Server, interval: Duration) -> io::Result<()>
+where S::Server: 'static {
+    let server = Arc::new(server);
+    let sweeper = Arc::clone(&server);
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+        sweeper.sweep_txs();
+        // If this fails, the points are dropped at the next sweep
+        let _ = sweeper.retire_expired();
+    });
+
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let server = Arc::clone(&server);
+        thread::spawn(move || {
+            // A connection error only ends that connection
+            let _ = serve_connection::<S>(stream, &server);
+        });
+    }
+    Ok(())
+}
+
+fn serve_connection<S: LoyaltyScheme>(mut stream: TcpStream, server: &S::Server) -> io::Result<()> {
+    loop {
+        let bytes = match read_frame(&mut stream) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
+        };
+
+        let reply = match Envelope::<S>::from_bytes(&bytes) {
+            Ok(env) => handle::<S>(server, env),
+            Err(e) => Envelope::new(NO_TX, Message::Error(e)),
+        };
+        write_frame(&mut stream, &reply.to_bytes())?;
+    }
+}
+
+// Runs one request against the server and returns the reply to send back.
+// Failures are reported to the client as `Message::Error`.
+pub fn handle<S: LoyaltyScheme>(server: &S::Server, env: Envelope<S>) -> Envelope<S> {
+    let tx_id = env.tx_id;
+    let reply = match step::<S>(server, env.msg, tx_id) {
+        Ok(msg) => msg,
+        Err(e) => Message::Error(e),
+    };
+    Envelope::new(tx_id, reply)
+}
+
+fn step<S: LoyaltyScheme>(server: &S::Server, msg: Message<S>, tx_id: Com) -> Result<Message<S>> {
+    match msg {
+        Message::Register(reg) => {
+            let uid = server.register_user(reg)?;
+            Ok(Message::Registered { uid })
+        }
+        Message::GetState => {
+            let (num_users, root, server_id, max_points, policy) = server.share_state()?;
+            Ok(Message::State { num_users, root, server_id, max_points, policy })
+        }
+        Message::TxHello { uid_s, merchant, sigma } => {
+            let i_s = server.process_tx_hello_response(tx_id, uid_s, merchant, &sigma)?;
+            Ok(Message::TxHelloResponse { i_s })
+        }
+        Message::TxOpen { i_c, r } => {
+            Ok(Message::BarcodeGen(server.process_tx_barcode_gen(i_c, r, tx_id)?))
+        }
+        Message::TxSubmit(tx) => {
+            Ok(Message::TxSignature(server.process_tx(tx, tx_id)?))
+        }
+        Message::TxAbort => {
+            server.abort_tx(tx_id);
+            Ok(Message::TxAborted)
+        }
+        Message::GetReceipts { uid } => {
+            let (rcts, _, _) = server.send_receipts(uid, 0, MAX_RECEIPT_PAGE)?;
+            Ok(Message::ReceiptBatch { uid, rcts })
+        }
+        Message::FetchReceipts { uid, after, limit } => {
+            let (rcts, last, more) = server.send_receipts(uid, after, limit)?;
+            Ok(Message::ReceiptPage { uid, rcts, last, more })
+        }
+        Message::AckReceipts { uid, upto, sigma } => {
+            server.ack_receipts(uid, upto, &sigma)?;
+            Ok(Message::Acked { uid })
+        }
+        Message::SettleStart { uid, account } => {
+            let hello = server.settle_balance_hello(uid, account)?;
+            Ok(Message::SettleHello { uid, hello })
+        }
+        Message::SettleRequest { uid, account, req } => {
+            let result = server.settle_balance(uid, account, req);
+            Ok(Message::SettleResult { uid, result })
+        }
+        Message::RedeemRequest { uid, account, req } => {
+            let result = server.redeem(uid, account, req);
+            Ok(Message::RedeemResult { uid, result })
+        }
+        _ => Err(CheckoutError::UnexpectedMessage),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lib_mal::{self, Malicious};
+    use crate::lib_sh::SemiHonest;
+    use crate::net::Connection;
+    use crate::scheme::{DEFAULT_ACCOUNT, DEFAULT_MERCHANT};
+
+    #[test]
+    fn unexpected_message() {
+        let server = lib_mal::Server::new();
+        let reply = handle::<Malicious>(&server, Envelope::new([7; 32], Message::TxAborted));
+        assert_eq!(reply.tx_id, [7; 32]);
+        assert!(matches!(reply.msg, Message::Error(CheckoutError::UnexpectedMessage)));
+    }
+
+    #[test]
+    fn protocol_over_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || serve::<Malicious>(listener, lib_mal::Server::new()));
+
+        let mut conn = Connection::<Malicious>::connect(addr).unwrap();
+        let mut clients: Vec<lib_mal::Client> = (0..3).map(lib_mal::Client::new).collect();
+        for (uid, client) in clients.iter().enumerate() {
+            assert_eq!(conn.register(client).unwrap(), uid as u32);
+        }
+        for (uid, client) in clients.iter_mut().enumerate() {
+            conn.update_state(client, uid as u32).unwrap();
+        }
+        conn.transact(&mut clients[0], 0, DEFAULT_MERCHANT, &[10]).unwrap();
+        conn.settle(&mut clients[0], 0, DEFAULT_ACCOUNT).unwrap();
+
+        // A message for another scheme is answered with an error, and the
+        // connection stays open
+        let mut stream = TcpStream::connect(addr).unwrap();
+        for _ in 0..2 {
+            write_frame(&mut stream, &Envelope::<SemiHonest>::new(NO_TX, Message::GetState).to_bytes()).unwrap();
+            let reply = Envelope::<Malicious>::from_bytes(&read_frame(&mut stream).unwrap()).unwrap();
+            assert!(matches!(reply.msg, Message::Error(CheckoutError::WrongScheme)));
+        }
+    }
+
+    #[test]
+    fn abandoned_transactions_are_swept() {
+        let mut server = lib_mal::Server::new();
+        server.set_tx_ttl(Duration::from_millis(50));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || serve_sweeping::<Malicious>(listener, server, Duration::from_millis(10)));
+
+        let mut conn = Connection::<Malicious>::connect(addr).unwrap();
+        let mut clients: Vec<lib_mal::Client> = (0..3).map(lib_mal::Client::new).collect();
+        for (uid, client) in clients.iter().enumerate() {
+            assert_eq!(conn.register(client).unwrap(), uid as u32);
+        }
+        for (uid, client) in clients.iter_mut().enumerate() {
+            conn.update_state(client, uid as u32).unwrap();
+        }
+
+        // The shopper says hello and goes quiet
+        let (com, sigma) = clients[0].process_tx_hello(DEFAULT_MERCHANT);
+        let i_s = match conn.call(com, Message::TxHello { uid_s: 0, merchant: DEFAULT_MERCHANT, sigma }).unwrap() {
+            Message::TxHelloResponse { i_s } => i_s,
+            _ => panic!("unexpected reply"),
+        };
+        let (i_c, r) = clients[0].process_tx_compute_id(i_s, com).unwrap();
+        thread::sleep(Duration::from_millis(300));
+
+        // A transaction that had only expired would be refused as expired;
+        // this one is gone
+        assert_eq!(conn.call(com, Message::TxOpen { i_c, r }).err(), Some(CheckoutError::UnknownTx));
+    }
+}
@@ -0,0 +1,270 @@
+// Range proofs: a proof that a Pedersen commitment C = v*g + r*h opens to an
+// amount v with 0 <= v <= max, without revealing v.
+//
+// v and max - v are each written with n bits, where n is the bit length of
+// max, and every bit is committed to on its own. Each bit commitment comes
+// with a proof that it opens to 0 or 1 (an OR of two Schnorr proofs), and the
+// bit commitments must add up to C and to max*g - C. Both amounts are then in
+// [0, 2^n), so v is in [0, max].
+
+use rand_core::OsRng;
+use curve25519_dalek::ristretto::RistrettoPoint;
+use curve25519_dalek::scalar::Scalar;
+use curve25519_dalek::traits::{IsIdentity, VartimeMultiscalarMul};
+use serde_derive::{Serialize, Deserialize};
+use crate::error::{CheckoutError, Result};
+use crate::transcript::Transcript;
+
+type Point = RistrettoPoint;
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RangeProof {
+    cs: Vec<Point>,     // Bit commitments: n for v, then n for max - v
+    a0s: Vec<Point>,    // Commitments for the "bit is 0" branch
+    a1s: Vec<Point>,    // Commitments for the "bit is 1" branch
+    c0s: Vec<Scalar>,   // Challenge of the "bit is 0" branch (the other is c - c0)
+    z0s: Vec<Scalar>,
+    z1s: Vec<Scalar>
+}
+
+// Number of bits needed to write any amount up to max
+fn bit_length(max: u32) -> usize {
+    (32 - max.leading_zeros()).max(1) as usize
+}
+
+// Binds the bases g and h, the commitment, the maximum and the prover's
+// commitments to the transcript.
+// Output: the challenge
+fn range_challenge(transcript: &mut Transcript, statement: [Point; 3], max: u32,
+                   cs: &[Point], a0s: &[Point], a1s: &[Point]) -> Scalar {
+    let statement_labels: [&'static [u8]; 3] = [b"g", b"h", b"com"];
+
+    transcript.domain_sep(b"range proof");
+    for (label, p) in statement_labels.into_iter().zip(statement) {
+        transcript.append_point(label, &p.compress().to_bytes());
+    }
+    transcript.append_u64(b"max", max as u64);
+    for ((c, a0), a1) in cs.iter().zip(a0s).zip(a1s) {
+        transcript.append_point(b"c", &c.compress().to_bytes());
+        transcript.append_point(b"a0", &a0.compress().to_bytes());
+        transcript.append_point(b"a1", &a1.compress().to_bytes());
+    }
+    transcript.challenge_scalar(b"c")
+}
+
+// Input: amount v and blinding r such that the commitment is v*g + r*h
+// Output: a proof that v <= max, or an error if it is not
+pub fn prove(transcript: &mut Transcript, v: u32, r: Scalar, g: Point, h: Point, max: u32) -> Result<RangeProof> {
+    let n = bit_length(max);
+    let rest = max.checked_sub(v).ok_or(CheckoutError::PointsOutOfRange)?;
+
+    // The bits of v and of max - v, and blindings for their commitments. The
+    // blindings are weighted by powers of two and add up to r and -r
+    let mut bits = Vec::<bool>::with_capacity(2 * n);
+    let mut rs = Vec::<Scalar>::with_capacity(2 * n);
+    for (amount, blinding) in [(v, r), (rest, -r)] {
+        let mut sum = Scalar::zero();
+        let mut power = Scalar::one();
+        let first = rs.len();
+        for i in 0..n {
+            let r_i = Scalar::random(&mut OsRng);
+            if i > 0 {
+                sum += power * r_i;
+            }
+            power += power;
+            bits.push((amount >> i) & 1 == 1);
+            rs.push(r_i);
+        }
+        rs[first] = blinding - sum;
+    }
+
+    // For each bit, a real Schnorr commitment for the branch that holds, and a
+    // simulated transcript for the one that doesn't
+    let mut cs = Vec::with_capacity(2 * n);
+    let mut a0s = Vec::with_capacity(2 * n);
+    let mut a1s = Vec::with_capacity(2 * n);
+    let mut ks = Vec::with_capacity(2 * n);
+    let mut sims = Vec::with_capacity(2 * n);
+    for (bit, r_i) in bits.iter().zip(&rs) {
+        let c_i = if *bit { g + r_i * h } else { r_i * h };
+        let k = Scalar::random(&mut OsRng);
+        let c_sim = Scalar::random(&mut OsRng);
+        let z_sim = Scalar::random(&mut OsRng);
+        if *bit {
+            a0s.push(z_sim * h - c_sim * c_i);
+            a1s.push(k * h);
+        } else {
+            a0s.push(k * h);
+            a1s.push(z_sim * h - c_sim * (c_i - g));
+        }
+        cs.push(c_i);
+        ks.push(k);
+        sims.push((c_sim, z_sim));
+    }
+
+    let com = Scalar::from(v) * g + r * h;
+    let c = range_challenge(transcript, [g, h, com], max, &cs, &a0s, &a1s);
+
+    let mut c0s = Vec::with_capacity(2 * n);
+    let mut z0s = Vec::with_capacity(2 * n);
+    let mut z1s = Vec::with_capacity(2 * n);
+    for (((bit, r_i), k), (c_sim, z_sim)) in bits.iter().zip(&rs).zip(&ks).zip(&sims) {
+        let c_real = c - c_sim;
+        let z_real = k + c_real * r_i;
+        if *bit {
+            c0s.push(*c_sim);
+            z0s.push(*z_sim);
+            z1s.push(z_real);
+        } else {
+            c0s.push(c_real);
+            z0s.push(z_real);
+            z1s.push(*z_sim);
+        }
+    }
+
+    Ok(RangeProof { cs, a0s, a1s, c0s, z0s, z1s })
+}
+
+// Every equation of the proof, weighted by a fresh random scalar, as terms of
+// a multiscalar multiplication that comes to the identity if the proof is
+// valid. Used directly by verifiers that check many proofs at once.
+// Input: the commitment C and the bases it was made with
+// Output: the scalars and points of the terms
+pub fn verification_terms(transcript: &mut Transcript, com: Point, g: Point, h: Point, max: u32,
+                          pi: &RangeProof) -> Result<(Vec<Scalar>, Vec<Point>)> {
+    let n = bit_length(max);
+    let lens = [pi.cs.len(), pi.a0s.len(), pi.a1s.len(), pi.c0s.len(), pi.z0s.len(), pi.z1s.len()];
+    if lens.iter().any(|&len| len != 2 * n) {
+        return Err(CheckoutError::InvalidProof);
+    }
+
+    let c = range_challenge(transcript, [g, h, com], max, &pi.cs, &pi.a0s, &pi.a1s);
+
+    // Each bit: z0*h = a0 + c0*C_i and z1*h = a1 + c1*(C_i - g). The bits of v
+    // add up to C, and those of max - v to max*g - C.
+    let w_low = Scalar::random(&mut OsRng);
+    let w_high = Scalar::random(&mut OsRng);
+    let mut g_scalar = -(w_high * Scalar::from(max));
+    let mut h_scalar = Scalar::zero();
+    let mut scalars = Vec::<Scalar>::with_capacity(6 * n + 3);
+    let mut points = Vec::<Point>::with_capacity(6 * n + 3);
+
+    let mut power = Scalar::one();
+    for i in 0..(2 * n) {
+        if i == n {
+            power = Scalar::one();
+        }
+        let w_sum = if i < n { w_low } else { w_high };
+        let w0 = Scalar::random(&mut OsRng);
+        let w1 = Scalar::random(&mut OsRng);
+        let c0 = pi.c0s[i];
+        let c1 = c - c0;
+
+        h_scalar += w0 * pi.z0s[i] + w1 * pi.z1s[i];
+        g_scalar += w1 * c1;
+        scalars.push(-w0);
+        points.push(pi.a0s[i]);
+        scalars.push(-w1);
+        points.push(pi.a1s[i]);
+        scalars.push(w_sum * power - w0 * c0 - w1 * c1);
+        points.push(pi.cs[i]);
+
+        power += power;
+    }
+    scalars.push(w_high - w_low);
+    points.push(com);
+    scalars.push(g_scalar);
+    points.push(g);
+    scalars.push(h_scalar);
+    points.push(h);
+
+    Ok((scalars, points))
+}
+
+// Input: the commitment C, the bases it was made with, and the proof
+pub fn verify(transcript: &mut Transcript, com: Point, g: Point, h: Point, max: u32, pi: &RangeProof) -> Result<()> {
+    let (scalars, points) = verification_terms(transcript, com, g, h, max, pi)?;
+
+    if Point::vartime_multiscalar_mul(&scalars, &points).is_identity() { Ok(()) }
+    else { Err(CheckoutError::InvalidProof) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use curve25519_dalek::constants;
+    use sha2::Sha512;
+
+    const MAX: u32 = 1000;
+
+    fn bases() -> (Point, Point) {
+        (constants::RISTRETTO_BASEPOINT_POINT, Point::hash_from_bytes::<Sha512>(b"range test h"))
+    }
+
+    fn transcript() -> Transcript {
+        Transcript::for_tx(&[1; 32], &[2; 32], 0)
+    }
+
+    // Output: a commitment to v and a proof that it is at most max
+    fn commit_and_prove(v: u32, max: u32) -> Result<(Point, RangeProof)> {
+        let (g, h) = bases();
+        let r = Scalar::random(&mut OsRng);
+        let pi = prove(&mut transcript(), v, r, g, h, max)?;
+        Ok((Scalar::from(v) * g + r * h, pi))
+    }
+
+    #[test]
+    fn round_trip() {
+        let (g, h) = bases();
+        for (v, max) in [(0, MAX), (1, MAX), (MAX - 1, MAX), (MAX, MAX), (0, 0), (1, 1), (u32::MAX, u32::MAX)] {
+            let (com, pi) = commit_and_prove(v, max).unwrap();
+            assert_eq!(verify(&mut transcript(), com, g, h, max, &pi), Ok(()), "{} <= {}", v, max);
+        }
+    }
+
+    #[test]
+    fn amount_above_max() {
+        let (g, h) = bases();
+        assert_eq!(commit_and_prove(MAX + 1, MAX).err(), Some(CheckoutError::PointsOutOfRange));
+
+        // A proof against a larger maximum with as many bits does not pass
+        // for the smaller one
+        let (com, pi) = commit_and_prove(MAX + 1, 1023).unwrap();
+        assert_eq!(verify(&mut transcript(), com, g, h, MAX, &pi), Err(CheckoutError::InvalidProof));
+    }
+
+    #[test]
+    fn negative_amount() {
+        let (g, h) = bases();
+        assert_eq!(commit_and_prove(-1i32 as u32, MAX).err(), Some(CheckoutError::PointsOutOfRange));
+
+        // A proof for 0 does not cover the commitment to -1 with the same
+        // blinding
+        let (com, pi) = commit_and_prove(0, MAX).unwrap();
+        assert_eq!(verify(&mut transcript(), com - g, g, h, MAX, &pi), Err(CheckoutError::InvalidProof));
+    }
+
+    #[test]
+    fn tampered_bit_commitment() {
+        let (g, h) = bases();
+        let (com, pi) = commit_and_prove(5, MAX).unwrap();
+        for i in [0, pi.cs.len() - 1] {
+            let mut tampered = pi.clone();
+            tampered.cs[i] += g;
+            assert_eq!(verify(&mut transcript(), com, g, h, MAX, &tampered), Err(CheckoutError::InvalidProof));
+        }
+
+        let mut truncated = pi.clone();
+        truncated.cs.pop();
+        assert_eq!(verify(&mut transcript(), com, g, h, MAX, &truncated), Err(CheckoutError::InvalidProof));
+    }
+
+    #[test]
+    fn bound_to_transcript() {
+        let (g, h) = bases();
+        let (com, pi) = commit_and_prove(5, MAX).unwrap();
+        for mut other in [Transcript::for_tx(&[3; 32], &[2; 32], 0), Transcript::for_tx(&[1; 32], &[3; 32], 0)] {
+            assert_eq!(verify(&mut other, com, g, h, MAX, &pi), Err(CheckoutError::InvalidProof));
+        }
+    }
+}
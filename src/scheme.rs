@@ -0,0 +1,368 @@
+use std::time::Duration;
+use rs_merkle::{algorithms, Hasher};
+use serde::{Serialize, de::DeserializeOwned};
+use ed25519_dalek::Signature;
+use crate::coin::{ClientShare, CoinFlip, SelectionPolicy, ServerShare};
+use crate::error::Result;
+
+pub type Com = [u8; 32];
+pub type Root = <algorithms::Sha256 as Hasher>::Hash;
+// Identifies a server in the proofs made for it (see `transcript`)
+pub type ServerId = [u8; 32];
+// Identifies one of the merchants that issue points through a server. Every
+// user has a separate balance with each merchant, while barcodes are swapped
+// among all of the server's users.
+pub type MerchantId = u32;
+
+// One of the kinds of points a server keeps, such as base points, bonus points
+// or fuel cents. A transaction moves an amount of every category at once, and
+// each category is kept in a balance of its own.
+pub type Category = u32;
+// A balance: the merchant it is with and the category of points it holds.
+// Every account is settled and redeemed on its own, with epochs of its own.
+pub type Account = (MerchantId, Category);
+
+// The merchant of a server that is set up with no others
+pub const DEFAULT_MERCHANT: MerchantId = 0;
+pub const DEFAULT_MERCHANT_NAME: &str = "default";
+
+// The category of a server that is set up with no others
+pub const DEFAULT_CATEGORY: Category = 0;
+pub const DEFAULT_CATEGORY_NAME: &str = "points";
+
+// The only account of a server with one merchant and one category
+pub const DEFAULT_ACCOUNT: Account = (DEFAULT_MERCHANT, DEFAULT_CATEGORY);
+
+// Most receipts a server returns from one `send_receipts` call
+pub const MAX_RECEIPT_PAGE: u32 = 1000;
+
+// Most points a single transaction may move, unless the server is configured
+// with another limit
+pub const DEFAULT_MAX_POINTS: u32 = 100_000;
+
+// The three CheckOut schemes, in increasing order of security. Application code
+// that is generic over `LoyaltyScheme` can pick one of these at runtime.
+// Sent on the wire as the scheme ID, so new levels must be added at the end.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde_derive::Serialize, serde_derive::Deserialize)]
+pub enum SecurityLevel {
+    SwapOnly,
+    SemiHonest,
+    Malicious,
+}
+
+// A CheckOut scheme: the server and client types that run it, and the messages
+// they exchange. Messages with no content in a given scheme (e.g. receipts in
+// the swap-only scheme) are `()`.
+pub trait LoyaltyScheme: Sized {
+    const LEVEL: SecurityLevel;
+
+    type Server: SchemeServer<Scheme = Self>;
+    type Client: SchemeClient<Scheme = Self>;
+
+    // Client -> server: data needed to register a new user
+    type Registration: Serialize + DeserializeOwned;
+    // Server -> client: barcode owner's details and Merkle inclusion proof
+    type BarcodeGen: Serialize + DeserializeOwned;
+    // Client -> server: the shopper's encrypted points and correctness proof
+    type TxSubmit: Serialize + DeserializeOwned;
+    // Server -> client: acknowledgment that completes a transaction
+    type TxSignature: Serialize + DeserializeOwned;
+    // Server -> client: receipts for transactions made with this user's barcode
+    type Receipts: Serialize + DeserializeOwned;
+    // Server -> client: the server's view of a balance to be settled
+    type SettleHello: Serialize + DeserializeOwned;
+    // Client -> server: the revealed balance and a proof that it is correct
+    type SettleRequest: Serialize + DeserializeOwned;
+    // Server -> client: the signed receipt for a settled balance (see
+    // `settlement`)
+    type Settlement: Serialize + DeserializeOwned;
+    // Client -> server: the amount to redeem and a proof that the balance
+    // covers it
+    type RedeemRequest: Serialize + DeserializeOwned;
+    // Server -> client: the signed receipt for a redemption (see `settlement`)
+    type Redemption: Serialize + DeserializeOwned;
+}
+
+// Servers are shared between threads: every step takes `&self` and the
+// implementation synchronizes its own state.
+pub trait SchemeServer: Send + Sync {
+    type Scheme: LoyaltyScheme;
+
+    // Registers a new user and returns their user ID
+    fn register_user(&self, reg: <Self::Scheme as LoyaltyScheme>::Registration) -> Result<u32>;
+
+    // Output: number of users, the root of the Merkle tree of registered users,
+    // the server's ID, the most points a single transaction may move, and
+    // which users the barcode coin flip may pick
+    fn share_state(&self) -> Result<(u32, Root, ServerId, u32, SelectionPolicy)>;
+
+    // Step 1 of a transaction request
+
+    // Input: commitment to the client's share of the coin flip, shopper user
+    // ID, the merchant the shopper is buying from, and the shopper's
+    // signature on them (see `auth`)
+    // Output: the server's share of the coin flip
+    fn process_tx_hello_response(&self, com: Com, uid_s: u32, merchant: MerchantId, sigma: &Signature) -> Result<ServerShare>;
+
+    // Step 2 of a transaction request
+
+    // Input: opened commitment contents: the client's share and mask
+    // Output: the barcode owner's details
+    fn process_tx_barcode_gen(&self, i_c: ClientShare, r: [u8; 32], tx_id: Com)
+        -> Result<<Self::Scheme as LoyaltyScheme>::BarcodeGen>;
+
+    // Step 3 of a transaction request
+    fn process_tx(&self, tx: <Self::Scheme as LoyaltyScheme>::TxSubmit, tx_id: Com)
+        -> Result<<Self::Scheme as LoyaltyScheme>::TxSignature>;
+
+    // Step 3 for many transactions at once. Schemes that make proofs check
+    // them all together, which is cheaper than checking each in turn.
+    // Output: for each transaction, in order, its signature or why it was rejected
+    fn process_tx_batch(&self, txs: Vec<(<Self::Scheme as LoyaltyScheme>::TxSubmit, Com)>)
+        -> Vec<Result<<Self::Scheme as LoyaltyScheme>::TxSignature>> {
+        txs.into_iter().map(|(tx, tx_id)| self.process_tx(tx, tx_id)).collect()
+    }
+
+    // Drops a transaction that the client has given up on, at whatever step
+    // it is. Transactions that take too long are also dropped on their own
+    // (see `session`).
+    fn abort_tx(&self, tx_id: Com);
+
+    // Drops every transaction that has taken too long.
+    // Output: how many were dropped
+    fn sweep_txs(&self) -> usize;
+
+    // Drops the parts of balances whose points have expired (see `expiry`).
+    // Output: how many were dropped
+    fn retire_expired(&self) -> Result<usize>;
+
+    // Receipt distribution. Receipts stay queued until they are acknowledged,
+    // so fetching again after a lost reply returns the same receipts.
+
+    // Input: user ID, sequence number of the last receipt already fetched (0
+    // for the oldest), maximum number of receipts to return
+    // Output: the receipts, the sequence number of the last one (the `after`
+    // for the next page), and whether more are queued after it
+    fn send_receipts(&self, uid: u32, after: u64, limit: u32)
+        -> Result<(<Self::Scheme as LoyaltyScheme>::Receipts, u64, bool)>;
+
+    // Removes a user's receipts up to and including sequence number `upto`,
+    // if the user signed the acknowledgment (see `auth`)
+    fn ack_receipts(&self, uid: u32, upto: u64, sigma: &Signature) -> Result<()>;
+
+    // Balance settling, for one account. Settling resets the user's balance
+    // in that account and starts their next epoch in it, so a request is
+    // only accepted once.
+    fn settle_balance_hello(&self, uid: u32, account: Account) -> Result<<Self::Scheme as LoyaltyScheme>::SettleHello>;
+    fn settle_balance(&self, uid: u32, account: Account, req: <Self::Scheme as LoyaltyScheme>::SettleRequest)
+        -> Result<<Self::Scheme as LoyaltyScheme>::Settlement>;
+
+    // Takes some points out of a balance without settling it. Redemptions
+    // start with `settle_balance_hello`, like settlements.
+    fn redeem(&self, uid: u32, account: Account, req: <Self::Scheme as LoyaltyScheme>::RedeemRequest)
+        -> Result<<Self::Scheme as LoyaltyScheme>::Redemption>;
+}
+
+// Clients are serializable so that their state can be saved in a wallet (see
+// `wallet`).
+pub trait SchemeClient: Serialize + DeserializeOwned {
+    type Scheme: LoyaltyScheme;
+
+    fn new(barcode: u64) -> Self;
+
+    fn register_with_server(&self) -> <Self::Scheme as LoyaltyScheme>::Registration;
+
+    fn update_state(&mut self, uid: u32, num_users: u32, merkle_root: Root, server_id: ServerId, max_points: u32,
+                    policy: SelectionPolicy);
+
+    // Sets the kind of share the client uses in the coin flip that picks the
+    // barcode (see `coin`)
+    fn set_coin_flip(&mut self, mode: CoinFlip);
+
+    // Step 1 of a transaction request
+
+    // Input: the merchant the shopper is buying from
+    // Output: commitment to the client's share of the coin flip, and the
+    // shopper's signature on it (see `auth`)
+    fn process_tx_hello(&mut self, merchant: MerchantId) -> (Com, Signature);
+
+    // Step 2 of a transaction request
+
+    // Input: the server's share of the coin flip
+    // Output: opened commitment to the client's share
+    fn process_tx_compute_id(&mut self, i_s: ServerShare, tx_id: Com) -> Result<(ClientShare, [u8; 32])>;
+
+    // Step 3 of a transaction request
+
+    // Input: the barcode owner's details, and the amount of each of the
+    // server's categories, in order
+    fn process_tx(&mut self, bg: &<Self::Scheme as LoyaltyScheme>::BarcodeGen, points: &[i32], tx_id: Com)
+        -> Result<<Self::Scheme as LoyaltyScheme>::TxSubmit>;
+
+    fn process_tx_coda(&mut self, sigma: <Self::Scheme as LoyaltyScheme>::TxSignature, tx_id: Com) -> Result<()>;
+
+    // Wipes the client's secrets for a transaction that will not be finished
+    fn forget_tx(&mut self, tx_id: Com);
+
+    // Wipes every transaction that started longer than `ttl` ago.
+    // Output: how many were wiped
+    fn expire_txs(&mut self, ttl: Duration) -> usize;
+
+    fn process_receipts(&mut self, rcts: <Self::Scheme as LoyaltyScheme>::Receipts) -> Result<()>;
+
+    // Output: the user's signature acknowledging their receipts up to and
+    // including sequence number `upto`, once they have processed them
+    fn ack_receipts(&self, upto: u64) -> Signature;
+
+    // Applies first any settlement the hello shows the client has missed
+    fn settle_balance(&mut self, hello: <Self::Scheme as LoyaltyScheme>::SettleHello)
+        -> Result<<Self::Scheme as LoyaltyScheme>::SettleRequest>;
+
+    // Resets the client's state once its balance is settled, and starts its
+    // next epoch
+    fn finish_settlement(&mut self, settlement: <Self::Scheme as LoyaltyScheme>::Settlement) -> Result<()>;
+
+    // Asks to redeem `points` of the balance, applying first any settlement
+    // or redemption the hello shows the client has missed
+    fn redeem(&mut self, hello: <Self::Scheme as LoyaltyScheme>::SettleHello, points: u32)
+        -> Result<<Self::Scheme as LoyaltyScheme>::RedeemRequest>;
+
+    // Takes the redeemed points out of the client's balance
+    fn finish_redemption(&mut self, redemption: <Self::Scheme as LoyaltyScheme>::Redemption) -> Result<()>;
+}
+
+// Runs one full transaction between a shopper and the server, in which the
+// shopper earns `points` with `merchant`, one amount for each of the server's
+// categories. If any step fails, the shopper's secrets for the transaction are
+// wiped and the server drops it.
+pub fn transact<S: LoyaltyScheme>(server: &S::Server, shopper: &mut S::Client, uid_s: u32, merchant: MerchantId,
+                                  points: &[i32]) -> Result<()> {
+    let (com, sigma) = shopper.process_tx_hello(merchant);
+    let res = transact_steps::<S>(server, shopper, (uid_s, merchant, &sigma), points, com);
+    if res.is_err() {
+        shopper.forget_tx(com);
+        server.abort_tx(com);
+    }
+    res
+}
+
+fn transact_steps<S: LoyaltyScheme>(server: &S::Server, shopper: &mut S::Client,
+                                    (uid_s, merchant, sigma): (u32, MerchantId, &Signature), points: &[i32],
+                                    com: Com) -> Result<()> {
+    let i_s = server.process_tx_hello_response(com, uid_s, merchant, sigma)?;
+    let (i_c, r) = shopper.process_tx_compute_id(i_s, com)?;
+    let bg = server.process_tx_barcode_gen(i_c, r, com)?;
+    let tx = shopper.process_tx(&bg, points, com)?;
+    let sigma = server.process_tx(tx, com)?;
+    shopper.process_tx_coda(sigma, com)
+}
+
+// Delivers all pending receipts to a user, one page at a time, acknowledging
+// each page once the client has processed it.
+pub fn deliver_receipts<S: LoyaltyScheme>(server: &S::Server, client: &mut S::Client, uid: u32) -> Result<()> {
+    let mut after = 0;
+    loop {
+        let (rcts, last, more) = server.send_receipts(uid, after, MAX_RECEIPT_PAGE)?;
+        client.process_receipts(rcts)?;
+        server.ack_receipts(uid, last, &client.ack_receipts(last))?;
+        if !more {
+            return Ok(());
+        }
+        after = last;
+    }
+}
+
+// Delivers all pending receipts to a user and settles their balance in
+// `account`.
+pub fn settle<S: LoyaltyScheme>(server: &S::Server, client: &mut S::Client, uid: u32, account: Account) -> Result<()> {
+    deliver_receipts::<S>(server, client, uid)?;
+
+    let hello = server.settle_balance_hello(uid, account)?;
+    let req = client.settle_balance(hello)?;
+    let settlement = server.settle_balance(uid, account, req)?;
+    client.finish_settlement(settlement)
+}
+
+// Delivers all pending receipts to a user and redeems `points` of their
+// balance in `account`.
+pub fn redeem<S: LoyaltyScheme>(server: &S::Server, client: &mut S::Client, uid: u32, account: Account,
+                                points: u32) -> Result<()> {
+    deliver_receipts::<S>(server, client, uid)?;
+
+    let hello = server.settle_balance_hello(uid, account)?;
+    let req = client.redeem(hello, points)?;
+    let redemption = server.redeem(uid, account, req)?;
+    client.finish_redemption(redemption)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::CheckoutError;
+    use crate::{lib_mal, lib_sh, lib_sh_swap_only};
+
+    // Registers `n` clients and tells each of them the server's state
+    fn register<S: LoyaltyScheme>(server: &S::Server, n: u32) -> Vec<S::Client> {
+        let mut clients: Vec<S::Client> = (0..n).map(|i| S::Client::new(i as u64)).collect();
+        for client in &clients {
+            server.register_user(client.register_with_server()).unwrap();
+        }
+        let (num_users, root, server_id, max_points, policy) = server.share_state().unwrap();
+        for (uid, client) in clients.iter_mut().enumerate() {
+            client.update_state(uid as u32, num_users, root, server_id, max_points, policy.clone());
+        }
+        clients
+    }
+
+    // Runs the whole protocol through the traits alone: transactions with
+    // both kinds of coin flip share, then a settlement for every user
+    fn check_full_protocol<S: LoyaltyScheme>(server: S::Server) {
+        let mut clients = register::<S>(&server, 3);
+        for mode in [CoinFlip::Index, CoinFlip::Bytes] {
+            clients[0].set_coin_flip(mode);
+            transact::<S>(&server, &mut clients[0], 0, DEFAULT_MERCHANT, &[10]).unwrap();
+        }
+        for (uid, client) in clients.iter_mut().enumerate() {
+            settle::<S>(&server, client, uid as u32, DEFAULT_ACCOUNT).unwrap();
+        }
+    }
+
+    // A transaction that fails partway is forgotten by the shopper, who can
+    // start another
+    fn check_failed_transaction<S: LoyaltyScheme>(server: S::Server) {
+        let mut clients = register::<S>(&server, 3);
+        assert_eq!(transact::<S>(&server, &mut clients[0], 1, DEFAULT_MERCHANT, &[10]), Err(CheckoutError::Unauthorized));
+        assert_eq!(clients[0].expire_txs(Duration::ZERO), 0);
+        transact::<S>(&server, &mut clients[0], 0, DEFAULT_MERCHANT, &[10]).unwrap();
+    }
+
+    #[test]
+    fn mal_full_protocol() {
+        check_full_protocol::<lib_mal::Malicious>(lib_mal::Server::new());
+    }
+
+    #[test]
+    fn sh_full_protocol() {
+        check_full_protocol::<lib_sh::SemiHonest>(lib_sh::Server::new());
+    }
+
+    #[test]
+    fn swap_only_full_protocol() {
+        check_full_protocol::<lib_sh_swap_only::SwapOnly>(lib_sh_swap_only::Server::new());
+    }
+
+    #[test]
+    fn mal_failed_transaction() {
+        check_failed_transaction::<lib_mal::Malicious>(lib_mal::Server::new());
+    }
+
+    #[test]
+    fn sh_failed_transaction() {
+        check_failed_transaction::<lib_sh::SemiHonest>(lib_sh::Server::new());
+    }
+
+    #[test]
+    fn swap_only_failed_transaction() {
+        check_failed_transaction::<lib_sh_swap_only::SwapOnly>(lib_sh_swap_only::Server::new());
+    }
+}
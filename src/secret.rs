@@ -0,0 +1,71 @@
+// Secret values (private keys, masks, encryption randomness) that are wiped
+// from memory when they are dropped. A secret cannot be cloned and prints as
+// "Secret(..)", so it does not end up in logs or debug output by accident.
+//
+// A secret serializes exactly like the value it holds, so wrapping a field
+// does not change the format of wallets or snapshots.
+
+use std::fmt;
+use serde_derive::{Serialize, Deserialize};
+use zeroize::{Zeroize, ZeroizeOnDrop};
+
+#[derive(Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Secret<T: Zeroize>(T);
+
+impl<T: Zeroize> Secret<T> {
+    pub fn new(value: T) -> Self {
+        Secret(value)
+    }
+
+    pub fn expose(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: Zeroize> Drop for Secret<T> {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl<T: Zeroize> ZeroizeOnDrop for Secret<T> {}
+
+impl<T: Zeroize> fmt::Debug for Secret<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Secret(..)")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use curve25519_dalek::scalar::Scalar;
+
+    #[derive(Debug)]
+    struct Keys {
+        id: u32,
+        sk: Secret<[u8; 32]>,
+    }
+
+    #[test]
+    fn debug_is_redacted() {
+        assert_eq!(format!("{:?}", Secret::new(Scalar::from(42u64))), "Secret(..)");
+
+        let keys = Keys { id: 7, sk: Secret::new([0xab; 32]) };
+        let printed = format!("{:?}", keys);
+        assert_eq!(printed, "Keys { id: 7, sk: Secret(..) }");
+        assert!(!printed.contains("171"));
+        assert_eq!((keys.id, keys.sk.expose()), (7, &[0xab; 32]));
+    }
+
+    #[test]
+    fn serializes_like_its_value() {
+        let value = [0xab; 32];
+        let bytes = bincode::serialize(&Secret::new(value)).unwrap();
+        assert_eq!(bytes, bincode::serialize(&value).unwrap());
+
+        let secret: Secret<[u8; 32]> = bincode::deserialize(&bytes).unwrap();
+        assert_eq!(secret.expose(), &value);
+    }
+}
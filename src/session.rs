@@ -0,0 +1,200 @@
+// Transactions in progress, from the hello to the submission. A client that
+// sends a hello and never finishes would otherwise leave its entry behind
+// forever, so every transaction expires a fixed time after it starts, and a
+// server keeps at most a fixed number open at once. A server drops an expired
+// transaction when it is next looked up, or when it sweeps (`net::serve`
+// sweeps every `SWEEP_INTERVAL`).
+//
+// Once a transaction is carried out, its ID is spent: a hello that reuses it
+// is refused, and so is a different submission under it. Submitting exactly
+// the same transaction again returns the reply it got the first time, so a
+// client whose reply was lost can simply resend. Spent IDs are remembered in
+// memory for the replay window. After that, an old submission sent again on
+// its own still fails, since its transaction is no longer open.
+//
+// Servers time transactions with the monotonic clock. Clients use the wall
+// clock instead, since their transactions are saved in wallets and can
+// outlive the process.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use crate::error::{CheckoutError, Result};
+use crate::scheme::Com;
+
+// How long a transaction may take, unless the server is configured otherwise
+pub const DEFAULT_TX_TTL: Duration = Duration::from_secs(300);
+
+// Most transactions a server keeps open at once, unless configured otherwise
+pub const DEFAULT_MAX_OPEN_TXS: usize = 100_000;
+
+// How long a server remembers the transactions it has carried out, unless it
+// is configured otherwise
+pub const DEFAULT_REPLAY_WINDOW: Duration = Duration::from_secs(24 * 60 * 60);
+
+// How often a network server drops expired transactions
+pub const SWEEP_INTERVAL: Duration = Duration::from_secs(30);
+
+// A server's transactions, with what it remembers about each one in progress
+// (`T`) and its reply to each one carried out (`R`). Its lock is never held
+// while another lock is taken.
+pub struct Sessions<T, R> {
+    ttl: Duration,
+    max_open: usize,
+    replay_window: Duration,
+    txs: Mutex<Txs<T, R>>,
+}
+
+struct Txs<T, R> {
+    open: HashMap<Com, (Instant, T)>,
+    // When each transaction was carried out, the hash of its submission (see
+    // `submission_hash`) and the reply
+    spent: HashMap<Com, (Instant, [u8; 32], R)>,
+}
+
+impl<T, R> Default for Sessions<T, R> {
+    fn default() -> Self {
+        Sessions {
+            ttl: DEFAULT_TX_TTL,
+            max_open: DEFAULT_MAX_OPEN_TXS,
+            replay_window: DEFAULT_REPLAY_WINDOW,
+            txs: Mutex::new(Txs { open: HashMap::new(), spent: HashMap::new() }),
+        }
+    }
+}
+
+impl<T: Clone, R: Clone> Sessions<T, R> {
+    pub fn set_ttl(&mut self, ttl: Duration) {
+        self.ttl = ttl;
+    }
+
+    pub fn set_max_open(&mut self, max_open: usize) {
+        self.max_open = max_open;
+    }
+
+    pub fn set_replay_window(&mut self, window: Duration) {
+        self.replay_window = window;
+    }
+
+    // Starts a transaction, unless its ID is already in use. If as many are
+    // open as allowed, the expired ones are dropped first, and the new one is
+    // refused if that frees no room.
+    pub fn open(&self, tx_id: Com, state: T) -> Result<()> {
+        let mut txs = self.txs.lock().unwrap();
+        if self.is_live(&txs, &tx_id) || self.is_spent(&txs, &tx_id) {
+            return Err(CheckoutError::DuplicateTx);
+        }
+        if txs.open.len() >= self.max_open {
+            let ttl = self.ttl;
+            txs.open.retain(|_, (started, _)| started.elapsed() < ttl);
+            if txs.open.len() >= self.max_open {
+                return Err(CheckoutError::TooManyTxs);
+            }
+        }
+        txs.open.insert(tx_id, (Instant::now(), state));
+        Ok(())
+    }
+
+    // Output: a copy of what is remembered about a transaction
+    pub fn get(&self, tx_id: &Com) -> Result<T> {
+        let mut txs = self.txs.lock().unwrap();
+        Ok(self.live(&mut txs, tx_id)?.clone())
+    }
+
+    // Changes what is remembered about a transaction
+    pub fn update<F: FnOnce(&mut T)>(&self, tx_id: &Com, f: F) -> Result<()> {
+        let mut txs = self.txs.lock().unwrap();
+        f(self.live(&mut txs, tx_id)?);
+        Ok(())
+    }
+
+    // Checks a submission against the transactions already carried out.
+    // Output: the earlier reply if this exact submission was carried out, or
+    // None if its transaction has not been carried out yet
+    pub fn replayed(&self, tx_id: &Com, hash: &[u8; 32]) -> Result<Option<R>> {
+        let txs = self.txs.lock().unwrap();
+        if !self.is_spent(&txs, tx_id) {
+            return Ok(None);
+        }
+        let (_, spent_hash, reply) = &txs.spent[tx_id];
+        if spent_hash != hash {
+            return Err(CheckoutError::DuplicateTx);
+        }
+        Ok(Some(reply.clone()))
+    }
+
+    // Spends the ID of a transaction that has just been carried out. This
+    // cannot fail, so it is called once the transaction is logged and applied;
+    // one that expired meanwhile is spent all the same.
+    pub fn spend(&self, tx_id: &Com, hash: [u8; 32], reply: R) {
+        let mut txs = self.txs.lock().unwrap();
+        txs.open.remove(tx_id);
+        txs.spent.insert(*tx_id, (Instant::now(), hash, reply));
+    }
+
+    // Ends a transaction that will not be carried out, at whatever step it
+    // is. Aborting one that is not open does nothing.
+    pub fn abort(&self, tx_id: &Com) {
+        self.txs.lock().unwrap().open.remove(tx_id);
+    }
+
+    // Drops every expired transaction, and forgets the transactions carried
+    // out before the replay window.
+    // Output: how many expired transactions were dropped
+    pub fn sweep(&self) -> usize {
+        let mut txs = self.txs.lock().unwrap();
+        let before = txs.open.len();
+        txs.open.retain(|_, (started, _)| started.elapsed() < self.ttl);
+        txs.spent.retain(|_, (spent, _, _)| spent.elapsed() < self.replay_window);
+        before - txs.open.len()
+    }
+
+    // Output: the number of transactions open, including expired ones that
+    // have not been dropped yet
+    pub fn len(&self) -> usize {
+        self.txs.lock().unwrap().open.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn is_live(&self, txs: &Txs<T, R>, tx_id: &Com) -> bool {
+        txs.open.get(tx_id).is_some_and(|(started, _)| started.elapsed() < self.ttl)
+    }
+
+    fn is_spent(&self, txs: &Txs<T, R>, tx_id: &Com) -> bool {
+        txs.spent.get(tx_id).is_some_and(|(spent, _, _)| spent.elapsed() < self.replay_window)
+    }
+
+    // Output: the transaction's state, or an error if it is unknown or has
+    // expired, in which case it is dropped
+    fn live<'a>(&self, txs: &'a mut Txs<T, R>, tx_id: &Com) -> Result<&'a mut T> {
+        let expired = txs.open.get(tx_id).ok_or(CheckoutError::UnknownTx)?.0.elapsed() >= self.ttl;
+        if expired {
+            txs.open.remove(tx_id);
+            return Err(CheckoutError::TxExpired);
+        }
+        Ok(&mut txs.open.get_mut(tx_id).unwrap().1)
+    }
+}
+
+// Output: the hash that identifies a submission, so that an exact replay can
+// be told apart from a different submission under the same ID
+pub fn submission_hash<M: Serialize>(msg: &M) -> [u8; 32] {
+    Sha256::digest(&bincode::serialize(msg).unwrap()).into()
+}
+
+// Output: seconds since the Unix epoch, which clients record when they start
+// a transaction
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+// Output: whether a client's transaction started at `started` (see `now`) is
+// older than `ttl`
+pub fn expired(started: u64, ttl: Duration) -> bool {
+    now().saturating_sub(started) >= ttl.as_secs()
+}
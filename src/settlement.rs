@@ -0,0 +1,227 @@
+// Settling a balance ends the user's current epoch. A user has a balance in
+// each account, a merchant and a category of points, and each is settled on
+// its own, with epochs of its own. The
+// server resets the balance it keeps for the user and signs a settlement
+// receipt saying what was settled, and the client uses the receipt to reset
+// its own state for the next epoch. Settle proofs are bound to the epoch (see `Transcript::for_settle`),
+// so a settle request is only accepted once: sent again after the balance has
+// been reset, its proof no longer verifies.
+//
+// The server keeps the last settlement receipt of each user and sends it when
+// a settlement starts, so a client whose receipt was lost on the way catches
+// up before it settles again.
+//
+// A user can also redeem part of a balance without revealing the rest. The
+// client proves that the balance covers the amount, and the server subtracts
+// the amount from the balance it keeps and signs a redemption receipt. The
+// epoch does not change, but the balance does, and redeem proofs are bound to
+// it, so a redeem request is only accepted once too. The last redemption is
+// sent along with the last settlement.
+//
+// Points expire with the period they were earned in (see `expiry`), so a
+// redemption spends the oldest unexpired points first. It is split into one
+// part for each period it takes points from, and each part is recorded in
+// its period and expires with it. The client proves for each part that the
+// period's share of the balance covers it, so no period is left below zero
+// to expire in the user's favour.
+
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use serde_derive::{Serialize, Deserialize};
+use sha2::{Digest, Sha256};
+use crate::error::{CheckoutError, Result};
+use crate::expiry::Period;
+use crate::scheme::{Account, Category, MerchantId, ServerId};
+
+// Signed messages start with this, so a settlement receipt cannot be passed
+// off as any other message the server signs
+const DOMAIN: &[u8] = b"checkout settlement";
+const REDEMPTION_DOMAIN: &[u8] = b"checkout redemption";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Settlement {
+    pub uid: u32,
+    pub merchant: MerchantId,
+    pub category: Category,
+    pub epoch: u64, // The epoch that was settled; the user's next one is `epoch + 1`
+    pub points: i32, // The balance that was settled
+    // How many of the client's receipts the settlement covers, oldest first
+    // (always 0 in the semihonest scheme, whose clients keep no receipts)
+    pub receipts: u32,
+    pub sigma: Signature,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Redemption {
+    pub uid: u32,
+    pub merchant: MerchantId,
+    pub category: Category,
+    pub epoch: u64, // The epoch the points were redeemed in
+    pub parts: Vec<RedeemedPart>, // Oldest period first
+    pub nonce: [u8; 32], // Chosen by the server, so every redemption has its own bases
+}
+
+// The points a redemption takes from one period's share of the balance
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RedeemedPart {
+    pub period: Period,
+    pub points: u32,
+    // The server's signature on the part's base (see `Redemption::base`). In
+    // the malicious scheme it is a receipt signature on (h, base), so the part
+    // is settled like any other receipt.
+    pub sigma: Signature,
+}
+
+// Server -> client, when a settlement or redemption starts: the account, the
+// user's current epoch in it, the oldest period whose points have not
+// expired, and their last settlement and redemption receipts in the account
+// if they have them
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SettleStatus {
+    pub merchant: MerchantId,
+    pub category: Category,
+    pub epoch: u64,
+    pub oldest: Period,
+    pub last: Option<Settlement>,
+    pub last_redemption: Option<Redemption>,
+}
+
+impl SettleStatus {
+    pub fn account(&self) -> Account {
+        (self.merchant, self.category)
+    }
+}
+
+impl Settlement {
+    pub fn sign(sk: &SigningKey, (uid, account): (u32, Account), epoch: u64, points: i32, receipts: u32) -> Self {
+        let sigma = sk.sign(&Settlement::message((uid, account), epoch, points, receipts));
+        Settlement { uid, merchant: account.0, category: account.1, epoch, points, receipts, sigma }
+    }
+
+    pub fn account(&self) -> Account {
+        (self.merchant, self.category)
+    }
+
+    // Checks that this receipt settles epoch `epoch` of user `uid` in
+    // `account`.
+    // Input: the ID of the server that should have signed the receipt, which
+    // is its verification key
+    pub fn verify(&self, server_id: &ServerId, (uid, account): (u32, Account), epoch: u64) -> Result<()> {
+        let vk = VerifyingKey::from_bytes(server_id).map_err(|_| CheckoutError::InvalidPoint)?;
+        vk.verify(&Settlement::message((self.uid, self.account()), self.epoch, self.points, self.receipts), &self.sigma)
+            .map_err(|_| CheckoutError::InvalidSignature)?;
+        if self.uid != uid || self.account() != account || self.epoch != epoch {
+            return Err(CheckoutError::StaleSettlement);
+        }
+        Ok(())
+    }
+
+    fn message((uid, (merchant, category)): (u32, Account), epoch: u64, points: i32, receipts: u32) -> Vec<u8> {
+        let mut msg = DOMAIN.to_vec();
+        msg.extend_from_slice(&uid.to_le_bytes());
+        msg.extend_from_slice(&merchant.to_le_bytes());
+        msg.extend_from_slice(&category.to_le_bytes());
+        msg.extend_from_slice(&epoch.to_le_bytes());
+        msg.extend_from_slice(&points.to_le_bytes());
+        msg.extend_from_slice(&receipts.to_le_bytes());
+        msg
+    }
+}
+
+impl Redemption {
+    // Signs each part's base directly, as the semihonest scheme does
+    // Input: the user and account, the epoch, the period and amount of each
+    // part (see `split`), and the server's nonce
+    pub fn sign(sk: &SigningKey, (uid, account): (u32, Account), epoch: u64, parts: &[(Period, u32)],
+                nonce: [u8; 32]) -> Self {
+        let parts = parts.iter().map(|&(period, points)| {
+            let sigma = sk.sign(&Redemption::base_for((uid, account), (epoch, period), points, &nonce));
+            RedeemedPart { period, points, sigma }
+        }).collect();
+        Redemption { uid, merchant: account.0, category: account.1, epoch, parts, nonce }
+    }
+
+    pub fn account(&self) -> Account {
+        (self.merchant, self.category)
+    }
+
+    // Output: the number of points redeemed, over all parts
+    pub fn points(&self) -> u32 {
+        self.parts.iter().map(|part| part.points).sum()
+    }
+
+    // Checks a receipt made by `sign` for a redemption by user `uid` in
+    // `account` in epoch `epoch`
+    pub fn verify(&self, server_id: &ServerId, (uid, account): (u32, Account), epoch: u64) -> Result<()> {
+        let vk = VerifyingKey::from_bytes(server_id).map_err(|_| CheckoutError::InvalidPoint)?;
+        for part in &self.parts {
+            vk.verify(&self.base(part), &part.sigma).map_err(|_| CheckoutError::InvalidSignature)?;
+        }
+        if self.uid != uid || self.account() != account || self.epoch != epoch {
+            return Err(CheckoutError::StaleSettlement);
+        }
+        Ok(())
+    }
+
+    // Output: the base a part of the redemption is recorded under, which
+    // binds the user, account, epoch, period and amount, so that a signature
+    // on it covers them too
+    pub fn base(&self, part: &RedeemedPart) -> [u8; 32] {
+        Redemption::base_for((self.uid, self.account()), (self.epoch, part.period), part.points, &self.nonce)
+    }
+
+    pub fn base_for((uid, (merchant, category)): (u32, Account), (epoch, period): (u64, Period), points: u32,
+                    nonce: &[u8; 32]) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        hasher.update(REDEMPTION_DOMAIN);
+        hasher.update(uid.to_le_bytes());
+        hasher.update(merchant.to_le_bytes());
+        hasher.update(category.to_le_bytes());
+        hasher.update(epoch.to_le_bytes());
+        hasher.update(period.to_le_bytes());
+        hasher.update(points.to_le_bytes());
+        hasher.update(nonce);
+        hasher.finalize().into()
+    }
+}
+
+// Splits an amount to redeem over the periods of a balance, taking the
+// oldest points first.
+// Input: each unexpired period's share of the balance, oldest first, and the
+// amount to redeem
+// Output: the period and amount of each part, or an error if the periods do
+// not cover the amount
+pub fn split(shares: &[(Period, i32)], points: u32) -> Result<Vec<(Period, u32)>> {
+    let mut parts = Vec::new();
+    let mut left = points;
+    for &(period, share) in shares {
+        let take = left.min(share.max(0) as u32);
+        if take > 0 {
+            parts.push((period, take));
+            left -= take;
+        }
+    }
+    if points == 0 || left > 0 {
+        return Err(CheckoutError::PointsOutOfRange);
+    }
+    Ok(parts)
+}
+
+// Checks the parts a redeem request is split into: at least one, each for
+// some points and in a period that has not expired, oldest first, and in all
+// no more than `max`. The proofs show each period covers its part, so the
+// server need not check that the oldest points were taken first; taking
+// newer ones only hurts the user.
+pub fn check_parts(parts: &[(Period, u32)], oldest: Period, max: u32) -> Result<()> {
+    let total: u64 = parts.iter().map(|(_, points)| *points as u64).sum();
+    if parts.is_empty() || parts.iter().any(|(_, points)| *points == 0) || total > max as u64 {
+        return Err(CheckoutError::PointsOutOfRange);
+    }
+    if parts[0].0 < oldest {
+        return Err(CheckoutError::PointsExpired);
+    }
+    if parts.windows(2).any(|w| w[0].0 >= w[1].0) {
+        return Err(CheckoutError::InvalidProof);
+    }
+    Ok(())
+}
+
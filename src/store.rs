@@ -0,0 +1,399 @@
+// Crash-safe storage for server state: a snapshot of the full state, plus a
+// write-ahead log of every change made since the snapshot was taken. Servers
+// log each change before applying it in memory and before replying, so after a
+// crash the snapshot and the log together give back every change a client was
+// told about.
+//
+// A log record is the payload length (u32, little endian), the SHA-256 hash of
+// the payload, and the payload: the bincode encoding of (sequence number,
+// event). A record that is cut short, or the last record if it fails its
+// hash, can only be the last write before a crash, so it marks the end of the
+// log and is discarded. A record that fails its hash with more records after
+// it means the log was damaged, and opening the store fails rather than drop
+// changes that clients were told about.
+//
+// If an append fails partway, the part of the record that was written is cut
+// off again, so that later records do not follow a damaged one. If even that
+// fails, the store refuses every append until the next checkpoint clears the
+// log.
+//
+// The snapshot stores the sequence number of the last event it includes. If
+// the server crashes after writing a snapshot but before clearing the log,
+// recovery skips the log records that the snapshot already covers.
+//
+// The snapshot holds the server's signing key unencrypted, so it is created
+// readable and writable by its owner only (mode 0600 on Unix). Whoever can
+// read the data directory's snapshot can sign receipts as the server.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use sha2::{Digest, Sha256};
+
+const SNAPSHOT: &str = "snapshot";
+const SNAPSHOT_TMP: &str = "snapshot.tmp";
+const WAL: &str = "wal";
+
+// Number of log records after which `append` asks for a checkpoint
+pub const CHECKPOINT_INTERVAL: u64 = 10_000;
+
+pub struct Store<E> {
+    dir: PathBuf,
+    wal: Mutex<Wal>,
+    event: PhantomData<fn(E)>,
+}
+
+struct Wal {
+    file: File,
+    len: u64, // Length of the log up to the end of its last complete record
+    next_seq: u64,
+    records: u64, // Records written since the last checkpoint
+    poisoned: bool, // A failed append could not be cut off the log
+    #[cfg(test)]
+    short_write: Option<usize>, // Bytes the next append writes before it fails
+}
+
+impl Wal {
+    // Writes a whole record and waits until it is durable
+    fn write_record(&mut self, record: &[u8]) -> io::Result<()> {
+        #[cfg(test)]
+        if let Some(n) = self.short_write.take() {
+            self.file.write_all(&record[..n])?;
+            return Err(io::Error::new(io::ErrorKind::WriteZero, "short write"));
+        }
+        self.file.write_all(record)?;
+        self.file.sync_data()
+    }
+}
+
+fn invalid_data<T: std::fmt::Display>(e: T) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+// Decodes the log record starting at `pos`.
+// Output: sequence number, event, and the position of the next record
+fn read_record<E: DeserializeOwned>(bytes: &[u8], pos: usize) -> Option<(u64, E, usize)> {
+    let header = bytes.get(pos..pos + 36)?;
+    let len = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+    let payload = bytes.get(pos + 36..pos + 36 + len)?;
+    if Sha256::digest(payload).as_slice() != &header[4..] {
+        return None;
+    }
+
+    let (seq, event) = bincode::deserialize(payload).ok()?;
+    Some((seq, event, pos + 36 + len))
+}
+
+// Output: the position after the log record starting at `pos`, if all of it
+// was written
+fn record_end(bytes: &[u8], pos: usize) -> Option<usize> {
+    let header = bytes.get(pos..pos + 36)?;
+    let end = pos + 36 + u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+    (end <= bytes.len()).then_some(end)
+}
+
+// Creates a file that only its owner can read or write
+#[cfg(unix)]
+fn create_private(path: &Path) -> io::Result<File> {
+    use std::os::unix::fs::OpenOptionsExt;
+    OpenOptions::new().write(true).create_new(true).mode(0o600).open(path)
+}
+
+#[cfg(not(unix))]
+fn create_private(path: &Path) -> io::Result<File> {
+    OpenOptions::new().write(true).create_new(true).open(path)
+}
+
+impl<E: Serialize + DeserializeOwned> Store<E> {
+    // Opens the store in `dir`, creating it if needed.
+    // Output: the store, the last snapshot (None for a new store), and the
+    // events logged after that snapshot, in order
+    pub fn open<S: DeserializeOwned>(dir: &Path) -> io::Result<(Self, Option<S>, Vec<E>)> {
+        fs::create_dir_all(dir)?;
+
+        let (snap_seq, state) = match fs::read(dir.join(SNAPSHOT)) {
+            Ok(bytes) => {
+                let (seq, state): (u64, S) = bincode::deserialize(&bytes).map_err(invalid_data)?;
+                (seq, Some(state))
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (0, None),
+            Err(e) => return Err(e),
+        };
+
+        let mut file = OpenOptions::new().read(true).append(true).create(true).open(dir.join(WAL))?;
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)?;
+
+        let mut events = Vec::new();
+        let mut next_seq = snap_seq + 1;
+        let mut records = 0;
+        let mut pos = 0;
+        while let Some((seq, event, end)) = read_record::<E>(&bytes, pos) {
+            if seq >= next_seq {
+                events.push(event);
+                next_seq = seq + 1;
+            }
+            records += 1;
+            pos = end;
+        }
+
+        // Drop a partly written record left by a crash
+        let len = pos as u64;
+        if pos < bytes.len() {
+            if record_end(&bytes, pos).is_some_and(|end| end < bytes.len()) {
+                return Err(invalid_data("damaged record in the middle of the write-ahead log"));
+            }
+            file.set_len(len)?;
+            file.sync_all()?;
+        }
+
+        let wal = Wal {
+            file,
+            len,
+            next_seq,
+            records,
+            poisoned: false,
+            #[cfg(test)]
+            short_write: None,
+        };
+        let store = Store {
+            dir: dir.to_path_buf(),
+            wal: Mutex::new(wal),
+            event: PhantomData,
+        };
+        Ok((store, state, events))
+    }
+
+    // Durably logs one event. Returns true once enough events have been logged
+    // that the caller should write a checkpoint.
+    pub fn append(&self, event: &E) -> io::Result<bool> {
+        let mut wal = self.wal.lock().unwrap();
+        if wal.poisoned {
+            return Err(io::Error::other("write-ahead log holds a partly written record"));
+        }
+        let payload = bincode::serialize(&(wal.next_seq, event)).map_err(invalid_data)?;
+        let len: u32 = payload.len().try_into().map_err(invalid_data)?;
+
+        let mut record = Vec::with_capacity(36 + payload.len());
+        record.extend_from_slice(&len.to_le_bytes());
+        record.extend_from_slice(&Sha256::digest(&payload));
+        record.extend_from_slice(&payload);
+        if let Err(e) = wal.write_record(&record) {
+            let len = wal.len;
+            if wal.file.set_len(len).and_then(|_| wal.file.sync_data()).is_err() {
+                wal.poisoned = true;
+            }
+            return Err(e);
+        }
+
+        wal.len += record.len() as u64;
+        wal.next_seq += 1;
+        wal.records += 1;
+        Ok(wal.records >= CHECKPOINT_INTERVAL)
+    }
+
+    // Replaces the snapshot with `state` and clears the log. `state` must
+    // include every event appended so far, so the caller has to stop other
+    // threads from appending until this returns.
+    pub fn checkpoint<S: Serialize>(&self, state: &S) -> io::Result<()> {
+        let mut wal = self.wal.lock().unwrap();
+        let bytes = bincode::serialize(&(wal.next_seq - 1, state)).map_err(invalid_data)?;
+
+        // Write the new snapshot next to the old one, then swap it in, so a
+        // crash at any point leaves one complete snapshot. A file left over
+        // from such a crash is removed first, so the new one is created with
+        // owner-only permissions.
+        let tmp = self.dir.join(SNAPSHOT_TMP);
+        match fs::remove_file(&tmp) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        let mut file = create_private(&tmp)?;
+        file.write_all(&bytes)?;
+        file.sync_all()?;
+        fs::rename(&tmp, self.dir.join(SNAPSHOT))?;
+        File::open(&self.dir)?.sync_all()?;
+
+        wal.file.set_len(0)?;
+        wal.file.sync_all()?;
+        wal.len = 0;
+        wal.records = 0;
+        wal.poisoned = false;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl<E> Store<E> {
+    // Opens a store in `dir` whose log is read-only, so every append fails
+    pub(crate) fn failing(dir: &Path) -> io::Result<Self> {
+        File::create(dir.join(WAL))?;
+        let file = File::open(dir.join(WAL))?;
+        let wal = Wal { file, len: 0, next_seq: 1, records: 0, poisoned: false, short_write: None };
+        Ok(Store {
+            dir: dir.to_path_buf(),
+            wal: Mutex::new(wal),
+            event: PhantomData,
+        })
+    }
+
+    // Makes the next append write only `n` bytes of its record, then fail
+    fn fail_after(&self, n: usize) {
+        self.wal.lock().unwrap().short_write = Some(n);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Output: the store in `dir`, and its snapshot and the events logged
+    // after it
+    fn open(dir: &Path) -> (Store<u32>, Option<String>, Vec<u32>) {
+        Store::open(dir).unwrap()
+    }
+
+    // Output: a new store in `dir` with events 1, 2 and 3 logged
+    fn logged(dir: &Path) -> Store<u32> {
+        let (store, _, _) = open(dir);
+        for event in 1..=3 {
+            store.append(&event).unwrap();
+        }
+        store
+    }
+
+    #[test]
+    fn torn_last_record() {
+        let dir = tempfile::tempdir().unwrap();
+        drop(logged(dir.path()));
+        let wal = dir.path().join(WAL);
+        let bytes = fs::read(&wal).unwrap();
+        let third = record_end(&bytes, record_end(&bytes, 0).unwrap()).unwrap();
+
+        // The last record is cut short by a crash, in its payload or in its
+        // header, and is dropped from the log
+        for cut in [bytes.len() - 1, third + 10] {
+            fs::write(&wal, &bytes[..cut]).unwrap();
+            assert_eq!(open(dir.path()).2, vec![1, 2]);
+            assert_eq!(fs::metadata(&wal).unwrap().len(), third as u64);
+        }
+
+        // The log goes on after the last good record
+        let (store, _, _) = open(dir.path());
+        store.append(&4).unwrap();
+        drop(store);
+        assert_eq!(open(dir.path()).2, vec![1, 2, 4]);
+    }
+
+    #[test]
+    fn bad_last_record() {
+        let dir = tempfile::tempdir().unwrap();
+        drop(logged(dir.path()));
+        let wal = dir.path().join(WAL);
+        let mut bytes = fs::read(&wal).unwrap();
+        *bytes.last_mut().unwrap() ^= 1;
+        fs::write(&wal, &bytes).unwrap();
+
+        assert_eq!(open(dir.path()).2, vec![1, 2]);
+    }
+
+    #[test]
+    fn bad_record_in_the_middle() {
+        let dir = tempfile::tempdir().unwrap();
+        drop(logged(dir.path()));
+        let wal = dir.path().join(WAL);
+        let mut bytes = fs::read(&wal).unwrap();
+        let second = record_end(&bytes, 0).unwrap();
+        bytes[second + 36] ^= 1;
+        fs::write(&wal, &bytes).unwrap();
+
+        let err = Store::<u32>::open::<String>(dir.path()).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        // Nothing was dropped from the log
+        assert_eq!(fs::read(&wal).unwrap(), bytes);
+    }
+
+    #[test]
+    fn failed_append_is_cut_off() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = logged(dir.path());
+        let wal = dir.path().join(WAL);
+        let len = fs::metadata(&wal).unwrap().len();
+
+        // The part of the record that was written is taken back, so the log
+        // goes on after the last good record
+        store.fail_after(10);
+        assert_eq!(store.append(&4).unwrap_err().kind(), io::ErrorKind::WriteZero);
+        assert_eq!(fs::metadata(&wal).unwrap().len(), len);
+        store.append(&5).unwrap();
+        drop(store);
+        assert_eq!(open(dir.path()).2, vec![1, 2, 3, 5]);
+
+        // The same goes for a crash right after the failed append
+        let (store, _, _) = open(dir.path());
+        store.fail_after(40);
+        assert!(store.append(&6).is_err());
+        drop(store);
+        let (store, _, events) = open(dir.path());
+        assert_eq!(events, vec![1, 2, 3, 5]);
+        store.append(&7).unwrap();
+        drop(store);
+        assert_eq!(open(dir.path()).2, vec![1, 2, 3, 5, 7]);
+    }
+
+    #[test]
+    fn append_that_cannot_be_cut_off() {
+        // The log is read-only, so the failed append cannot be cut off either,
+        // and the store refuses to append after it
+        let dir = tempfile::tempdir().unwrap();
+        let store = Store::<u32>::failing(dir.path()).unwrap();
+        assert_ne!(store.append(&1).unwrap_err().kind(), io::ErrorKind::Other);
+        assert!(store.wal.lock().unwrap().poisoned);
+        assert_eq!(store.append(&2).unwrap_err().kind(), io::ErrorKind::Other);
+    }
+
+    #[test]
+    fn snapshot_overlapping_the_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = logged(dir.path());
+        let wal = dir.path().join(WAL);
+        let before = fs::read(&wal).unwrap();
+
+        // A crash after the snapshot of events 1 to 3 was written, but before
+        // the log was cleared, leaves them in the log as well
+        store.checkpoint(&"after 3".to_string()).unwrap();
+        store.append(&4).unwrap();
+        drop(store);
+        let after = fs::read(&wal).unwrap();
+        fs::write(&wal, [before, after].concat()).unwrap();
+
+        let (store, snapshot, events) = open(dir.path());
+        assert_eq!(snapshot.as_deref(), Some("after 3"));
+        assert_eq!(events, vec![4]);
+
+        // Sequence numbers carry on from the last record
+        store.append(&5).unwrap();
+        drop(store);
+        assert_eq!(open(dir.path()).2, vec![4, 5]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn snapshot_is_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let store = logged(dir.path());
+        // A file left by a crash with looser permissions is not reused
+        fs::write(dir.path().join(SNAPSHOT_TMP), b"").unwrap();
+        fs::set_permissions(dir.path().join(SNAPSHOT_TMP), fs::Permissions::from_mode(0o644)).unwrap();
+        store.checkpoint(&"after 3".to_string()).unwrap();
+
+        let mode = fs::metadata(dir.path().join(SNAPSHOT)).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+}
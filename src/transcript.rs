@@ -0,0 +1,148 @@
+// Fiat-Shamir transcripts, in the style of Merlin. A prover and verifier
+// build the same transcript by appending every public input of a proof, each
+// under a label, and then draw the challenge from it.
+//
+// Each append is framed with the label and the message length, so no two
+// different sequences of appends hash the same way. A transcript starts with
+// the server and the transaction and category (or user, account and epoch)
+// the proof is made for, and each proof then adds its own domain separator, so a proof
+// cannot be reused for another server, another transaction or another kind of
+// statement.
+
+use curve25519_dalek::scalar::Scalar;
+use sha2::{Digest, Sha512};
+use crate::scheme::{Account, Category, Com, ServerId};
+
+// Changes whenever the way transcripts are built changes
+const PROTOCOL_LABEL: &[u8] = b"CheckOut transcript v3";
+
+#[derive(Clone)]
+pub struct Transcript {
+    hasher: Sha512,
+}
+
+impl Transcript {
+    fn new() -> Self {
+        let mut t = Transcript { hasher: Sha512::new() };
+        t.append_message(b"protocol", PROTOCOL_LABEL);
+        t
+    }
+
+    // A transcript for the proof about the amount of `category` moved by
+    // transaction `tx_id`
+    pub fn for_tx(server: &ServerId, tx_id: &Com, category: Category) -> Self {
+        let mut t = Transcript::new();
+        t.append_message(b"server", server);
+        t.append_message(b"tx_id", tx_id);
+        t.append_u64(b"category", category as u64);
+        t
+    }
+
+    // A transcript for a proof about user `uid`'s balance in `account` in
+    // epoch `epoch` (see `settlement`)
+    pub fn for_settle(server: &ServerId, uid: u32, (merchant, category): Account, epoch: u64) -> Self {
+        let mut t = Transcript::new();
+        t.append_message(b"server", server);
+        t.append_u64(b"uid", uid as u64);
+        t.append_u64(b"merchant", merchant as u64);
+        t.append_u64(b"category", category as u64);
+        t.append_u64(b"epoch", epoch);
+        t
+    }
+
+    // Names the proof being made. Called by each proof before anything else.
+    pub fn domain_sep(&mut self, domain: &'static [u8]) {
+        self.append_message(b"domain", domain);
+    }
+
+    pub fn append_message(&mut self, label: &'static [u8], msg: &[u8]) {
+        self.hasher.update((label.len() as u64).to_le_bytes());
+        self.hasher.update(label);
+        self.hasher.update((msg.len() as u64).to_le_bytes());
+        self.hasher.update(msg);
+    }
+
+    pub fn append_u64(&mut self, label: &'static [u8], x: u64) {
+        self.append_message(label, &x.to_le_bytes());
+    }
+
+    // Appends a compressed point
+    pub fn append_point(&mut self, label: &'static [u8], p: &[u8; 32]) {
+        self.append_message(label, p);
+    }
+
+    pub fn append_scalar(&mut self, label: &'static [u8], s: &Scalar) {
+        self.append_message(label, s.as_bytes());
+    }
+
+    // Output: the challenge, derived from everything appended so far
+    pub fn challenge_scalar(&mut self, label: &'static [u8]) -> Scalar {
+        self.append_message(b"challenge", label);
+        Scalar::from_hash(self.hasher.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SERVER: ServerId = [1; 32];
+
+    // Output: the challenge drawn after appending `msgs`, each under its label
+    fn challenge(mut t: Transcript, msgs: &[(&'static [u8], &[u8])]) -> Scalar {
+        for (label, msg) in msgs {
+            t.append_message(label, msg);
+        }
+        t.challenge_scalar(b"c")
+    }
+
+    #[test]
+    fn same_appends_same_challenge() {
+        let msgs: [(&'static [u8], &[u8]); 2] = [(b"a", b"x"), (b"b", b"y")];
+        let t = Transcript::for_tx(&SERVER, &[2; 32], 0);
+        assert_eq!(challenge(t.clone(), &msgs), challenge(t, &msgs));
+    }
+
+    #[test]
+    fn appends_are_framed() {
+        let t = Transcript::for_tx(&SERVER, &[2; 32], 0);
+        let c = challenge(t.clone(), &[(b"a", b"bc")]);
+        // Moving bytes between the label and the message, or between two
+        // messages, changes the challenge
+        assert_ne!(c, challenge(t.clone(), &[(b"ab", b"c")]));
+        assert_ne!(c, challenge(t.clone(), &[(b"a", b"b"), (b"a", b"c")]));
+        assert_ne!(c, challenge(t, &[(b"b", b"bc")]));
+    }
+
+    #[test]
+    fn bound_to_what_the_proof_is_for() {
+        let tx = challenge(Transcript::for_tx(&SERVER, &[2; 32], 0), &[]);
+        for other in [Transcript::for_tx(&[3; 32], &[2; 32], 0), Transcript::for_tx(&SERVER, &[3; 32], 0),
+                      Transcript::for_tx(&SERVER, &[2; 32], 1)] {
+            assert_ne!(tx, challenge(other, &[]));
+        }
+
+        let settle = challenge(Transcript::for_settle(&SERVER, 0, (0, 0), 0), &[]);
+        for other in [Transcript::for_settle(&[3; 32], 0, (0, 0), 0), Transcript::for_settle(&SERVER, 1, (0, 0), 0),
+                      Transcript::for_settle(&SERVER, 0, (1, 0), 0), Transcript::for_settle(&SERVER, 0, (0, 1), 0),
+                      Transcript::for_settle(&SERVER, 0, (0, 0), 1)] {
+            assert_ne!(settle, challenge(other, &[]));
+        }
+    }
+
+    #[test]
+    fn domains_are_separated() {
+        let mut a = Transcript::for_tx(&SERVER, &[2; 32], 0);
+        let mut b = a.clone();
+        a.domain_sep(b"one proof");
+        b.domain_sep(b"another proof");
+        assert_ne!(a.challenge_scalar(b"c"), b.challenge_scalar(b"c"));
+    }
+
+    #[test]
+    fn challenges_follow_each_other() {
+        // Drawing a challenge is part of the transcript, so the next one differs
+        let mut t = Transcript::for_tx(&SERVER, &[2; 32], 0);
+        assert_ne!(t.challenge_scalar(b"c"), t.challenge_scalar(b"c"));
+    }
+}
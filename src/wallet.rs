@@ -0,0 +1,233 @@
+// Encrypted wallet files, so that a client's state (keys, balance, receipts
+// waiting to be settled) survives the app being closed.
+//
+// A wallet is the bincode encoding of a header and the encrypted client. The
+// key is derived from the user's passphrase with Argon2id, and the client is
+// encrypted with AES-256-GCM, with the header as associated data so that it
+// cannot be altered either. The header carries the KDF parameters, so they can
+// be raised later without breaking existing wallets.
+
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, Payload},
+    Aes256Gcm, Nonce
+};
+use argon2::{Algorithm, Argon2, Params, Version};
+use rand::{rngs, Rng};
+use serde_derive::{Serialize, Deserialize};
+use zeroize::Zeroizing;
+use crate::error::{CheckoutError, Result};
+use crate::scheme::{LoyaltyScheme, SchemeClient, SecurityLevel};
+
+// Version of the wallet format produced and read by this library
+pub const WALLET_VERSION: u16 = 1;
+
+const MAGIC: [u8; 4] = *b"CKWL";
+
+#[derive(Serialize, Deserialize)]
+struct Header {
+    magic: [u8; 4],
+    version: u16,
+    scheme: SecurityLevel,
+    // Argon2id memory (KiB), iterations and parallelism
+    m_cost: u32,
+    t_cost: u32,
+    p_cost: u32,
+    salt: [u8; 16],
+    nonce: [u8; 12],
+}
+
+fn derive_key(header: &Header, passphrase: &str) -> Result<Zeroizing<[u8; 32]>> {
+    let params = Params::new(header.m_cost, header.t_cost, header.p_cost, Some(32))
+        .map_err(|_| CheckoutError::MalformedMessage)?;
+    let mut key = Zeroizing::new([0u8; 32]);
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(passphrase.as_bytes(), &header.salt, key.as_mut())
+        .map_err(|_| CheckoutError::MalformedMessage)?;
+    Ok(key)
+}
+
+// Encrypts all of a client's state under `passphrase`.
+// Output: the wallet file contents
+pub fn export<C: SchemeClient>(client: &C, passphrase: &str) -> Result<Vec<u8>> {
+    // The encoded client holds its secret key, so it is wiped once encrypted
+    let pt = Zeroizing::new(bincode::serialize(client).map_err(|_| CheckoutError::MalformedMessage)?);
+    seal((WALLET_VERSION, <C::Scheme as LoyaltyScheme>::LEVEL), &pt, passphrase)
+}
+
+// Input: the wallet version and scheme, the encoded client, and the passphrase
+// Output: the wallet file contents
+fn seal((version, scheme): (u16, SecurityLevel), pt: &[u8], passphrase: &str) -> Result<Vec<u8>> {
+    let header = Header {
+        magic: MAGIC,
+        version,
+        scheme,
+        m_cost: Params::DEFAULT_M_COST,
+        t_cost: Params::DEFAULT_T_COST,
+        p_cost: Params::DEFAULT_P_COST,
+        salt: rand::thread_rng().gen(),
+        nonce: Aes256Gcm::generate_nonce(&mut rngs::OsRng).into(),
+    };
+    let key = derive_key(&header, passphrase)?;
+    let aad = bincode::serialize(&header).unwrap();
+    let cipher = Aes256Gcm::new(&(*key).into());
+    let ct = cipher.encrypt(Nonce::from_slice(&header.nonce), Payload { msg: pt, aad: &aad })
+        .map_err(|_| CheckoutError::InvalidCiphertext)?;
+
+    Ok(bincode::serialize(&(header, ct)).unwrap())
+}
+
+// Decrypts a wallet written by `export` and restores the client.
+pub fn import<C: SchemeClient>(bytes: &[u8], passphrase: &str) -> Result<C> {
+    let (header, ct): (Header, Vec<u8>) = bincode::deserialize(bytes)
+        .map_err(|_| CheckoutError::MalformedMessage)?;
+    if header.magic != MAGIC {
+        return Err(CheckoutError::MalformedMessage);
+    }
+    if header.version != WALLET_VERSION {
+        return Err(CheckoutError::UnsupportedVersion);
+    }
+    if header.scheme != <C::Scheme as LoyaltyScheme>::LEVEL {
+        return Err(CheckoutError::WrongScheme);
+    }
+
+    let key = derive_key(&header, passphrase)?;
+    let aad = bincode::serialize(&header).unwrap();
+    let cipher = Aes256Gcm::new(&(*key).into());
+    let pt = Zeroizing::new(cipher.decrypt(Nonce::from_slice(&header.nonce), Payload { msg: &ct, aad: &aad })
+        .map_err(|_| CheckoutError::WrongPassphrase)?);
+
+    bincode::deserialize(&pt).map_err(|_| CheckoutError::MalformedMessage)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scheme::{self, SchemeServer, DEFAULT_ACCOUNT, DEFAULT_MERCHANT};
+    use crate::settlement::Settlement;
+    use crate::{lib_mal, lib_sh, lib_sh_swap_only};
+
+    const PASSPHRASE: &str = "correct horse battery staple";
+
+    // Registers two users, has user 0 shop for 100 points, and hands out the
+    // receipts
+    // Output: the clients
+    fn setup<S: LoyaltyScheme>(server: &S::Server) -> Vec<S::Client> {
+        let mut clients: Vec<S::Client> = (0..2).map(<S::Client as SchemeClient>::new).collect();
+        for client in &clients {
+            server.register_user(client.register_with_server()).unwrap();
+        }
+        let (num_users, root, server_id, max_points, policy) = server.share_state().unwrap();
+        for (uid, client) in clients.iter_mut().enumerate() {
+            client.update_state(uid as u32, num_users, root, server_id, max_points, policy.clone());
+        }
+        scheme::transact::<S>(server, &mut clients[0], 0, DEFAULT_MERCHANT, &[100]).unwrap();
+        for (uid, client) in clients.iter_mut().enumerate() {
+            scheme::deliver_receipts::<S>(server, client, uid as u32).unwrap();
+        }
+        clients
+    }
+
+    // Output: the user's balance, which is settled to find it
+    fn settled_balance<S>(server: &S::Server, client: &mut S::Client, uid: u32) -> i32
+    where S: LoyaltyScheme<Settlement = Settlement> {
+        let hello = server.settle_balance_hello(uid, DEFAULT_ACCOUNT).unwrap();
+        let req = client.settle_balance(hello).unwrap();
+        let settlement = server.settle_balance(uid, DEFAULT_ACCOUNT, req).unwrap();
+        client.finish_settlement(settlement).unwrap();
+        settlement.points
+    }
+
+    // Output: the clients, each exported to a wallet and imported again
+    fn restore<S: LoyaltyScheme>(clients: &[S::Client]) -> Vec<S::Client> {
+        clients.iter()
+            .map(|client| import(&export(client, PASSPHRASE).unwrap(), PASSPHRASE).unwrap())
+            .collect()
+    }
+
+    // Input: the user who earns the points when user 0 shops
+    fn check_round_trip<S>(server: S::Server, earner: usize)
+    where S: LoyaltyScheme<Settlement = Settlement> {
+        let clients = setup::<S>(&server);
+        let mut restored = restore::<S>(&clients);
+
+        // The restored clients still sign as their users, and hold their points
+        scheme::transact::<S>(&server, &mut restored[0], 0, DEFAULT_MERCHANT, &[10]).unwrap();
+        scheme::deliver_receipts::<S>(&server, &mut restored[earner], earner as u32).unwrap();
+        assert_eq!(settled_balance::<S>(&server, &mut restored[earner], earner as u32), 110);
+    }
+
+    fn check_wrong_passphrase<S: LoyaltyScheme, Other: LoyaltyScheme>(server: S::Server) {
+        let clients = setup::<S>(&server);
+        let wallet = export(&clients[0], PASSPHRASE).unwrap();
+        assert_eq!(import::<S::Client>(&wallet, "incorrect horse battery staple").err(),
+                   Some(CheckoutError::WrongPassphrase));
+        assert_eq!(import::<Other::Client>(&wallet, PASSPHRASE).err(), Some(CheckoutError::WrongScheme));
+
+        let mut tampered = wallet.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert_eq!(import::<S::Client>(&tampered, PASSPHRASE).err(), Some(CheckoutError::WrongPassphrase));
+    }
+
+    fn check_other_version<S: LoyaltyScheme>(server: S::Server) {
+        let clients = setup::<S>(&server);
+        let pt = Zeroizing::new(bincode::serialize(&clients[0]).unwrap());
+        for version in [0, WALLET_VERSION + 1] {
+            let wallet = seal((version, S::LEVEL), &pt, PASSPHRASE).unwrap();
+            assert_eq!(import::<S::Client>(&wallet, PASSPHRASE).err(), Some(CheckoutError::UnsupportedVersion));
+        }
+    }
+
+    #[test]
+    fn mal_round_trip() {
+        check_round_trip::<lib_mal::Malicious>(lib_mal::Server::new(), 0);
+    }
+
+    #[test]
+    fn sh_round_trip() {
+        check_round_trip::<lib_sh::SemiHonest>(lib_sh::Server::new(), 1);
+    }
+
+    #[test]
+    fn swap_only_round_trip() {
+        let server = lib_sh_swap_only::Server::new();
+        let clients = setup::<lib_sh_swap_only::SwapOnly>(&server);
+        let mut restored = restore::<lib_sh_swap_only::SwapOnly>(&clients);
+
+        // The restored clients keep their barcodes and keys, and still sign as
+        // their users
+        for (client, restored) in clients.iter().zip(&restored) {
+            assert_eq!(restored.register_with_server(), client.register_with_server());
+        }
+        scheme::transact::<lib_sh_swap_only::SwapOnly>(&server, &mut restored[0], 0, DEFAULT_MERCHANT, &[]).unwrap();
+    }
+
+    #[test]
+    fn mal_wrong_passphrase() {
+        check_wrong_passphrase::<lib_mal::Malicious, lib_sh::SemiHonest>(lib_mal::Server::new());
+    }
+
+    #[test]
+    fn sh_wrong_passphrase() {
+        check_wrong_passphrase::<lib_sh::SemiHonest, lib_mal::Malicious>(lib_sh::Server::new());
+    }
+
+    #[test]
+    fn swap_only_wrong_passphrase() {
+        check_wrong_passphrase::<lib_sh_swap_only::SwapOnly, lib_sh::SemiHonest>(lib_sh_swap_only::Server::new());
+    }
+
+    #[test]
+    fn mal_other_version() {
+        check_other_version::<lib_mal::Malicious>(lib_mal::Server::new());
+    }
+
+    #[test]
+    fn sh_other_version() {
+        check_other_version::<lib_sh::SemiHonest>(lib_sh::Server::new());
+    }
+
+    #[test]
+    fn swap_only_other_version() {
+        check_other_version::<lib_sh_swap_only::SwapOnly>(lib_sh_swap_only::Server::new());
+    }
+}
@@ -0,0 +1,207 @@
+// Versioned message envelopes for sending protocol messages between a client
+// and a server. Every message is wrapped with the protocol version it was
+// encoded with, the scheme it belongs to and the ID of the transaction it is
+// part of, so the receiver can reject a message it cannot interpret instead
+// of misreading its bytes. Only the current version is encoded; a message
+// from an older version is rejected rather than translated.
+//
+// Wire format (bincode): version (u16), scheme ID, tx ID (32 bytes), then the
+// message itself. The first three fields are the same for every version.
+
+use serde_derive::{Serialize, Deserialize};
+use ed25519_dalek::Signature;
+use crate::coin::{ClientShare, SelectionPolicy, ServerShare};
+use crate::error::{CheckoutError, Result};
+use crate::scheme::{Account, Com, LoyaltyScheme, MerchantId, Root, SecurityLevel, ServerId};
+
+// Version of the wire format produced by this library. Changes:
+//   2: paged, acknowledged receipt delivery
+//   3: proofs bound to the server and transaction
+//   4: range proofs on transactions
+//   5: 32-byte coin flip shares
+//   6: barcode selection policy
+//   7: `TxAbort`
+//   8: settlement epochs and receipts
+//   9: redemptions
+//  10: merchants
+//  11: point expiry
+//  12: point categories
+//  13: signed transaction hellos
+//  14: signed receipt acknowledgments, and `GetReceipts` no longer acknowledges
+//  15: redemptions split over the periods their points were earned in
+//  16: settle proofs bind each receipt's amount
+//  17: index coin flip shares are the 32 bytes the index is drawn from
+pub const PROTOCOL_VERSION: u16 = 17;
+// Oldest version this library decodes. Messages are only encoded in the
+// current layout, so this is raised with every change to a message, and a
+// peer on an older version is answered with `UnsupportedVersion`.
+pub const MIN_PROTOCOL_VERSION: u16 = 17;
+
+// Transaction ID used by messages that are not part of a transaction
+pub const NO_TX: Com = [0u8; 32];
+
+// One step of the protocol. The transaction ID is carried by the envelope,
+// so the variants only hold what the scheme functions take beyond that.
+// Variants are only added at the end, so each keeps its encoding.
+#[derive(Serialize, Deserialize)]
+#[serde(bound = "")]
+pub enum Message<S: LoyaltyScheme> {
+    // Client -> server: start a transaction with a merchant (the tx ID is the
+    // commitment), signed by the shopper
+    TxHello { uid_s: u32, merchant: MerchantId, sigma: Signature },
+    // Server -> client: the server's share of the coin flip
+    TxHelloResponse { i_s: ServerShare },
+    // Client -> server: opened commitment to the client's share
+    TxOpen { i_c: ClientShare, r: [u8; 32] },
+    // Server -> client: barcode owner's details
+    BarcodeGen(S::BarcodeGen),
+    // Client -> server: encrypted points and proof
+    TxSubmit(S::TxSubmit),
+    // Server -> client: completes the transaction
+    TxSignature(S::TxSignature),
+    // Server -> client: reply to `GetReceipts`
+    ReceiptBatch { uid: u32, rcts: S::Receipts },
+    // Server -> client: the server's view of a balance to be settled
+    SettleHello { uid: u32, hello: S::SettleHello },
+    // Client -> server: revealed balance and proof
+    SettleRequest { uid: u32, account: Account, req: S::SettleRequest },
+    // Server -> client: outcome of a settle request
+    SettleResult { uid: u32, result: std::result::Result<S::Settlement, CheckoutError> },
+    // Client -> server: register a new user
+    Register(S::Registration),
+    // Server -> client: the newly registered user's ID
+    Registered { uid: u32 },
+    // Client -> server: ask for the number of users and the Merkle root
+    GetState,
+    // Server -> client: number of users, the Merkle root, the server's ID, the
+    // most points a transaction may move and the barcode selection policy
+    State { num_users: u32, root: Root, server_id: ServerId, max_points: u32, policy: SelectionPolicy },
+    // Client -> server: ask for the first page of a user's pending receipts.
+    // They stay queued until acknowledged with `AckReceipts`.
+    GetReceipts { uid: u32 },
+    // Client -> server: start settling a user's balance in an account
+    SettleStart { uid: u32, account: Account },
+    // Server -> client: the request could not be processed
+    Error(CheckoutError),
+    // Client -> server: ask for up to `limit` of a user's receipts queued
+    // after sequence number `after`
+    FetchReceipts { uid: u32, after: u64, limit: u32 },
+    // Server -> client: one page of receipts, the sequence number of the last
+    // one, and whether more follow
+    ReceiptPage { uid: u32, rcts: S::Receipts, last: u64, more: bool },
+    // Client -> server: the user has processed their receipts up to `upto`,
+    // signed by the user
+    AckReceipts { uid: u32, upto: u64, sigma: Signature },
+    // Server -> client: reply to `AckReceipts`
+    Acked { uid: u32 },
+    // Client -> server: the client gives up on the transaction
+    TxAbort,
+    // Server -> client: reply to `TxAbort`
+    TxAborted,
+    // Client -> server: redeem part of a balance. Sent after `SettleStart`.
+    RedeemRequest { uid: u32, account: Account, req: S::RedeemRequest },
+    // Server -> client: outcome of a redeem request
+    RedeemResult { uid: u32, result: std::result::Result<S::Redemption, CheckoutError> },
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(bound = "")]
+pub struct Envelope<S: LoyaltyScheme> {
+    pub version: u16,
+    pub scheme: SecurityLevel,
+    pub tx_id: Com,
+    pub msg: Message<S>,
+}
+
+// The fixed prefix of every envelope, decoded before the message so that the
+// version and scheme can be checked first.
+#[derive(Deserialize)]
+struct Header {
+    version: u16,
+    scheme: SecurityLevel,
+    _tx_id: Com,
+}
+
+impl<S: LoyaltyScheme> Envelope<S> {
+    pub fn new(tx_id: Com, msg: Message<S>) -> Self {
+        Envelope {
+            version: PROTOCOL_VERSION,
+            scheme: S::LEVEL,
+            tx_id,
+            msg,
+        }
+    }
+
+    // Answer this message in the same transaction
+    pub fn reply(&self, msg: Message<S>) -> Self {
+        Envelope::new(self.tx_id, msg)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        // Encoding into memory can't fail for any of the message types
+        bincode::serialize(self).expect("message encoding failed")
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let header: Header = bincode::deserialize(bytes)
+            .map_err(|_| CheckoutError::MalformedMessage)?;
+        if header.version < MIN_PROTOCOL_VERSION || header.version > PROTOCOL_VERSION {
+            return Err(CheckoutError::UnsupportedVersion);
+        }
+        if header.scheme != S::LEVEL {
+            return Err(CheckoutError::WrongScheme);
+        }
+
+        bincode::deserialize(bytes).map_err(|_| CheckoutError::MalformedMessage)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{lib_mal, lib_sh};
+
+    type Mal = lib_mal::Malicious;
+
+    // Output: a `GetState` envelope in a transaction, with its version
+    // set to `version`, encoded
+    fn encoded(version: u16) -> Vec<u8> {
+        let mut env = Envelope::<Mal>::new([7; 32], Message::GetState);
+        env.version = version;
+        env.to_bytes()
+    }
+
+    #[test]
+    fn round_trip() {
+        let env = Envelope::<Mal>::from_bytes(&encoded(PROTOCOL_VERSION)).unwrap();
+        assert_eq!((env.version, env.scheme, env.tx_id), (PROTOCOL_VERSION, SecurityLevel::Malicious, [7; 32]));
+        assert!(matches!(env.msg, Message::GetState));
+
+        // A reply stays in the same transaction
+        let reply = env.reply(Message::Registered { uid: 3 });
+        let reply = Envelope::<Mal>::from_bytes(&reply.to_bytes()).unwrap();
+        assert_eq!(reply.tx_id, [7; 32]);
+        assert!(matches!(reply.msg, Message::Registered { uid: 3 }));
+    }
+
+    #[test]
+    fn other_versions_are_refused() {
+        for version in [MIN_PROTOCOL_VERSION - 1, PROTOCOL_VERSION + 1] {
+            assert_eq!(Envelope::<Mal>::from_bytes(&encoded(version)).err(), Some(CheckoutError::UnsupportedVersion));
+        }
+    }
+
+    #[test]
+    fn other_scheme_is_refused() {
+        assert_eq!(Envelope::<lib_sh::SemiHonest>::from_bytes(&encoded(PROTOCOL_VERSION)).err(),
+                   Some(CheckoutError::WrongScheme));
+    }
+
+    #[test]
+    fn truncated_message_is_refused() {
+        let bytes = Envelope::<Mal>::new(NO_TX, Message::Registered { uid: 3 }).to_bytes();
+        for cut in [4, bytes.len() - 1] {
+            assert_eq!(Envelope::<Mal>::from_bytes(&bytes[..cut]).err(), Some(CheckoutError::MalformedMessage));
+        }
+    }
+}
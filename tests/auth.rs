@@ -0,0 +1,42 @@
+// A transaction can only be started by the user it takes points from
+
+mod common;
+
+use checkout::error::CheckoutError;
+use checkout::scheme::{self, DEFAULT_MERCHANT};
+use checkout::{lib_mal, lib_sh, lib_sh_swap_only, LoyaltyScheme, SchemeClient, SchemeServer};
+
+// Mallory signs a hello in her own name and sends it as Alice's
+fn check_hello_in_someone_elses_name<S: LoyaltyScheme>(server: S::Server) {
+    let mut clients = common::register::<S>(&server, 3);
+    let (alice, mallory) = (0, 2);
+
+    let (com, sigma) = clients[mallory].process_tx_hello(DEFAULT_MERCHANT);
+    assert_eq!(server.process_tx_hello_response(com, alice as u32, DEFAULT_MERCHANT, &sigma).err(),
+               Some(CheckoutError::Unauthorized));
+
+    // Alice's own signature is not good for another merchant or user ID either
+    let (com, sigma) = clients[alice].process_tx_hello(DEFAULT_MERCHANT);
+    assert_eq!(server.process_tx_hello_response(com, mallory as u32, DEFAULT_MERCHANT, &sigma).err(),
+               Some(CheckoutError::Unauthorized));
+    assert_eq!(server.process_tx_hello_response(com, alice as u32, DEFAULT_MERCHANT + 1, &sigma).err(),
+               Some(CheckoutError::Unauthorized));
+    clients[alice].forget_tx(com);
+
+    scheme::transact::<S>(&server, &mut clients[alice], alice as u32, DEFAULT_MERCHANT, &[10]).unwrap();
+}
+
+#[test]
+fn mal_hello_in_someone_elses_name() {
+    check_hello_in_someone_elses_name::<lib_mal::Malicious>(lib_mal::Server::new());
+}
+
+#[test]
+fn sh_hello_in_someone_elses_name() {
+    check_hello_in_someone_elses_name::<lib_sh::SemiHonest>(lib_sh::Server::new());
+}
+
+#[test]
+fn swap_only_hello_in_someone_elses_name() {
+    check_hello_in_someone_elses_name::<lib_sh_swap_only::SwapOnly>(lib_sh_swap_only::Server::new());
+}
@@ -0,0 +1,20 @@
+// Shared setup for the integration tests
+
+use checkout::{LoyaltyScheme, SchemeClient, SchemeServer};
+
+// Registers `n` new clients with the server and tells each of them the
+// server's state.
+// Output: the clients, in order of user ID
+pub fn register<S: LoyaltyScheme>(server: &S::Server, n: u32) -> Vec<S::Client> {
+    let mut clients = Vec::new();
+    for i in 0..n {
+        let client = <S::Client as SchemeClient>::new(i as u64);
+        assert_eq!(server.register_user(client.register_with_server()).unwrap(), i);
+        clients.push(client);
+    }
+    let (num_users, root, server_id, max_points, policy) = server.share_state().unwrap();
+    for (uid, client) in clients.iter_mut().enumerate() {
+        client.update_state(uid as u32, num_users, root, server_id, max_points, policy.clone());
+    }
+    clients
+}
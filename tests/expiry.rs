@@ -0,0 +1,50 @@
+// Redeemed points are taken from the periods they were earned in, and expire
+// with them
+
+mod common;
+
+use std::time::Duration;
+use checkout::expiry::{Expiry, Period};
+use checkout::scheme::{self, DEFAULT_ACCOUNT, DEFAULT_MERCHANT};
+use checkout::settlement::Settlement;
+use checkout::{lib_mal, lib_sh, LoyaltyScheme, SchemeClient, SchemeServer};
+
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+// Periods follow the wall clock, so time is moved forward by shortening them:
+// a period of the default length is a long-past period in days.
+// Output: periods of a day, the oldest unexpired one being `oldest`
+fn days_from(oldest: Period) -> Expiry {
+    let current = Expiry { period_length: DAY, lifetime: None }.current();
+    Expiry { period_length: DAY, lifetime: Some(current + 1 - oldest) }
+}
+
+// Earns 100 points in one period, redeems them in a later one, and lets the
+// first period expire
+// Input: a server, how to set its expiry, and which of two users earns the
+// points when user 0 shops
+fn check_redeemed_points_expire<S>(mut server: S::Server, set_expiry: fn(&mut S::Server, Expiry), earner: usize)
+where S: LoyaltyScheme<Settlement = Settlement> {
+    let mut clients = common::register::<S>(&server, 2);
+    let earned = Expiry::default().current();
+    scheme::transact::<S>(&server, &mut clients[0], 0, DEFAULT_MERCHANT, &[100]).unwrap();
+
+    set_expiry(&mut server, days_from(earned));
+    scheme::redeem::<S>(&server, &mut clients[earner], earner as u32, DEFAULT_ACCOUNT, 100).unwrap();
+
+    set_expiry(&mut server, days_from(earned + 1));
+    server.retire_expired().unwrap();
+    let hello = server.settle_balance_hello(earner as u32, DEFAULT_ACCOUNT).unwrap();
+    let req = clients[earner].settle_balance(hello).unwrap();
+    assert_eq!(server.settle_balance(earner as u32, DEFAULT_ACCOUNT, req).unwrap().points, 0);
+}
+
+#[test]
+fn mal_redeemed_points_expire() {
+    check_redeemed_points_expire::<lib_mal::Malicious>(lib_mal::Server::new(), lib_mal::Server::set_expiry, 0);
+}
+
+#[test]
+fn sh_redeemed_points_expire() {
+    check_redeemed_points_expire::<lib_sh::SemiHonest>(lib_sh::Server::new(), lib_sh::Server::set_expiry, 1);
+}
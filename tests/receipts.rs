@@ -0,0 +1,44 @@
+// Only the user a receipt is for can take it off the server
+
+mod common;
+
+use checkout::error::CheckoutError;
+use checkout::lib_mal::{Malicious, Server};
+use checkout::net;
+use checkout::scheme::{self, DEFAULT_MERCHANT, MAX_RECEIPT_PAGE};
+use checkout::wire::{Envelope, Message, NO_TX};
+
+// Output: the sequence number of the last receipt queued for a user
+fn last_queued(server: &Server, uid: u32) -> u64 {
+    server.send_receipts(uid, 0, MAX_RECEIPT_PAGE).unwrap().1
+}
+
+#[test]
+fn ack_needs_the_users_signature() {
+    let server = Server::new();
+    let mut clients = common::register::<Malicious>(&server, 3);
+    for _ in 0..10 {
+        scheme::transact::<Malicious>(&server, &mut clients[0], 0, DEFAULT_MERCHANT, &[5]).unwrap();
+    }
+
+    // Shopper 0's barcodes belong to users 1 and 2, so one of them has receipts
+    // and the other tries to acknowledge them
+    let uid = (1..3).find(|&uid| last_queued(&server, uid) > 0).unwrap();
+    let mallory = 3 - uid as usize;
+    let last = last_queued(&server, uid);
+
+    let sigma = clients[mallory].ack_receipts(last);
+    assert_eq!(server.ack_receipts(uid, last, &sigma).err(), Some(CheckoutError::Unauthorized));
+    let sigma = clients[uid as usize].ack_receipts(last + 1);
+    assert_eq!(server.ack_receipts(uid, last, &sigma).err(), Some(CheckoutError::Unauthorized));
+    assert_eq!(last_queued(&server, uid), last);
+
+    // Asking for receipts over the network does not acknowledge them either
+    let reply = net::handle::<Malicious>(&server, Envelope::new(NO_TX, Message::GetReceipts { uid }));
+    assert!(matches!(reply.msg, Message::ReceiptBatch { rcts, .. } if !rcts.is_empty()));
+    assert_eq!(last_queued(&server, uid), last);
+
+    scheme::deliver_receipts::<Malicious>(&server, &mut clients[uid as usize], uid).unwrap();
+    let (rcts, _, more) = server.send_receipts(uid, 0, MAX_RECEIPT_PAGE).unwrap();
+    assert!(rcts.is_empty() && !more);
+}
@@ -0,0 +1,120 @@
+// A redemption only goes through once, for points the user has, against the
+// balance and epoch it was proven for
+
+mod common;
+
+use checkout::error::{CheckoutError, Result};
+use checkout::scheme::{self, DEFAULT_ACCOUNT, DEFAULT_MERCHANT};
+use checkout::settlement::Settlement;
+use checkout::{lib_mal, lib_sh, LoyaltyScheme, SchemeClient, SchemeServer};
+
+// Registers two users, and has user 0 shop for 100 points
+// Output: the server and the clients
+fn setup<S: LoyaltyScheme>(server: S::Server) -> (S::Server, Vec<S::Client>) {
+    let mut clients = common::register::<S>(&server, 2);
+    scheme::transact::<S>(&server, &mut clients[0], 0, DEFAULT_MERCHANT, &[100]).unwrap();
+    (server, clients)
+}
+
+// Output: the request to redeem `points` from the user's balance as it is now
+fn redeem_request<S: LoyaltyScheme>(server: &S::Server, client: &mut S::Client, uid: u32,
+                                    points: u32) -> Result<S::RedeemRequest> {
+    scheme::deliver_receipts::<S>(server, client, uid)?;
+    let hello = server.settle_balance_hello(uid, DEFAULT_ACCOUNT)?;
+    client.redeem(hello, points)
+}
+
+// Output: a copy of a request, as a server would receive it again
+fn resend<S: LoyaltyScheme>(req: &S::RedeemRequest) -> S::RedeemRequest {
+    bincode::deserialize(&bincode::serialize(req).unwrap()).unwrap()
+}
+
+// Output: the user's balance, which is settled to find it
+fn settled_balance<S>(server: &S::Server, client: &mut S::Client, uid: u32) -> i32
+where S: LoyaltyScheme<Settlement = Settlement> {
+    scheme::deliver_receipts::<S>(server, client, uid).unwrap();
+    let hello = server.settle_balance_hello(uid, DEFAULT_ACCOUNT).unwrap();
+    let req = client.settle_balance(hello).unwrap();
+    let settlement = server.settle_balance(uid, DEFAULT_ACCOUNT, req).unwrap();
+    client.finish_settlement(settlement).unwrap();
+    settlement.points
+}
+
+// Input: the user who earns the points when user 0 shops
+fn check_redeem_more_than_balance<S>(server: S::Server, earner: usize)
+where S: LoyaltyScheme<Settlement = Settlement> {
+    let (server, mut clients) = setup::<S>(server);
+    let uid = earner as u32;
+    assert_eq!(redeem_request::<S>(&server, &mut clients[earner], uid, 101).err(),
+               Some(CheckoutError::PointsOutOfRange));
+    assert_eq!(redeem_request::<S>(&server, &mut clients[earner], uid, 0).err(),
+               Some(CheckoutError::PointsOutOfRange));
+
+    scheme::redeem::<S>(&server, &mut clients[earner], uid, DEFAULT_ACCOUNT, 100).unwrap();
+    assert_eq!(redeem_request::<S>(&server, &mut clients[earner], uid, 1).err(),
+               Some(CheckoutError::PointsOutOfRange));
+    assert_eq!(settled_balance::<S>(&server, &mut clients[earner], uid), 0);
+}
+
+fn check_replayed_redeem<S>(server: S::Server, earner: usize)
+where S: LoyaltyScheme<Settlement = Settlement> {
+    let (server, mut clients) = setup::<S>(server);
+    let uid = earner as u32;
+    let req = redeem_request::<S>(&server, &mut clients[earner], uid, 30).unwrap();
+    let replayed = resend::<S>(&req);
+
+    let redemption = server.redeem(uid, DEFAULT_ACCOUNT, req).unwrap();
+    clients[earner].finish_redemption(redemption).unwrap();
+    assert_eq!(server.redeem(uid, DEFAULT_ACCOUNT, replayed).err(), Some(CheckoutError::InvalidProof));
+    assert_eq!(settled_balance::<S>(&server, &mut clients[earner], uid), 70);
+}
+
+fn check_stale_redeem<S>(server: S::Server, earner: usize)
+where S: LoyaltyScheme<Settlement = Settlement> {
+    let (server, mut clients) = setup::<S>(server);
+    let uid = earner as u32;
+
+    // The balance changed after the proof was made
+    let req = redeem_request::<S>(&server, &mut clients[earner], uid, 30).unwrap();
+    scheme::transact::<S>(&server, &mut clients[0], 0, DEFAULT_MERCHANT, &[10]).unwrap();
+    assert_eq!(server.redeem(uid, DEFAULT_ACCOUNT, req).err(), Some(CheckoutError::InvalidProof));
+
+    // The balance was settled after the proof was made, starting a new epoch
+    let req = redeem_request::<S>(&server, &mut clients[earner], uid, 30).unwrap();
+    let stale_hello = server.settle_balance_hello(uid, DEFAULT_ACCOUNT).unwrap();
+    assert_eq!(settled_balance::<S>(&server, &mut clients[earner], uid), 110);
+    assert_eq!(server.redeem(uid, DEFAULT_ACCOUNT, req).err(), Some(CheckoutError::InvalidProof));
+
+    // Nor does the client prove anything against an epoch it has moved past
+    assert_eq!(clients[earner].redeem(stale_hello, 1).err(), Some(CheckoutError::StaleSettlement));
+}
+
+#[test]
+fn mal_redeem_more_than_balance() {
+    check_redeem_more_than_balance::<lib_mal::Malicious>(lib_mal::Server::new(), 0);
+}
+
+#[test]
+fn sh_redeem_more_than_balance() {
+    check_redeem_more_than_balance::<lib_sh::SemiHonest>(lib_sh::Server::new(), 1);
+}
+
+#[test]
+fn mal_replayed_redeem() {
+    check_replayed_redeem::<lib_mal::Malicious>(lib_mal::Server::new(), 0);
+}
+
+#[test]
+fn sh_replayed_redeem() {
+    check_replayed_redeem::<lib_sh::SemiHonest>(lib_sh::Server::new(), 1);
+}
+
+#[test]
+fn mal_stale_redeem() {
+    check_stale_redeem::<lib_mal::Malicious>(lib_mal::Server::new(), 0);
+}
+
+#[test]
+fn sh_stale_redeem() {
+    check_stale_redeem::<lib_sh::SemiHonest>(lib_sh::Server::new(), 1);
+}